@@ -1,13 +1,45 @@
+use std::mem;
 use std::sync::mpsc;
-use file_handling::{AggregationMessages, FileHandlingMessages};
-use record_handling;
 use std::collections::HashMap;
+use std::io;
+use file_handling;
+use file_handling::{AggregationMessages, FileHandlingMessages};
+use std::io::Write;
 use FileAggregation;
+use record_handling;
+use spill;
 use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Instant;
+use status::{Phase, PhaseTimings, RunMetrics};
 
 pub struct AggregationController {
     agg_msg_receiver: mpsc::Receiver<AggregationMessages>,
     file_handling_msg_senders: Vec<mpsc::Sender<FileHandlingMessages>>,
+    metrics: Arc<RunMetrics>,
+    report_progress: bool,
+    listed_sizes: Arc<file_handling::FileSizes>,
+    profile: Option<Arc<PhaseTimings>>,
+    max_aggregates: Option<usize>,
+    merge_threads: Option<usize>,
+    keep_spill_files: bool,
+    count_overflow_policy: record_handling::CountOverflowPolicy,
+}
+
+/// Returned when the merged aggregation grew past the limit set by `limit_aggregates`.
+#[derive(Debug, PartialEq)]
+pub struct TooManyAggregates {
+    pub num_aggregates: usize,
+    pub max_aggregates: usize,
+}
+
+/// Returned when the aggregations of the file handlers couldn't be merged.
+#[derive(Debug)]
+pub enum AggregationError {
+    TooManyAggregates(TooManyAggregates),
+    /// The aggregations spilled to disk couldn't be read back, so their aggregates would be
+    /// missing from the results.
+    Spill(io::Error),
 }
 
 impl AggregationController {
@@ -15,19 +47,111 @@ impl AggregationController {
                file_handling_msg_senders: Vec<mpsc::Sender<FileHandlingMessages>>)
                -> AggregationController {
 
+        AggregationController::with_metrics(agg_msg_rec,
+                                            file_handling_msg_senders,
+                                            Arc::new(RunMetrics::new()))
+    }
+
+    pub fn with_metrics(agg_msg_rec: mpsc::Receiver<AggregationMessages>,
+                        file_handling_msg_senders: Vec<mpsc::Sender<FileHandlingMessages>>,
+                        metrics: Arc<RunMetrics>)
+                        -> AggregationController {
+
         AggregationController {
             agg_msg_receiver: agg_msg_rec,
             file_handling_msg_senders: file_handling_msg_senders,
+            metrics: metrics,
+            report_progress: false,
+            listed_sizes: Arc::new(file_handling::FileSizes::new()),
+            profile: None,
+            max_aggregates: None,
+            merge_threads: None,
+            keep_spill_files: false,
+            count_overflow_policy: record_handling::CountOverflowPolicy::Saturate,
         }
     }
 
-    pub fn run_aggregation(&mut self, mut filenames: &mut Vec<PathBuf>) -> FileAggregation {
-        let mut number_of_raw_records = 0;
+    /// Reports the percentage of bytes processed to stderr as files are finished.
+    pub fn enable_progress(&mut self) -> () {
+        self.report_progress = true;
+    }
+
+    /// Counts the files with the sizes they were listed with in the progress, see
+    /// `file_handling::total_file_size`.
+    pub fn set_listed_sizes(&mut self, listed_sizes: Arc<file_handling::FileSizes>) -> () {
+        self.listed_sizes = listed_sizes;
+    }
+
+    /// Adds the time spent merging the aggregations of the file handlers to the timings.
+    pub fn enable_profiling(&mut self, timings: Arc<PhaseTimings>) -> () {
+        self.profile = Some(timings);
+    }
+
+    /// Stops the aggregation once it holds more than `max_aggregates` distinct keys. The limit is
+    /// checked as the aggregation of each file handler is merged. The aggregation is also stopped
+    /// as soon as a file handler is merged while the file handlers hold more aggregates than that
+    /// between them, as they stop reading then, see
+    /// `file_handling::FileAggregatorConfig::max_aggregates`.
+    pub fn limit_aggregates(&mut self, max_aggregates: usize) -> () {
+        self.max_aggregates = Some(max_aggregates);
+    }
+
+    /// Merges the aggregation of every file handler as it arrives on `num_threads` threads, each
+    /// merging a partition of the keys, see `record_handling::PartitionedAggregation`, rather than
+    /// on this thread. The merged aggregation is left in `partitions` of the FileAggregation
+    /// rather than in `aggregation`.
+    pub fn merge_in_parallel(&mut self, num_threads: usize) -> () {
+        self.merge_threads = Some(num_threads);
+    }
+
+    /// Leaves the aggregations the file handlers spilled to disk in `spill_files` of the merged
+    /// aggregation rather than merging them back into memory, spilling the merged aggregation
+    /// along with them, so that they can be written as they're merged, see
+    /// `spill::for_each_spilled_aggregate`. The limit on the number of aggregates is only checked
+    /// on the aggregates held in memory.
+    pub fn keep_spill_files(&mut self) -> () {
+        self.keep_spill_files = true;
+    }
+
+    /// Adds the totals of the aggregations by the overflow policy rather than saturating them.
+    /// Totals that would overflow under the error policy are counted in `num_count_overflows` of
+    /// the merged aggregation.
+    pub fn set_count_overflow_policy(&mut self,
+                                     policy: record_handling::CountOverflowPolicy)
+                                     -> () {
+        self.count_overflow_policy = policy;
+    }
+
+    pub fn metrics(&self) -> Arc<RunMetrics> {
+        self.metrics.clone()
+    }
+
+    pub fn run_aggregation(&mut self,
+                           mut filenames: &mut Vec<PathBuf>)
+                           -> Result<FileAggregation, AggregationError> {
         let mut remaining_workers = self.file_handling_msg_senders.len();
-        let mut final_agg = HashMap::new();
+        let mut final_agg = FileAggregation::new();
+        // The first Next message from a file handler announces it has started, every one after
+        // that means it finished a file.
+        let mut started_workers = vec![false; self.file_handling_msg_senders.len()];
+        let total_bytes = if self.report_progress {
+            file_handling::total_file_size(filenames, &self.listed_sizes)
+        } else {
+            0
+        };
+        let mut bytes_read = 0;
+        let mut last_reported_percent = None;
+        let policy = self.count_overflow_policy;
+        let mut partitioned = self.merge_threads
+            .map(|num_threads| record_handling::PartitionedAggregation::new(num_threads, policy));
         loop {
             match self.agg_msg_receiver.recv() {
                 Ok(AggregationMessages::Next(sender_id)) => {
+                    if started_workers[sender_id] {
+                        self.metrics.file_processed();
+                    } else {
+                        started_workers[sender_id] = true;
+                    }
                     let sender = &self.file_handling_msg_senders[sender_id];
                     if let Some(filename) = filenames.pop() {
                         let _ = sender.send(FileHandlingMessages::Filename(filename));
@@ -35,10 +159,39 @@ impl AggregationController {
                         let _ = sender.send(FileHandlingMessages::Done);
                     }
                 }
-                Ok(AggregationMessages::Aggregate(num_parsed_records, new_agg)) => {
-                    debug!("Received new_agg having {} records.", new_agg.len());
-                    number_of_raw_records += num_parsed_records;
-                    record_handling::merge_aggregates(&new_agg, &mut final_agg);
+                Ok(AggregationMessages::BytesRead(num_bytes)) => {
+                    bytes_read += num_bytes;
+                    if self.report_progress {
+                        let percent = percent_complete(bytes_read, total_bytes);
+                        if last_reported_percent != Some(percent) {
+                            last_reported_percent = Some(percent);
+                            println_stderr!("{}", progress_message(bytes_read, total_bytes));
+                        }
+                    }
+                }
+                Ok(AggregationMessages::Aggregate(mut new_agg)) => {
+                    debug!("Received new_agg having {} records.", new_agg.aggregation.len());
+                    self.check_num_held_aggregates()?;
+                    let num_held_aggregates = new_agg.aggregation.len();
+                    let start = Instant::now();
+                    match partitioned {
+                        Some(ref mut partitioned) => {
+                            // The counts are still merged on this thread.
+                            partitioned.merge(mem::replace(&mut new_agg.aggregation,
+                                                           HashMap::new()));
+                            final_agg.merge_with_policy(&new_agg, policy);
+                            self.check_num_aggregates(partitioned.len())?;
+                        }
+                        None => {
+                            final_agg.merge_with_policy(&new_agg, policy);
+                            self.check_num_aggregates(final_agg.aggregation.len())?;
+                        }
+                    }
+                    if let Some(ref timings) = self.profile {
+                        timings.add(Phase::Merging, start.elapsed());
+                    }
+                    // The aggregates are now counted among the merged ones instead.
+                    self.metrics.remove_held_aggregates(num_held_aggregates);
                     remaining_workers -= 1;
                     if remaining_workers == 0 {
                         break;
@@ -48,21 +201,124 @@ impl AggregationController {
             }
         }
 
-        FileAggregation {
-            num_raw_records: number_of_raw_records,
-            aggregation: final_agg,
+        if let Some(partitioned) = partitioned {
+            final_agg.num_count_overflows += partitioned.num_overflows();
+            final_agg.partitions = partitioned.into_partitions();
+        }
+
+        if !final_agg.spill_files.is_empty() && self.keep_spill_files {
+            // Every partition is sorted into a spill file of its own.
+            for mut aggregation in final_agg.take_aggregations() {
+                if !aggregation.is_empty() {
+                    let path = spill::spill(&mut aggregation).map_err(AggregationError::Spill)?;
+                    final_agg.spill_files.push(path);
+                }
+            }
+        } else if !final_agg.spill_files.is_empty() {
+            final_agg.combine_partitions();
+            let spill_files = mem::replace(&mut final_agg.spill_files, Vec::new());
+            let merged = match self.profile {
+                Some(ref timings) => {
+                    timings.time(Phase::Merging, || {
+                        spill::merge_spill_files(&spill_files, &mut final_agg.aggregation, policy)
+                    })
+                }
+                None => spill::merge_spill_files(&spill_files, &mut final_agg.aggregation, policy),
+            };
+            spill::remove_spill_files(&spill_files);
+            final_agg.num_count_overflows += merged.map_err(AggregationError::Spill)?;
+            self.check_num_aggregates(final_agg.aggregation.len())?;
+        }
+
+        Ok(final_agg)
+    }
+
+    /// Fails once the file handlers hold more aggregates between them than the limit, as they
+    /// skipped the records left then and the merged aggregation would be missing them.
+    fn check_num_held_aggregates(&self) -> Result<(), AggregationError> {
+        let num_held_aggregates = self.metrics.num_held_aggregates();
+        match self.max_aggregates {
+            Some(max_aggregates) if num_held_aggregates > max_aggregates => {
+                Err(AggregationError::TooManyAggregates(TooManyAggregates {
+                    num_aggregates: num_held_aggregates,
+                    max_aggregates: max_aggregates,
+                }))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn check_num_aggregates(&self, num_aggregates: usize) -> Result<(), AggregationError> {
+        self.metrics.set_num_aggregates(num_aggregates);
+        match self.max_aggregates {
+            Some(max_aggregates) if num_aggregates > max_aggregates => {
+                Err(AggregationError::TooManyAggregates(TooManyAggregates {
+                    num_aggregates: num_aggregates,
+                    max_aggregates: max_aggregates,
+                }))
+            }
+            _ => Ok(()),
         }
     }
 }
 
+fn percent_complete(bytes_read: u64, total_bytes: u64) -> u64 {
+    if total_bytes == 0 {
+        100
+    } else {
+        (bytes_read * 100 / total_bytes).min(100)
+    }
+}
+
+/// Formats progress like "47% (3.2/6.8 GB)", scaling both sizes to the unit of the total.
+fn progress_message(bytes_read: u64, total_bytes: u64) -> String {
+    const UNITS: [&'static str; 5] = ["B", "KB", "MB", "GB", "TB"];
+    let mut unit_idx = 0;
+    let mut divisor = 1.0;
+    while unit_idx < UNITS.len() - 1 && total_bytes as f64 >= divisor * 1024.0 {
+        unit_idx += 1;
+        divisor *= 1024.0;
+    }
+
+    format!("{}% ({:.1}/{:.1} {})",
+            percent_complete(bytes_read, total_bytes),
+            bytes_read as f64 / divisor,
+            total_bytes as f64 / divisor,
+            UNITS[unit_idx])
+}
+
+#[cfg(test)]
+mod progress_message_tests {
+
+    #[test]
+    fn progress_message_should_scale_to_the_unit_of_the_total() {
+        let gigabyte = 1024 * 1024 * 1024;
+
+        let msg = super::progress_message(32 * gigabyte / 10, 68 * gigabyte / 10);
+
+        assert_eq!(msg, "47% (3.2/6.8 GB)")
+    }
+
+    #[test]
+    fn progress_message_should_report_complete_when_there_are_no_bytes() {
+        assert_eq!(super::progress_message(0, 0), "100% (0.0/0.0 B)")
+    }
+}
+
 #[cfg(test)]
 mod aggregation_controller_unit_tests {
 
-    use std::sync::mpsc;
+    use std::sync::{mpsc, Arc};
+    use status::RunMetrics;
     use test_common;
     use std::path::PathBuf;
+    use file_handling;
     use file_handling::{AggregationMessages, FileHandlingMessages};
-    use std::collections::HashMap;
+    use FileAggregation;
+    use record_handling;
+    use spill;
+    use std::fs::File;
+    use std::io::{BufRead, BufReader};
 
     #[test]
     fn run_aggregation_returns_when_all_of_the_file_handlers_have_sent_their_aggs() {
@@ -77,10 +333,10 @@ mod aggregation_controller_unit_tests {
         }
 
         for _ in 0..num_file_handlers {
-            let _ = agg_sndr.send(AggregationMessages::Aggregate(0, HashMap::new()));
+            let _ = agg_sndr.send(AggregationMessages::Aggregate(FileAggregation::new()));
         }
         let mut agg_ctrl = super::AggregationController::new(agg_recv, file_handler_senders);
-        let file_agg = agg_ctrl.run_aggregation(&mut Vec::new());
+        let file_agg = agg_ctrl.run_aggregation(&mut Vec::new()).unwrap();
 
         assert_eq!(file_agg.num_raw_records, 0);
         assert_eq!(file_agg.aggregation.len(), 0);
@@ -103,11 +359,10 @@ mod aggregation_controller_unit_tests {
         let _ = agg_sndr.send(AggregationMessages::Next(file_handler_of_interest));
         // Once all of the file_handlers are finished the main loop of the controller will shutdown.
         for _ in 0..num_file_handlers {
-            let _ = agg_sndr.send(AggregationMessages::Aggregate(test_common::TEST_LOG_FILE_AGGS,
-                                                                 HashMap::new()));
+            let _ = agg_sndr.send(AggregationMessages::Aggregate(test_file_aggregation()));
         }
         let mut agg_ctrl = super::AggregationController::new(agg_recv, file_handler_senders);
-        agg_ctrl.run_aggregation(&mut files);
+        let _ = agg_ctrl.run_aggregation(&mut files);
         let received_msg = file_handler_receivers[file_handler_of_interest].recv();
 
         assert_eq!(received_msg.unwrap(), FileHandlingMessages::Done);
@@ -136,11 +391,10 @@ mod aggregation_controller_unit_tests {
         }
         // Once all of the file_handlers are finished the main loop of the controller will shutdown.
         for _ in 0..num_file_handlers {
-            let _ = agg_sndr.send(AggregationMessages::Aggregate(test_common::TEST_LOG_FILE_AGGS,
-                                                                 HashMap::new()));
+            let _ = agg_sndr.send(AggregationMessages::Aggregate(test_file_aggregation()));
         }
         let mut agg_ctrl = super::AggregationController::new(agg_recv, file_handler_senders);
-        agg_ctrl.run_aggregation(&mut files);
+        let _ = agg_ctrl.run_aggregation(&mut files);
         for _ in 0..num_files {
             let received_msg = file_handler_receivers[file_handler_of_interest].recv();
 
@@ -170,14 +424,295 @@ mod aggregation_controller_unit_tests {
         let _ = agg_sndr.send(AggregationMessages::Next(file_handler_of_interest));
         // Once all of the file_handlers are finished the main loop of the controller will shutdown.
         for _ in 0..num_file_handlers {
-            let _ = agg_sndr.send(AggregationMessages::Aggregate(test_common::TEST_LOG_FILE_AGGS,
-                                                                 HashMap::new()));
+            let _ = agg_sndr.send(AggregationMessages::Aggregate(test_file_aggregation()));
         }
         let mut agg_ctrl = super::AggregationController::new(agg_recv, file_handler_senders);
-        agg_ctrl.run_aggregation(&mut files);
+        let _ = agg_ctrl.run_aggregation(&mut files);
         let received_msg = file_handler_receivers[file_handler_of_interest].recv();
 
         assert_eq!(received_msg.unwrap(),
         FileHandlingMessages::Filename(test_file_path_buf));
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn run_aggregation_updates_the_metrics_as_files_are_processed() {
+        let num_files = 3;
+        let test_file_path_buf = PathBuf::from(test_common::TEST_LOG_FILE);
+        let mut files = vec![test_file_path_buf; num_files];
+        let (sndr, _recv) = mpsc::channel();
+        let (agg_sndr, agg_recv) = mpsc::channel();
+        // The startup Next message plus one for each finished file and the final Done.
+        for _ in 0..num_files + 1 {
+            let _ = agg_sndr.send(AggregationMessages::Next(0));
+        }
+        let _ = agg_sndr.send(AggregationMessages::Aggregate(test_file_aggregation()));
+        let mut agg_ctrl = super::AggregationController::new(agg_recv, vec![sndr]);
+        let _ = agg_ctrl.run_aggregation(&mut files);
+        let metrics = agg_ctrl.metrics();
+
+        assert_eq!(metrics.files_processed(), num_files);
+        assert_eq!(metrics.num_aggregates(), 0);
+    }
+
+    #[test]
+    fn run_aggregation_sums_keys_repeated_across_many_partial_aggregations() {
+        let lines = BufReader::new(File::open(test_common::TEST_LOG_FILE).unwrap())
+            .lines()
+            .map(|line| line.unwrap())
+            .collect::<Vec<_>>();
+        let config = Default::default();
+        let mut whole_agg = FileAggregation::new();
+        for line in &lines {
+            record_handling::try_parse_record(line, None, &config, &mut whole_agg.aggregation);
+        }
+        // Small partials make the same keys arrive many times, as they would from a file flushed
+        // before it is finished.
+        let partial_size = 7;
+        let num_partials = (lines.len() + partial_size - 1) / partial_size;
+        let (sndr, _recv) = mpsc::channel();
+        let (agg_sndr, agg_recv) = mpsc::channel();
+        for chunk in lines.chunks(partial_size) {
+            let mut partial = FileAggregation::new();
+            for line in chunk {
+                record_handling::try_parse_record(line, None, &config, &mut partial.aggregation);
+            }
+            let _ = agg_sndr.send(AggregationMessages::Aggregate(partial));
+        }
+        let senders = vec![sndr; num_partials];
+        let mut agg_ctrl = super::AggregationController::new(agg_recv, senders);
+
+        let final_agg = agg_ctrl.run_aggregation(&mut Vec::new()).unwrap();
+
+        assert!(num_partials > 1);
+        assert_eq!(final_agg.aggregation, whole_agg.aggregation)
+    }
+
+    #[test]
+    fn run_aggregation_merges_in_parallel_to_the_same_aggregation() {
+        let config = Default::default();
+        let (sndr, _recv) = mpsc::channel();
+        let mut serial_agg = FileAggregation::new();
+        let (agg_sndr, agg_recv) = mpsc::channel();
+        let lines = BufReader::new(File::open(test_common::TEST_LOG_FILE).unwrap())
+            .lines()
+            .map(|line| line.unwrap())
+            .collect::<Vec<_>>();
+        let partial_size = 7;
+        let num_partials = (lines.len() + partial_size - 1) / partial_size;
+        for chunk in lines.chunks(partial_size) {
+            let mut partial = FileAggregation::new();
+            for line in chunk {
+                record_handling::try_parse_record(line, None, &config, &mut partial.aggregation);
+            }
+            partial.num_raw_records = chunk.len();
+            serial_agg.merge(&partial);
+            let _ = agg_sndr.send(AggregationMessages::Aggregate(partial));
+        }
+        let mut agg_ctrl = super::AggregationController::new(agg_recv, vec![sndr; num_partials]);
+        agg_ctrl.merge_in_parallel(3);
+
+        let mut final_agg = agg_ctrl.run_aggregation(&mut Vec::new()).unwrap();
+
+        assert_eq!(agg_ctrl.metrics().num_aggregates(), test_common::TEST_LOG_FILE_AGGS);
+        assert!(final_agg.aggregation.is_empty());
+        assert_eq!(final_agg.partitions.len(), 3);
+        assert_eq!(final_agg.num_aggregates(), test_common::TEST_LOG_FILE_AGGS);
+        final_agg.combine_partitions();
+        assert_eq!(final_agg, serial_agg)
+    }
+
+    #[test]
+    fn run_aggregation_counts_the_totals_overflowing_under_the_error_policy() {
+        let config = Default::default();
+        let mut file_agg = FileAggregation::new();
+        let records = BufReader::new(File::open(test_common::TEST_LOG_FILE).unwrap()).lines();
+        for line in records {
+            record_handling::try_parse_record(&line.unwrap(),
+                                              None,
+                                              &config,
+                                              &mut file_agg.aggregation);
+        }
+        let mut saturated_agg = FileAggregation::new();
+        for (agg_key, _) in &file_agg.aggregation {
+            saturated_agg.aggregation.insert(agg_key.clone(), record_handling::SATURATED_TOTAL);
+        }
+        let (sndr, _recv) = mpsc::channel();
+        let (agg_sndr, agg_recv) = mpsc::channel();
+        let _ = agg_sndr.send(AggregationMessages::Aggregate(saturated_agg));
+        let _ = agg_sndr.send(AggregationMessages::Aggregate(file_agg));
+        let mut agg_ctrl = super::AggregationController::new(agg_recv, vec![sndr.clone(), sndr]);
+        agg_ctrl.set_count_overflow_policy(record_handling::CountOverflowPolicy::Error);
+
+        let final_agg = agg_ctrl.run_aggregation(&mut Vec::new()).unwrap();
+
+        assert_eq!(final_agg.num_count_overflows, test_common::TEST_LOG_FILE_AGGS);
+        assert!(final_agg.aggregation
+            .values()
+            .all(|&total| total == record_handling::SATURATED_TOTAL))
+    }
+
+    #[test]
+    fn run_aggregation_checks_the_limit_when_merging_in_parallel() {
+        let config = Default::default();
+        let mut file_agg = FileAggregation::new();
+        let records = BufReader::new(File::open(test_common::TEST_LOG_FILE).unwrap()).lines();
+        for line in records {
+            record_handling::try_parse_record(&line.unwrap(),
+                                              None,
+                                              &config,
+                                              &mut file_agg.aggregation);
+        }
+        let (sndr, _recv) = mpsc::channel();
+        let (agg_sndr, agg_recv) = mpsc::channel();
+        let _ = agg_sndr.send(AggregationMessages::Aggregate(file_agg));
+        let mut agg_ctrl = super::AggregationController::new(agg_recv, vec![sndr]);
+        agg_ctrl.limit_aggregates(10);
+        agg_ctrl.merge_in_parallel(2);
+
+        let result = agg_ctrl.run_aggregation(&mut Vec::new());
+
+        match result {
+            Err(super::AggregationError::TooManyAggregates(too_many)) => {
+                assert_eq!(too_many,
+                           super::TooManyAggregates {
+                               num_aggregates: test_common::TEST_LOG_FILE_AGGS,
+                               max_aggregates: 10,
+                           })
+            }
+            _ => panic!("The aggregation should have been stopped by the limit."),
+        }
+    }
+
+    #[test]
+    fn run_aggregation_stops_once_there_are_more_aggregates_than_the_limit() {
+        let config = Default::default();
+        let mut file_agg = FileAggregation::new();
+        let records = BufReader::new(File::open(test_common::TEST_LOG_FILE).unwrap()).lines();
+        for line in records {
+            record_handling::try_parse_record(&line.unwrap(),
+                                              None,
+                                              &config,
+                                              &mut file_agg.aggregation);
+        }
+        let (sndr, _recv) = mpsc::channel();
+        let (agg_sndr, agg_recv) = mpsc::channel();
+        let _ = agg_sndr.send(AggregationMessages::Aggregate(file_agg));
+        let mut agg_ctrl = super::AggregationController::new(agg_recv, vec![sndr.clone(), sndr]);
+        agg_ctrl.limit_aggregates(10);
+
+        let result = agg_ctrl.run_aggregation(&mut Vec::new());
+
+        match result {
+            Err(super::AggregationError::TooManyAggregates(too_many)) => {
+                assert_eq!(too_many,
+                           super::TooManyAggregates {
+                               num_aggregates: test_common::TEST_LOG_FILE_AGGS,
+                               max_aggregates: 10,
+                           })
+            }
+            _ => panic!("The aggregation should have been stopped by the limit."),
+        }
+    }
+
+    #[test]
+    fn run_aggregation_stops_once_the_file_handlers_hold_more_aggregates_than_the_limit() {
+        let metrics = Arc::new(RunMetrics::new());
+        for _ in 0..11 {
+            metrics.add_held_aggregate();
+        }
+        let (sndr, _recv) = mpsc::channel();
+        let (agg_sndr, agg_recv) = mpsc::channel();
+        let _ = agg_sndr.send(AggregationMessages::Aggregate(FileAggregation::new()));
+        let mut agg_ctrl = super::AggregationController::with_metrics(agg_recv,
+                                                                      vec![sndr.clone(), sndr],
+                                                                      metrics);
+        agg_ctrl.limit_aggregates(10);
+
+        let result = agg_ctrl.run_aggregation(&mut Vec::new());
+
+        match result {
+            Err(super::AggregationError::TooManyAggregates(too_many)) => {
+                assert_eq!(too_many,
+                           super::TooManyAggregates {
+                               num_aggregates: 11,
+                               max_aggregates: 10,
+                           })
+            }
+            _ => panic!("The aggregation should have been stopped by the limit."),
+        }
+    }
+
+    #[test]
+    fn run_aggregation_uncounts_the_held_aggregates_of_a_file_handler_once_merged() {
+        let metrics = Arc::new(RunMetrics::new());
+        let mut file_agg = FileAggregation::new();
+        for system_name in &["sys1", "sys2"] {
+            file_agg.aggregation.insert(test_common::aggregate(system_name), 1);
+            metrics.add_held_aggregate();
+        }
+        metrics.add_held_aggregate();
+        let (sndr, _recv) = mpsc::channel();
+        let (agg_sndr, agg_recv) = mpsc::channel();
+        let _ = agg_sndr.send(AggregationMessages::Aggregate(file_agg));
+        let mut agg_ctrl =
+            super::AggregationController::with_metrics(agg_recv, vec![sndr], metrics.clone());
+
+        let _ = agg_ctrl.run_aggregation(&mut Vec::new());
+
+        assert_eq!(metrics.num_aggregates(), 2);
+        assert_eq!(metrics.num_held_aggregates(), 1)
+    }
+
+    #[test]
+    fn run_aggregation_spills_the_merged_aggregation_along_with_the_kept_spill_files() {
+        let mut spilled = FileAggregation::new();
+        spilled.aggregation.insert(test_common::aggregate("sys1"), 1);
+        spilled.spill_files.push(spill::spill(&mut spilled.aggregation).unwrap());
+        let mut in_memory = FileAggregation::new();
+        in_memory.aggregation.insert(test_common::aggregate("sys2"), 2);
+        let (sndr, _recv) = mpsc::channel();
+        let (agg_sndr, agg_recv) = mpsc::channel();
+        let _ = agg_sndr.send(AggregationMessages::Aggregate(spilled));
+        let _ = agg_sndr.send(AggregationMessages::Aggregate(in_memory));
+        let mut agg_ctrl = super::AggregationController::new(agg_recv, vec![sndr.clone(), sndr]);
+        agg_ctrl.keep_spill_files();
+
+        let final_agg = agg_ctrl.run_aggregation(&mut Vec::new()).unwrap();
+
+        let mut merged = FileAggregation::new();
+        let num_overflows = spill::merge_spill_files(&final_agg.spill_files,
+                                                     &mut merged.aggregation,
+                                                     record_handling::CountOverflowPolicy::Error);
+        spill::remove_spill_files(&final_agg.spill_files);
+        assert_eq!(num_overflows.unwrap(), 0);
+        assert!(final_agg.aggregation.is_empty());
+        assert_eq!(final_agg.spill_files.len(), 2);
+        assert_eq!(merged.aggregation.len(), 2)
+    }
+
+    #[test]
+    fn run_aggregation_fails_when_a_spilled_aggregation_cannot_be_read_back() {
+        let file_agg = FileAggregation {
+            spill_files: vec![::std::env::temp_dir().join("counter-spill-missing.tsv")],
+            ..FileAggregation::new()
+        };
+        let (sndr, _recv) = mpsc::channel();
+        let (agg_sndr, agg_recv) = mpsc::channel();
+        let _ = agg_sndr.send(AggregationMessages::Aggregate(file_agg));
+        let mut agg_ctrl = super::AggregationController::new(agg_recv, vec![sndr]);
+
+        let result = agg_ctrl.run_aggregation(&mut Vec::new());
+
+        match result {
+            Err(super::AggregationError::Spill(_)) => {}
+            _ => panic!("The missing spill file should have failed the aggregation."),
+        }
+    }
+
+    fn test_file_aggregation() -> FileAggregation {
+        FileAggregation {
+            num_raw_records: test_common::TEST_LOG_FILE_AGGS,
+            ..FileAggregation::new()
+        }
+    }
+}
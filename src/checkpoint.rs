@@ -0,0 +1,311 @@
+use std::collections::HashSet;
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use chrono::{Date, DateTime, UTC};
+use file_handling;
+use spill;
+use FileAggregation;
+
+/// The first line of a checkpoint starts with this, naming the version of the format, and ends
+/// with the options of the run that wrote it.
+const HEADER: &'static str = "counter-checkpoint\t2";
+
+/// The files a run has fully aggregated and the aggregation of them, written between batches of
+/// files so that a run that dies can be resumed without reading those files again.
+///
+/// A checkpoint is a text file of tab separated lines. The first holds the options, and every
+/// other line starts with what it holds: the counts of the aggregation, the first and last day,
+/// the subtotal and malformed records of a file, a file read or an aggregate, as its encoded key
+/// and total, see `spill::encode_key`. Error samples, timespans, metrics and the checks of the
+/// files for sorting and overlapping days aren't kept.
+#[derive(Debug, Default, PartialEq)]
+pub struct Checkpoint {
+    /// The options of the run that wrote the checkpoint, which a run resuming from it has to be
+    /// run with too for the aggregation to be of the same kind.
+    pub options: String,
+    pub read_files: HashSet<PathBuf>,
+    pub file_agg: FileAggregation,
+}
+
+impl Checkpoint {
+    pub fn new() -> Checkpoint {
+        Default::default()
+    }
+
+    /// An empty checkpoint of a run with these options.
+    pub fn with_options(options: String) -> Checkpoint {
+        Checkpoint { options: options, ..Default::default() }
+    }
+
+    /// Replaces the checkpoint at `path` with this one. The checkpoint is written next to it
+    /// first, synced to disk and renamed over it, so that a run or machine dying while writing
+    /// leaves the last checkpoint.
+    pub fn write(&self, path: &Path) -> io::Result<()> {
+        let mut partial_path = path.as_os_str().to_os_string();
+        partial_path.push(".partial");
+        let partial_path = PathBuf::from(partial_path);
+        {
+            let mut output = BufWriter::new(File::create(&partial_path)?);
+            self.write_to(&mut output)?;
+            output.flush()?;
+            // Otherwise the rename can reach the disk ahead of the checkpoint it names.
+            output.get_ref().sync_all()?;
+        }
+        fs::rename(&partial_path, path)?;
+        sync_parent_dir(path)
+    }
+
+    fn write_to<W: Write>(&self, output: &mut W) -> io::Result<()> {
+        writeln!(output, "{}\t{}", HEADER, spill::escape(&self.options))?;
+        let agg = &self.file_agg;
+        for &(name, count) in &[("raw", agg.num_raw_records),
+                                ("duplicate", agg.num_duplicate_records),
+                                ("fingerprint_collisions", agg.num_fingerprint_collisions),
+                                ("squashed", agg.num_squashed_records),
+                                ("no_system", agg.num_no_system_records),
+                                ("unweighted", agg.num_unweighted_records),
+                                ("excluded_status", agg.num_excluded_status_records),
+                                ("outside_date_range", agg.num_outside_date_range_records),
+                                ("outside_business_hours", agg.num_outside_business_hours_records),
+                                ("filtered", agg.num_filtered_records),
+                                ("malformed", agg.num_malformed_records),
+                                ("count_overflows", agg.num_count_overflows)] {
+            writeln!(output, "count\t{}\t{}", name, count)?;
+        }
+        for &(name, day) in &[("first", agg.first_day), ("last", agg.last_day)] {
+            if let Some(day) = day {
+                writeln!(output, "day\t{}\t{}", name, day.format("%Y-%m-%d"))?;
+            }
+        }
+        for (path, num_records) in &agg.file_subtotals {
+            writeln!(output, "subtotal\t{}\t{}", escape_path(path), num_records)?;
+        }
+        for (path, malformed) in &agg.malformed_records {
+            writeln!(output,
+                     "malformed_file\t{}\t{}\t{}\t{}",
+                     escape_path(path),
+                     malformed.num_records,
+                     malformed.first_line_num,
+                     spill::escape(&malformed.first_error))?;
+        }
+        // Sorted so that checkpoints of the same run are the same file.
+        let mut read_files = self.read_files.iter().collect::<Vec<_>>();
+        read_files.sort();
+        for path in read_files {
+            writeln!(output, "file\t{}", escape_path(path))?;
+        }
+        for (key, total) in &agg.aggregation {
+            writeln!(output, "aggregate\t{}\t{}", spill::encode_key(key), total)?;
+        }
+        Ok(())
+    }
+
+    /// Reads the checkpoint written to `path`, failing with invalid data for anything that isn't
+    /// a checkpoint of this version.
+    pub fn read(path: &Path) -> io::Result<Checkpoint> {
+        let mut lines = BufReader::new(File::open(path)?).lines();
+        let options = match lines.next() {
+            Some(line) => {
+                let line = line?;
+                let header = format!("{}\t", HEADER);
+                if !line.starts_with(&header) {
+                    return Err(invalid_data(&line));
+                }
+                spill::unescape(&line[header.len()..])
+            }
+            None => return Err(invalid_data("")),
+        };
+        let mut checkpoint = Checkpoint::with_options(options);
+        for line in lines {
+            let line = line?;
+            checkpoint.read_line(&line).ok_or_else(|| invalid_data(&line))?;
+        }
+        Ok(checkpoint)
+    }
+
+    fn read_line(&mut self, line: &str) -> Option<()> {
+        let agg = &mut self.file_agg;
+        let (kind, rest) = split_field(line)?;
+        match kind {
+            "count" => {
+                let (name, count) = split_field(rest)?;
+                let count = count.parse().ok()?;
+                match name {
+                    "raw" => agg.num_raw_records = count,
+                    "duplicate" => agg.num_duplicate_records = count,
+                    "fingerprint_collisions" => agg.num_fingerprint_collisions = count,
+                    "squashed" => agg.num_squashed_records = count,
+                    "no_system" => agg.num_no_system_records = count,
+                    "unweighted" => agg.num_unweighted_records = count,
+                    "excluded_status" => agg.num_excluded_status_records = count,
+                    "outside_date_range" => agg.num_outside_date_range_records = count,
+                    "outside_business_hours" => agg.num_outside_business_hours_records = count,
+                    "filtered" => agg.num_filtered_records = count,
+                    "malformed" => agg.num_malformed_records = count,
+                    "count_overflows" => agg.num_count_overflows = count,
+                    _ => return None,
+                }
+            }
+            "day" => {
+                let (name, day) = split_field(rest)?;
+                let day = parse_day(day)?;
+                match name {
+                    "first" => agg.first_day = Some(day),
+                    "last" => agg.last_day = Some(day),
+                    _ => return None,
+                }
+            }
+            "subtotal" => {
+                let (path, num_records) = split_field(rest)?;
+                agg.file_subtotals.insert(unescape_path(path), num_records.parse().ok()?);
+            }
+            "malformed_file" => {
+                let fields = rest.split('\t').collect::<Vec<_>>();
+                if fields.len() != 4 {
+                    return None;
+                }
+                agg.malformed_records.insert(unescape_path(fields[0]),
+                                             file_handling::MalformedRecords {
+                                                 num_records: fields[1].parse().ok()?,
+                                                 first_line_num: fields[2].parse().ok()?,
+                                                 first_error: spill::unescape(fields[3]),
+                                             });
+            }
+            "file" => {
+                self.read_files.insert(unescape_path(rest));
+            }
+            "aggregate" => {
+                // The key is itself tab separated, but the total can't hold a tab.
+                let split = rest.rfind('\t')?;
+                let key = spill::decode_key(&rest[..split])?;
+                agg.aggregation.insert(key, rest[split + 1..].parse().ok()?);
+            }
+            _ => return None,
+        }
+        Some(())
+    }
+}
+
+/// Syncs the directory holding `path`, so that a rename into it is on disk too.
+#[cfg(unix)]
+fn sync_parent_dir(path: &Path) -> io::Result<()> {
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    File::open(dir)?.sync_all()
+}
+
+/// Directories can't be opened to be synced on other platforms.
+#[cfg(not(unix))]
+fn sync_parent_dir(_: &Path) -> io::Result<()> {
+    Ok(())
+}
+
+fn split_field(line: &str) -> Option<(&str, &str)> {
+    line.find('\t').map(|split| (&line[..split], &line[split + 1..]))
+}
+
+fn parse_day(day: &str) -> Option<Date<UTC>> {
+    format!("{}T00:00:00Z", day).parse::<DateTime<UTC>>().ok().map(|day| day.date())
+}
+
+fn escape_path(path: &Path) -> String {
+    spill::escape(&path.to_string_lossy())
+}
+
+fn unescape_path(path: &str) -> PathBuf {
+    PathBuf::from(spill::unescape(path))
+}
+
+fn invalid_data(line: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData,
+                   format!("Not a line of a checkpoint: {}", line))
+}
+
+#[cfg(test)]
+mod checkpoint_tests {
+
+    use std::env;
+    use std::fs;
+    use std::fs::File;
+    use std::io::Write;
+    use std::path::PathBuf;
+    use chrono::{DateTime, UTC};
+    use file_handling::MalformedRecords;
+    use record_handling::AggregateELBRecord;
+    use super::Checkpoint;
+    use test_common;
+
+    fn checkpoint_path(name: &str) -> PathBuf {
+        let path = env::temp_dir().join(name);
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    fn test_checkpoint() -> Checkpoint {
+        let mut checkpoint = Checkpoint::with_options("--group-by\tsystem,day".to_owned());
+        let day = "2015-08-15T00:00:00Z".parse::<DateTime<UTC>>().unwrap().date();
+        let path = PathBuf::from("logs/with\ttab.log");
+        checkpoint.read_files.insert(path.clone());
+        checkpoint.read_files.insert(PathBuf::from("logs/other.log"));
+        checkpoint.file_agg.num_raw_records = 3;
+        checkpoint.file_agg.num_malformed_records = 1;
+        checkpoint.file_agg.first_day = Some(day);
+        checkpoint.file_agg.last_day = Some(day);
+        checkpoint.file_agg.file_subtotals.insert(path.clone(), 2);
+        checkpoint.file_agg.malformed_records.insert(path,
+                                                     MalformedRecords {
+                                                         num_records: 1,
+                                                         first_line_num: 4,
+                                                         first_error: "bad\nrecord".to_owned(),
+                                                     });
+        let key = AggregateELBRecord {
+            url: Some("/path?with\\escapes".to_owned()),
+            hour: Some(23),
+            elb_status_code: Some(200),
+            ..test_common::aggregate("sys\t1")
+        };
+        checkpoint.file_agg.aggregation.insert(key, 2);
+        checkpoint
+    }
+
+    #[test]
+    fn a_written_checkpoint_should_read_back_the_same() {
+        let path = checkpoint_path("counter_checkpoint_round_trip");
+        let checkpoint = test_checkpoint();
+
+        checkpoint.write(&path).unwrap();
+
+        assert_eq!(Checkpoint::read(&path).unwrap(), checkpoint);
+    }
+
+    #[test]
+    fn writing_a_checkpoint_should_replace_the_last_one() {
+        let path = checkpoint_path("counter_checkpoint_replace");
+        test_checkpoint().write(&path).unwrap();
+
+        Checkpoint::new().write(&path).unwrap();
+
+        assert_eq!(Checkpoint::read(&path).unwrap(), Checkpoint::new());
+    }
+
+    #[test]
+    fn reading_a_file_that_is_not_a_checkpoint_should_fail() {
+        let path = checkpoint_path("counter_checkpoint_invalid");
+        File::create(&path).unwrap().write_all(b"not a checkpoint\n").unwrap();
+
+        assert!(Checkpoint::read(&path).is_err());
+    }
+
+    #[test]
+    fn reading_a_checkpoint_without_the_options_should_fail() {
+        let path = checkpoint_path("counter_checkpoint_first_version");
+        File::create(&path).unwrap().write_all(b"counter-checkpoint\t1\nfile\ta.log\n").unwrap();
+
+        assert!(Checkpoint::read(&path).is_err());
+    }
+}
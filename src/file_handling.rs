@@ -1,27 +1,38 @@
+use std::borrow::Cow;
+use std::fs;
 use std::fs::File;
 use std::io;
-use std::io::{BufRead, BufReader};
+use std::io::{BufRead, BufReader, BufWriter, Read};
 use walkdir;
 use walkdir::WalkDir;
-use ELBRecordAggregation;
-use std::collections::HashMap;
+use {ELBRecordAggregation, FileAggregation};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::collections::hash_map::{DefaultHasher, Entry};
+use std::hash::{Hash, Hasher};
+use output;
 use record_handling;
+use spill;
+use status::{Phase, PhaseTimings, RunMetrics};
 use std::io::Write;
-use std::sync::mpsc;
+use std::sync::{mpsc, Arc, Mutex};
 use std::sync::mpsc::RecvTimeoutError;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::thread;
+use std::net::Ipv4Addr;
+use chrono::{Date, DateTime, UTC};
+use flate2::read::MultiGzDecoder;
+use rand::{Rng, SeedableRng, XorShiftRng};
+#[cfg(feature = "tar")]
+use tar;
 
 pub fn file_list(dir: &Path) -> Result<Vec<PathBuf>, walkdir::Error> {
     let mut filenames = Vec::new();
     let dir_entries = WalkDir::new(dir);
     for entry in dir_entries {
         let dir_entry = entry?;
-        if dir_entry
-            .path()
-            .extension()
-            .map(|ext| ext.eq("log"))
-            .unwrap_or(false) {
+        if is_log_file(dir_entry.path()) || is_archive(dir_entry.path()) {
             filenames.push(dir_entry.path().to_path_buf());
         }
     }
@@ -29,9 +40,135 @@ pub fn file_list(dir: &Path) -> Result<Vec<PathBuf>, walkdir::Error> {
     Ok(filenames)
 }
 
+/// The sizes the files of a listing were listed with, for the files whose size can't be looked
+/// up on disk, such as S3 objects.
+pub type FileSizes = HashMap<PathBuf, u64>;
+
+/// Lists the log files of the log location, which is either a directory, see `file_list`, or an
+/// `s3://bucket/prefix` URI, see `s3_file_list`, along with the sizes of the S3 objects listed.
+pub fn log_file_list(location: &Path) -> Result<(Vec<PathBuf>, FileSizes), String> {
+    if is_s3_uri(location) {
+        let objects = s3_file_list(location)?;
+        let sizes = objects.iter().cloned().collect();
+        Ok((objects.into_iter().map(|(path, _)| path).collect(), sizes))
+    } else {
+        let files = file_list(location).map_err(|err| err.to_string())?;
+        Ok((files, FileSizes::new()))
+    }
+}
+
+/// Lists the log files under an S3 prefix with `aws s3 ls --recursive`, so the credentials and
+/// region the AWS CLI is configured with are used. The objects are listed as `s3://bucket/key`
+/// paths, which FileAggregators stream the objects from, see `is_s3_uri`, with the sizes they were
+/// listed with for the progress of the run, see `total_file_size`.
+pub fn s3_file_list(location: &Path) -> Result<Vec<(PathBuf, u64)>, String> {
+    let uri = location.to_string_lossy();
+    let bucket = uri.trim_left_matches("s3://").split('/').next().unwrap_or("");
+    let output = Command::new("aws").args(&["s3", "ls", "--recursive"])
+        .arg(uri.as_ref())
+        .stderr(Stdio::inherit())
+        .output()
+        .map_err(|err| format!("Couldn't run aws s3 ls on {}: {}", uri, aws_command_error(err)))?;
+    if !output.status.success() {
+        return Err(format!("aws s3 ls on {} exited with {}", uri, output.status));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .filter_map(s3_listing_entry)
+        .map(|(size, key)| (PathBuf::from(format!("s3://{}/{}", bucket, key)), size))
+        .filter(|&(ref path, _)| is_log_file(path) || is_archive(path))
+        .collect())
+}
+
+/// The error of starting the aws command, which says the AWS CLI is needed when the command
+/// isn't found rather than only that no such file exists.
+fn aws_command_error(err: io::Error) -> io::Error {
+    if err.kind() != io::ErrorKind::NotFound {
+        return err;
+    }
+    io::Error::new(io::ErrorKind::NotFound,
+                   "the aws command wasn't found, and S3 is read with the AWS CLI, which has to \
+                    be installed and on the PATH")
+}
+
+/// Finds the size and key in a line of `aws s3 ls --recursive`, which lists an object as its
+/// date, time, size and key, such as `2015-08-15 23:45:01      12345 logs/file.log`.
+fn s3_listing_entry(line: &str) -> Option<(u64, &str)> {
+    let mut rest = line;
+    let mut size = "";
+    for _ in 0..3 {
+        rest = rest.trim_left();
+        let field_end = rest.find(char::is_whitespace)?;
+        size = &rest[..field_end];
+        rest = &rest[field_end..];
+    }
+    match rest.trim_left() {
+        "" => None,
+        key => Some((size.parse().ok()?, key)),
+    }
+}
+
+/// Log locations and files given as `s3://` URIs are read from S3 rather than from disk.
+pub fn is_s3_uri(path: &Path) -> bool {
+    path.to_str().map_or(false, |path| path.starts_with("s3://"))
+}
+
+fn has_extension(path: &Path, extension: &str) -> bool {
+    path.extension()
+        .map(|ext| ext.eq(extension))
+        .unwrap_or(false)
+}
+
+fn is_log_file(path: &Path) -> bool {
+    has_extension(path, "log") || is_gzipped(path)
+}
+
+/// Gzipped files are decompressed as they are read, so they needn't be decompressed beforehand.
+fn is_gzipped(path: &Path) -> bool {
+    has_extension(path, "gz")
+}
+
+/// Archives are treated as a single unit of work, their log file members are read by whichever
+/// FileAggregator receives the archive.
+#[cfg(feature = "tar")]
+fn is_archive(path: &Path) -> bool {
+    has_extension(path, "tar") || is_gzipped_archive(path)
+}
+
+/// `.tar.gz` and `.tgz` archives are decompressed as they are read, like gzipped log files.
+#[cfg(feature = "tar")]
+fn is_gzipped_archive(path: &Path) -> bool {
+    let has_tar_stem = path.file_stem()
+        .map_or(false, |stem| has_extension(Path::new(stem), "tar"));
+    has_extension(path, "tgz") || (is_gzipped(path) && has_tar_stem)
+}
+
+#[cfg(not(feature = "tar"))]
+fn is_archive(_: &Path) -> bool {
+    false
+}
+
+/// The combined size of the files, used to estimate how far through a run we are. Files count
+/// with the size they were listed with, see `log_file_list`, and S3 objects count as empty when
+/// they weren't listed.
+pub fn total_file_size(filenames: &[PathBuf], listed_sizes: &FileSizes) -> u64 {
+    filenames.iter().map(|filename| file_size(filename, listed_sizes)).sum()
+}
+
+fn file_size(path: &Path, listed_sizes: &FileSizes) -> u64 {
+    if let Some(&size) = listed_sizes.get(path) {
+        return size;
+    }
+    if is_s3_uri(path) {
+        return 0;
+    }
+    fs::metadata(path).map(|metadata| metadata.len()).unwrap_or(0)
+}
+
 #[derive(Debug, PartialEq)]
 pub enum AggregationMessages {
-    Aggregate(usize, ELBRecordAggregation),
+    Aggregate(FileAggregation),
+    BytesRead(u64),
     Next(usize),
 }
 
@@ -41,223 +178,2743 @@ pub enum FileHandlingMessages {
     Done,
 }
 
-#[derive(Debug)]
-enum FileHandlingErrors<'a> {
-    FileReadError { path: &'a Path, err: io::Error },
-    LineReadError {
-        path: &'a Path,
-        line_nums: Vec<usize>,
-    },
-}
+#[derive(Debug)]
+enum FileHandlingErrors<'a> {
+    FileReadError { path: &'a Path, err: io::Error },
+    LineReadError {
+        path: &'a Path,
+        line_nums: Vec<usize>,
+    },
+    TooFewRecords {
+        path: &'a Path,
+        num_lines: u64,
+        num_parsed: u64,
+    },
+    TimedOut { path: &'a Path, num_lines: u64 },
+    ParserCommandFailed { path: &'a Path, err: String },
+    DayRangeExceeded { path: &'a Path, num_days: i64 },
+    CountOverflowed { path: &'a Path, line_num: usize },
+    TooManyAggregates { path: &'a Path, num_aggregates: usize },
+}
+
+/// Controls which records are treated as duplicates of one another. A record is a duplicate
+/// when its line is identical to one already seen, and duplicates are skipped before they are
+/// aggregated. Lines are remembered by two independent 64 bit fingerprints rather than by their
+/// content, and a line is only taken for a duplicate when both match those of a line seen
+/// before. A line sharing only the first fingerprint of an earlier line is a collision, which
+/// is aggregated and counted in `FileAggregation::num_fingerprint_collisions`.
+#[derive(Clone, Debug)]
+pub enum RecordDeduplication {
+    /// Only lines within the same file are compared. The fingerprints are dropped once the file
+    /// is finished, so memory is bounded by the largest file.
+    PerFile,
+    /// Lines are compared across every file in the run. The fingerprints are shared by all of
+    /// the FileAggregators and kept for the whole run, costing roughly 16 bytes plus hash map
+    /// overhead for every distinct record.
+    Global(Arc<Mutex<HashMap<u64, u64>>>),
+}
+
+/// A named aggregation dimension taken from the directory `depth` levels below `log_root` that a
+/// log file was found in.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PathDimension {
+    pub name: String,
+    pub log_root: PathBuf,
+    pub depth: usize,
+}
+
+/// The buffered file of a `RecordTrace`, `KeyDictionary` or `RejectsFile`, written to under
+/// their lock. The first write that fails is reported on stderr, as the file misses lines from
+/// then on, but the writes failing after it aren't.
+#[derive(Debug)]
+struct SharedFile {
+    /// What the file holds, for the report of a failed write.
+    contents: &'static str,
+    writer: BufWriter<File>,
+    failed: bool,
+}
+
+impl SharedFile {
+    fn new(contents: &'static str, file: File) -> SharedFile {
+        SharedFile {
+            contents: contents,
+            writer: BufWriter::new(file),
+            failed: false,
+        }
+    }
+
+    /// Returns whether the line was written.
+    fn write_line(&mut self, line: &str) -> bool {
+        let result = writeln!(self.writer, "{}", line);
+        self.check(result)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let result = self.writer.flush();
+        if result.is_err() {
+            // Reported by the caller.
+            self.failed = true;
+        }
+        result
+    }
+
+    fn check(&mut self, result: io::Result<()>) -> bool {
+        match result {
+            Ok(()) => true,
+            Err(err) => {
+                if !self.failed {
+                    self.failed = true;
+                    println_stderr!("Failed to write {}, which misses the ones written from now \
+                                     on. {}",
+                                    self.contents,
+                                    err);
+                }
+                false
+            }
+        }
+    }
+}
+
+/// Writes up to `limit` of the raw records aggregated under one system, day and client to a file,
+/// to show how that key's total came about. Shared by every FileAggregator of a run, and flushed
+/// once the run is done, see `flush`.
+#[derive(Debug)]
+pub struct RecordTrace {
+    system_name: String,
+    day: Date<UTC>,
+    client_address: Ipv4Addr,
+    // Matching records are sure to contain the client address, which is far cheaper to look for
+    // than parsing the record.
+    client_address_text: String,
+    limit: usize,
+    output: Mutex<(usize, SharedFile)>,
+}
+
+impl RecordTrace {
+    pub fn new(system_name: String,
+               day: Date<UTC>,
+               client_address: Ipv4Addr,
+               limit: usize,
+               output: File)
+               -> RecordTrace {
+        RecordTrace {
+            system_name: system_name,
+            day: day,
+            client_address: client_address,
+            client_address_text: client_address.to_string(),
+            limit: limit,
+            output: Mutex::new((0, SharedFile::new("a traced record", output))),
+        }
+    }
+
+    pub fn num_traced(&self) -> usize {
+        self.output.lock().unwrap().0
+    }
+
+    /// Writes out the traced records still buffered.
+    pub fn flush(&self) -> io::Result<()> {
+        self.output.lock().unwrap().1.flush()
+    }
+
+    fn trace(&self,
+             record: &str,
+             path_dimension: Option<&str>,
+             config: &record_handling::RecordConfig)
+             -> () {
+        if !record.contains(&self.client_address_text) {
+            return;
+        }
+        // When the run doesn't group by client the key has no client address to compare, and
+        // finding the client address in the record has to do.
+        let is_match = record_handling::record_key(record, path_dimension, config)
+            .map_or(false, |key| {
+                key.client_address.map_or(true, |address| address == self.client_address) &&
+                key.day == self.day && key.system_name == self.system_name
+            });
+        if is_match {
+            let mut output = self.output.lock().unwrap();
+            let record = record_handling::round_record_timestamp(record, config);
+            if output.0 < self.limit && output.1.write_line(&record) {
+                output.0 += 1;
+            }
+        }
+    }
+}
+
+/// Writes the fields every hashed key was hashed from to a file, as CSV rows of the hash followed
+/// by the dimensions grouped by other than the day, the path dimension and the latency bucket,
+/// so that aggregates keyed by hashes can be decoded. Every hash is written once, which keeps
+/// the hashes written in memory. Shared by every FileAggregator of a run, and flushed once the
+/// run is done, see `flush`.
+#[derive(Debug)]
+pub struct KeyDictionary {
+    columns: Vec<record_handling::Dimension>,
+    output: Mutex<(HashSet<String>, SharedFile)>,
+}
+
+impl KeyDictionary {
+    /// The columns are written in the order of `group_by`.
+    pub fn new(group_by: &[record_handling::Dimension], output: File) -> KeyDictionary {
+        KeyDictionary {
+            columns: group_by.iter()
+                .cloned()
+                .filter(|&dimension| dimension != record_handling::Dimension::Day)
+                .collect(),
+            output: Mutex::new((HashSet::new(), SharedFile::new("a hashed key", output))),
+        }
+    }
+
+    pub fn num_keys(&self) -> usize {
+        self.output.lock().unwrap().0.len()
+    }
+
+    /// Writes out the keys still buffered.
+    pub fn flush(&self) -> io::Result<()> {
+        self.output.lock().unwrap().1.flush()
+    }
+
+    /// Adds a key with all of its fields, see `record_handling::full_record_key`, unless its hash
+    /// was already written.
+    fn add(&self, key: &record_handling::AggregateELBRecord) -> () {
+        let key_hash = key.key_hash();
+        let mut output = self.output.lock().unwrap();
+        if output.0.contains(&key_hash) {
+            return;
+        }
+        let delimiter = record_handling::CSV_DELIMITER;
+        let mut row = key_hash.clone();
+        let fields = self.columns
+            .iter()
+            .filter_map(|&dimension| key.dimension_value(dimension))
+            .chain(key.path_dimension.as_ref().map(|dimension| Cow::Borrowed(dimension.as_str())))
+            .chain(key.latency_bucket.as_ref().map(|bucket| Cow::Borrowed(bucket.as_str())));
+        for field in fields {
+            row.push(delimiter);
+            row.push_str(&output::quote_field(&field, delimiter));
+        }
+        if output.1.write_line(&row) {
+            output.0.insert(key_hash);
+        }
+    }
+}
+
+/// Writes every record that couldn't be parsed to a file, as CSV rows of the file it was read
+/// from, its line number counting from one, why it couldn't be parsed and the raw record. Shared
+/// by every FileAggregator of a run, and flushed once the run is done, see `flush`.
+#[derive(Debug)]
+pub struct RejectsFile {
+    output: Mutex<(usize, SharedFile)>,
+}
+
+impl RejectsFile {
+    pub fn new(output: File) -> RejectsFile {
+        RejectsFile { output: Mutex::new((0, SharedFile::new("a rejected record", output))) }
+    }
+
+    pub fn num_rejected(&self) -> usize {
+        self.output.lock().unwrap().0
+    }
+
+    /// Writes out the rejected records still buffered.
+    pub fn flush(&self) -> io::Result<()> {
+        self.output.lock().unwrap().1.flush()
+    }
+
+    fn reject(&self, path: &Path, line_num: usize, error: &str, record: &str) -> () {
+        let delimiter = record_handling::CSV_DELIMITER;
+        let row = format!("{}{}{}{}{}{}{}",
+                          output::quote_field(&path.to_string_lossy(), delimiter),
+                          delimiter,
+                          line_num,
+                          delimiter,
+                          output::quote_field(error, delimiter),
+                          delimiter,
+                          output::quote_field(record, delimiter));
+        let mut output = self.output.lock().unwrap();
+        if output.1.write_line(&row) {
+            output.0 += 1;
+        }
+    }
+}
+
+/// The records of a file elp couldn't parse.
+#[derive(Clone, Debug, PartialEq)]
+pub struct MalformedRecords {
+    pub num_records: usize,
+    /// The line number, counting from one, of the first malformed record.
+    pub first_line_num: usize,
+    /// Why elp couldn't parse the first malformed record.
+    pub first_error: String,
+}
+
+/// The records of a file that are earlier than a record before them, found when verifying that
+/// the files are sorted by timestamp.
+#[derive(Clone, Debug, PartialEq)]
+pub struct UnsortedRecords {
+    pub num_records: usize,
+    /// The line number, counting from one, of the first record out of order.
+    pub first_line_num: usize,
+}
+
+/// An unparsable record and the file it was read from.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ErrorSample {
+    pub path: PathBuf,
+    pub record: String,
+}
+
+/// Keeps up to `capacity` distinct unparsable records by reservoir sampling, so every record has
+/// the same chance of being kept however many are seen. A record identical to one already kept
+/// is ignored. The records kept are drawn from a generator seeded by `seed`, so that the same
+/// records seen in the same order keep the same sample.
+#[derive(Debug)]
+struct ErrorSampler {
+    capacity: usize,
+    num_seen: usize,
+    samples: Vec<ErrorSample>,
+    rng: XorShiftRng,
+}
+
+impl ErrorSampler {
+    fn new(capacity: usize, seed: u64) -> ErrorSampler {
+        // The last word keeps the seed from being all zeros, which the generator can't take.
+        let rng = XorShiftRng::from_seed([seed as u32, (seed >> 32) as u32, 0, 0x9e37_79b9]);
+        ErrorSampler {
+            capacity: capacity,
+            num_seen: 0,
+            samples: Vec::new(),
+            rng: rng,
+        }
+    }
+
+    fn sample(&mut self, path: &Path, record: &str) -> () {
+        if self.capacity == 0 || self.samples.iter().any(|sample| sample.record == record) {
+            return;
+        }
+        self.num_seen += 1;
+        let sample = ErrorSample {
+            path: path.to_path_buf(),
+            record: record.to_owned(),
+        };
+        if self.samples.len() < self.capacity {
+            self.samples.push(sample);
+        } else {
+            let slot = self.rng.gen_range(0, self.num_seen);
+            if slot < self.capacity {
+                self.samples[slot] = sample;
+            }
+        }
+    }
+}
+
+/// The records a file holds from a single day.
+#[derive(Clone, Debug, PartialEq)]
+pub struct FileDay {
+    pub num_records: u64,
+    pub first: DateTime<UTC>,
+    pub last: DateTime<UTC>,
+}
+
+impl FileDay {
+    fn new(timestamp: DateTime<UTC>) -> FileDay {
+        FileDay {
+            num_records: 0,
+            first: timestamp,
+            last: timestamp,
+        }
+    }
+
+    fn include(&mut self, timestamp: DateTime<UTC>) -> () {
+        self.num_records += 1;
+        self.first = self.first.min(timestamp);
+        self.last = self.last.max(timestamp);
+    }
+
+    fn merge(&mut self, src: &FileDay) -> () {
+        self.num_records += src.num_records;
+        self.first = self.first.min(src.first);
+        self.last = self.last.max(src.last);
+    }
+
+    fn overlaps(&self, other: &FileDay) -> bool {
+        self.first <= other.last && other.first <= self.last
+    }
+}
+
+/// The records each file holds from each day, by day.
+pub type FileDays = BTreeMap<Date<UTC>, BTreeMap<PathBuf, FileDay>>;
+
+pub fn merge_file_days(src: &FileDays, dst: &mut FileDays) -> () {
+    for (day, files) in src {
+        let dst_files = dst.entry(*day).or_insert_with(BTreeMap::new);
+        for (path, file_day) in files {
+            match dst_files.get_mut(path) {
+                Some(dst_file_day) => dst_file_day.merge(file_day),
+                None => {
+                    dst_files.insert(path.clone(), file_day.clone());
+                }
+            }
+        }
+    }
+}
+
+/// The number of days from the first day to the last, both included, or 0 without any.
+pub fn num_days(first_day: Option<Date<UTC>>, last_day: Option<Date<UTC>>) -> i64 {
+    match (first_day, last_day) {
+        (Some(first), Some(last)) => (last - first).num_days() + 1,
+        _ => 0,
+    }
+}
+
+/// Files holding a smaller number of records from a day than this fraction of the larger number
+/// are too different to be duplicates of each other.
+const SIMILAR_RECORD_COUNT_RATIO: f64 = 0.9;
+
+/// Two files holding records from the same day that look like copies of each other.
+#[derive(Debug, PartialEq)]
+pub struct OverlappingFiles<'a> {
+    pub day: Date<UTC>,
+    pub files: [(&'a Path, &'a FileDay); 2],
+}
+
+/// Finds the pairs of files whose records from a day span overlapping times and number about the
+/// same, as happens when the same logs are delivered twice. This is only a heuristic: busy hours
+/// can make genuinely different files look alike, and a copy missing some records, or with its
+/// records spread over a different file layout, isn't caught.
+pub fn overlapping_files(file_days: &FileDays) -> Vec<OverlappingFiles> {
+    let mut overlapping = Vec::new();
+    for (day, files) in file_days {
+        let files = files.iter().collect::<Vec<_>>();
+        for (i, &(path, file_day)) in files.iter().enumerate() {
+            for &(other_path, other_file_day) in &files[i + 1..] {
+                let num_records = file_day.num_records.min(other_file_day.num_records);
+                let other_num_records = file_day.num_records.max(other_file_day.num_records);
+                if file_day.overlaps(other_file_day) &&
+                   num_records as f64 >= other_num_records as f64 * SIMILAR_RECORD_COUNT_RATIO {
+                    overlapping.push(OverlappingFiles {
+                        day: *day,
+                        files: [(path.as_path(), file_day), (other_path.as_path(), other_file_day)],
+                    });
+                }
+            }
+        }
+    }
+    overlapping
+}
+
+/// The number of records a FileAggregator reads between updates of the shared record counter.
+/// Batching the updates keeps the counter from being contended on every record.
+const RECORD_COUNTER_BATCH_SIZE: u64 = 1000;
+
+#[derive(Clone, Debug, Default)]
+pub struct FileAggregatorConfig {
+    pub dedupe: Option<RecordDeduplication>,
+    /// Skip records repeating the request of the previous record from the same client to the same
+    /// URL, query left out, within this long of it, so a burst of retries counts once. The last
+    /// request of every client and URL of a file is kept in memory while the file is read, and
+    /// the files have to be sorted by time for the bursts to be found.
+    pub squash_window: Option<Duration>,
+    pub path_dimension: Option<PathDimension>,
+    /// Write the key fields of every parsed record to stdout instead of aggregating them.
+    pub passthrough: bool,
+    /// Parse every record and discard the result without aggregating it.
+    pub parse_only: bool,
+    /// The byte separating records in a file. When None records are separated by newlines, with
+    /// any carriage return before the newline ignored.
+    pub record_separator: Option<u8>,
+    /// Pipe every file, decompressed when gzipped, through this command, run by `sh -c`, and
+    /// aggregate the records it writes in the normalized format of
+    /// `record_handling::NormalizedRecord` instead of parsing ELB records. Records are then
+    /// separated by newlines whatever the record separator.
+    pub parser_command: Option<String>,
+    /// Warn about files yielding fewer parseable records than this, which usually means the file
+    /// was truncated or only partially downloaded.
+    pub min_records_per_file: Option<u64>,
+    /// Abandon a file once this long has been spent on it, keeping the records aggregated so far.
+    /// The deadline is checked between records, so a read blocked on stuck storage isn't
+    /// interrupted.
+    pub file_timeout: Option<Duration>,
+    /// Stop once the aggregated records span more than this many days, skipping the rest of the
+    /// file and every file after it, and keep the first and last day, see
+    /// `FileAggregation::first_day`. Each FileAggregator only knows the span of its own records,
+    /// so a longer span across FileAggregators is only found from the merged aggregation.
+    pub max_day_range: Option<i64>,
+    /// Stop once the FileAggregators of the run hold more than this many aggregates between
+    /// them, see `RunMetrics::add_held_aggregate`, skipping the rest of the file and every file
+    /// after it. The AggregationController fails the run when it finds them stopped, see
+    /// `AggregationController::limit_aggregates`.
+    pub max_aggregates: Option<usize>,
+    pub record_config: record_handling::RecordConfig,
+    /// Time the reading, parsing and key building of every record into these timings.
+    pub profile: Option<Arc<PhaseTimings>>,
+    pub trace: Option<Arc<RecordTrace>>,
+    /// Write the fields of every hashed key to this dictionary when the keys are hashed, see
+    /// `record_handling::RecordConfig::hash_keys`.
+    pub key_dictionary: Option<Arc<KeyDictionary>>,
+    /// Write every record that can't be parsed to this file, see `RejectsFile`.
+    pub rejects: Option<Arc<RejectsFile>>,
+    /// Keep a sample of up to this many distinct unparsable records, see `ErrorSample`.
+    pub sample_errors: usize,
+    /// The seed of every random choice of the run, so far the sample of unparsable records. Each
+    /// FileAggregator adds its id to it, so that they don't all draw the same slots.
+    pub seed: u64,
+    /// Count the records aggregated from every file, see `FileAggregation::file_subtotals`.
+    pub file_subtotals: bool,
+    /// Keep the records each file holds from each day, see `FileAggregation::file_days`.
+    pub file_days: bool,
+    /// Keep the first and last timestamp of every aggregate, see `FileAggregation::timespans`.
+    pub timespans: bool,
+    /// Keep the bytes, latencies and status codes of every aggregate, see
+    /// `FileAggregation::aggregate_metrics`.
+    pub aggregate_metrics: bool,
+    /// Spill the aggregation to a temporary file whenever it holds more than this many
+    /// aggregates, see `FileAggregation::spill_files`.
+    pub spill_after: Option<usize>,
+    /// Check that the records of every file are sorted by timestamp, see
+    /// `FileAggregation::unsorted_files`. The members of an archive are checked separately.
+    pub verify_sorted: bool,
+    /// Shared by every FileAggregator of a run so the number of raw records read and of the
+    /// aggregates held are known while the run is in flight.
+    pub metrics: Arc<RunMetrics>,
+    /// The sizes the files were listed with, reported as the bytes read once each file is read,
+    /// see `total_file_size`.
+    pub listed_sizes: Arc<FileSizes>,
+}
+
+#[derive(Debug)]
+pub struct FileAggregator {
+    id: usize,
+    config: FileAggregatorConfig,
+    num_raw_records: usize,
+    num_duplicate_records: usize,
+    num_fingerprint_collisions: usize,
+    num_squashed_records: usize,
+    num_no_system_records: usize,
+    num_unweighted_records: usize,
+    num_excluded_status_records: usize,
+    num_outside_date_range_records: usize,
+    num_outside_business_hours_records: usize,
+    num_filtered_records: usize,
+    num_malformed_records: usize,
+    malformed_records: BTreeMap<PathBuf, MalformedRecords>,
+    unsorted_files: BTreeMap<PathBuf, UnsortedRecords>,
+    error_samples: ErrorSampler,
+    file_subtotals: BTreeMap<PathBuf, u64>,
+    file_days: FileDays,
+    first_day: Option<Date<UTC>>,
+    last_day: Option<Date<UTC>>,
+    timespans: record_handling::Timespans,
+    aggregate_metrics: record_handling::Metrics,
+    spill_files: Vec<PathBuf>,
+    num_count_overflows: usize,
+    final_agg: ELBRecordAggregation,
+    /// When the file currently being read has to be abandoned by.
+    file_deadline: Option<Instant>,
+}
+
+impl FileAggregator {
+    pub fn new(id: usize) -> FileAggregator {
+        FileAggregator::with_config(id, FileAggregatorConfig::default())
+    }
+
+    pub fn with_config(id: usize, config: FileAggregatorConfig) -> FileAggregator {
+        FileAggregator {
+            id: id,
+            error_samples: ErrorSampler::new(config.sample_errors,
+                                             config.seed.wrapping_add(id as u64)),
+            config: config,
+            num_raw_records: 0,
+            num_duplicate_records: 0,
+            num_fingerprint_collisions: 0,
+            num_squashed_records: 0,
+            num_no_system_records: 0,
+            num_unweighted_records: 0,
+            num_excluded_status_records: 0,
+            num_outside_date_range_records: 0,
+            num_outside_business_hours_records: 0,
+            num_filtered_records: 0,
+            num_malformed_records: 0,
+            malformed_records: BTreeMap::new(),
+            unsorted_files: BTreeMap::new(),
+            file_subtotals: BTreeMap::new(),
+            file_days: BTreeMap::new(),
+            first_day: None,
+            last_day: None,
+            timespans: HashMap::new(),
+            aggregate_metrics: HashMap::new(),
+            spill_files: Vec::new(),
+            num_count_overflows: 0,
+            final_agg: HashMap::new(),
+            file_deadline: None,
+        }
+    }
+
+    pub fn run(mut self,
+               filename_receiver: &mpsc::Receiver<FileHandlingMessages>,
+               aggregate_sender: &mpsc::Sender<AggregationMessages>)
+               -> () {
+        let _ = aggregate_sender.send(AggregationMessages::Next(self.id));
+        let timeout = Duration::from_millis(10000);
+        loop {
+            match filename_receiver.recv_timeout(timeout) {
+                Ok(FileHandlingMessages::Filename(filename)) => {
+                    self.aggregate_file(filename.as_path());
+                    let num_bytes = file_size(&filename, &self.config.listed_sizes);
+                    let _ = aggregate_sender.send(AggregationMessages::BytesRead(num_bytes));
+                    let _ = aggregate_sender.send(AggregationMessages::Next(self.id));
+                }
+                Ok(FileHandlingMessages::Done) => break,
+                Err(RecvTimeoutError::Timeout) => {
+                    debug!("A timeout occurred while FileAggregator {} was waiting \
+                    for a filename. This may mean that the controller is having a hard time \
+                    keeping up. But in most cases it just means there are no more files \
+                    to process and this FileAggregator is just waiting for the shut down \
+                    message.",
+                    self.id)
+                }
+                Err(RecvTimeoutError::Disconnected) => {
+                    // If this channel has disconnected there's no guarantee the other
+                    // channels are still connected. This is very bad and is going to produce
+                    // bad aggregates. Therefore, we write to stderr so the message can be captured
+                    // and we kill the thread.
+                    let msg = format!("FileAggregator {} has disconnected from the controller.\
+                            This is unrecoverable error and should be reported to the developers.",
+                                      self.id);
+                    println_stderr!("{}", msg);
+                    panic!(msg);
+                }
+            }
+        }
+
+        let _ = aggregate_sender.send(AggregationMessages::Aggregate(FileAggregation {
+            num_raw_records: self.num_raw_records,
+            num_duplicate_records: self.num_duplicate_records,
+            num_fingerprint_collisions: self.num_fingerprint_collisions,
+            num_squashed_records: self.num_squashed_records,
+            num_no_system_records: self.num_no_system_records,
+            num_unweighted_records: self.num_unweighted_records,
+            num_excluded_status_records: self.num_excluded_status_records,
+            num_outside_date_range_records: self.num_outside_date_range_records,
+            num_outside_business_hours_records: self.num_outside_business_hours_records,
+            num_filtered_records: self.num_filtered_records,
+            num_malformed_records: self.num_malformed_records,
+            malformed_records: self.malformed_records,
+            unsorted_files: self.unsorted_files,
+            error_samples: self.error_samples.samples,
+            file_subtotals: self.file_subtotals,
+            file_days: self.file_days,
+            first_day: self.first_day,
+            last_day: self.last_day,
+            timespans: self.timespans,
+            aggregate_metrics: self.aggregate_metrics,
+            spill_files: self.spill_files,
+            num_count_overflows: self.num_count_overflows,
+            aggregation: self.final_agg,
+            partitions: Vec::new(),
+        }));
+    }
+
+    /// Spills the aggregation when it holds more aggregates than configured to. When the spill
+    /// file can't be written the aggregation is kept in memory instead, and no more spills are
+    /// tried.
+    fn spill_if_full(&mut self) -> () {
+        match self.config.spill_after {
+            Some(max_aggregates) if self.final_agg.len() > max_aggregates => {}
+            _ => return,
+        }
+        let num_aggregates = self.final_agg.len();
+        match spill::spill(&mut self.final_agg) {
+            Ok(path) => {
+                self.config.metrics.remove_held_aggregates(num_aggregates);
+                debug!("FileAggregator {} spilled its aggregation to {}.",
+                       self.id,
+                       path.display());
+                self.spill_files.push(path)
+            }
+            Err(err) => {
+                println_stderr!("FileAggregator {} could not spill its aggregation and keeps it \
+                                 in memory. {}",
+                                self.id,
+                                err);
+                self.config.spill_after = None;
+            }
+        }
+    }
+
+    fn aggregate_file(&mut self, file_path: &Path) -> () {
+        debug!("FileAggregator {} received filename {}.",
+        self.id,
+        file_path.display());
+        if self.exceeds_day_range() {
+            debug!("FileAggregator {} skipped file {} as its records exceed the day range.",
+                   self.id,
+                   file_path.display());
+            return;
+        }
+        if self.num_count_overflows > 0 {
+            debug!("FileAggregator {} skipped file {} as the total of an aggregate overflowed.",
+                   self.id,
+                   file_path.display());
+            return;
+        }
+        if self.holds_too_many_aggregates() {
+            debug!("FileAggregator {} skipped file {} as the aggregates held exceed the limit.",
+                   self.id,
+                   file_path.display());
+            return;
+        }
+        if let Err(err) = self.read_file(file_path) {
+            self.report_error(err)
+        }
+    }
+
+    fn report_error(&self, err: FileHandlingErrors) -> () {
+        match err {
+            FileHandlingErrors::FileReadError { path, err } => {
+                println_stderr!("Failed to read file {} with error {}. ",
+                                path.display(),
+                                err)
+            }
+            FileHandlingErrors::LineReadError { path, line_nums } => {
+                println_stderr!("Failed to read lines {:?} from file {}. ",
+                                line_nums,
+                                path.display())
+            }
+            FileHandlingErrors::TooFewRecords { path, num_lines: 0, .. } => {
+                println_stderr!("File {} is empty. ", path.display())
+            }
+            FileHandlingErrors::TooFewRecords { path, num_lines, num_parsed } => {
+                println_stderr!("File {} only has {} parseable records in {} lines and may be \
+                                 truncated. ",
+                                path.display(),
+                                num_parsed,
+                                num_lines)
+            }
+            FileHandlingErrors::TimedOut { path, num_lines } => {
+                println_stderr!("Abandoned file {} after {} records because it took longer than \
+                                 the file timeout. The records read before then are included in \
+                                 the aggregates. ",
+                                path.display(),
+                                num_lines)
+            }
+            FileHandlingErrors::DayRangeExceeded { path, num_days } => {
+                println_stderr!("Stopped reading file {} as the records read span {} days, more \
+                                 than the maximum day range. The files left are skipped. ",
+                                path.display(),
+                                num_days)
+            }
+            FileHandlingErrors::CountOverflowed { path, line_num } => {
+                println_stderr!("Stopped reading file {} on line {} as counting the record would \
+                                 overflow the total of its aggregate. The files left are \
+                                 skipped. ",
+                                path.display(),
+                                line_num)
+            }
+            // The run fails with the number of aggregates, so each FileAggregator stopping isn't
+            // reported on its own.
+            FileHandlingErrors::TooManyAggregates { path, num_aggregates } => {
+                debug!("FileAggregator {} stopped reading file {} as {} aggregates are held, more \
+                        than the limit.",
+                       self.id,
+                       path.display(),
+                       num_aggregates)
+            }
+            FileHandlingErrors::ParserCommandFailed { path, err } => {
+                println_stderr!("The parser command failed on file {}: {}. The records it wrote \
+                                 before failing are included in the aggregates. ",
+                                path.display(),
+                                err)
+            }
+        }
+    }
+
+    fn read_file<'a>(&mut self, path: &'a Path) -> Result<(), FileHandlingErrors<'a>> {
+        debug!("Processing file {}.", path.display());
+        self.file_deadline = self.config.file_timeout.map(|timeout| Instant::now() + timeout);
+        if is_s3_uri(path) {
+            return self.read_s3_object(path);
+        }
+        match File::open(path) {
+            Ok(file) => self.read_opened_file(path, file),
+            Err(err) => {
+                Err(FileHandlingErrors::FileReadError {
+                    path: path,
+                    err: err,
+                })
+            }
+        }
+    }
+
+    fn read_opened_file<'a, R>(&mut self,
+                               path: &'a Path,
+                               reader: R)
+                               -> Result<(), FileHandlingErrors<'a>>
+        where R: Read + Send + 'static
+    {
+        if let Some(command) = self.config.parser_command.clone() {
+            self.read_through_parser(path, reader, &command)
+        } else if is_archive(path) {
+            self.read_archive(path, reader)
+        } else if is_gzipped(path) {
+            self.read_gzipped_records(path, reader)
+        } else {
+            self.read_records(path, reader)
+        }
+    }
+
+    /// Streams the object from `aws s3 cp URI -` as it is read, so it is never written to disk.
+    /// The object is read like a file of the same name, so gzipped objects are decompressed.
+    fn read_s3_object<'a>(&mut self, path: &'a Path) -> Result<(), FileHandlingErrors<'a>> {
+        let to_read_error = |err| {
+            FileHandlingErrors::FileReadError {
+                path: path,
+                err: err,
+            }
+        };
+        let mut child = Command::new("aws").args(&["s3", "cp"])
+            .arg(path)
+            .arg("-")
+            .stdout(Stdio::piped())
+            .spawn()
+            .map_err(|err| to_read_error(aws_command_error(err)))?;
+        // It was asked to be piped, so the child has it.
+        let stdout = child.stdout.take().unwrap();
+
+        let result = self.read_opened_file(path, stdout);
+        if result.is_err() {
+            // Stops the download writing to a pipe nothing reads anymore.
+            let _ = child.kill();
+        }
+        let status = child.wait();
+        result?;
+        match status {
+            Ok(ref status) if status.success() => Ok(()),
+            Ok(status) => {
+                Err(to_read_error(io::Error::new(io::ErrorKind::Other,
+                                                 format!("aws s3 cp exited with {}", status))))
+            }
+            Err(err) => Err(to_read_error(err)),
+        }
+    }
+
+    /// Writes the file to the parser command from another thread, so that neither side blocks on
+    /// a full pipe, while the normalized records it writes are aggregated. The file timeout is
+    /// only checked between records, so a command that stops writing holds up the file.
+    fn read_through_parser<'a, R>(&mut self,
+                                  path: &'a Path,
+                                  file: R,
+                                  command: &str)
+                                  -> Result<(), FileHandlingErrors<'a>>
+        where R: Read + Send + 'static
+    {
+        let to_parser_error = |err: String| {
+            FileHandlingErrors::ParserCommandFailed {
+                path: path,
+                err: err,
+            }
+        };
+        if is_archive(path) {
+            return Err(to_parser_error("archives can't be piped through a parser command"
+                .to_owned()));
+        }
+        let mut input: Box<Read + Send> = if is_gzipped(path) {
+            match MultiGzDecoder::new(file) {
+                Ok(decoder) => Box::new(decoder),
+                Err(err) => {
+                    return Err(FileHandlingErrors::FileReadError {
+                        path: path,
+                        err: err,
+                    })
+                }
+            }
+        } else {
+            Box::new(file)
+        };
+        let mut child = Command::new("sh").arg("-c")
+            .arg(command)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .map_err(|err| to_parser_error(format!("couldn't start {}: {}", command, err)))?;
+        // Both were asked to be piped, so the child has them.
+        let mut stdin = child.stdin.take().unwrap();
+        let stdout = child.stdout.take().unwrap();
+        let writer = thread::spawn(move || io::copy(&mut input, &mut stdin));
+
+        let result = self.read_lines(path, BufReader::new(stdout).lines());
+        if result.is_err() {
+            // Stops the command writing to a pipe nothing reads anymore.
+            let _ = child.kill();
+        }
+        let written = writer.join();
+        let status = child.wait();
+        result?;
+        match status {
+            Ok(ref status) if status.success() => {}
+            Ok(status) => {
+                return Err(to_parser_error(format!("{} exited with {}", command, status)))
+            }
+            Err(err) => {
+                return Err(to_parser_error(format!("couldn't wait for {}: {}", command, err)))
+            }
+        }
+        match written {
+            Ok(Ok(_)) => Ok(()),
+            // A command may succeed without reading all of its input, such as one stopping at a
+            // footer.
+            Ok(Err(ref err)) if err.kind() == io::ErrorKind::BrokenPipe => Ok(()),
+            Ok(Err(err)) => {
+                Err(to_parser_error(format!("couldn't write the file to {}: {}", command, err)))
+            }
+            Err(_) => Err(to_parser_error("the thread writing the file to it panicked".to_owned())),
+        }
+    }
+
+    #[cfg(feature = "tar")]
+    fn read_archive<'a, R: Read>(&mut self,
+                                 path: &'a Path,
+                                 file: R)
+                                 -> Result<(), FileHandlingErrors<'a>> {
+        if is_gzipped_archive(path) {
+            match MultiGzDecoder::new(file) {
+                Ok(decoder) => self.read_archive_members(path, tar::Archive::new(decoder)),
+                Err(err) => {
+                    Err(FileHandlingErrors::FileReadError {
+                        path: path,
+                        err: err,
+                    })
+                }
+            }
+        } else {
+            self.read_archive_members(path, tar::Archive::new(file))
+        }
+    }
+
+    /// A member that can't be read is reported and the members after it are still read, while
+    /// the errors that stop the FileAggregator stop the whole archive. A broken header leaves no
+    /// way to find the next member, so it stops the archive too.
+    #[cfg(feature = "tar")]
+    fn read_archive_members<'a, R: Read>(&mut self,
+                                         path: &'a Path,
+                                         mut archive: tar::Archive<R>)
+                                         -> Result<(), FileHandlingErrors<'a>> {
+        let to_read_error = |err| {
+            FileHandlingErrors::FileReadError {
+                path: path,
+                err: err,
+            }
+        };
+        for possible_entry in archive.entries().map_err(&to_read_error)? {
+            let entry = possible_entry.map_err(&to_read_error)?;
+            let member_path = match entry.path() {
+                Ok(member_path) => member_path.into_owned(),
+                Err(err) => {
+                    println_stderr!("Skipped a member of archive {} as its path can't be read: \
+                                     {}. ",
+                                    path.display(),
+                                    err);
+                    continue;
+                }
+            };
+            let is_regular_file = entry.header().entry_type() == tar::EntryType::Regular;
+            if !is_regular_file || !is_log_file(&member_path) {
+                debug!("Skipping member {} of archive {}.",
+                       member_path.display(),
+                       path.display());
+                continue;
+            }
+            debug!("Processing member {} of archive {}.",
+                   member_path.display(),
+                   path.display());
+            let result = if is_gzipped(&member_path) {
+                self.read_gzipped_records(path, entry)
+            } else {
+                self.read_records(path, entry)
+            };
+            match result {
+                Err(err @ FileHandlingErrors::FileReadError { .. }) |
+                Err(err @ FileHandlingErrors::LineReadError { .. }) |
+                Err(err @ FileHandlingErrors::TooFewRecords { .. }) => {
+                    println_stderr!("Failed to read member {} of archive {}, the members after \
+                                     it are still read. ",
+                                    member_path.display(),
+                                    path.display());
+                    self.report_error(err)
+                }
+                result => result?,
+            }
+        }
+
+        Ok(())
+    }
+
+    #[cfg(not(feature = "tar"))]
+    fn read_archive<'a, R: Read>(&mut self,
+                                 path: &'a Path,
+                                 file: R)
+                                 -> Result<(), FileHandlingErrors<'a>> {
+        self.read_records(path, file)
+    }
+
+    fn read_gzipped_records<'a, R: Read>(&mut self,
+                                         path: &'a Path,
+                                         reader: R)
+                                         -> Result<(), FileHandlingErrors<'a>> {
+        // Concatenated gzip members are all read, as gzip itself does.
+        match MultiGzDecoder::new(reader) {
+            Ok(decoder) => self.read_records(path, decoder),
+            Err(err) => {
+                Err(FileHandlingErrors::FileReadError {
+                    path: path,
+                    err: err,
+                })
+            }
+        }
+    }
+
+    fn read_records<'a, R: Read>(&mut self,
+                                 path: &'a Path,
+                                 reader: R)
+                                 -> Result<(), FileHandlingErrors<'a>> {
+        match self.config.record_separator {
+            None => self.read_lines(path, BufReader::new(reader).lines()),
+            Some(separator) => {
+                let records = BufReader::new(reader).split(separator).map(|possible_bytes| {
+                    possible_bytes.and_then(|bytes| {
+                        String::from_utf8(bytes)
+                            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+                    })
+                });
+                self.read_lines(path, records)
+            }
+        }
+    }
+
+    fn read_lines<'a, I>(&mut self, path: &'a Path, lines: I) -> Result<(), FileHandlingErrors<'a>>
+        where I: Iterator<Item = io::Result<String>>
+    {
+        let mut bad_line_nums = Vec::new();
+        let mut records_processed = 0;
+        let mut records_parsed = 0;
+        let mut records_aggregated = 0;
+        let mut records_outside_date_range = 0;
+        let mut file_fingerprints = HashMap::new();
+        let mut last_requests = HashMap::new();
+        let path_dimension = self.config
+            .path_dimension
+            .as_ref()
+            .map(|dimension| {
+                record_handling::path_dimension_value(&dimension.log_root, path, dimension.depth)
+            });
+        let lines = ProfiledLines {
+            lines: lines,
+            timings: self.config.profile.clone(),
+        };
+        let mut timed_out = false;
+        let mut overflowed_line_num = None;
+        let mut latest_timestamp = None;
+        for (line_num, possible_record) in lines.enumerate() {
+            if self.file_deadline.map_or(false, |deadline| Instant::now() >= deadline) {
+                timed_out = true;
+                break;
+            }
+            if self.exceeds_day_range() || overflowed_line_num.is_some() ||
+               self.holds_too_many_aggregates() {
+                break;
+            }
+            if let Ok(record) = possible_record {
+                let record = if self.config.parser_command.is_some() {
+                    record.as_str()
+                } else {
+                    record_handling::as_elb_record(&record, self.config.record_config.log_format)
+                };
+                records_processed += 1;
+                if records_processed % RECORD_COUNTER_BATCH_SIZE == 0 {
+                    self.config.metrics.add_raw_records(RECORD_COUNTER_BATCH_SIZE);
+                }
+                if self.config.verify_sorted {
+                    latest_timestamp = self.check_sorted(path, line_num, &record, latest_timestamp);
+                }
+                if self.is_duplicate(&record, &mut file_fingerprints) {
+                    self.num_duplicate_records += 1;
+                    continue;
+                }
+                if self.is_squashed(&record, &mut last_requests) {
+                    self.num_squashed_records += 1;
+                    continue;
+                }
+                let path_dimension = path_dimension.as_ref().map(|d| d.as_str());
+                if self.config.parse_only {
+                    if record_handling::parse_only(&record) {
+                        records_parsed += 1;
+                    }
+                } else if self.config.passthrough {
+                    if let Some(fields) =
+                        record_handling::passthrough_record(&record,
+                                                            path_dimension,
+                                                            &self.config.record_config) {
+                        records_parsed += 1;
+                        println!("{}", fields);
+                    }
+                } else {
+                    if let Some(ref trace) = self.config.trace {
+                        trace.trace(&record, path_dimension, &self.config.record_config);
+                    }
+                    let num_aggregates = self.final_agg.len();
+                    let metrics = if self.config.aggregate_metrics {
+                        Some(&mut self.aggregate_metrics)
+                    } else {
+                        None
+                    };
+                    // The key of an ELB record is only kept once aggregated when it's needed,
+                    // as keeping it clones it.
+                    let mut counted = record_handling::CountedRecord::default();
+                    let keeps_key = self.config.timespans || self.config.key_dictionary.is_some();
+                    let outcome = match self.config.profile {
+                        _ if self.config.parser_command.is_some() => {
+                            record_handling::try_parse_normalized_record(&record,
+                                                                         path_dimension,
+                                                                         &self.config.record_config,
+                                                                         &mut self.final_agg,
+                                                                         Some(&mut counted))
+                        }
+                        Some(ref timings) => {
+                            record_handling::try_parse_record_profiled(&record,
+                                                                       path_dimension,
+                                                                       &self.config.record_config,
+                                                                       &mut self.final_agg,
+                                                                       metrics,
+                                                                       if keeps_key {
+                                                                           Some(&mut counted)
+                                                                       } else {
+                                                                           None
+                                                                       },
+                                                                       timings)
+                        }
+                        None => {
+                            record_handling::try_parse_record_with_metrics(&record,
+                                                                           path_dimension,
+                                                                           &self.config
+                                                                               .record_config,
+                                                                           &mut self.final_agg,
+                                                                           metrics,
+                                                                           if keeps_key {
+                                                                               Some(&mut counted)
+                                                                           } else {
+                                                                               None
+                                                                           })
+                        }
+                    };
+                    match outcome {
+                        record_handling::RecordOutcome::Aggregated => {
+                            records_parsed += 1;
+                            records_aggregated += 1;
+                            self.add_to_key_dictionary(&counted, num_aggregates);
+                            self.add_file_day(path, &record);
+                            self.add_timespan(&counted);
+                            self.add_day(&record)
+                        }
+                        record_handling::RecordOutcome::AggregatedWithoutSystem => {
+                            records_parsed += 1;
+                            records_aggregated += 1;
+                            self.num_no_system_records += 1;
+                            self.add_to_key_dictionary(&counted, num_aggregates);
+                            self.add_file_day(path, &record);
+                            self.add_timespan(&counted);
+                            self.add_day(&record)
+                        }
+                        record_handling::RecordOutcome::DroppedWithoutSystem => {
+                            records_parsed += 1;
+                            self.num_no_system_records += 1
+                        }
+                        record_handling::RecordOutcome::ExcludedByStatus => {
+                            records_parsed += 1;
+                            self.num_excluded_status_records += 1
+                        }
+                        record_handling::RecordOutcome::OutsideDateRange => {
+                            records_parsed += 1;
+                            records_outside_date_range += 1
+                        }
+                        record_handling::RecordOutcome::OutsideBusinessHours => {
+                            records_parsed += 1;
+                            self.num_outside_business_hours_records += 1
+                        }
+                        record_handling::RecordOutcome::Filtered => {
+                            records_parsed += 1;
+                            self.num_filtered_records += 1
+                        }
+                        record_handling::RecordOutcome::Unparsable(error) => {
+                            if let Some(ref rejects) = self.config.rejects {
+                                rejects.reject(path, line_num + 1, &error, &record);
+                            }
+                            self.num_malformed_records += 1;
+                            self.malformed_records
+                                .entry(path.to_path_buf())
+                                .or_insert_with(|| {
+                                    MalformedRecords {
+                                        num_records: 0,
+                                        first_line_num: line_num + 1,
+                                        first_error: error,
+                                    }
+                                })
+                                .num_records += 1;
+                            self.error_samples.sample(path, &record)
+                        }
+                        record_handling::RecordOutcome::CountOverflowed => {
+                            records_parsed += 1;
+                            self.num_count_overflows += 1;
+                            overflowed_line_num = Some(line_num + 1)
+                        }
+                    }
+                    if counted.is_unweighted {
+                        self.num_unweighted_records += 1;
+                    }
+                    if self.final_agg.len() > num_aggregates {
+                        self.config.metrics.add_held_aggregate();
+                    }
+                    // Checked after every record, so that one large file can't grow the
+                    // aggregation past the limit either.
+                    self.spill_if_full()
+                }
+            } else {
+                bad_line_nums.push(line_num);
+            }
+        }
+
+        debug!("Found {} records in file {}.",
+        records_processed,
+        path.display());
+        self.config.metrics.add_raw_records(records_processed % RECORD_COUNTER_BATCH_SIZE);
+        // Records from outside of the date range are left out of the raw record count, as if the
+        // file had never held them.
+        self.num_raw_records += (records_processed - records_outside_date_range) as usize;
+        self.num_outside_date_range_records += records_outside_date_range as usize;
+        if self.config.file_subtotals {
+            // The members of an archive are all counted under the archive.
+            *self.file_subtotals.entry(path.to_path_buf()).or_insert(0) += records_aggregated;
+        }
+        if self.exceeds_day_range() {
+            Err(FileHandlingErrors::DayRangeExceeded {
+                path: path,
+                num_days: num_days(self.first_day, self.last_day),
+            })
+        } else if let Some(line_num) = overflowed_line_num {
+            Err(FileHandlingErrors::CountOverflowed {
+                path: path,
+                line_num: line_num,
+            })
+        } else if self.holds_too_many_aggregates() {
+            Err(FileHandlingErrors::TooManyAggregates {
+                path: path,
+                num_aggregates: self.config.metrics.num_held_aggregates(),
+            })
+        } else if timed_out {
+            Err(FileHandlingErrors::TimedOut {
+                path: path,
+                num_lines: records_processed,
+            })
+        } else if !bad_line_nums.is_empty() {
+            Err(FileHandlingErrors::LineReadError {
+                line_nums: bad_line_nums,
+                path: path,
+            })
+        } else if self.config.min_records_per_file.map_or(false, |min| records_parsed < min) {
+            Err(FileHandlingErrors::TooFewRecords {
+                path: path,
+                num_lines: records_processed,
+                num_parsed: records_parsed,
+            })
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Counts an aggregated record towards the day of the file it is from when configured to.
+    fn add_file_day(&mut self, path: &Path, record: &str) -> () {
+        if !self.config.file_days {
+            return;
+        }
+        // The record was already parsed, so only its timestamp, the first field, is parsed again.
+        let timestamp = record.split(' ')
+            .next()
+            .and_then(|field| field.parse::<DateTime<UTC>>().ok());
+        if let Some(timestamp) = timestamp {
+            self.file_days
+                .entry(timestamp.date())
+                .or_insert_with(BTreeMap::new)
+                .entry(path.to_path_buf())
+                .or_insert_with(|| FileDay::new(timestamp))
+                .include(timestamp);
+        }
+    }
+
+    /// Widens the span of days of the aggregated records when a maximum day range is set.
+    fn add_day(&mut self, record: &str) -> () {
+        if self.config.max_day_range.is_none() {
+            return;
+        }
+        // Records normalized by a parser command are tab separated.
+        let day = record.split(|c| c == ' ' || c == '\t')
+            .next()
+            .and_then(|field| field.parse::<DateTime<UTC>>().ok())
+            .map(|timestamp| timestamp.date());
+        if let Some(day) = day {
+            self.first_day = Some(self.first_day.map_or(day, |first| first.min(day)));
+            self.last_day = Some(self.last_day.map_or(day, |last| last.max(day)));
+        }
+    }
+
+    /// Counts the record towards the unsorted records of the file when it is earlier than the
+    /// latest record before it, and returns the latest timestamp so far. Records without a
+    /// timestamp are passed over.
+    fn check_sorted(&mut self,
+                    path: &Path,
+                    line_num: usize,
+                    record: &str,
+                    latest_timestamp: Option<DateTime<UTC>>)
+                    -> Option<DateTime<UTC>> {
+        // Records normalized by a parser command are tab separated.
+        let timestamp = record.split(|c| c == ' ' || c == '\t')
+            .next()
+            .and_then(|field| field.parse::<DateTime<UTC>>().ok());
+        match (latest_timestamp, timestamp) {
+            (Some(latest), Some(timestamp)) if timestamp < latest => {
+                self.unsorted_files
+                    .entry(path.to_path_buf())
+                    .or_insert(UnsortedRecords {
+                        num_records: 0,
+                        first_line_num: line_num + 1,
+                    })
+                    .num_records += 1;
+                Some(latest)
+            }
+            (latest, None) => latest,
+            (_, timestamp) => timestamp,
+        }
+    }
+
+    fn exceeds_day_range(&self) -> bool {
+        self.config
+            .max_day_range
+            .map_or(false, |max_days| num_days(self.first_day, self.last_day) > max_days)
+    }
+
+    /// Whether the FileAggregators of the run hold more aggregates between them than the limit,
+    /// which stops every one of them and not only the one that crossed it.
+    fn holds_too_many_aggregates(&self) -> bool {
+        self.config
+            .max_aggregates
+            .map_or(false, |max_aggregates| {
+                self.config.metrics.num_held_aggregates() > max_aggregates
+            })
+    }
+
+    /// Adds the fields of a record to the key dictionary when configured with one and the record
+    /// was aggregated under a key new to this FileAggregator, which made the aggregation grow
+    /// past `num_aggregates`.
+    fn add_to_key_dictionary(&self,
+                             counted: &record_handling::CountedRecord,
+                             num_aggregates: usize)
+                             -> () {
+        if let Some(ref dictionary) = self.config.key_dictionary {
+            if self.final_agg.len() > num_aggregates {
+                if let Some(ref key) = counted.full_key {
+                    dictionary.add(key);
+                }
+            }
+        }
+    }
+
+    /// Widens the timespan of the aggregate an aggregated record was counted towards when
+    /// configured to.
+    fn add_timespan(&mut self, counted: &record_handling::CountedRecord) -> () {
+        if !self.config.timespans {
+            return;
+        }
+        let key = counted.full_key.as_ref().map(|key| {
+            if self.config.record_config.hash_keys { key.hashed() } else { key.clone() }
+        });
+        if let (Some(timestamp), Some(key)) = (counted.timestamp, key) {
+            self.timespans
+                .entry(key)
+                .or_insert_with(|| record_handling::Timespan::new(timestamp))
+                .include(timestamp);
+        }
+    }
+
+    /// Whether the record repeats the last request from its client to its URL within the squash
+    /// window. Only the fields needed are parsed, as the record is parsed again to aggregate it.
+    fn is_squashed(&self,
+                   record: &str,
+                   last_requests: &mut HashMap<(String, String), DateTime<UTC>>)
+                   -> bool {
+        let window = match self.config.squash_window {
+            Some(window) => window,
+            None => return false,
+        };
+        let mut fields = record.split(' ');
+        let timestamp = fields.next().and_then(|field| field.parse::<DateTime<UTC>>().ok());
+        let client = fields.nth(1).and_then(|address| address.rsplitn(2, ':').last());
+        let url = record.splitn(2, '"')
+            .nth(1)
+            .and_then(|request| request.split(' ').nth(1))
+            .and_then(|url| url.split('?').next());
+        match (timestamp, client, url) {
+            (Some(timestamp), Some(client), Some(url)) => {
+                let window_millis = window.as_secs() as i64 * 1000 +
+                                    (window.subsec_nanos() / 1_000_000) as i64;
+                let key = (client.to_owned(), url.to_owned());
+                match last_requests.insert(key, timestamp) {
+                    Some(last) => {
+                        let since_last = (timestamp - last).num_milliseconds();
+                        0 <= since_last && since_last <= window_millis
+                    }
+                    None => false,
+                }
+            }
+            _ => false,
+        }
+    }
+
+    fn is_duplicate(&mut self,
+                    record: &str,
+                    file_fingerprints: &mut HashMap<u64, u64>)
+                    -> bool {
+        let fingerprinted = match self.config.dedupe {
+            None => return false,
+            Some(RecordDeduplication::PerFile) => remember_fingerprints(file_fingerprints, record),
+            Some(RecordDeduplication::Global(ref fingerprints)) => {
+                remember_fingerprints(&mut fingerprints.lock().unwrap(), record)
+            }
+        };
+        if fingerprinted == Fingerprinted::Collision {
+            self.num_fingerprint_collisions += 1;
+        }
+        fingerprinted == Fingerprinted::Duplicate
+    }
+}
+
+/// Adds the time spent waiting on each record to the read I/O timing when profiling.
+struct ProfiledLines<I> {
+    lines: I,
+    timings: Option<Arc<PhaseTimings>>,
+}
+
+impl<I: Iterator> Iterator for ProfiledLines<I> {
+    type Item = I::Item;
+
+    fn next(&mut self) -> Option<I::Item> {
+        match self.timings {
+            Some(ref timings) => {
+                let start = Instant::now();
+                let line = self.lines.next();
+                timings.add(Phase::ReadIo, start.elapsed());
+                line
+            }
+            None => self.lines.next(),
+        }
+    }
+}
+
+/// What remembering the fingerprints of a line found, see `RecordDeduplication`.
+#[derive(Debug, PartialEq)]
+enum Fingerprinted {
+    New,
+    Duplicate,
+    /// The line has the first fingerprint of another line, which is expected about n^2 / 2^65
+    /// times in n distinct lines. It is left out of the fingerprints, so a duplicate of it isn't
+    /// skipped.
+    Collision,
+}
+
+/// Remembers the fingerprints of the line, keeping its second fingerprint by its first.
+fn remember_fingerprints(fingerprints: &mut HashMap<u64, u64>, record: &str) -> Fingerprinted {
+    let (first, second) = (fingerprint(record, 0), fingerprint(record, 1));
+    match fingerprints.entry(first) {
+        Entry::Vacant(entry) => {
+            entry.insert(second);
+            Fingerprinted::New
+        }
+        Entry::Occupied(ref entry) if *entry.get() == second => Fingerprinted::Duplicate,
+        Entry::Occupied(_) => Fingerprinted::Collision,
+    }
+}
+
+/// The fingerprint of the line hashed after the salt, which makes an independent fingerprint for
+/// every salt.
+fn fingerprint(record: &str, salt: u8) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    salt.hash(&mut hasher);
+    record.hash(&mut hasher);
+    hasher.finish()
+}
+
+#[cfg(test)]
+mod file_aggregator_run_tests {
+
+    use std::sync::mpsc;
+
+    #[test]
+    fn sends_the_final_agg_after_receiving_the_done_message() {
+        let (filename_sender, filename_receiver) = mpsc::channel();
+        let (agg_sender, agg_receiver) = mpsc::channel();
+        let _ = filename_sender.send(super::FileHandlingMessages::Done);
+        let file_aggregator = super::FileAggregator::new(1);
+        file_aggregator.run(&filename_receiver, &agg_sender);
+
+        // Dump the AggregationMessages::Next message sent at startup.
+        let _ = agg_receiver.recv();
+
+        match agg_receiver.recv().unwrap() {
+            super::AggregationMessages::Aggregate(file_agg) => {
+                assert_eq!(file_agg.num_raw_records, 0);
+                assert_eq!(file_agg.aggregation.len(), 0);
+            }
+            other => panic!("Received an unexpected message {:?}.", other),
+        }
+    }
+
+    #[test]
+    fn sends_a_next_message_after_starting_up() {
+        let (filename_sender, filename_receiver) = mpsc::channel();
+        let (agg_sender, agg_receiver) = mpsc::channel();
+        let _ = filename_sender.send(super::FileHandlingMessages::Done);
+        let file_aggregator = super::FileAggregator::new(1);
+
+        file_aggregator.run(&filename_receiver, &agg_sender);
+
+        match agg_receiver.recv().unwrap() {
+            super::AggregationMessages::Next(id) => assert_eq!(id, 1),
+            other => panic!("Received an unexpected message {:?}.", other),
+        }
+    }
+}
+
+#[cfg(test)]
+mod total_file_size_tests {
+
+    use std::fs;
+    use std::io;
+    use std::path::PathBuf;
+    use test_common;
+
+    #[test]
+    fn total_file_size_should_sum_the_size_of_every_file() {
+        let file_size = fs::metadata(test_common::TEST_LOG_FILE).unwrap().len();
+        let files = vec![PathBuf::from(test_common::TEST_LOG_FILE); 3];
+
+        assert_eq!(super::total_file_size(&files, &super::FileSizes::new()), 3 * file_size)
+    }
+
+    #[test]
+    fn total_file_size_should_treat_missing_files_as_empty() {
+        let files = vec![PathBuf::from("bad_filename")];
+
+        assert_eq!(super::total_file_size(&files, &super::FileSizes::new()), 0)
+    }
+
+    #[test]
+    fn total_file_size_should_count_s3_objects_with_their_listed_size() {
+        let listed = PathBuf::from("s3://bucket/total_file_size_test/listed.log");
+        let mut listed_sizes = super::FileSizes::new();
+        listed_sizes.insert(listed.clone(), 12345);
+        let files = vec![listed, PathBuf::from("s3://bucket/total_file_size_test/unlisted.log")];
+
+        assert_eq!(super::total_file_size(&files, &listed_sizes), 12345)
+    }
+
+    #[test]
+    fn aws_command_error_should_say_the_aws_cli_is_needed_when_it_is_missing() {
+        let missing = super::aws_command_error(io::Error::from(io::ErrorKind::NotFound));
+        let denied = super::aws_command_error(io::Error::from(io::ErrorKind::PermissionDenied));
+
+        assert!(missing.to_string().contains("AWS CLI"));
+        assert_eq!(denied.kind(), io::ErrorKind::PermissionDenied)
+    }
+}
+
+#[cfg(test)]
+mod file_aggregator_read_records {
+
+    use std::collections::HashMap;
+    use std::fs::File;
+    use std::io::Read;
+    use std::path::{Path, PathBuf};
+    use std::sync::{Arc, Mutex};
+    use chrono::{DateTime, UTC};
+    use record_handling;
+    use test_common;
+
+    #[test]
+    fn read_records() {
+        let path = Path::new(test_common::TEST_LOG_FILE);
+        let file = File::open(&path).unwrap();
+        let mut file_aggregator = super::FileAggregator::new(0);
+
+
+        let _ = file_aggregator.read_records(&path, &file);
+
+        assert_eq!(file_aggregator.final_agg.len(),
+        test_common::TEST_LOG_FILE_AGGS)
+    }
+
+    #[test]
+    fn read_records_should_count_the_records_aggregated_from_the_file_when_configured_to() {
+        let path = Path::new(test_common::TEST_LOG_FILE);
+        let config = super::FileAggregatorConfig {
+            file_subtotals: true,
+            ..Default::default()
+        };
+        let mut file_aggregator = super::FileAggregator::with_config(0, config);
+
+        let _ = file_aggregator.read_records(&path, &File::open(&path).unwrap());
+
+        let total = file_aggregator.final_agg.values().sum::<i64>() as u64;
+        assert_eq!(file_aggregator.file_subtotals.into_iter().collect::<Vec<_>>(),
+                   vec![(path.to_path_buf(), total)])
+    }
+
+    #[test]
+    fn read_records_should_keep_the_records_of_the_file_from_each_day_when_configured_to() {
+        let path = Path::new(test_common::TEST_LOG_FILE);
+        let config = super::FileAggregatorConfig {
+            file_days: true,
+            ..Default::default()
+        };
+        let mut file_aggregator = super::FileAggregator::with_config(0, config);
+
+        let _ = file_aggregator.read_records(&path, &File::open(&path).unwrap());
+
+        let total = file_aggregator.final_agg.values().sum::<i64>() as u64;
+        let (day, files) = file_aggregator.file_days.into_iter().next().unwrap();
+        assert_eq!(day, "2016-12-05T00:00:00Z".parse::<DateTime<UTC>>().unwrap().date());
+        assert_eq!(files[path].num_records, total);
+        assert!(files[path].first < files[path].last)
+    }
+
+    #[test]
+    fn read_records_should_keep_the_timespan_of_every_aggregate_when_configured_to() {
+        let path = Path::new(test_common::TEST_LOG_FILE);
+        let config = super::FileAggregatorConfig {
+            timespans: true,
+            ..Default::default()
+        };
+        let mut file_aggregator = super::FileAggregator::with_config(0, config);
+
+        let _ = file_aggregator.read_records(&path, &File::open(&path).unwrap());
+
+        let timespans = &file_aggregator.timespans;
+        assert_eq!(timespans.len(), file_aggregator.final_agg.len());
+        assert!(file_aggregator.final_agg.keys().all(|key| timespans.contains_key(key)));
+        assert!(timespans.values().all(|timespan| timespan.first_seen <= timespan.last_seen))
+    }
+
+    #[test]
+    fn read_records_should_keep_the_timespans_of_the_records_of_a_parser_command() {
+        let path = Path::new(test_common::TEST_LOG_FILE);
+        let config = super::FileAggregatorConfig {
+            parser_command: Some("awk -v OFS='\\t' '{print $1, \"sys1\", $3, $11, $9}'".to_owned()),
+            timespans: true,
+            ..Default::default()
+        };
+        let mut file_aggregator = super::FileAggregator::with_config(0, config);
+
+        let _ = file_aggregator.read_file(&path);
+
+        let timespans = &file_aggregator.timespans;
+        assert!(!timespans.is_empty());
+        assert_eq!(timespans.len(), file_aggregator.final_agg.len())
+    }
+
+    #[test]
+    fn read_records_should_keep_the_metrics_of_every_aggregate_when_configured_to() {
+        let path = Path::new(test_common::TEST_LOG_FILE);
+        let config = super::FileAggregatorConfig {
+            aggregate_metrics: true,
+            ..Default::default()
+        };
+        let mut file_aggregator = super::FileAggregator::with_config(0, config);
+
+        let _ = file_aggregator.read_records(&path, &File::open(&path).unwrap());
+
+        let metrics = &file_aggregator.aggregate_metrics;
+        assert_eq!(metrics.len(), file_aggregator.final_agg.len());
+        for (key, total) in &file_aggregator.final_agg {
+            assert_eq!(metrics[key].status_classes.iter().sum::<u64>(), *total as u64);
+        }
+    }
+
+    #[test]
+    fn read_records_should_spill_the_aggregation_while_reading_a_file_when_configured_to() {
+        let path = Path::new(test_common::TEST_LOG_FILE);
+        let config = super::FileAggregatorConfig {
+            spill_after: Some(test_common::TEST_LOG_FILE_AGGS / 4),
+            ..Default::default()
+        };
+        let mut file_aggregator = super::FileAggregator::with_config(0, config);
+
+        let _ = file_aggregator.read_records(&path, &File::open(&path).unwrap());
+
+        let spill_files = file_aggregator.spill_files.clone();
+        ::spill::remove_spill_files(&spill_files);
+        assert!(spill_files.len() >= 3);
+        assert!(file_aggregator.final_agg.len() <= test_common::TEST_LOG_FILE_AGGS / 4)
+    }
+
+    #[test]
+    fn read_records_should_not_keep_metrics_by_default() {
+        let path = Path::new(test_common::TEST_LOG_FILE);
+        let mut file_aggregator = super::FileAggregator::new(0);
+
+        let _ = file_aggregator.read_records(&path, &File::open(&path).unwrap());
+
+        assert!(file_aggregator.aggregate_metrics.is_empty())
+    }
+
+    #[test]
+    fn read_records_should_make_fewer_larger_aggregates_when_grouping_by_fewer_dimensions() {
+        let path = Path::new(test_common::TEST_LOG_FILE);
+        let config = super::FileAggregatorConfig {
+            record_config: record_handling::RecordConfig {
+                group_by: vec![record_handling::Dimension::System, record_handling::Dimension::Day],
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let mut file_aggregator = super::FileAggregator::new(0);
+        let mut coarse_file_aggregator = super::FileAggregator::with_config(1, config);
+
+        let _ = file_aggregator.read_records(&path, &File::open(&path).unwrap());
+        let _ = coarse_file_aggregator.read_records(&path, &File::open(&path).unwrap());
+
+        let aggs = &file_aggregator.final_agg;
+        let coarse_aggs = &coarse_file_aggregator.final_agg;
+        assert!(coarse_aggs.len() < aggs.len());
+        assert!(coarse_aggs.values().max() > aggs.values().max());
+        assert_eq!(coarse_aggs.values().sum::<i64>(), aggs.values().sum::<i64>())
+    }
+
+    #[test]
+    fn read_records_should_not_count_subtotals_by_default() {
+        let path = Path::new(test_common::TEST_LOG_FILE);
+        let mut file_aggregator = super::FileAggregator::new(0);
+
+        let _ = file_aggregator.read_records(&path, &File::open(&path).unwrap());
+
+        assert!(file_aggregator.file_subtotals.is_empty())
+    }
+
+    #[test]
+    fn read_records_should_not_skip_records_repeated_in_another_file_when_deduping_per_file() {
+        let path = Path::new(test_common::TEST_LOG_FILE);
+        let config = super::FileAggregatorConfig {
+            dedupe: Some(super::RecordDeduplication::PerFile),
+            ..Default::default()
+        };
+        let mut file_aggregator = super::FileAggregator::with_config(0, config);
+
+        let _ = file_aggregator.read_records(&path, &File::open(&path).unwrap());
+        let _ = file_aggregator.read_records(&path, &File::open(&path).unwrap());
+
+        assert_eq!(file_aggregator.num_duplicate_records, 0);
+        assert_eq!(total_records(&file_aggregator), 2 * total_records_without_dedupe(&path))
+    }
+
+    #[test]
+    fn read_records_should_skip_records_repeated_in_another_file_when_deduping_globally() {
+        let path = Path::new(test_common::TEST_LOG_FILE);
+        let fingerprints = Arc::new(Mutex::new(HashMap::new()));
+        let config = super::FileAggregatorConfig {
+            dedupe: Some(super::RecordDeduplication::Global(fingerprints)),
+            ..Default::default()
+        };
+        let mut file_aggregator = super::FileAggregator::with_config(0, config);
+
+        let _ = file_aggregator.read_records(&path, &File::open(&path).unwrap());
+        let _ = file_aggregator.read_records(&path, &File::open(&path).unwrap());
+
+        assert_eq!(file_aggregator.num_duplicate_records,
+                   file_aggregator.num_raw_records / 2);
+        assert_eq!(total_records(&file_aggregator), total_records_without_dedupe(&path))
+    }
+
+    #[test]
+    fn remembering_fingerprints_should_tell_a_collision_from_a_duplicate() {
+        let mut fingerprints = HashMap::new();
+        fingerprints.insert(super::fingerprint("colliding", 0),
+                            !super::fingerprint("colliding", 1));
+
+        assert_eq!(super::remember_fingerprints(&mut fingerprints, "new"),
+                   super::Fingerprinted::New);
+        assert_eq!(super::remember_fingerprints(&mut fingerprints, "new"),
+                   super::Fingerprinted::Duplicate);
+        assert_eq!(super::remember_fingerprints(&mut fingerprints, "colliding"),
+                   super::Fingerprinted::Collision)
+    }
+
+    #[test]
+    fn read_records_should_squash_repeated_requests_within_the_window() {
+        let path = Path::new("squashed.log");
+        let record = |timestamp: &str, client: &str| {
+            format!("{} elb-name {}:54814 172.16.1.5:9000 0.000039 0.145507 0.00003 200 200 0 \
+                     7582 \"GET http://some.domain.com:80/path0?system=sys1&param1={} \
+                     HTTP/1.1\"\n",
+                    timestamp,
+                    client,
+                    timestamp)
+        };
+        let records = vec![record("2015-08-15T23:43:05.302180Z", "172.16.1.6"),
+                           record("2015-08-15T23:43:05.802180Z", "172.16.1.6"),
+                           record("2015-08-15T23:43:05.902180Z", "172.16.1.7"),
+                           record("2015-08-15T23:43:06.202180Z", "172.16.1.6"),
+                           record("2015-08-15T23:43:08.302180Z", "172.16.1.6")]
+            .concat();
+        let config = super::FileAggregatorConfig {
+            squash_window: Some(::std::time::Duration::from_millis(1000)),
+            ..Default::default()
+        };
+        let mut file_aggregator = super::FileAggregator::with_config(0, config);
+
+        let _ = file_aggregator.read_records(&path, records.as_bytes());
+
+        assert_eq!(file_aggregator.num_squashed_records, 2);
+        assert_eq!(total_records(&file_aggregator), 3)
+    }
+
+    #[test]
+    fn read_records_should_key_records_by_the_path_dimension() {
+        let path = Path::new(test_common::TEST_LOG_FILE);
+        let config = super::FileAggregatorConfig {
+            path_dimension: Some(super::PathDimension {
+                name: "artifacts".to_owned(),
+                log_root: PathBuf::from("."),
+                depth: 1,
+            }),
+            ..Default::default()
+        };
+        let mut file_aggregator = super::FileAggregator::with_config(0, config);
+
+        let _ = file_aggregator.read_records(&path, &File::open(&path).unwrap());
+
+        assert_eq!(file_aggregator.final_agg.len(), test_common::TEST_LOG_FILE_AGGS);
+        assert!(file_aggregator.final_agg
+            .keys()
+            .all(|key| key.path_dimension == Some("test_artifacts".to_owned())))
+    }
+
+    #[test]
+    fn read_records_should_not_aggregate_in_passthrough_mode() {
+        let path = Path::new(test_common::TEST_LOG_FILE);
+        let config = super::FileAggregatorConfig { passthrough: true, ..Default::default() };
+        let mut file_aggregator = super::FileAggregator::with_config(0, config);
+
+        let _ = file_aggregator.read_records(&path, &File::open(&path).unwrap());
+
+        assert!(file_aggregator.num_raw_records > 0);
+        assert_eq!(file_aggregator.final_agg.len(), 0)
+    }
+
+    #[test]
+    fn read_records_should_add_every_record_read_to_the_shared_record_counter() {
+        let path = Path::new(test_common::TEST_LOG_FILE);
+        let config = super::FileAggregatorConfig::default();
+        let metrics = config.metrics.clone();
+        let mut file_aggregator = super::FileAggregator::with_config(0, config);
+
+        let _ = file_aggregator.read_records(&path, &File::open(&path).unwrap());
+        let _ = file_aggregator.read_records(&path, &File::open(&path).unwrap());
+
+        assert_eq!(metrics.num_raw_records(), file_aggregator.num_raw_records as u64)
+    }
+
+    #[test]
+    fn read_records_should_not_aggregate_in_parse_only_mode() {
+        let path = Path::new(test_common::TEST_LOG_FILE);
+        let config = super::FileAggregatorConfig { parse_only: true, ..Default::default() };
+        let mut file_aggregator = super::FileAggregator::with_config(0, config);
+
+        let _ = file_aggregator.read_records(&path, &File::open(&path).unwrap());
+
+        assert!(file_aggregator.num_raw_records > 0);
+        assert_eq!(file_aggregator.final_agg.len(), 0)
+    }
+
+    #[test]
+    fn read_records_should_return_an_error_when_a_file_has_too_few_records() {
+        let path = Path::new(test_common::TEST_LOG_FILE);
+        let config = super::FileAggregatorConfig {
+            min_records_per_file: Some(1000),
+            ..Default::default()
+        };
+        let mut file_aggregator = super::FileAggregator::with_config(0, config);
+
+        let result = file_aggregator.read_records(&path, &File::open(&path).unwrap());
+
+        match result {
+            Err(super::FileHandlingErrors::TooFewRecords { num_lines, .. }) => {
+                assert_eq!(num_lines, file_aggregator.num_raw_records as u64)
+            }
+            other => panic!("Unexpected result {:?}.", other),
+        }
+    }
+
+    #[test]
+    fn read_records_should_report_no_lines_for_an_empty_file() {
+        let path = Path::new(test_common::EMPTY_LOG_FILE);
+        let config = super::FileAggregatorConfig {
+            min_records_per_file: Some(1),
+            ..Default::default()
+        };
+        let mut file_aggregator = super::FileAggregator::with_config(0, config);
+
+        let result = file_aggregator.read_records(&path, &File::open(&path).unwrap());
+
+        match result {
+            Err(super::FileHandlingErrors::TooFewRecords { num_lines, .. }) => {
+                assert_eq!(num_lines, 0)
+            }
+            other => panic!("Unexpected result {:?}.", other),
+        }
+    }
+
+    #[test]
+    fn read_records_should_succeed_when_a_file_has_enough_records() {
+        let path = Path::new(test_common::TEST_LOG_FILE);
+        let config = super::FileAggregatorConfig {
+            min_records_per_file: Some(1),
+            ..Default::default()
+        };
+        let mut file_aggregator = super::FileAggregator::with_config(0, config);
+
+        let result = file_aggregator.read_records(&path, &File::open(&path).unwrap());
+
+        assert!(result.is_ok())
+    }
+
+    #[test]
+    fn read_records_should_split_records_on_the_record_separator() {
+        let path = Path::new(test_common::NUL_SEPARATED_LOG_FILE);
+        let config = super::FileAggregatorConfig {
+            record_separator: Some(0),
+            ..Default::default()
+        };
+        let mut file_aggregator = super::FileAggregator::with_config(0, config);
+        let mut newline_separated = String::new();
+        let _ = File::open(&path).unwrap().read_to_string(&mut newline_separated);
+        let newline_separated = newline_separated.replace('\0', "\n");
+        let mut expected_file_aggregator = super::FileAggregator::new(0);
+
+        let _ = file_aggregator.read_records(&path, &File::open(&path).unwrap());
+        let _ = expected_file_aggregator.read_records(&path, newline_separated.as_bytes());
+
+        assert_eq!(file_aggregator.num_raw_records, 10);
+        assert_eq!(file_aggregator.final_agg, expected_file_aggregator.final_agg)
+    }
+
+    #[test]
+    fn read_records_should_time_reading_and_parsing_when_profiling() {
+        let path = Path::new(test_common::TEST_LOG_FILE);
+        let timings = Arc::new(::status::PhaseTimings::new());
+        let config = super::FileAggregatorConfig {
+            profile: Some(timings.clone()),
+            ..Default::default()
+        };
+        let mut file_aggregator = super::FileAggregator::with_config(0, config);
+
+        let _ = file_aggregator.read_records(&path, &File::open(&path).unwrap());
+
+        assert!(timings.nanos(::status::Phase::ReadIo) > 0);
+        assert!(timings.nanos(::status::Phase::Parsing) > 0);
+        assert_eq!(timings.nanos(::status::Phase::Merging), 0)
+    }
+
+    fn total_records(file_aggregator: &super::FileAggregator) -> i64 {
+        file_aggregator.final_agg.values().sum()
+    }
+
+    fn total_records_without_dedupe(path: &Path) -> i64 {
+        let mut file_aggregator = super::FileAggregator::new(0);
+        let _ = file_aggregator.read_records(&path, &File::open(&path).unwrap());
+        total_records(&file_aggregator)
+    }
+}
+
+#[cfg(test)]
+mod error_sampler_tests {
+
+    use std::env;
+    use std::fs::File;
+    use std::io::{BufRead, BufReader, Write};
+    use std::path::Path;
+    use test_common;
+
+    #[test]
+    fn the_sampler_should_keep_every_record_until_it_is_full() {
+        let mut sampler = super::ErrorSampler::new(3, 0);
+
+        sampler.sample(Path::new("a.log"), "bad0");
+        sampler.sample(Path::new("b.log"), "bad1");
+
+        assert_eq!(sampler.samples,
+                   vec![super::ErrorSample {
+                            path: Path::new("a.log").to_path_buf(),
+                            record: "bad0".to_owned(),
+                        },
+                        super::ErrorSample {
+                            path: Path::new("b.log").to_path_buf(),
+                            record: "bad1".to_owned(),
+                        }])
+    }
+
+    #[test]
+    fn the_sampler_should_never_keep_more_than_its_capacity() {
+        let mut sampler = super::ErrorSampler::new(3, 0);
+
+        for i in 0..100 {
+            sampler.sample(Path::new("a.log"), &format!("bad{}", i));
+        }
+
+        assert_eq!(sampler.samples.len(), 3);
+        assert_eq!(sampler.num_seen, 100)
+    }
+
+    #[test]
+    fn samplers_of_the_same_seed_should_keep_the_same_records() {
+        let mut samplers = vec![super::ErrorSampler::new(3, 7), super::ErrorSampler::new(3, 7)];
+
+        for sampler in samplers.iter_mut() {
+            for i in 0..100 {
+                sampler.sample(Path::new("a.log"), &format!("bad{}", i));
+            }
+        }
+
+        assert_eq!(samplers[0].samples, samplers[1].samples)
+    }
+
+    #[test]
+    fn the_sampler_should_ignore_records_it_already_kept() {
+        let mut sampler = super::ErrorSampler::new(3, 0);
+
+        sampler.sample(Path::new("a.log"), "bad0");
+        sampler.sample(Path::new("b.log"), "bad0");
+
+        assert_eq!(sampler.samples.len(), 1);
+        assert_eq!(sampler.num_seen, 1)
+    }
+
+    #[test]
+    fn read_records_should_sample_the_unparsable_records_with_their_file() {
+        let path = env::temp_dir().join("counter_sample_errors_test.log");
+        let record = BufReader::new(File::open(test_common::TEST_LOG_FILE).unwrap())
+            .lines()
+            .next()
+            .unwrap()
+            .unwrap();
+        {
+            let mut file = File::create(&path).unwrap();
+            writeln!(file, "{}\nnot a record\n{}\nnot a record", record, record).unwrap();
+        }
+        let config = super::FileAggregatorConfig {
+            sample_errors: 5,
+            ..Default::default()
+        };
+        let mut file_aggregator = super::FileAggregator::with_config(0, config);
+
+        let _ = file_aggregator.read_records(&path, &File::open(&path).unwrap());
+
+        assert_eq!(file_aggregator.error_samples.samples,
+                   vec![super::ErrorSample {
+                            path: path.clone(),
+                            record: "not a record".to_owned(),
+                        }])
+    }
+
+    #[test]
+    fn the_sampler_should_keep_nothing_when_its_capacity_is_zero() {
+        let mut sampler = super::ErrorSampler::new(0, 0);
+
+        sampler.sample(Path::new("a.log"), "bad0");
+
+        assert!(sampler.samples.is_empty())
+    }
+}
+
+#[cfg(test)]
+mod record_trace_tests {
+
+    use chrono::{DateTime, UTC};
+    use std::env;
+    use std::fs::File;
+    use std::io::{BufRead, BufReader};
+    use std::net::Ipv4Addr;
+    use std::path::{Path, PathBuf};
+    use std::sync::Arc;
+    use test_common;
+
+    fn read_trace(file_name: &str, limit: usize) -> (usize, Vec<String>) {
+        let trace_path = env::temp_dir().join(file_name);
+        let record = BufReader::new(File::open(test_common::TEST_LOG_FILE).unwrap())
+            .lines()
+            .next()
+            .unwrap()
+            .unwrap();
+        let key = ::record_handling::record_key(&record, None, &Default::default()).unwrap();
+        let trace = Arc::new(super::RecordTrace::new(key.system_name,
+                                                     key.day,
+                                                     key.client_address.unwrap(),
+                                                     limit,
+                                                     File::create(&trace_path).unwrap()));
+        let config = super::FileAggregatorConfig {
+            trace: Some(trace.clone()),
+            ..Default::default()
+        };
+        let mut file_aggregator = super::FileAggregator::with_config(0, config);
+        let path = Path::new(test_common::TEST_LOG_FILE);
+
+        let _ = file_aggregator.read_records(&path, &File::open(&path).unwrap());
+        trace.flush().unwrap();
+
+        let traced = BufReader::new(File::open(&trace_path).unwrap())
+            .lines()
+            .map(|line| line.unwrap())
+            .collect::<Vec<_>>();
+        assert!(traced.contains(&record));
+        (trace.num_traced(), traced)
+    }
+
+    #[test]
+    fn trace_should_write_the_records_matching_the_key() {
+        let (num_traced, traced) = read_trace("counter_trace_all.log", 1000);
+
+        assert_eq!(num_traced, traced.len());
+        assert!(num_traced > 0)
+    }
+
+    #[test]
+    fn trace_should_write_no_more_than_the_limit() {
+        let (num_traced, traced) = read_trace("counter_trace_limited.log", 1);
+
+        assert_eq!(num_traced, 1);
+        assert_eq!(traced.len(), 1)
+    }
+
+    #[test]
+    fn trace_should_ignore_records_of_other_keys() {
+        let trace_path: PathBuf = env::temp_dir().join("counter_trace_none.log");
+        let day = "2000-01-01T00:00:00Z".parse::<DateTime<UTC>>().unwrap().date();
+        let trace = Arc::new(super::RecordTrace::new("no-such-system".to_owned(),
+                                                     day,
+                                                     "10.0.0.1".parse::<Ipv4Addr>().unwrap(),
+                                                     10,
+                                                     File::create(&trace_path).unwrap()));
+        let config = super::FileAggregatorConfig {
+            trace: Some(trace.clone()),
+            ..Default::default()
+        };
+        let mut file_aggregator = super::FileAggregator::with_config(0, config);
+        let path = Path::new(test_common::TEST_LOG_FILE);
+
+        let _ = file_aggregator.read_records(&path, &File::open(&path).unwrap());
+
+        assert_eq!(trace.num_traced(), 0)
+    }
+}
+
+#[cfg(test)]
+mod key_dictionary_tests {
+
+    use std::collections::HashSet;
+    use std::env;
+    use std::fs::File;
+    use std::io::{BufRead, BufReader};
+    use std::path::Path;
+    use std::sync::Arc;
+    use test_common;
+
+    #[test]
+    fn key_dictionary_should_write_the_fields_of_every_hashed_key_once() {
+        let dictionary_path = env::temp_dir().join("counter_key_dictionary.csv");
+        let record_config = ::record_handling::RecordConfig {
+            hash_keys: true,
+            ..Default::default()
+        };
+        let dictionary =
+            Arc::new(super::KeyDictionary::new(&record_config.group_by,
+                                               File::create(&dictionary_path).unwrap()));
+        let config = super::FileAggregatorConfig {
+            record_config: record_config,
+            key_dictionary: Some(dictionary.clone()),
+            ..Default::default()
+        };
+        let mut file_aggregator = super::FileAggregator::with_config(0, config);
+        let path = Path::new(test_common::TEST_LOG_FILE);
+
+        let _ = file_aggregator.read_records(&path, &File::open(&path).unwrap());
+        let _ = file_aggregator.read_records(&path, &File::open(&path).unwrap());
+        dictionary.flush().unwrap();
+
+        let rows = BufReader::new(File::open(&dictionary_path).unwrap())
+            .lines()
+            .map(|line| line.unwrap())
+            .collect::<Vec<_>>();
+        let record = BufReader::new(File::open(test_common::TEST_LOG_FILE).unwrap())
+            .lines()
+            .next()
+            .unwrap()
+            .unwrap();
+        let key = ::record_handling::record_key(&record, None, &Default::default()).unwrap();
+        let hashes = file_aggregator.final_agg
+            .keys()
+            .map(|key| key.system_name.clone())
+            .collect::<HashSet<_>>();
+        assert_eq!(dictionary.num_keys(), hashes.len());
+        assert_eq!(rows.len(), hashes.len());
+        assert!(rows.contains(&format!("{},{},{}",
+                                       key.key_hash(),
+                                       key.system_name,
+                                       key.client_address.unwrap())))
+    }
+
+    #[test]
+    fn key_dictionary_should_write_the_hashed_keys_of_the_records_of_a_parser_command() {
+        let dictionary_path = env::temp_dir().join("counter_key_dictionary_normalized.csv");
+        let record_config = ::record_handling::RecordConfig {
+            hash_keys: true,
+            ..Default::default()
+        };
+        let dictionary =
+            Arc::new(super::KeyDictionary::new(&record_config.group_by,
+                                               File::create(&dictionary_path).unwrap()));
+        let config = super::FileAggregatorConfig {
+            parser_command: Some("awk -v OFS='\\t' '{print $1, \"sys1\", $3, $11, $9}'".to_owned()),
+            record_config: record_config,
+            key_dictionary: Some(dictionary.clone()),
+            ..Default::default()
+        };
+        let mut file_aggregator = super::FileAggregator::with_config(0, config);
+
+        let _ = file_aggregator.read_file(Path::new(test_common::TEST_LOG_FILE));
+        dictionary.flush().unwrap();
+
+        let rows = BufReader::new(File::open(&dictionary_path).unwrap())
+            .lines()
+            .map(|line| line.unwrap())
+            .collect::<Vec<_>>();
+        let hashes = file_aggregator.final_agg
+            .keys()
+            .map(|key| key.system_name.clone())
+            .collect::<HashSet<_>>();
+        assert!(!rows.is_empty());
+        assert_eq!(rows.len(), hashes.len());
+        assert!(rows.iter().all(|row| row.split(',').nth(1) == Some("sys1")))
+    }
+}
+
+#[cfg(test)]
+mod rejects_file_tests {
+
+    use std::env;
+    use std::fs::{File, OpenOptions};
+    use std::io::{BufRead, BufReader};
+    use std::path::Path;
+    use std::sync::Arc;
+    use test_common;
+
+    #[test]
+    fn the_rejects_file_should_hold_every_malformed_record_with_its_line_and_error() {
+        let rejects_path = env::temp_dir().join("counter_rejects.csv");
+        let rejects = Arc::new(super::RejectsFile::new(File::create(&rejects_path).unwrap()));
+        let config = super::FileAggregatorConfig {
+            rejects: Some(rejects.clone()),
+            ..Default::default()
+        };
+        let mut file_aggregator = super::FileAggregator::with_config(0, config);
+        let log_path = Path::new(test_common::MALFORMED_LOG_FILE);
+
+        let _ = file_aggregator.read_file(&log_path);
+        rejects.flush().unwrap();
+
+        let rows = BufReader::new(File::open(&rejects_path).unwrap())
+            .lines()
+            .map(|line| line.unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(rejects.num_rejected(), file_aggregator.num_malformed_records);
+        assert_eq!(rows.len(), file_aggregator.num_malformed_records);
+        assert!(rows[0].starts_with(&format!("{},5,", test_common::MALFORMED_LOG_FILE)));
+        assert!(rows[0].ends_with(",this line is not an ELB record"))
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn flushing_a_rejects_file_that_can_not_be_written_should_fail() {
+        let full = OpenOptions::new().write(true).open("/dev/full").unwrap();
+        let rejects = Arc::new(super::RejectsFile::new(full));
+        let config = super::FileAggregatorConfig {
+            rejects: Some(rejects.clone()),
+            ..Default::default()
+        };
+        let mut file_aggregator = super::FileAggregator::with_config(0, config);
+
+        let _ = file_aggregator.read_file(Path::new(test_common::MALFORMED_LOG_FILE));
+
+        assert!(rejects.flush().is_err())
+    }
+}
+
+#[cfg(test)]
+mod file_aggregator_process_file_tests {
+    use std::path::Path;
+    use std::fs::File;
+    use std::io::{BufRead, BufReader, Read};
+    use std::time::Duration;
+    use chrono::{DateTime, UTC};
+    use record_handling;
+    use test_common;
+
+    #[test]
+    fn process_file_should_return_a_result_with_the_correct_number_of_processed_records() {
+        let num_lines = BufReader::new(File::open(test_common::TEST_LOG_FILE).unwrap())
+            .lines()
+            .collect::<Vec<_>>()
+            .len();
+        let log_path = Path::new(test_common::TEST_LOG_FILE);
+        let mut file_aggregator = super::FileAggregator::new(0);
+
+        let _ = file_aggregator.read_file(&log_path);
+
+        assert_eq!(file_aggregator.num_raw_records, num_lines)
+    }
+
+    #[test]
+    fn process_file_should_leave_records_outside_of_the_date_range_out_of_every_count() {
+        let day = |day: &str| format!("{}T00:00:00Z", day).parse::<DateTime<UTC>>().unwrap().date();
+        let log_path = Path::new(test_common::TEST_LOG_FILE);
+        let mut file_aggregator = super::FileAggregator::new(0);
+        let config = super::FileAggregatorConfig {
+            record_config: record_handling::RecordConfig {
+                date_range: Some(record_handling::DateRange {
+                    from: Some(day("2016-12-06")),
+                    to: Some(day("2016-12-06")),
+                }),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let mut windowed_file_aggregator = super::FileAggregator::with_config(1, config);
+
+        let _ = file_aggregator.read_file(&log_path);
+        let _ = windowed_file_aggregator.read_file(&log_path);
+
+        assert_eq!(windowed_file_aggregator.final_agg.len(), 0);
+        assert_eq!(windowed_file_aggregator.num_raw_records, 0);
+        assert_eq!(windowed_file_aggregator.num_outside_date_range_records,
+                   file_aggregator.num_raw_records)
+    }
+
+    #[test]
+    fn process_file_should_count_the_malformed_records_and_keep_reading_the_file() {
+        let log_path = Path::new(test_common::MALFORMED_LOG_FILE);
+        let mut file_aggregator = super::FileAggregator::new(0);
+
+        let _ = file_aggregator.read_file(&log_path);
+
+        assert_eq!(file_aggregator.num_raw_records, 12);
+        assert_eq!(file_aggregator.num_malformed_records, 3);
+        assert_eq!(file_aggregator.final_agg.values().sum::<i64>(), 9)
+    }
+
+    #[test]
+    fn process_file_should_report_the_first_malformed_record_of_the_file() {
+        let log_path = Path::new(test_common::MALFORMED_LOG_FILE);
+        let mut file_aggregator = super::FileAggregator::new(0);
+
+        let _ = file_aggregator.read_file(&log_path);
+
+        let malformed_records = &file_aggregator.malformed_records[log_path];
+        assert_eq!(file_aggregator.malformed_records.len(), 1);
+        assert_eq!(malformed_records.num_records, 3);
+        assert_eq!(malformed_records.first_line_num, 5);
+        assert!(!malformed_records.first_error.is_empty())
+    }
+
+    #[test]
+    fn read_lines_should_report_the_records_earlier_than_a_record_before_them() {
+        let path = Path::new("unsorted.log");
+        let record = |time: &str| {
+            format!("2015-08-15T{}Z elb-name 172.16.1.6:54814 172.16.1.5:9000 0.000039 \
+                     0.145507 0.00003 200 200 0 7582 \"GET \
+                     http://some.domain.com:80/path0/path1?system=sys1&param1=p1 HTTP/1.1\"",
+                    time)
+        };
+        let lines = vec![record("10:00:00"),
+                         record("10:00:02"),
+                         record("10:00:01"),
+                         record("10:00:02"),
+                         record("10:00:00"),
+                         record("10:00:03")];
+        let config = super::FileAggregatorConfig { verify_sorted: true, ..Default::default() };
+        let mut file_aggregator = super::FileAggregator::with_config(0, config);
+
+        let _ = file_aggregator.read_lines(&path, lines.into_iter().map(Ok));
+
+        assert_eq!(file_aggregator.unsorted_files[path],
+                   super::UnsortedRecords {
+                       num_records: 2,
+                       first_line_num: 3,
+                   });
+        assert_eq!(file_aggregator.final_agg.values().sum::<i64>(), 6)
+    }
+
+    #[test]
+    fn read_lines_should_aggregate_a_mix_of_alb_and_elb_records() {
+        let path = Path::new("mixed.log");
+        let lines = vec!["2015-08-15T10:00:00Z elb-name 172.16.1.6:54814 172.16.1.5:9000 \
+                          0.000039 0.145507 0.00003 200 200 0 7582 \"GET \
+                          http://some.domain.com:80/path0?system=sys1 HTTP/1.1\""
+                             .to_owned(),
+                         "http 2015-08-15T10:00:01Z app/alb-name/50dc6c495c0c9188 \
+                          172.16.1.6:54815 172.16.1.5:9000 0.000039 0.145507 0.00003 200 200 0 \
+                          7582 \"GET http://some.domain.com:80/path0?system=sys1 HTTP/1.1\" \
+                          \"curl/7.46.0\" - - \
+                          arn:aws:elasticloadbalancing:us-east-2:123456789012:targetgroup/tg/1 \
+                          \"Root=1-58337262-36d228ad5d99923122bbe354\""
+                             .to_owned()];
+        let mut file_aggregator = super::FileAggregator::new(0);
+
+        let _ = file_aggregator.read_lines(&path, lines.into_iter().map(Ok));
+
+        assert!(file_aggregator.malformed_records.is_empty());
+        assert_eq!(file_aggregator.final_agg.len(), 1);
+        assert_eq!(file_aggregator.final_agg.values().sum::<i64>(), 2)
+    }
+
+    #[test]
+    fn read_lines_should_not_report_files_sorted_by_timestamp() {
+        let path = Path::new("sorted.log");
+        let config = super::FileAggregatorConfig { verify_sorted: true, ..Default::default() };
+        let mut file_aggregator = super::FileAggregator::with_config(0, config);
+        let lines = vec!["2015-08-15T10:00:00Z a".to_owned(),
+                         "not a record".to_owned(),
+                         "2015-08-15T10:00:00Z b".to_owned(),
+                         "2015-08-15T10:00:01Z c".to_owned()];
+
+        let _ = file_aggregator.read_lines(&path, lines.into_iter().map(Ok));
+
+        assert!(file_aggregator.unsorted_files.is_empty())
+    }
+
+    #[test]
+    fn process_file_should_abandon_the_file_once_the_timeout_has_passed() {
+        let log_path = Path::new(test_common::TEST_LOG_FILE);
+        let config = super::FileAggregatorConfig {
+            file_timeout: Some(Duration::from_secs(0)),
+            ..Default::default()
+        };
+        let mut file_aggregator = super::FileAggregator::with_config(0, config);
 
-#[derive(Debug)]
-pub struct FileAggregator {
-    id: usize,
-    num_raw_records: usize,
-    final_agg: ELBRecordAggregation,
-}
+        let result = file_aggregator.read_file(&log_path);
 
-impl FileAggregator {
-    pub fn new(id: usize) -> FileAggregator {
-        FileAggregator {
-            id: id,
-            num_raw_records: 0,
-            final_agg: HashMap::new(),
+        match result {
+            Err(super::FileHandlingErrors::TimedOut { num_lines, .. }) => assert_eq!(num_lines, 0),
+            _ => panic!("The file was not abandoned."),
         }
+        assert_eq!(file_aggregator.final_agg.len(), 0)
     }
 
-    pub fn run(mut self,
-               filename_receiver: &mpsc::Receiver<FileHandlingMessages>,
-               aggregate_sender: &mpsc::Sender<AggregationMessages>)
-               -> () {
-        let _ = aggregate_sender.send(AggregationMessages::Next(self.id));
-        let timeout = Duration::from_millis(10000);
-        loop {
-            match filename_receiver.recv_timeout(timeout) {
-                Ok(FileHandlingMessages::Filename(filename)) => {
-                    self.aggregate_file(filename.as_path());
-                    let _ = aggregate_sender.send(AggregationMessages::Next(self.id));
-                }
-                Ok(FileHandlingMessages::Done) => break,
-                Err(RecvTimeoutError::Timeout) => {
-                    debug!("A timeout occurred while FileAggregator {} was waiting \
-                    for a filename. This may mean that the controller is having a hard time \
-                    keeping up. But in most cases it just means there are no more files \
-                    to process and this FileAggregator is just waiting for the shut down \
-                    message.",
-                    self.id)
-                }
-                Err(RecvTimeoutError::Disconnected) => {
-                    // If this channel has disconnected there's no guarantee the other
-                    // channels are still connected. This is very bad and is going to produce
-                    // bad aggregates. Therefore, we write to stderr so the message can be captured
-                    // and we kill the thread.
-                    let msg = format!("FileAggregator {} has disconnected from the controller.\
-                            This is unrecoverable error and should be reported to the developers.",
-                                      self.id);
-                    println_stderr!("{}", msg);
-                    panic!(msg);
-                }
-            }
-        }
+    #[test]
+    fn process_file_should_read_the_whole_file_within_the_timeout() {
+        let log_path = Path::new(test_common::TEST_LOG_FILE);
+        let config = super::FileAggregatorConfig {
+            file_timeout: Some(Duration::from_secs(3600)),
+            ..Default::default()
+        };
+        let mut file_aggregator = super::FileAggregator::with_config(0, config);
 
-        let _ = aggregate_sender.send(AggregationMessages::Aggregate(self.num_raw_records,
-                                                                     self.final_agg));
+        let result = file_aggregator.read_file(&log_path);
+
+        assert!(result.is_ok());
+        assert_eq!(file_aggregator.final_agg.len(), test_common::TEST_LOG_FILE_AGGS)
     }
 
-    fn aggregate_file(&mut self, file_path: &Path) -> () {
-        debug!("FileAggregator {} received filename {}.",
-        self.id,
-        file_path.display());
-        match self.read_file(file_path) {
-            Err(FileHandlingErrors::FileReadError { path, err }) => {
-                println_stderr!("Failed to read file {} with error {}. ",
-                                path.display(),
-                                err)
-            }
-            Err(FileHandlingErrors::LineReadError { path, line_nums }) => {
-                println_stderr!("Failed to read lines {:?} from file {}. ",
-                                line_nums,
-                                path.display())
-            }
-            Ok(()) => {}
-        }
+    #[test]
+    fn process_file_should_aggregate_the_normalized_records_written_by_the_parser_command() {
+        let log_path = Path::new(test_common::TEST_LOG_FILE);
+        let config = super::FileAggregatorConfig {
+            parser_command: Some("awk -v OFS='\\t' '{print $1, \"sys1\", $3, $11, $9}'".to_owned()),
+            ..Default::default()
+        };
+        let mut file_aggregator = super::FileAggregator::with_config(0, config);
+
+        let result = file_aggregator.read_file(&log_path);
+
+        assert!(result.is_ok());
+        assert!(file_aggregator.num_raw_records > 0);
+        assert_eq!(file_aggregator.final_agg.values().sum::<i64>() as usize,
+                   file_aggregator.num_raw_records);
+        assert!(file_aggregator.final_agg.keys().all(|key| key.system_name == "sys1"))
     }
 
-    fn read_file<'a>(&mut self, path: &'a Path) -> Result<(), FileHandlingErrors<'a>> {
-        debug!("Processing file {}.", path.display());
-        match File::open(path) {
-            Ok(file) => self.read_records(path, &file),
-            Err(err) => {
-                Err(FileHandlingErrors::FileReadError {
-                    path: path,
-                    err: err,
-                })
+    #[test]
+    fn process_file_should_count_the_normalized_records_without_a_weight() {
+        let log_path = Path::new(test_common::TEST_LOG_FILE);
+        let config = super::FileAggregatorConfig {
+            parser_command: Some("awk -v OFS='\\t' '{print $1, \"sys1\", $3, \"-\", $9}'"
+                .to_owned()),
+            record_config: ::record_handling::RecordConfig {
+                weight_field: Some(::record_handling::WeightField::SentBytes),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let mut file_aggregator = super::FileAggregator::with_config(0, config);
+
+        let result = file_aggregator.read_file(&log_path);
+
+        assert!(result.is_ok());
+        assert!(file_aggregator.num_raw_records > 0);
+        assert_eq!(file_aggregator.num_unweighted_records, file_aggregator.num_raw_records);
+        assert_eq!(file_aggregator.final_agg.values().sum::<i64>(), 0)
+    }
+
+    #[test]
+    fn process_file_should_report_a_parser_command_exiting_with_an_error() {
+        let log_path = Path::new(test_common::TEST_LOG_FILE);
+        let config = super::FileAggregatorConfig {
+            parser_command: Some("exit 3".to_owned()),
+            ..Default::default()
+        };
+        let mut file_aggregator = super::FileAggregator::with_config(0, config);
+
+        let result = file_aggregator.read_file(&log_path);
+
+        match result {
+            Err(super::FileHandlingErrors::ParserCommandFailed { err, .. }) => {
+                assert!(err.contains("exited"))
             }
+            _ => panic!("The failure of the parser command was not reported."),
         }
     }
 
-    fn read_records<'a>(&mut self,
-                        path: &'a Path,
-                        file: &File)
-                        -> Result<(), FileHandlingErrors<'a>> {
-        let mut bad_line_nums = Vec::new();
-        let mut records_processed = 0;
-        for (line_num, possible_record) in BufReader::new(file).lines().enumerate() {
-            if let Ok(record) = possible_record {
-                record_handling::try_parse_record(&record, &mut self.final_agg);
-                records_processed += 1;
-            } else {
-                bad_line_nums.push(line_num);
+    #[test]
+    fn process_file_should_stop_once_the_records_span_more_than_the_max_day_range() {
+        let log_path = Path::new(test_common::TEST_LOG_FILE);
+        let config = super::FileAggregatorConfig {
+            max_day_range: Some(0),
+            ..Default::default()
+        };
+        let mut file_aggregator = super::FileAggregator::with_config(0, config);
+
+        let result = file_aggregator.read_file(&log_path);
+
+        match result {
+            Err(super::FileHandlingErrors::DayRangeExceeded { num_days, .. }) => {
+                assert_eq!(num_days, 1)
             }
+            _ => panic!("The file was read past the maximum day range."),
         }
+        assert_eq!(file_aggregator.final_agg.values().sum::<i64>(), 1)
+    }
 
-        debug!("Found {} records in file {}.",
-        records_processed,
-        path.display());
-        self.num_raw_records += records_processed;
-        if bad_line_nums.is_empty() {
-            Ok(())
-        } else {
-            Err(FileHandlingErrors::LineReadError {
-                line_nums: bad_line_nums,
-                path: path,
-            })
+    #[test]
+    fn process_file_should_stop_once_more_aggregates_than_the_limit_are_held() {
+        let log_path = Path::new(test_common::TEST_LOG_FILE);
+        let config = super::FileAggregatorConfig {
+            max_aggregates: Some(2),
+            ..Default::default()
+        };
+        let metrics = config.metrics.clone();
+        let mut file_aggregator = super::FileAggregator::with_config(0, config);
+
+        let result = file_aggregator.read_file(&log_path);
+
+        match result {
+            Err(super::FileHandlingErrors::TooManyAggregates { num_aggregates, .. }) => {
+                assert_eq!(num_aggregates, 3)
+            }
+            _ => panic!("The file was read past the limit of aggregates."),
         }
+        assert_eq!(file_aggregator.final_agg.len(), 3);
+        assert_eq!(metrics.num_held_aggregates(), 3)
     }
-}
 
-#[cfg(test)]
-mod file_aggregator_run_tests {
+    #[test]
+    fn process_file_should_stop_once_other_file_aggregators_hold_more_aggregates_than_the_limit() {
+        let log_path = Path::new(test_common::TEST_LOG_FILE);
+        let config = super::FileAggregatorConfig {
+            max_aggregates: Some(1),
+            ..Default::default()
+        };
+        config.metrics.add_held_aggregate();
+        config.metrics.add_held_aggregate();
+        let mut file_aggregator = super::FileAggregator::with_config(0, config);
 
-    use std::sync::mpsc;
+        let result = file_aggregator.read_file(&log_path);
+
+        match result {
+            Err(super::FileHandlingErrors::TooManyAggregates { num_aggregates, .. }) => {
+                assert_eq!(num_aggregates, 2)
+            }
+            _ => panic!("The file was read past the limit of aggregates."),
+        }
+        assert!(file_aggregator.final_agg.is_empty())
+    }
 
     #[test]
-    fn sends_the_final_agg_after_receiving_the_done_message() {
-        let (filename_sender, filename_receiver) = mpsc::channel();
-        let (agg_sender, agg_receiver) = mpsc::channel();
-        let _ = filename_sender.send(super::FileHandlingMessages::Done);
-        let file_aggregator = super::FileAggregator::new(1);
-        file_aggregator.run(&filename_receiver, &agg_sender);
+    fn process_file_should_stop_at_a_record_overflowing_its_total_under_the_error_policy() {
+        let log_path = Path::new(test_common::TEST_LOG_FILE);
+        let config = super::FileAggregatorConfig {
+            record_config: record_handling::RecordConfig {
+                count_overflow_policy: record_handling::CountOverflowPolicy::Error,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let mut file_aggregator = super::FileAggregator::with_config(0, config);
+        let _ = file_aggregator.read_file(&log_path);
+        for total in file_aggregator.final_agg.values_mut() {
+            *total = record_handling::SATURATED_TOTAL;
+        }
 
-        // Dump the AggregationMessages::Next message sent at startup.
-        let _ = agg_receiver.recv();
+        let result = file_aggregator.read_file(&log_path);
 
-        match agg_receiver.recv().unwrap() {
-            super::AggregationMessages::Aggregate(size, agg) => {
-                assert_eq!(size, 0);
-                assert_eq!(agg.len(), 0);
+        match result {
+            Err(super::FileHandlingErrors::CountOverflowed { line_num, .. }) => {
+                assert_eq!(line_num, 1)
             }
-            super::AggregationMessages::Next(_) => panic!("Received an unexpected Next message."),
+            _ => panic!("The overflow of the total was not reported."),
         }
+        assert_eq!(file_aggregator.num_count_overflows, 1);
+        assert!(file_aggregator.final_agg
+            .values()
+            .all(|&total| total == record_handling::SATURATED_TOTAL))
     }
 
     #[test]
-    fn sends_a_next_message_after_starting_up() {
-        let (filename_sender, filename_receiver) = mpsc::channel();
-        let (agg_sender, agg_receiver) = mpsc::channel();
-        let _ = filename_sender.send(super::FileHandlingMessages::Done);
-        let file_aggregator = super::FileAggregator::new(1);
+    fn process_file_should_read_records_within_the_max_day_range() {
+        let log_path = Path::new(test_common::TEST_LOG_FILE);
+        let config = super::FileAggregatorConfig {
+            max_day_range: Some(1),
+            ..Default::default()
+        };
+        let mut file_aggregator = super::FileAggregator::with_config(0, config);
 
-        file_aggregator.run(&filename_receiver, &agg_sender);
+        let result = file_aggregator.read_file(&log_path);
 
-        match agg_receiver.recv().unwrap() {
-            super::AggregationMessages::Next(id) => assert_eq!(id, 1),
-            super::AggregationMessages::Aggregate(_, _) => panic!("Received an unexpected Aggregate message."),
-        }
+        assert!(result.is_ok());
+        assert_eq!(file_aggregator.final_agg.len(), test_common::TEST_LOG_FILE_AGGS);
+        assert_eq!(super::num_days(file_aggregator.first_day, file_aggregator.last_day), 1)
     }
-}
 
-#[cfg(test)]
-mod file_aggregator_read_records {
+    #[test]
+    fn process_file_should_read_a_gzipped_file_like_the_uncompressed_one() {
+        let mut file_aggregator = super::FileAggregator::new(0);
+        let mut gzipped_file_aggregator = super::FileAggregator::new(1);
 
-    use std::fs::File;
-    use std::path::Path;
-    use test_common;
+        let _ = file_aggregator.read_file(Path::new(test_common::TEST_LOG_FILE));
+        let result = gzipped_file_aggregator.read_file(Path::new(test_common::GZIPPED_LOG_FILE));
+
+        assert!(result.is_ok());
+        assert_eq!(gzipped_file_aggregator.num_raw_records,
+                   file_aggregator.num_raw_records);
+        assert_eq!(gzipped_file_aggregator.final_agg, file_aggregator.final_agg)
+    }
 
     #[test]
-    fn read_records() {
-        let path = Path::new(test_common::TEST_LOG_FILE);
-        let file = File::open(&path).unwrap();
+    fn process_file_should_read_every_member_of_a_multi_member_gzipped_file() {
         let mut file_aggregator = super::FileAggregator::new(0);
+        let mut gzipped_file_aggregator = super::FileAggregator::new(1);
 
+        let _ = file_aggregator.read_file(Path::new(test_common::TEST_LOG_FILE));
+        let result = gzipped_file_aggregator
+            .read_file(Path::new(test_common::MULTI_MEMBER_GZIPPED_LOG_FILE));
 
-        let _ = file_aggregator.read_records(&path, &file);
+        assert!(result.is_ok());
+        assert_eq!(gzipped_file_aggregator.num_raw_records, 100 + 150);
+        assert_eq!(gzipped_file_aggregator.num_raw_records,
+                   file_aggregator.num_raw_records);
+        assert_eq!(gzipped_file_aggregator.final_agg, file_aggregator.final_agg)
+    }
 
-        assert_eq!(file_aggregator.final_agg.len(),
-        test_common::TEST_LOG_FILE_AGGS)
+    #[test]
+    fn process_file_should_return_an_error_when_the_file_cannot_be_opened() {
+        let log_path = Path::new("bad_filename");
+        let mut file_aggregator = super::FileAggregator::new(0);
+
+        let result = file_aggregator.read_file(&log_path);
+
+        assert!(result.is_err())
     }
 }
 
-#[cfg(test)]
-mod file_aggregator_process_file_tests {
-    use std::path::Path;
+#[cfg(all(test, feature = "tar"))]
+mod file_aggregator_read_archive_tests {
+
     use std::fs::File;
-    use std::io::{BufRead, BufReader};
+    use std::io::{Cursor, Read};
+    use std::path::{Path, PathBuf};
+    use tar;
     use test_common;
 
     #[test]
-    fn process_file_should_return_a_result_with_the_correct_number_of_processed_records() {
-        let num_lines = BufReader::new(File::open(test_common::TEST_LOG_FILE).unwrap())
-            .lines()
-            .collect::<Vec<_>>()
-            .len();
-        let log_path = Path::new(test_common::TEST_LOG_FILE);
+    fn read_archive_should_aggregate_the_log_file_members() {
+        let path = Path::new(test_common::TEST_LOG_ARCHIVE);
         let mut file_aggregator = super::FileAggregator::new(0);
 
-        let _ = file_aggregator.read_file(&log_path);
+        let result = file_aggregator.read_archive(&path, File::open(&path).unwrap());
 
-        assert_eq!(file_aggregator.num_raw_records, num_lines)
+        assert!(result.is_ok());
+        assert_eq!(file_aggregator.final_agg.len(), test_common::TEST_LOG_FILE_AGGS)
     }
 
     #[test]
-    fn process_file_should_return_an_error_when_the_file_cannot_be_opened() {
-        let log_path = Path::new("bad_filename");
+    fn read_archive_should_aggregate_the_log_file_members_of_a_gzipped_archive() {
+        let path = Path::new(test_common::GZIPPED_LOG_ARCHIVE);
         let mut file_aggregator = super::FileAggregator::new(0);
 
-        let result = file_aggregator.read_file(&log_path);
+        let result = file_aggregator.read_archive(&path, File::open(&path).unwrap());
 
-        assert!(result.is_err())
+        assert!(result.is_ok());
+        assert_eq!(file_aggregator.final_agg.len(), test_common::TEST_LOG_FILE_AGGS)
+    }
+
+    #[test]
+    fn read_archive_should_read_the_members_after_one_that_fails() {
+        let mut log_file = Vec::new();
+        File::open(test_common::TEST_LOG_FILE).unwrap().read_to_end(&mut log_file).unwrap();
+        let mut builder = tar::Builder::new(Vec::new());
+        let members: [(&str, &[u8]); 2] = [("logs/broken.log.gz", b"not gzipped"),
+                                          ("logs/test_elb_log_file.log", &log_file)];
+        for &(member_path, contents) in &members {
+            let mut header = tar::Header::new_gnu();
+            header.set_path(member_path).unwrap();
+            header.set_size(contents.len() as u64);
+            header.set_entry_type(tar::EntryType::Regular);
+            header.set_cksum();
+            builder.append(&header, contents).unwrap();
+        }
+        let archive = builder.into_inner().unwrap();
+        let path = Path::new("logs.tar");
+        let mut file_aggregator = super::FileAggregator::new(0);
+
+        let result = file_aggregator.read_archive(&path, Cursor::new(archive));
+
+        assert!(result.is_ok());
+        assert_eq!(file_aggregator.final_agg.len(), test_common::TEST_LOG_FILE_AGGS)
+    }
+
+    #[test]
+    fn is_archive_should_match_tar_and_gzipped_tar_files() {
+        assert!(super::is_archive(Path::new("logs.tar")));
+        assert!(super::is_archive(Path::new("logs.tar.gz")));
+        assert!(super::is_archive(Path::new("logs.tgz")));
+        assert!(!super::is_archive(Path::new("logs.log.gz")))
+    }
+
+    #[test]
+    fn file_list_should_include_archives() {
+        let files = super::file_list(Path::new("./test_artifacts")).unwrap();
+
+        assert!(files.contains(&PathBuf::from(test_common::TEST_LOG_ARCHIVE)))
     }
 }
 
@@ -268,7 +2925,7 @@ mod file_list_tests {
 
     use std::{fs, panic, sync};
     use std::io::Write;
-    use std::path::Path;
+    use std::path::{Path, PathBuf};
     use self::rand::distributions::{IndependentSample, Range};
 
     #[test]
@@ -304,6 +2961,30 @@ mod file_list_tests {
         })
     }
 
+    #[test]
+    fn file_list_should_include_gzipped_files() {
+        let files = super::file_list(Path::new("./test_artifacts")).unwrap();
+
+        assert!(files.contains(&PathBuf::from(::test_common::GZIPPED_LOG_FILE)))
+    }
+
+    #[test]
+    fn s3_listing_entry_should_return_the_size_and_key_of_a_listed_object() {
+        assert_eq!(super::s3_listing_entry("2015-08-15 23:45:01      12345 logs/2015/file.log"),
+                   Some((12345, "logs/2015/file.log")));
+        assert_eq!(super::s3_listing_entry("2015-08-15 23:45:01 7 logs/a file.log"),
+                   Some((7, "logs/a file.log")));
+        assert_eq!(super::s3_listing_entry("                           PRE logs/"), None);
+        assert_eq!(super::s3_listing_entry(""), None)
+    }
+
+    #[test]
+    fn is_s3_uri_should_only_accept_s3_uris() {
+        assert!(super::is_s3_uri(Path::new("s3://bucket/logs/file.log")));
+        assert!(!super::is_s3_uri(Path::new("./test_artifacts/file.log")));
+        assert!(!super::is_s3_uri(Path::new("bucket/s3://file.log")))
+    }
+
     #[test]
     fn file_list_should_return_0_when_there_are_no_files_in_the_directory() {
         run_int_test_in_test_dir(|test_dir| {
@@ -358,4 +3039,73 @@ mod file_list_tests {
     }
 
     const NAME_GENERATOR: NameGenerator = NameGenerator { generator: None };
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod overlapping_files_tests {
+    use std::collections::BTreeMap;
+    use std::path::{Path, PathBuf};
+    use chrono::{DateTime, UTC};
+
+    fn file_day(num_records: u64, first: &str, last: &str) -> super::FileDay {
+        let timestamp = |time: &str| {
+            format!("2016-12-05T{}Z", time).parse::<DateTime<UTC>>().unwrap()
+        };
+        super::FileDay {
+            num_records: num_records,
+            first: timestamp(first),
+            last: timestamp(last),
+        }
+    }
+
+    fn file_days(files: Vec<(&str, super::FileDay)>) -> super::FileDays {
+        let day = "2016-12-05T00:00:00Z".parse::<DateTime<UTC>>().unwrap().date();
+        let mut file_days = BTreeMap::new();
+        file_days.insert(day,
+                         files.into_iter()
+                             .map(|(path, file_day)| (PathBuf::from(path), file_day))
+                             .collect());
+        file_days
+    }
+
+    #[test]
+    fn overlapping_files_should_find_files_with_similar_records_over_the_same_times() {
+        let file_days = file_days(vec![("a.log", file_day(100, "10:00:00", "11:00:00")),
+                                       ("b.log", file_day(95, "10:30:00", "11:30:00"))]);
+
+        let overlapping = super::overlapping_files(&file_days);
+
+        assert_eq!(overlapping.len(), 1);
+        assert_eq!(overlapping[0].files[0].0, Path::new("a.log"));
+        assert_eq!(overlapping[0].files[1].0, Path::new("b.log"))
+    }
+
+    #[test]
+    fn overlapping_files_should_not_find_files_from_different_times_of_the_day() {
+        let file_days = file_days(vec![("a.log", file_day(100, "10:00:00", "11:00:00")),
+                                       ("b.log", file_day(100, "11:00:01", "12:00:00"))]);
+
+        assert!(super::overlapping_files(&file_days).is_empty())
+    }
+
+    #[test]
+    fn overlapping_files_should_not_find_files_with_very_different_numbers_of_records() {
+        let file_days = file_days(vec![("a.log", file_day(100, "10:00:00", "11:00:00")),
+                                       ("b.log", file_day(50, "10:00:00", "11:00:00"))]);
+
+        assert!(super::overlapping_files(&file_days).is_empty())
+    }
+
+    #[test]
+    fn merge_file_days_should_combine_the_records_of_the_same_file_and_day() {
+        let mut dst = file_days(vec![("a.log", file_day(100, "10:00:00", "11:00:00"))]);
+        let src = file_days(vec![("a.log", file_day(20, "09:00:00", "10:30:00")),
+                                 ("b.log", file_day(5, "12:00:00", "12:30:00"))]);
+
+        super::merge_file_days(&src, &mut dst);
+
+        let files = dst.values().next().unwrap();
+        assert_eq!(files[Path::new("a.log")], file_day(120, "09:00:00", "11:00:00"));
+        assert_eq!(files[Path::new("b.log")], file_day(5, "12:00:00", "12:30:00"))
+    }
+}
@@ -0,0 +1,177 @@
+use kafka;
+use kafka::producer::{Producer, Record, RequiredAcks};
+use output::aggregate_json;
+use record_handling::AggregateELBRecord;
+use std::collections::HashMap;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::time::Duration;
+
+const BATCH_SIZE: usize = 1000;
+
+/// Publishes aggregates to a Kafka topic as one JSON message per aggregate, keyed by system name.
+/// Each message is sent to a partition picked by its system name, see `partition_of`, so that all
+/// of a system's aggregates land in the same partition and every message is known to be
+/// delivered or not by the confirmation of its partition.
+pub struct KafkaPublisher {
+    producer: Producer,
+    topic: String,
+    path_dimension_name: Option<String>,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct PublishSummary {
+    pub num_delivered: usize,
+    pub num_failed: usize,
+}
+
+impl KafkaPublisher {
+    /// When `require_acks` is set every in-sync replica must acknowledge a message before it is
+    /// considered delivered, otherwise only the partition leader has to.
+    pub fn new(brokers: Vec<String>,
+               topic: &str,
+               require_acks: bool,
+               path_dimension_name: Option<String>)
+               -> Result<KafkaPublisher, kafka::Error> {
+        let required_acks = if require_acks {
+            RequiredAcks::All
+        } else {
+            RequiredAcks::One
+        };
+        let producer = Producer::from_hosts(brokers)
+            .with_ack_timeout(Duration::from_secs(5))
+            .with_required_acks(required_acks)
+            .create()?;
+
+        Ok(KafkaPublisher {
+            producer: producer,
+            topic: topic.to_owned(),
+            path_dimension_name: path_dimension_name,
+        })
+    }
+
+    /// Sends the aggregates in batches and waits for each batch to be confirmed, so every message
+    /// has either been delivered or reported as failed by the time this returns.
+    pub fn publish(&mut self,
+                   aggs: &[(AggregateELBRecord, i64)])
+                   -> Result<PublishSummary, kafka::Error> {
+        let mut summary = PublishSummary {
+            num_delivered: 0,
+            num_failed: 0,
+        };
+        let partition_ids = self.partition_ids();
+        for batch in aggs.chunks(BATCH_SIZE) {
+            let path_dimension_name = self.path_dimension_name.as_ref().map(|name| name.as_str());
+            let payloads = batch.iter()
+                .map(|&(ref agg_key, total)| {
+                    aggregate_json(agg_key, total, path_dimension_name).to_string()
+                })
+                .collect::<Vec<_>>();
+            let partitions = batch.iter()
+                .map(|&(ref agg_key, _)| partition_of(&agg_key.system_name, &partition_ids))
+                .collect::<Vec<_>>();
+            let records = batch.iter()
+                .zip(payloads.iter())
+                .zip(partitions.iter())
+                .map(|((&(ref agg_key, _), payload), &partition)| {
+                    Record::from_key_value(self.topic.as_str(),
+                                           agg_key.system_name.as_bytes(),
+                                           payload.as_bytes())
+                        .with_partition(partition)
+                })
+                .collect::<Vec<_>>();
+
+            // A partition is confirmed once for all of the messages of the batch sent to it.
+            let mut delivered = HashMap::new();
+            for confirm in self.producer.send_all(&records)? {
+                for partition_confirm in confirm.partition_confirms {
+                    if let Err(ref code) = partition_confirm.offset {
+                        debug!("Kafka rejected the messages for partition {} of {}. {:?}",
+                               partition_confirm.partition,
+                               confirm.topic,
+                               code);
+                    }
+                    delivered.insert(partition_confirm.partition, partition_confirm.offset.is_ok());
+                }
+            }
+            add_confirmed(&mut summary, &partitions, &delivered);
+        }
+
+        Ok(summary)
+    }
+
+    /// The partitions of the topic that have a leader to send to.
+    fn partition_ids(&self) -> Vec<i32> {
+        self.producer
+            .client()
+            .topics()
+            .partitions(&self.topic)
+            .map(|partitions| partitions.available_ids())
+            .unwrap_or_else(Vec::new)
+    }
+}
+
+/// The partition the messages of a system are sent to, the same for every run as long as the
+/// topic has the same partitions. Without any partitions the partition is left to the producer,
+/// which fails the send.
+fn partition_of(system_name: &str, partition_ids: &[i32]) -> i32 {
+    if partition_ids.is_empty() {
+        return -1;
+    }
+    let mut hasher = DefaultHasher::new();
+    system_name.hash(&mut hasher);
+    partition_ids[(hasher.finish() % partition_ids.len() as u64) as usize]
+}
+
+/// Counts every message of a batch as delivered or failed by the confirmation of the partition
+/// it was sent to, `partitions` holding the partition of each message. A message to a partition
+/// that wasn't confirmed counts as failed.
+fn add_confirmed(summary: &mut PublishSummary,
+                 partitions: &[i32],
+                 delivered: &HashMap<i32, bool>)
+                 -> () {
+    for partition in partitions {
+        if delivered.get(partition).cloned().unwrap_or(false) {
+            summary.num_delivered += 1
+        } else {
+            summary.num_failed += 1
+        }
+    }
+}
+
+#[cfg(test)]
+mod publish_summary_tests {
+
+    use std::collections::HashMap;
+    use super::PublishSummary;
+
+    #[test]
+    fn every_message_should_be_counted_by_the_confirmation_of_its_partition() {
+        let mut summary = PublishSummary {
+            num_delivered: 1,
+            num_failed: 0,
+        };
+        let mut delivered = HashMap::new();
+        delivered.insert(0, true);
+        delivered.insert(1, false);
+
+        super::add_confirmed(&mut summary, &[0, 0, 1, 2, 0], &delivered);
+
+        assert_eq!(summary,
+                   PublishSummary {
+                       num_delivered: 4,
+                       num_failed: 2,
+                   })
+    }
+
+    #[test]
+    fn the_messages_of_a_system_should_be_sent_to_one_of_the_partitions() {
+        let partition_ids = [3, 5, 8];
+
+        let partition = super::partition_of("sys1", &partition_ids);
+
+        assert!(partition_ids.contains(&partition));
+        assert_eq!(super::partition_of("sys1", &partition_ids), partition);
+        assert_eq!(super::partition_of("sys1", &[]), -1)
+    }
+}
@@ -8,11 +8,24 @@ extern crate regex;
 extern crate lazy_static;
 extern crate scoped_pool as sp;
 extern crate num_cpus;
+extern crate sha2;
+extern crate rand;
+extern crate flate2;
+#[cfg(feature = "kafka")]
+extern crate kafka;
+extern crate rustc_serialize;
+#[cfg(feature = "tar")]
+extern crate tar;
+#[cfg(feature = "yaml")]
+extern crate yaml_rust;
 
 use std::fmt;
 use std::fmt::{Display, Formatter};
 use std::error::Error;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
+use std::mem;
+use std::path::PathBuf;
+use chrono::{Date, UTC};
 
 #[macro_export]
 macro_rules! println_stderr(
@@ -27,11 +40,172 @@ macro_rules! println_stderr(
 pub mod file_handling;
 pub mod record_handling;
 pub mod aggregation_control;
+pub mod runner;
+pub mod status;
+pub mod output;
+pub mod spill;
+pub mod checkpoint;
+pub mod statsd_output;
+#[cfg(feature = "kafka")]
+pub mod kafka_output;
+#[cfg(feature = "yaml")]
+pub mod yaml_output;
 
 pub type ELBRecordAggregation = HashMap<record_handling::AggregateELBRecord, i64>;
+#[derive(Debug, Default, PartialEq)]
 pub struct FileAggregation {
+    /// The number of records read, not counting those from outside of the date range.
     pub num_raw_records: usize,
+    pub num_duplicate_records: usize,
+    /// The number of records sharing the first fingerprint of a different record when deduping,
+    /// which are aggregated rather than skipped, see `file_handling::RecordDeduplication`.
+    pub num_fingerprint_collisions: usize,
+    /// The number of records skipped for repeating a request within the squash window.
+    pub num_squashed_records: usize,
+    pub num_no_system_records: usize,
+    /// The number of records weighted as zero for lacking a numeric weight, see
+    /// `record_handling::CountedRecord`.
+    pub num_unweighted_records: usize,
+    pub num_excluded_status_records: usize,
+    pub num_outside_date_range_records: usize,
+    pub num_outside_business_hours_records: usize,
+    /// The number of records skipped for not matching a filter, see
+    /// `record_handling::RecordFilter`.
+    pub num_filtered_records: usize,
+    /// The number of records elp couldn't parse, which are read but otherwise skipped.
+    pub num_malformed_records: usize,
+    /// The malformed records of each file having any.
+    pub malformed_records: BTreeMap<PathBuf, file_handling::MalformedRecords>,
+    /// The records out of timestamp order of each file having any, kept when the FileAggregators
+    /// are configured to verify that the files are sorted.
+    pub unsorted_files: BTreeMap<PathBuf, file_handling::UnsortedRecords>,
+    /// Unparsable records sampled by the FileAggregators configured to keep some.
+    pub error_samples: Vec<file_handling::ErrorSample>,
+    /// The number of records aggregated from each file, kept when the FileAggregators are
+    /// configured to.
+    pub file_subtotals: BTreeMap<PathBuf, u64>,
+    /// The records each file holds from each day, kept when the FileAggregators are configured
+    /// to.
+    pub file_days: file_handling::FileDays,
+    /// The earliest and latest day of the records aggregated, kept when the FileAggregators are
+    /// configured with a maximum day range.
+    pub first_day: Option<Date<UTC>>,
+    pub last_day: Option<Date<UTC>>,
+    /// The earliest and latest timestamp of the records counted towards every aggregate, kept
+    /// when the FileAggregators are configured to.
+    pub timespans: record_handling::Timespans,
+    /// The bytes, latencies and status codes of the records counted towards every aggregate,
+    /// kept when the FileAggregators are configured to.
+    pub aggregate_metrics: record_handling::Metrics,
+    /// The files the FileAggregators spilled aggregations to when configured to, see
+    /// `spill::spill`. Their aggregates aren't in `aggregation` until the AggregationController
+    /// merges them in.
+    pub spill_files: Vec<PathBuf>,
+    /// The number of times adding to the total of an aggregate would have overflowed it under
+    /// the error overflow policy, see `record_handling::CountOverflowPolicy`.
+    pub num_count_overflows: usize,
     pub aggregation: ELBRecordAggregation,
+    /// The aggregates merged in parallel, as partitions holding disjoint keys, see
+    /// `AggregationController::merge_in_parallel`. Every key is either in `aggregation` or in one
+    /// of them, see `num_aggregates` and `take_aggregations`.
+    pub partitions: Vec<ELBRecordAggregation>,
+}
+
+impl FileAggregation {
+    pub fn new() -> FileAggregation {
+        Default::default()
+    }
+
+    /// Adds the counts of `src` to this aggregation. Merging sums every key rather than replacing
+    /// it, so partial aggregations of the same file can be merged in any order and grouping.
+    pub fn merge(&mut self, src: &FileAggregation) -> () {
+        self.merge_with_policy(src, record_handling::CountOverflowPolicy::Saturate)
+    }
+
+    /// Merges like `merge` while adding the totals by the overflow policy.
+    pub fn merge_with_policy(&mut self,
+                             src: &FileAggregation,
+                             policy: record_handling::CountOverflowPolicy)
+                             -> () {
+        self.num_raw_records += src.num_raw_records;
+        self.num_duplicate_records += src.num_duplicate_records;
+        self.num_fingerprint_collisions += src.num_fingerprint_collisions;
+        self.num_squashed_records += src.num_squashed_records;
+        self.num_no_system_records += src.num_no_system_records;
+        self.num_unweighted_records += src.num_unweighted_records;
+        self.num_excluded_status_records += src.num_excluded_status_records;
+        self.num_outside_date_range_records += src.num_outside_date_range_records;
+        self.num_outside_business_hours_records += src.num_outside_business_hours_records;
+        self.num_filtered_records += src.num_filtered_records;
+        self.num_malformed_records += src.num_malformed_records;
+        for (path, malformed_records) in &src.malformed_records {
+            // The first malformed record of whichever part of the file was merged first is kept.
+            self.malformed_records
+                .entry(path.clone())
+                .or_insert_with(|| {
+                    file_handling::MalformedRecords { num_records: 0, ..malformed_records.clone() }
+                })
+                .num_records += malformed_records.num_records;
+        }
+        for (path, unsorted_records) in &src.unsorted_files {
+            self.unsorted_files
+                .entry(path.clone())
+                .or_insert_with(|| {
+                    file_handling::UnsortedRecords { num_records: 0, ..unsorted_records.clone() }
+                })
+                .num_records += unsorted_records.num_records;
+        }
+        self.error_samples.extend(src.error_samples.iter().cloned());
+        for (path, num_records) in &src.file_subtotals {
+            *self.file_subtotals.entry(path.clone()).or_insert(0) += *num_records;
+        }
+        file_handling::merge_file_days(&src.file_days, &mut self.file_days);
+        self.first_day = match (self.first_day, src.first_day) {
+            (Some(first), Some(src_first)) => Some(first.min(src_first)),
+            (first, src_first) => first.or(src_first),
+        };
+        self.last_day = match (self.last_day, src.last_day) {
+            (Some(last), Some(src_last)) => Some(last.max(src_last)),
+            (last, src_last) => last.or(src_last),
+        };
+        record_handling::merge_timespans(&src.timespans, &mut self.timespans);
+        record_handling::merge_metrics(&src.aggregate_metrics, &mut self.aggregate_metrics);
+        self.spill_files.extend(src.spill_files.iter().cloned());
+        let mut num_overflows = 0;
+        for aggregation in Some(&src.aggregation).into_iter().chain(&src.partitions) {
+            num_overflows += record_handling::merge_aggregates_with_policy(aggregation,
+                                                                           &mut self.aggregation,
+                                                                           policy);
+        }
+        self.num_count_overflows += src.num_count_overflows + num_overflows;
+    }
+
+    /// The number of aggregates, in `aggregation` and the partitions.
+    pub fn num_aggregates(&self) -> usize {
+        self.aggregation.len() +
+        self.partitions.iter().map(|partition| partition.len()).sum::<usize>()
+    }
+
+    /// Takes `aggregation` and the partitions, which hold disjoint keys, leaving both empty. The
+    /// aggregates are read from them one after the other rather than merged into one map.
+    pub fn take_aggregations(&mut self) -> Vec<ELBRecordAggregation> {
+        let mut aggregations = vec![mem::replace(&mut self.aggregation, HashMap::new())];
+        aggregations.extend(self.partitions.drain(..));
+        aggregations
+    }
+
+    /// Moves the aggregates of the partitions into `aggregation`, for the readers needing every
+    /// aggregate in one map.
+    pub fn combine_partitions(&mut self) -> () {
+        for partition in self.partitions.drain(..) {
+            self.aggregation.extend(partition);
+        }
+    }
+
+    /// The number of days the records aggregated span, see `file_handling::num_days`.
+    pub fn num_days(&self) -> i64 {
+        file_handling::num_days(self.first_day, self.last_day)
+    }
 }
 pub type CounterResult<'a> = Result<elp::ELBRecord<'a>, CounterError<'a>>;
 
@@ -8,15 +8,46 @@ extern crate chrono;
 #[macro_use]
 extern crate counter;
 extern crate num_cpus;
-extern crate scoped_pool as sp;
+extern crate flate2;
+extern crate atty;
+#[cfg(feature = "yaml")]
+extern crate yaml_rust;
 
 use std::path::Path;
-use chrono::{DateTime, UTC};
+use chrono::{Date, DateTime, UTC};
+use counter::checkpoint::Checkpoint;
 use counter::file_handling;
-use counter::aggregation_control::AggregationController;
+use counter::record_handling;
+use counter::output;
+use counter::output::{AggregateSink, CsvSink, InfluxSink, JsonLinesSink, JsonSink, MatrixSink};
+use counter::runner::{RunError, Runner, Watch};
+use counter::spill;
+use counter::statsd_output;
+use counter::statsd_output::{Datagrams, MetricProtocol, MetricSink};
+use counter::status::{Phase, PhaseTimings, RunMetrics};
+#[cfg(feature = "status-socket")]
+use counter::status::StatusServer;
+#[cfg(feature = "kafka")]
+use counter::kafka_output::KafkaPublisher;
+#[cfg(feature = "yaml")]
+use counter::yaml_output;
+#[cfg(feature = "yaml")]
+use counter::yaml_output::YamlSink;
+use std::cell::Cell;
+use std::io;
 use std::io::Write;
-use std::sync::mpsc;
+use std::sync::{Arc, Mutex};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::env;
+use std::ffi::OsString;
+use std::fs;
+use std::fs::File;
+use std::net::Ipv4Addr;
 use std::path::PathBuf;
+use std::thread;
+use std::time::{Duration, Instant};
+#[cfg(feature = "status-socket")]
+use std::net::SocketAddr;
 
 const EXIT_SUCCESS: i32 = 0;
 const EXIT_FAILURE: i32 = 1;
@@ -24,6 +55,72 @@ const EXIT_FAILURE: i32 = 1;
 fn main() {
     env_logger::init().unwrap();
     let runtime_context = RuntimeContext::new();
+    if let Err(e) = runtime_context.date_range() {
+        println_stderr!("{}", e);
+        std::process::exit(EXIT_FAILURE);
+    }
+    if let Err(e) = runtime_context.time_window() {
+        println_stderr!("{}", e);
+        std::process::exit(EXIT_FAILURE);
+    }
+    if let Err(e) = runtime_context.parser_command() {
+        println_stderr!("{}", e);
+        std::process::exit(EXIT_FAILURE);
+    }
+    if let Err(e) = runtime_context.check_day_range() {
+        println_stderr!("{}", e);
+        std::process::exit(EXIT_FAILURE);
+    }
+    if let Err(e) = runtime_context.check_max_memory() {
+        println_stderr!("{}", e);
+        std::process::exit(EXIT_FAILURE);
+    }
+    if let Some(record) = runtime_context.explain_record() {
+        for step in record_handling::explain_record(record, &runtime_context.record_config()) {
+            println!("{}", step);
+        }
+        std::process::exit(EXIT_SUCCESS);
+    }
+    if runtime_context.print_schema() {
+        match runtime_context.output_schema() {
+            Ok(schema) => println!("{}", schema),
+            Err(e) => {
+                println_stderr!("{}", e);
+                std::process::exit(EXIT_FAILURE);
+            }
+        }
+        std::process::exit(EXIT_SUCCESS);
+    }
+    // The output file is created before the run so an unwritable path fails it straight away.
+    let output_file = match runtime_context.output_path() {
+        Some(path) => {
+            match File::create(path) {
+                Ok(file) => Some(file),
+                Err(e) => {
+                    println_stderr!("Could not create the output file {}. {}", path.display(), e);
+                    std::process::exit(EXIT_FAILURE);
+                }
+            }
+        }
+        None => {
+            if runtime_context.compress_gzip() && atty::is(atty::Stream::Stdout) {
+                println_stderr!("Refusing to write gzip compressed aggregates to a terminal. \
+                                 Redirect stdout or write them to a file with --output.");
+                std::process::exit(EXIT_FAILURE);
+            }
+            None
+        }
+    };
+    let baseline = match runtime_context.baseline() {
+        Ok(baseline) => baseline,
+        Err(e) => {
+            println_stderr!("{}", e);
+            std::process::exit(EXIT_FAILURE);
+        }
+    };
+    if let Some(flush_interval) = runtime_context.flush_interval() {
+        std::process::exit(watch(&runtime_context, flush_interval));
+    }
     let log_location = runtime_context.log_location();
 
     debug!("Running summary on {}.", log_location.to_str().unwrap());
@@ -34,38 +131,393 @@ fn main() {
         None
     };
 
-    let exit_code = match file_handling::file_list(log_location) {
-        Ok(ref mut filenames) => {
+    let file_listing_start = Instant::now();
+    let exit_code = match file_handling::log_file_list(log_location) {
+        Ok((mut filenames, listed_sizes)) => {
             let num_files = filenames.len();
             debug!("Found {} files.", num_files);
 
-            let mut runner = Runner::new();
-            let final_agg = runner.run(num_cpus::get(), filenames);
+            let mut file_aggregator_config = runtime_context.file_aggregator_config();
+            file_aggregator_config.listed_sizes = Arc::new(listed_sizes);
+            match runtime_context.record_trace() {
+                Ok(trace) => file_aggregator_config.trace = trace.map(Arc::new),
+                Err(e) => {
+                    println_stderr!("{}", e);
+                    std::process::exit(EXIT_FAILURE);
+                }
+            }
+            match runtime_context.key_dictionary() {
+                Ok(dictionary) => file_aggregator_config.key_dictionary = dictionary.map(Arc::new),
+                Err(e) => {
+                    println_stderr!("{}", e);
+                    std::process::exit(EXIT_FAILURE);
+                }
+            }
+            match runtime_context.rejects() {
+                Ok(rejects) => file_aggregator_config.rejects = rejects.map(Arc::new),
+                Err(e) => {
+                    println_stderr!("{}", e);
+                    std::process::exit(EXIT_FAILURE);
+                }
+            }
+            let checkpoint = match runtime_context.checkpoint() {
+                Ok(checkpoint) => checkpoint,
+                Err(e) => {
+                    println_stderr!("{}", e);
+                    std::process::exit(EXIT_FAILURE);
+                }
+            };
+            if let Some(ref checkpoint) = checkpoint {
+                if !checkpoint.read_files.is_empty() {
+                    println_stderr!("Resuming from {}, skipping the {} files read before.",
+                                    runtime_context.checkpoint_path().unwrap().display(),
+                                    checkpoint.read_files.len());
+                }
+            }
+            let spills = file_aggregator_config.spill_after.is_some();
+            let trace = file_aggregator_config.trace.clone();
+            let key_dictionary = file_aggregator_config.key_dictionary.clone();
+            let rejects = file_aggregator_config.rejects.clone();
+            let profile = file_aggregator_config.profile.clone();
+            if let Some(ref timings) = profile {
+                timings.add(Phase::FileListing, file_listing_start.elapsed());
+            }
+            if file_aggregator_config.passthrough {
+                println_stderr!("Passthrough mode writes a line for every one of the records in \
+                                 the {} files found. Expect output as large as the logs.",
+                                num_files);
+            }
+            let mut runner = Runner::with_config(file_aggregator_config);
+            #[cfg(feature = "status-socket")]
+            let _status_server = runtime_context.status_addr().map(|addr| {
+                match StatusServer::start(addr, runner.metrics()) {
+                    Ok(server) => server,
+                    Err(e) => {
+                        println_stderr!("Unable to start the status socket on {}. {}", addr, e);
+                        std::process::exit(EXIT_FAILURE);
+                    }
+                }
+            });
+            if runtime_context.progress() {
+                runner.enable_progress();
+            }
+            if let Some(max_aggregates) = runtime_context.max_aggregates() {
+                runner.limit_aggregates(max_aggregates);
+            }
+            if let Some(merge_threads) = runtime_context.merge_threads() {
+                runner.merge_in_parallel(merge_threads);
+            }
+            if spills {
+                runner.keep_spill_files();
+            }
+            let run_result = match checkpoint {
+                Some(checkpoint) => {
+                    runner.run_checkpointed(runtime_context.num_threads(),
+                                            &mut filenames,
+                                            checkpoint,
+                                            runtime_context.checkpoint_path().unwrap())
+                }
+                None => runner.run(runtime_context.num_threads(), &mut filenames),
+            };
+            // Also before aborting, for the records read up to then.
+            let mut flushed = true;
+            if let Some(ref trace) = trace {
+                flushed &= report_flush_error(runtime_context.trace_output().unwrap(),
+                                              trace.flush());
+            }
+            if let Some(ref key_dictionary) = key_dictionary {
+                flushed &= report_flush_error(runtime_context.key_dictionary_output().unwrap(),
+                                              key_dictionary.flush());
+            }
+            if let Some(ref rejects) = rejects {
+                flushed &= report_flush_error(runtime_context.rejects_output().unwrap(),
+                                              rejects.flush());
+            }
+            let mut final_agg = match run_result {
+                Ok(final_agg) => final_agg,
+                Err(RunError::TooManyAggregates(e)) => {
+                    println_stderr!("Aborted after accumulating {} aggregates, more than the limit \
+                                     of {}. Aggregate by fewer dimensions, such as without \
+                                     --path-dimension, or raise --max-aggregates.",
+                                    e.num_aggregates,
+                                    e.max_aggregates);
+                    runner.shutdown();
+                    std::process::exit(EXIT_FAILURE);
+                }
+                Err(e) => {
+                    println_stderr!("Aborted. {}", e);
+                    runner.shutdown();
+                    std::process::exit(EXIT_FAILURE);
+                }
+            };
+            if !flushed {
+                spill::remove_spill_files(&final_agg.spill_files);
+                runner.shutdown();
+                std::process::exit(EXIT_FAILURE);
+            }
+
+            if let Err(e) = check_aggregation(&runtime_context, &final_agg) {
+                println_stderr!("{}", e);
+                spill::remove_spill_files(&final_agg.spill_files);
+                runner.shutdown();
+                std::process::exit(EXIT_FAILURE);
+            }
 
             debug!("Processed {} records in {} files.",
             final_agg.num_raw_records,
             num_files);
 
-            for (aggregate, total) in &final_agg.aggregation {
-                println!("{},{},{},{}",
-                         aggregate.system_name,
-                         aggregate.day.format("%Y-%m-%d").to_string(),
-                         aggregate.client_address,
-                         total);
+            if let Some(trace) = trace {
+                println_stderr!("Traced {} records to {}.",
+                                trace.num_traced(),
+                                runtime_context.trace_output().unwrap());
+            }
+
+            if let Some(key_dictionary) = key_dictionary {
+                println_stderr!("Wrote the fields of {} hashed keys to {}.",
+                                key_dictionary.num_keys(),
+                                runtime_context.key_dictionary_output().unwrap());
+            }
+
+            if runtime_context.dedupe_enabled() {
+                println_stderr!("Skipped {} duplicate records.", final_agg.num_duplicate_records);
+                if final_agg.num_fingerprint_collisions > 0 {
+                    println_stderr!("Counted {} records sharing a fingerprint with a different \
+                                     record, whose duplicates weren't skipped.",
+                                    final_agg.num_fingerprint_collisions);
+                }
+            }
+
+            if runtime_context.squash_window().is_some() {
+                println_stderr!("Squashed {} records repeating a request within --squash-window.",
+                                final_agg.num_squashed_records);
+            }
+
+            if final_agg.num_no_system_records > 0 {
+                if runtime_context.drop_no_system() {
+                    println_stderr!("Skipped {} records without a system name.",
+                                    final_agg.num_no_system_records);
+                } else {
+                    println_stderr!("Found {} records without a system name.",
+                                    final_agg.num_no_system_records);
+                }
+            }
+
+            if final_agg.num_unweighted_records > 0 {
+                println_stderr!("Weighted {} records without a numeric --weight-field as 0.",
+                                final_agg.num_unweighted_records);
+            }
+
+            // Merged in parallel, the aggregates are in partitions, read one after the other.
+            let mut aggregations = final_agg.take_aggregations();
+            let num_saturated =
+                aggregations.iter().map(record_handling::num_saturated).sum::<usize>();
+            let saturates = runtime_context.count_overflow_policy() ==
+                            record_handling::CountOverflowPolicy::Saturate;
+            if saturates && num_saturated > 0 {
+                report_saturated(num_saturated);
+            }
+
+            if !final_agg.error_samples.is_empty() {
+                println_stderr!("Sampled {} unparsable records:", final_agg.error_samples.len());
+                for sample in &final_agg.error_samples {
+                    println_stderr!("  {}: {}", sample.path.display(), sample.record);
+                }
+            }
+
+            // A file with malformed records is usually a truncated or corrupted delivery, so
+            // every one of them is named.
+            if final_agg.num_malformed_records > 0 || runtime_context.run_benchmark() {
+                println_stderr!("Skipped {} malformed records across {} files.",
+                                final_agg.num_malformed_records,
+                                final_agg.malformed_records.len());
+                for (path, malformed_records) in &final_agg.malformed_records {
+                    println_stderr!("  {}: {} malformed records, the first on line {}. {}",
+                                    path.display(),
+                                    malformed_records.num_records,
+                                    malformed_records.first_line_num,
+                                    malformed_records.first_error);
+                }
+            }
+
+            if let Some(rejects) = rejects {
+                println_stderr!("Wrote {} malformed records to {}.",
+                                rejects.num_rejected(),
+                                runtime_context.rejects_output().unwrap());
+            }
+
+            if runtime_context.verify_sorted() {
+                if final_agg.unsorted_files.is_empty() {
+                    println_stderr!("The records of every file are sorted by timestamp.");
+                } else {
+                    println_stderr!("Found records out of timestamp order in {} files:",
+                                    final_agg.unsorted_files.len());
+                    for (path, unsorted_records) in &final_agg.unsorted_files {
+                        println_stderr!("  {}: {} records earlier than a record before them, the \
+                                         first on line {}.",
+                                        path.display(),
+                                        unsorted_records.num_records,
+                                        unsorted_records.first_line_num);
+                    }
+                }
+            }
+
+            if final_agg.num_outside_date_range_records > 0 {
+                println_stderr!("Skipped {} records from times outside of --from, --to, --since \
+                                 and --until.",
+                                final_agg.num_outside_date_range_records);
+            }
+
+            for overlapping in file_handling::overlapping_files(&final_agg.file_days) {
+                let (path, file_day) = overlapping.files[0];
+                let (other_path, other_file_day) = overlapping.files[1];
+                println_stderr!("Files {} and {} hold {} and {} records from {} over overlapping \
+                                 times and may be duplicates, counting the day twice.",
+                                path.display(),
+                                other_path.display(),
+                                file_day.num_records,
+                                other_file_day.num_records,
+                                overlapping.day.format("%Y-%m-%d"));
+            }
+
+            if final_agg.num_outside_business_hours_records > 0 {
+                println_stderr!("Skipped {} records from times outside of --business-hours.",
+                                final_agg.num_outside_business_hours_records);
+            }
+
+            if final_agg.num_filtered_records > 0 {
+                println_stderr!("Skipped {} records not matching --filter.",
+                                final_agg.num_filtered_records);
             }
 
+            if final_agg.num_excluded_status_records > 0 {
+                println_stderr!("Skipped {} records with a backend status outside of \
+                                 --count-status.",
+                                final_agg.num_excluded_status_records);
+            }
+
+            for aggregation in &mut aggregations {
+                apply_count_floor(&runtime_context, aggregation);
+            }
+
+            // Compared before the limit per system, which would otherwise read as a drop.
+            let regressions = baseline.map(|baseline| {
+                record_handling::regressions(aggregations.iter()
+                                                 .flat_map(|aggregation| aggregation),
+                                             &baseline,
+                                             runtime_context.max_drop().unwrap())
+            });
+
+            let mut num_aggregates =
+                aggregations.iter().map(|aggregation| aggregation.len()).sum::<usize>();
+            let aggregates = output_aggregates(aggregations.into_iter()
+                                                   .flat_map(|aggregation| aggregation),
+                                               runtime_context.limit_per_system(),
+                                               runtime_context.sort_order(),
+                                               runtime_context.reverse_sort());
+            let written_aggregates = if final_agg.spill_files.is_empty() {
+                OutputAggregates::Sorted(&aggregates)
+            } else {
+                OutputAggregates::Spilled {
+                    spill_files: &final_agg.spill_files,
+                    policy: runtime_context.record_config().count_overflow_policy,
+                    totals: Cell::new(Default::default()),
+                }
+            };
+
+            let exit_code = if publish_aggregates(&runtime_context, &aggregates) {
+                EXIT_SUCCESS
+            } else {
+                EXIT_FAILURE
+            };
+
+            let printed = if runtime_context.suppress_output() {
+                true
+            } else {
+                match output_file {
+                    Some(file) => {
+                        write_output(&runtime_context,
+                                     &written_aggregates,
+                                     &final_agg.timespans,
+                                     &final_agg.aggregate_metrics,
+                                     &final_agg.file_subtotals,
+                                     io::BufWriter::new(file))
+                    }
+                    None => {
+                        let stdout = io::stdout();
+                        write_output(&runtime_context,
+                                     &written_aggregates,
+                                     &final_agg.timespans,
+                                     &final_agg.aggregate_metrics,
+                                     &final_agg.file_subtotals,
+                                     stdout.lock())
+                    }
+                }
+            };
+            let exit_code = if printed { exit_code } else { EXIT_FAILURE };
+
+            let exit_code = match written_aggregates {
+                OutputAggregates::Spilled { spill_files, ref totals, .. } => {
+                    spill::remove_spill_files(spill_files);
+                    let totals = totals.get();
+                    num_aggregates = totals.num_aggregates;
+                    if saturates && totals.num_saturated > 0 {
+                        report_saturated(totals.num_saturated);
+                    }
+                    if totals.num_overflows > 0 {
+                        println_stderr!("Merging the aggregations spilled to disk would have \
+                                         overflowed the totals of aggregates {} times, so the \
+                                         totals written fall short. Pass --count-overflow-policy \
+                                         saturate to stop the totals at {} instead.",
+                                        totals.num_overflows,
+                                        record_handling::SATURATED_TOTAL);
+                        EXIT_FAILURE
+                    } else {
+                        exit_code
+                    }
+                }
+                OutputAggregates::Sorted(_) => exit_code,
+            };
+
+            let exit_code = match regressions {
+                Some(ref regressions) if !regressions.is_empty() => {
+                    println_stderr!("The totals of {} systems fell by more than --max-drop of {}% \
+                                     compared to the baseline:",
+                                    regressions.len(),
+                                    runtime_context.max_drop().unwrap());
+                    for regression in regressions {
+                        println_stderr!("  {}: {} to {}, {:.1}%",
+                                        regression.system_name,
+                                        regression.baseline_total,
+                                        regression.total,
+                                        regression.percent_change);
+                    }
+                    EXIT_FAILURE
+                }
+                _ => exit_code,
+            };
+
             if let Some(start_time) = start {
                 let end_time = UTC::now();
                 let time = end_time - start_time;
-                println!("Processed {} files having {} records in {} milliseconds and produced \
-                          {} aggregates.",
-                         num_files,
-                         final_agg.num_raw_records,
-                         time.num_milliseconds(),
-                         final_agg.aggregation.len());
+                // The summary goes to stderr to keep machine readable output on stdout intact.
+                println_stderr!("Processed {} files having {} records in {} milliseconds and \
+                                 produced {} aggregates.",
+                                num_files,
+                                final_agg.num_raw_records,
+                                time.num_milliseconds(),
+                                num_aggregates);
+                if runtime_context.parse_only() {
+                    println_stderr!("Parsed {:.0} records per second.",
+                                    final_agg.num_raw_records as f64 * 1000.0 /
+                                    time.num_milliseconds().max(1) as f64);
+                }
+            }
+            if let Some(timings) = profile {
+                println_stderr!("{}", timings.report());
             }
             runner.shutdown();
-            EXIT_SUCCESS
+            exit_code
         }
 
         Err(e) => {
@@ -79,212 +531,4547 @@ fn main() {
     std::process::exit(exit_code);
 }
 
-struct Runner {
-    thread_pool: sp::Pool,
-    file_handling_msg_senders: Vec<mpsc::Sender<file_handling::FileHandlingMessages>>,
+/// Aggregates the log files of the log location as they appear, writing the aggregation of every
+/// file read so far after every flush interval in which new files were read. Files that can't be
+/// listed are tried again after the interval, as a location that logs are delivered to is usually
+/// remote. Only returns when the run fails.
+fn watch(runtime_context: &RuntimeContext, flush_interval: Duration) -> i32 {
+    let mut file_aggregator_config = runtime_context.file_aggregator_config();
+    match runtime_context.rejects() {
+        Ok(rejects) => file_aggregator_config.rejects = rejects.map(Arc::new),
+        Err(e) => {
+            println_stderr!("{}", e);
+            return EXIT_FAILURE;
+        }
+    }
+    let profile = file_aggregator_config.profile.clone();
+    let rejects = file_aggregator_config.rejects.clone();
+    let mut runner = Runner::with_config(file_aggregator_config);
+    if let Some(max_aggregates) = runtime_context.max_aggregates() {
+        runner.limit_aggregates(max_aggregates);
+    }
+    if let Some(merge_threads) = runtime_context.merge_threads() {
+        runner.merge_in_parallel(merge_threads);
+    }
+    let mut watch = Watch::new(runner,
+                               runtime_context.num_threads(),
+                               runtime_context.log_location());
+    #[cfg(feature = "status-socket")]
+    let _status_server = match runtime_context.status_addr() {
+        Some(addr) => {
+            match StatusServer::start(addr, watch.metrics()) {
+                Ok(server) => Some(server),
+                Err(e) => {
+                    println_stderr!("Unable to start the status socket on {}. {}", addr, e);
+                    watch.shutdown();
+                    return EXIT_FAILURE;
+                }
+            }
+        }
+        None => None,
+    };
+    loop {
+        let poll_start = Instant::now();
+        match watch.poll() {
+            Ok(0) => {}
+            Ok(num_files) => {
+                println_stderr!("Read {} new files, {} in all.",
+                                num_files,
+                                watch.num_files_read());
+                if let Some(ref rejects) = rejects {
+                    if !report_flush_error(runtime_context.rejects_output().unwrap(),
+                                           rejects.flush()) {
+                        watch.shutdown();
+                        return EXIT_FAILURE;
+                    }
+                }
+                if !flush_watch(runtime_context, watch.aggregation(), profile.as_ref()) {
+                    watch.shutdown();
+                    return EXIT_FAILURE;
+                }
+            }
+            Err(RunError::FileListing { err, .. }) => {
+                println_stderr!("Unable to list the files, trying again in {} seconds. {}",
+                                flush_interval.as_secs(),
+                                err);
+            }
+            Err(e @ RunError::TooManyAggregates(_)) => {
+                println_stderr!("{} Aggregate by fewer dimensions or raise --max-aggregates.", e);
+                watch.shutdown();
+                return EXIT_FAILURE;
+            }
+            Err(e) => {
+                println_stderr!("Aborted. {}", e);
+                watch.shutdown();
+                return EXIT_FAILURE;
+            }
+        }
+        if let Some(remaining) = flush_interval.checked_sub(poll_start.elapsed()) {
+            thread::sleep(remaining);
+        }
+    }
 }
 
-impl Runner {
-
-    fn new() -> Runner {
-        Runner {
-            thread_pool: sp::Pool::empty(),
-            file_handling_msg_senders: Vec::new(),
+/// Checks the aggregation of a run, watched or not, against --max-day-range and for totals that
+/// would have overflowed, returning why the run is aborted when it fails either check.
+fn check_aggregation(runtime_context: &RuntimeContext,
+                     file_agg: &counter::FileAggregation)
+                     -> Result<(), String> {
+    if let Some(max_days) = runtime_context.max_day_range() {
+        if file_agg.num_days() > max_days {
+            return Err(format!("Aborted as the records read span {} days, more than \
+                                --max-day-range of {}. Narrow the run with --from and --to, or \
+                                pass --allow-large-range to count them anyway.",
+                               file_agg.num_days(),
+                               max_days));
         }
     }
+    if file_agg.num_count_overflows > 0 {
+        return Err(format!("Aborted as counting the records would have overflowed the totals of \
+                            aggregates {} times. Pass --count-overflow-policy saturate to stop \
+                            the totals at {} instead.",
+                           file_agg.num_count_overflows,
+                           record_handling::SATURATED_TOTAL));
+    }
+    Ok(())
+}
 
-    fn run(&mut self, num_file_aggregators: usize, mut filenames: &mut Vec<PathBuf>) -> counter::FileAggregation {
-        let (agg_msg_sender, agg_msg_receiver) = mpsc::channel::<_>();
-        for sender_id in 0..num_file_aggregators {
-            let (file_handling_msg_sender, file_handling_msg_receiver) = mpsc::channel::<_>();
-            self.file_handling_msg_senders.push(file_handling_msg_sender);
-            let cloned_agg_msg_sender = agg_msg_sender.clone();
-            self.thread_pool.expand();
-            self.thread_pool.spawn(move || {
-                file_handling::FileAggregator::new(sender_id)
-                    .run(&file_handling_msg_receiver, &cloned_agg_msg_sender);
-            });
-        }
-        let mut agg_control = AggregationController::new(agg_msg_receiver,
-                                                         self.file_handling_msg_senders.clone());
-        agg_control.run_aggregation(filenames)
+/// Applies --count-floor to the aggregation of a run, watched or not.
+fn apply_count_floor(runtime_context: &RuntimeContext,
+                     aggregation: &mut counter::ELBRecordAggregation)
+                     -> () {
+    if let Some(floor) = runtime_context.count_floor() {
+        record_handling::apply_count_floor(aggregation, floor, runtime_context.floor_mode());
     }
+}
 
-    #[cfg(test)]
-    fn num_threads_in_pool(&self) -> usize {
-        self.thread_pool.workers()
+/// Prints the hash of the aggregates when asked to and publishes them to Kafka and to --sink.
+/// Returns false when publishing failed in a way that should fail the run.
+fn publish_aggregates(runtime_context: &RuntimeContext,
+                      aggregates: &[(record_handling::AggregateELBRecord, i64)])
+                      -> bool {
+    if runtime_context.result_hash() {
+        println_stderr!("Result hash: {}", record_handling::result_hash(aggregates));
     }
+    #[cfg(feature = "kafka")]
+    let published = publish_to_kafka(runtime_context, aggregates);
+    #[cfg(not(feature = "kafka"))]
+    let published = true;
+    push_to_sink(runtime_context, aggregates) && published
+}
 
-    #[cfg(test)]
-    fn num_file_handling_msg_senders(&self) -> usize {
-        self.thread_pool.workers()
+/// Checks, floors, publishes and writes the aggregation of every file a watch has read, as a run
+/// that isn't watched does once it's done. The output file is replaced once the aggregation has
+/// been written in full, so that readers never see part of one. Returns false when the watch
+/// should stop, as the aggregation failed a check or could not be published or written.
+fn flush_watch(runtime_context: &RuntimeContext,
+               file_agg: &counter::FileAggregation,
+               profile: Option<&Arc<PhaseTimings>>)
+               -> bool {
+    if let Err(e) = check_aggregation(runtime_context, file_agg) {
+        println_stderr!("{}", e);
+        return false;
     }
+    let mut aggregation = file_agg.aggregation.clone();
+    apply_count_floor(runtime_context, &mut aggregation);
+    let aggregates = output_aggregates(aggregation,
+                                       runtime_context.limit_per_system(),
+                                       runtime_context.sort_order(),
+                                       runtime_context.reverse_sort());
+    let published = publish_aggregates(runtime_context, &aggregates);
+    if let Some(timings) = profile {
+        println_stderr!("{}", timings.report());
+    }
+    if runtime_context.suppress_output() {
+        return published;
+    }
+    let aggregates = OutputAggregates::Sorted(&aggregates);
+    let written = match runtime_context.output_path() {
+        Some(path) => replace_output(runtime_context, path, &aggregates, file_agg),
+        None => {
+            let stdout = io::stdout();
+            write_output(runtime_context,
+                         &aggregates,
+                         &file_agg.timespans,
+                         &file_agg.aggregate_metrics,
+                         &file_agg.file_subtotals,
+                         stdout.lock())
+        }
+    };
+    written && published
+}
 
-    fn shutdown(&mut self) -> () {
-        // Shutting down the file handlers is a precaution to prevent hangs and aids in testing.
-        for msg_sender in self.file_handling_msg_senders.iter() {
-            let _ = msg_sender.send(counter::file_handling::FileHandlingMessages::Done);
+/// Writes the aggregates next to the output file at `path` and renames them over it.
+fn replace_output(runtime_context: &RuntimeContext,
+                  path: &Path,
+                  aggregates: &OutputAggregates,
+                  file_agg: &counter::FileAggregation)
+                  -> bool {
+    let mut partial_path = path.as_os_str().to_owned();
+    partial_path.push(".partial");
+    let file = match File::create(&partial_path) {
+        Ok(file) => file,
+        Err(e) => {
+            println_stderr!("Could not create the output file {}. {}",
+                            Path::new(&partial_path).display(),
+                            e);
+            return false;
         }
-        self.thread_pool.shutdown()
-    }
+    };
+    write_output(runtime_context,
+                 aggregates,
+                 &file_agg.timespans,
+                 &file_agg.aggregate_metrics,
+                 &file_agg.file_subtotals,
+                 io::BufWriter::new(file)) &&
+    report_write_error(fs::rename(&partial_path, path))
 }
 
-const LOG_LOCATION_ARG: &'static str = "log-location";
-const BENCHMARK_ARG: &'static str = "benchmark";
+/// The aggregates to write, limited per system when asked to, in the order asked for. Every order
+/// falls back on the key, see `record_handling::compare_by`, so that runs over the same logs
+/// write identical output.
+fn output_aggregates<I>(aggregation: I,
+                        limit_per_system: Option<usize>,
+                        sort_order: record_handling::SortOrder,
+                        reverse_sort: bool)
+                        -> Vec<(record_handling::AggregateELBRecord, i64)>
+    where I: IntoIterator<Item = (record_handling::AggregateELBRecord, i64)>
+{
+    let mut aggregates: Vec<_> = match limit_per_system {
+        Some(limit) => record_handling::limit_per_system(aggregation, limit),
+        None => aggregation.into_iter().collect(),
+    };
+    aggregates.sort_by(|lhs, rhs| {
+        record_handling::compare_by(sort_order, reverse_sort, lhs, rhs)
+    });
+    aggregates
+}
 
-struct RuntimeContext<'a> {
-    arg_matches: clap::ArgMatches<'a>,
+/// The aggregates `write_results` writes: sorted in memory, or merged from the files
+/// --max-memory spilled them to as they're written, so that they're never all in memory. Spilled
+/// aggregates are written in key order, see `spill::compare_spilled`, as `--sort-by key` writes
+/// them.
+enum OutputAggregates<'a> {
+    Sorted(&'a [(record_handling::AggregateELBRecord, i64)]),
+    Spilled {
+        spill_files: &'a [PathBuf],
+        policy: record_handling::CountOverflowPolicy,
+        /// Counted as the aggregates are written.
+        totals: Cell<output::SpilledTotals>,
+    },
 }
 
-impl<'a> RuntimeContext<'a> {
-    fn new() -> RuntimeContext<'a> {
-        let arg_matches = RuntimeContext::new_app().get_matches();
+impl<'a> OutputAggregates<'a> {
+    /// The aggregates sorted in memory, of which spilled aggregates have none. The options that
+    /// read them, --baseline, --count-floor, --limit-per-system, --list-systems, --result-hash,
+    /// --sink, --kafka-brokers and --strict-csv, conflict with --max-memory.
+    fn sorted(&self) -> &[(record_handling::AggregateELBRecord, i64)] {
+        match *self {
+            OutputAggregates::Sorted(aggregates) => aggregates,
+            OutputAggregates::Spilled { .. } => &[],
+        }
+    }
 
-        RuntimeContext { arg_matches: arg_matches }
+    fn write_to<S: AggregateSink>(&self,
+                                  timespans: &record_handling::Timespans,
+                                  metrics: &record_handling::Metrics,
+                                  sink: &mut S)
+                                  -> io::Result<()> {
+        match *self {
+            OutputAggregates::Sorted(aggregates) => {
+                output::write_metrics_aggregates(aggregates, timespans, metrics, sink)
+            }
+            OutputAggregates::Spilled { spill_files, policy, ref totals } => {
+                totals.set(output::write_spilled_aggregates(spill_files, policy, sink)?);
+                Ok(())
+            }
+        }
     }
+}
 
-    #[cfg(test)]
-    fn new_test_runtime_context(args: Vec<&str>) -> RuntimeContext<'a> {
-        let arg_matches = RuntimeContext::new_app()
-            .get_matches_from_safe_borrow(args)
-            .unwrap();
+/// Writes the results, see `write_results`, compressing them when asked to. The gzip stream is
+/// finished before returning so that readers get a complete stream.
+fn write_output<W: Write>(runtime_context: &RuntimeContext,
+                          aggregates: &OutputAggregates,
+                          timespans: &record_handling::Timespans,
+                          metrics: &record_handling::Metrics,
+                          file_subtotals: &BTreeMap<PathBuf, u64>,
+                          writer: W)
+                          -> bool {
+    if runtime_context.compress_gzip() {
+        let mut encoder = flate2::write::GzEncoder::new(writer, flate2::Compression::Default);
+        write_results(runtime_context,
+                      aggregates,
+                      timespans,
+                      metrics,
+                      file_subtotals,
+                      &mut encoder) &&
+        report_write_error(encoder.finish().and_then(|mut writer| writer.flush()))
+    } else {
+        write_results(runtime_context, aggregates, timespans, metrics, file_subtotals, writer)
+    }
+}
 
-        RuntimeContext { arg_matches: arg_matches }
+/// Writes the aggregates, with their timespans and metrics when there are any, or the total of
+/// every system when listing systems, followed by the file subtotals when there are any. Returns
+/// false when they could not all be written.
+fn write_results<W: Write>(runtime_context: &RuntimeContext,
+                           aggregates: &OutputAggregates,
+                           timespans: &record_handling::Timespans,
+                           metrics: &record_handling::Metrics,
+                           file_subtotals: &BTreeMap<PathBuf, u64>,
+                           mut writer: W)
+                           -> bool {
+    let written = match runtime_context.output_format() {
+        _ if runtime_context.sink_dry_run() => {
+            // The metrics that would be pushed take the place of the aggregates and subtotals.
+            let address = runtime_context.sink_address().unwrap();
+            let mut sink = MetricSink::new(&mut writer,
+                                           address.protocol,
+                                           runtime_context.metric_prefix());
+            return write_to_sink(aggregates, timespans, metrics, &mut sink);
+        }
+        record_handling::OutputFormat::Discard => return true,
+        format if runtime_context.list_systems() => {
+            let result = write_system_totals(&mut writer,
+                                             format,
+                                             aggregates.sorted(),
+                                             runtime_context.truncate_field());
+            report_write_error(result)
+        }
+        record_handling::OutputFormat::Csv => {
+            let mut sink = CsvSink::new(&mut writer);
+            sink.order_columns_by(&runtime_context.group_by());
+            if let Some(max_chars) = runtime_context.truncate_field() {
+                sink.truncate_fields_to(max_chars);
+            }
+            include_csv_header(runtime_context, &mut sink);
+            print_csv(aggregates,
+                      timespans,
+                      metrics,
+                      record_handling::CSV_DELIMITER,
+                      runtime_context.strict_csv(),
+                      &mut sink)
+        }
+        record_handling::OutputFormat::Tsv => {
+            let delimiter = record_handling::TSV_DELIMITER;
+            let mut sink = CsvSink::with_delimiter(&mut writer, delimiter);
+            sink.order_columns_by(&runtime_context.group_by());
+            if let Some(max_chars) = runtime_context.truncate_field() {
+                sink.truncate_fields_to(max_chars);
+            }
+            include_csv_header(runtime_context, &mut sink);
+            print_csv(aggregates,
+                      timespans,
+                      metrics,
+                      delimiter,
+                      runtime_context.strict_csv(),
+                      &mut sink)
+        }
+        record_handling::OutputFormat::Matrix => {
+            let mut sink = MatrixSink::new(&mut writer);
+            if let Some(max_chars) = runtime_context.truncate_field() {
+                sink.truncate_fields_to(max_chars);
+            }
+            print_csv(aggregates,
+                      timespans,
+                      metrics,
+                      record_handling::CSV_DELIMITER,
+                      runtime_context.strict_csv(),
+                      &mut sink)
+        }
+        record_handling::OutputFormat::Json => {
+            let path_dimension_name = runtime_context.path_dimension_name();
+            let mut sink = JsonSink::new(&mut writer, path_dimension_name);
+            if let Some(max_chars) = runtime_context.truncate_field() {
+                sink.truncate_fields_to(max_chars);
+            }
+            if !file_subtotals.is_empty() {
+                sink.include_file_subtotals(output::file_subtotals_json(file_subtotals));
+            }
+            write_to_sink(aggregates, timespans, metrics, &mut sink)
+        }
+        #[cfg(feature = "yaml")]
+        record_handling::OutputFormat::Yaml => {
+            let path_dimension_name = runtime_context.path_dimension_name();
+            let mut sink = YamlSink::new(&mut writer, path_dimension_name);
+            if let Some(max_chars) = runtime_context.truncate_field() {
+                sink.truncate_fields_to(max_chars);
+            }
+            if !file_subtotals.is_empty() {
+                sink.include_file_subtotals(output::file_subtotals_json(file_subtotals));
+            }
+            write_to_sink(aggregates, timespans, metrics, &mut sink)
+        }
+        record_handling::OutputFormat::JsonLines => {
+            let path_dimension_name = runtime_context.path_dimension_name();
+            let mut sink = JsonLinesSink::new(&mut writer, path_dimension_name);
+            if let Some(max_chars) = runtime_context.truncate_field() {
+                sink.truncate_fields_to(max_chars);
+            }
+            write_to_sink(aggregates, timespans, metrics, &mut sink)
+        }
+        record_handling::OutputFormat::Influx => {
+            let path_dimension_name = runtime_context.path_dimension_name();
+            let mut sink = InfluxSink::new(&mut writer, path_dimension_name);
+            if let Some(max_chars) = runtime_context.truncate_field() {
+                sink.truncate_fields_to(max_chars);
+            }
+            write_to_sink(aggregates, timespans, metrics, &mut sink)
+        }
+    };
+
+    written &&
+    report_write_error(write_file_subtotals(&mut writer,
+                                            runtime_context.output_format(),
+                                            file_subtotals)
+        .and_then(|()| writer.flush()))
+}
+
+fn write_system_totals<W: Write>(writer: &mut W,
+                                 format: record_handling::OutputFormat,
+                                 aggregates: &[(record_handling::AggregateELBRecord, i64)],
+                                 max_field_chars: Option<usize>)
+                                 -> io::Result<()> {
+    let totals = record_handling::totals_by_system(aggregates)
+        .into_iter()
+        .map(|(system_name, total)| {
+            let system_name = match max_field_chars {
+                Some(max_chars) => {
+                    record_handling::truncate_field(&system_name, max_chars).into_owned()
+                }
+                None => system_name,
+            };
+            (system_name, total)
+        });
+    match format {
+        record_handling::OutputFormat::Json => {
+            let totals = totals.map(|(system_name, total)| {
+                    output::system_total_json(&system_name, total)
+                })
+                .collect::<Vec<_>>();
+            return writeln!(writer, "{}", rustc_serialize::json::Json::Array(totals));
+        }
+        #[cfg(feature = "yaml")]
+        record_handling::OutputFormat::Yaml => {
+            let totals = totals.map(|(system_name, total)| {
+                    yaml_output::json_to_yaml(&output::system_total_json(&system_name, total))
+                })
+                .collect::<Vec<_>>();
+            return yaml_output::write_yaml(writer, &yaml_rust::Yaml::Array(totals));
+        }
+        _ => {}
+    }
+    for (system_name, total) in totals {
+        match format {
+            record_handling::OutputFormat::Influx => {
+                writeln!(writer, "{}", record_handling::influx_system_line(&system_name, total))?
+            }
+            record_handling::OutputFormat::JsonLines => {
+                writeln!(writer, "{}", output::system_total_json(&system_name, total))?
+            }
+            record_handling::OutputFormat::Tsv => {
+                let delimiter = record_handling::TSV_DELIMITER;
+                writeln!(writer,
+                         "{}{}{}",
+                         output::quote_field(&system_name, delimiter),
+                         delimiter,
+                         total)?
+            }
+            _ => {
+                let delimiter = record_handling::CSV_DELIMITER;
+                writeln!(writer,
+                         "{}{}{}",
+                         output::quote_field(&system_name, delimiter),
+                         delimiter,
+                         total)?
+            }
+        }
     }
+    Ok(())
+}
 
-    fn new_app<'b>() -> clap::App<'a, 'b> {
-        clap::App::new("counter")
-            .version(include_str!("version.txt"))
-            .version_short("v")
-            .arg(clap::Arg::with_name(LOG_LOCATION_ARG)
-                .required(true)
-                .help("The root directory when the log files are stored."))
-            .arg(clap::Arg::with_name(BENCHMARK_ARG)
-                .required(false)
-                .help("Time the run and provide statistics at the end of the run.")
-                .long("benchmark")
-                .short("b"))
+fn write_file_subtotals<W: Write>(writer: &mut W,
+                                  format: record_handling::OutputFormat,
+                                  file_subtotals: &BTreeMap<PathBuf, u64>)
+                                  -> io::Result<()> {
+    if file_subtotals.is_empty() {
+        return Ok(());
+    }
+    match format {
+        // The JSON array is written with the subtotals alongside it.
+        record_handling::OutputFormat::Json |
+        record_handling::OutputFormat::Discard => {}
+        // So is the YAML sequence.
+        #[cfg(feature = "yaml")]
+        record_handling::OutputFormat::Yaml => {}
+        record_handling::OutputFormat::JsonLines => {
+            if let rustc_serialize::json::Json::Array(subtotals) =
+                   output::file_subtotals_json(file_subtotals) {
+                for subtotal in subtotals {
+                    writeln!(writer, "{}", subtotal)?;
+                }
+            }
+        }
+        _ => {
+            let delimiter = record_handling::CSV_DELIMITER;
+            writeln!(writer, "")?;
+            writeln!(writer, "file{}records", delimiter)?;
+            for (path, num_records) in file_subtotals {
+                writeln!(writer, "{}{}{}", path.display(), delimiter, num_records)?;
+            }
+        }
     }
+    Ok(())
+}
 
-    fn run_benchmark(&self) -> bool {
-        self.arg_matches.is_present(BENCHMARK_ARG)
+fn report_saturated(num_saturated: usize) -> () {
+    println_stderr!("The totals of {} aggregates reached the largest representable total, {}, \
+                     and stopped there. Their true totals are larger.",
+                    num_saturated,
+                    record_handling::SATURATED_TOTAL);
+}
+
+/// Returns false, after reporting the error, when the result is one.
+fn report_write_error(result: io::Result<()>) -> bool {
+    match result {
+        Ok(()) => true,
+        Err(e) => {
+            println_stderr!("The following error occurred while writing the aggregates. {}", e);
+            false
+        }
     }
+}
 
-    fn log_location(&self) -> &Path {
-        Path::new(self.arg_matches.value_of(LOG_LOCATION_ARG).unwrap())
+/// Returns false when writing out the rest of the trace output, key dictionary or rejects file
+/// failed.
+fn report_flush_error(output_path: &str, result: io::Result<()>) -> bool {
+    match result {
+        Ok(()) => true,
+        Err(e) => {
+            println_stderr!("Unable to write out the rest of {}. {}", output_path, e);
+            false
+        }
     }
 }
 
-#[cfg(test)]
-mod full_run_tests {
+/// Returns false when the aggregates were not printed because one of them has a field containing
+/// the delimiter, which would have to be quoted, and strict CSV output was requested, or when they
+/// could not be written.
+/// Names the columns of the CSV or TSV output in a header row, as `output::schema_json` does.
+fn include_csv_header<W: Write>(runtime_context: &RuntimeContext, sink: &mut CsvSink<W>) {
+    let path_dimension_name = runtime_context.path_dimension_name();
+    let total_name = output::total_column_name(runtime_context.output_format(),
+                                               false,
+                                               &runtime_context.record_config())
+        .unwrap();
+    sink.include_header(path_dimension_name, total_name);
+}
 
-    use std::path::PathBuf;
-    use counter::file_handling;
+fn print_csv<S: AggregateSink>(aggregates: &OutputAggregates,
+                               timespans: &record_handling::Timespans,
+                               metrics: &record_handling::Metrics,
+                               delimiter: char,
+                               strict: bool,
+                               sink: &mut S)
+                               -> bool {
+    if strict {
+        let quoted_fields: Vec<_> = aggregates.sorted()
+            .iter()
+            .filter_map(|&(ref aggregate, _)| {
+                record_handling::field_containing(aggregate, delimiter)
+            })
+            .collect();
+        if let Some(field) = quoted_fields.first() {
+            println_stderr!("{} aggregates have a field containing the delimiter '{}', such as \
+                             \"{}\". Refusing to write them quoted because --strict-csv is set.",
+                            quoted_fields.len(),
+                            delimiter.escape_default(),
+                            field);
+            return false;
+        }
+    }
 
-    #[test]
-    #[ignore]
-    fn a_full_run_should_return_the_correct_aggregation_results() {
-        let num_cpus = ::num_cpus::get();
-        let mut files = file_handling::file_list(&PathBuf::from("./test_artifacts/log_files")).unwrap();
-        let mut runner = super::Runner::new();
+    write_to_sink(aggregates, timespans, metrics, sink)
+}
 
-        let file_agg = runner.run(num_cpus, &mut files);
+/// Returns false when the aggregates could not all be written.
+fn write_to_sink<S: AggregateSink>(aggregates: &OutputAggregates,
+                                   timespans: &record_handling::Timespans,
+                                   metrics: &record_handling::Metrics,
+                                   sink: &mut S)
+                                   -> bool {
+    report_write_error(aggregates.write_to(timespans, metrics, sink))
+}
 
-        assert_eq!(file_agg.num_raw_records, 838140);
-        assert_eq!(file_agg.aggregation.len(), 95479);
+/// Pushes the aggregates as metrics to the --sink, unless it's a dry run. Returns false when
+/// they could not all be pushed.
+fn push_to_sink(runtime_context: &RuntimeContext,
+                aggregates: &[(record_handling::AggregateELBRecord, i64)])
+                -> bool {
+    let address = match runtime_context.sink_address() {
+        Some(address) => address,
+        None => return true,
+    };
+    if runtime_context.sink_dry_run() {
+        return true;
+    }
+    let prefix = runtime_context.metric_prefix();
+    let result = match address.protocol {
+        MetricProtocol::Statsd => {
+            Datagrams::connect(&address.host, address.port).and_then(|datagrams| {
+                push_metrics(MetricSink::new(datagrams, address.protocol, prefix), aggregates)
+            })
+        }
+        MetricProtocol::Graphite => {
+            statsd_output::connect_graphite(&address.host, address.port).and_then(|stream| {
+                push_metrics(MetricSink::new(stream, address.protocol, prefix), aggregates)
+            })
+        }
+    };
+    match result {
+        Ok((num_metrics, num_batches)) => {
+            debug!("Pushed {} metrics in {} batches to {}:{}.",
+                   num_metrics,
+                   num_batches,
+                   address.host,
+                   address.port);
+            true
+        }
+        Err(e) => {
+            println_stderr!("The following error occurred while pushing metrics to {}:{}. {}",
+                            address.host,
+                            address.port,
+                            e);
+            false
+        }
     }
 }
 
-#[cfg(test)]
-mod runner_tests {
+/// Writes the aggregates to the sink, returning the number of metrics and batches written.
+fn push_metrics<W: Write>(mut sink: MetricSink<W>,
+                          aggregates: &[(record_handling::AggregateELBRecord, i64)])
+                          -> io::Result<(usize, usize)> {
+    sink.check_metric_paths(aggregates.iter().map(|&(ref key, _)| key))?;
+    output::write_aggregates(aggregates, &mut sink)?;
+    Ok((sink.num_metrics(), sink.num_batches()))
+}
 
-    use std::path::PathBuf;
+/// Returns false when publishing failed in a way that should fail the run.
+#[cfg(feature = "kafka")]
+fn publish_to_kafka(runtime_context: &RuntimeContext,
+                    aggregates: &[(record_handling::AggregateELBRecord, i64)])
+                    -> bool {
+    let (brokers, topic) = match (runtime_context.kafka_brokers(), runtime_context.kafka_topic()) {
+        (Some(brokers), Some(topic)) => (brokers, topic),
+        _ => return true,
+    };
+    let require_acks = runtime_context.kafka_require_acks();
 
-    #[test]
-    fn runner_should_create_the_same_number_of_file_handling_message_senders_as_host_cpus() {
-        let num_cpus = ::num_cpus::get();
-        let mut files = Vec::new();
-        files.push(PathBuf::from("./test_artifacts/test_elb_log_file.log"));
-        let mut runner = super::Runner::new();
+    let path_dimension_name = runtime_context.path_dimension_name();
 
-        let _ = runner.run(num_cpus, &mut files);
+    let publish_result = KafkaPublisher::new(brokers, topic, require_acks, path_dimension_name)
+        .and_then(|mut publisher| publisher.publish(aggregates));
+    match publish_result {
+        Ok(summary) => {
+            debug!("Published {} aggregates to Kafka topic {}.",
+                   summary.num_delivered,
+                   topic);
+            if summary.num_failed > 0 {
+                println_stderr!("Failed to deliver {} of {} aggregates to Kafka topic {}.",
+                                summary.num_failed,
+                                aggregates.len(),
+                                topic);
+            }
+            !require_acks || summary.num_failed == 0
+        }
+        Err(e) => {
+            println_stderr!("The following error occurred while publishing to Kafka topic {}. {}",
+                            topic,
+                            e);
+            !require_acks
+        }
+    }
+}
 
-        assert_eq!(runner.num_file_handling_msg_senders(), num_cpus);
+const LOG_LOCATION_ARG: &'static str = "log-location";
+#[cfg(not(feature = "tar"))]
+const LOG_LOCATION_HELP: &'static str = "The root directory when the log files are stored, or an \
+                                         s3://bucket/prefix URI of the log files, which are \
+                                         listed and streamed with the AWS CLI. The aws command \
+                                         has to be installed and on the PATH for S3, and is run \
+                                         with the credentials and region it is configured with.";
+#[cfg(feature = "tar")]
+const LOG_LOCATION_HELP: &'static str = "The root directory when the log files are stored, a tar \
+                                         archive of log files, gzipped or not, or an \
+                                         s3://bucket/prefix URI of \
+                                         the log files, which are listed and streamed with the \
+                                         AWS CLI. The aws command has to be installed and on the \
+                                         PATH for S3, and is run with the credentials and region \
+                                         it is configured with.";
+const BENCHMARK_ARG: &'static str = "benchmark";
+const LIMIT_PER_SYSTEM_ARG: &'static str = "limit-per-system";
+const RESULT_HASH_ARG: &'static str = "result-hash";
+const DEDUPE_RECORDS_ARG: &'static str = "dedupe-records";
+const SQUASH_WINDOW_ARG: &'static str = "squash-window";
+const PROGRESS_ARG: &'static str = "progress";
+const PATH_DIMENSION_ARG: &'static str = "path-dimension";
+const PASSTHROUGH_ARG: &'static str = "passthrough";
+const DROP_NO_SYSTEM_ARG: &'static str = "drop-no-system";
+const NULL_SYSTEM_LABEL_ARG: &'static str = "null-system-label";
+const PARSE_ONLY_ARG: &'static str = "parse-only";
+const MIN_RECORDS_PER_FILE_ARG: &'static str = "min-records-per-file";
+const COUNT_FLOOR_ARG: &'static str = "count-floor";
+const RECORD_SEPARATOR_ARG: &'static str = "record-separator";
+const PARSER_COMMAND_ARG: &'static str = "parser-command";
+const PROFILE_ARG: &'static str = "profile";
+const STRICT_CSV_ARG: &'static str = "strict-csv";
+const THREADS_PER_CORE_ARG: &'static str = "threads-per-core";
+const THREADS_ARG: &'static str = "threads";
+const FORMAT_ARG: &'static str = "format";
+#[cfg(not(feature = "yaml"))]
+const OUTPUT_FORMATS: [&'static str; 7] =
+    ["csv", "tsv", "json", "jsonl", "influx", "matrix", "none"];
+#[cfg(feature = "yaml")]
+const OUTPUT_FORMATS: [&'static str; 8] =
+    ["csv", "tsv", "json", "jsonl", "influx", "matrix", "none", "yaml"];
+const OUTPUT_ARG: &'static str = "output";
+const COMPRESS_ARG: &'static str = "compress";
+const FILE_TIMEOUT_ARG: &'static str = "file-timeout";
+const TRACE_KEY_ARG: &'static str = "trace-key";
+const TRACE_OUTPUT_ARG: &'static str = "trace-output";
+const TRACE_LIMIT_ARG: &'static str = "trace-limit";
+const HASH_KEYS_ARG: &'static str = "hash-keys";
+const KEY_DICTIONARY_ARG: &'static str = "key-dictionary";
+const REJECTS_ARG: &'static str = "rejects";
+const WEIGHT_FIELD_ARG: &'static str = "weight-field";
+const ROUND_TIMESTAMPS_TO_ARG: &'static str = "round-timestamps-to";
+const MAX_AGGREGATES_ARG: &'static str = "max-aggregates";
+const MERGE_THREADS_ARG: &'static str = "merge-threads";
+const EXPLAIN_RECORD_ARG: &'static str = "explain-record";
+const PRINT_SCHEMA_ARG: &'static str = "print-schema";
+const COUNT_STATUS_ARG: &'static str = "count-status";
+const LIST_SYSTEMS_ARG: &'static str = "list-systems";
+const TRUNCATE_FIELD_ARG: &'static str = "truncate-field";
+const BY_LATENCY_ARG: &'static str = "by-latency";
+const LATENCY_BUCKETS_ARG: &'static str = "latency-buckets";
+const GROUP_BY_ARG: &'static str = "group-by";
+const SORT_BY_ARG: &'static str = "sort-by";
+const REVERSE_SORT_ARG: &'static str = "reverse-sort";
+const DAY_OF_MONTH_ARG: &'static str = "day-of-month";
+const SAMPLE_ERRORS_ARG: &'static str = "sample-errors";
+const SEED_ARG: &'static str = "seed";
+const WITH_FILE_SUBTOTALS_ARG: &'static str = "with-file-subtotals";
+const WITH_TIMESPAN_ARG: &'static str = "with-timespan";
+const METRICS_ARG: &'static str = "metrics";
+const WARN_OVERLAPPING_DAYS_ARG: &'static str = "warn-overlapping-days";
+const VERIFY_SORTED_INPUT_ARG: &'static str = "verify-sorted-input";
+const BASELINE_ARG: &'static str = "baseline";
+const MAX_DROP_ARG: &'static str = "max-drop";
+const FROM_ARG: &'static str = "from";
+const TO_ARG: &'static str = "to";
+const SINCE_ARG: &'static str = "since";
+const UNTIL_ARG: &'static str = "until";
+const FILTER_ARG: &'static str = "filter";
+const MAX_DAY_RANGE_ARG: &'static str = "max-day-range";
+const ALLOW_LARGE_RANGE_ARG: &'static str = "allow-large-range";
+const BUSINESS_HOURS_ARG: &'static str = "business-hours";
+const FLOOR_MODE_ARG: &'static str = "floor-mode";
+const COUNT_OVERFLOW_POLICY_ARG: &'static str = "count-overflow-policy";
+const LOG_FORMAT_ARG: &'static str = "log-format";
+const WATCH_ARG: &'static str = "watch";
+const FLUSH_INTERVAL_ARG: &'static str = "flush-interval";
+const MAX_MEMORY_ARG: &'static str = "max-memory";
+const CHECKPOINT_ARG: &'static str = "checkpoint";
+const RESUME_ARG: &'static str = "resume";
+const SINK_ARG: &'static str = "sink";
+const METRIC_PREFIX_ARG: &'static str = "metric-prefix";
+const SINK_DRY_RUN_ARG: &'static str = "sink-dry-run";
+#[cfg(feature = "kafka")]
+const KAFKA_BROKERS_ARG: &'static str = "kafka-brokers";
+#[cfg(feature = "kafka")]
+const KAFKA_TOPIC_ARG: &'static str = "kafka-topic";
+#[cfg(feature = "kafka")]
+const KAFKA_REQUIRE_ACKS_ARG: &'static str = "kafka-require-acks";
+#[cfg(feature = "kafka")]
+const KAFKA_SUPPRESS_OUTPUT_ARG: &'static str = "kafka-suppress-output";
+#[cfg(feature = "status-socket")]
+const STATUS_ADDR_ARG: &'static str = "status-addr";
 
-        runner.shutdown()
-    }
+/// The options that change which records are counted or what they're aggregated under, which a
+/// run resuming from a checkpoint has to share with the run that wrote it, see
+/// `RuntimeContext::checkpoint_options`.
+const CHECKPOINTED_ARGS: &'static [&'static str] = &[GROUP_BY_ARG,
+                                                    LIST_SYSTEMS_ARG,
+                                                    FILTER_ARG,
+                                                    FORMAT_ARG,
+                                                    FROM_ARG,
+                                                    TO_ARG,
+                                                    SINCE_ARG,
+                                                    UNTIL_ARG,
+                                                    BUSINESS_HOURS_ARG,
+                                                    COUNT_STATUS_ARG,
+                                                    WEIGHT_FIELD_ARG,
+                                                    PATH_DIMENSION_ARG,
+                                                    DROP_NO_SYSTEM_ARG,
+                                                    NULL_SYSTEM_LABEL_ARG,
+                                                    BY_LATENCY_ARG,
+                                                    LATENCY_BUCKETS_ARG,
+                                                    DAY_OF_MONTH_ARG,
+                                                    ROUND_TIMESTAMPS_TO_ARG,
+                                                    HASH_KEYS_ARG,
+                                                    LOG_FORMAT_ARG,
+                                                    PARSER_COMMAND_ARG,
+                                                    RECORD_SEPARATOR_ARG,
+                                                    DEDUPE_RECORDS_ARG,
+                                                    SQUASH_WINDOW_ARG,
+                                                    MIN_RECORDS_PER_FILE_ARG,
+                                                    FILE_TIMEOUT_ARG,
+                                                    PARSE_ONLY_ARG,
+                                                    COUNT_OVERFLOW_POLICY_ARG];
 
-    #[test]
-    fn runner_should_create_a_thread_pool_having_the_same_number_of_cpus_as_the_host() {
-        let num_cpus = ::num_cpus::get();
-        let mut files = Vec::new();
-        files.push(PathBuf::from("./test_artifacts/test_elb_log_file.log"));
-        let mut runner = super::Runner::new();
+/// The prefix of the environment variables options fall back to, such as COUNTER_THREADS for
+/// --threads.
+const ENV_PREFIX: &'static str = "COUNTER_";
 
-        let _ = runner.run(num_cpus, &mut files);
+/// An option that can be set from the environment when it isn't given on the command line.
+struct EnvOption {
+    name: &'static str,
+    short: Option<char>,
+    takes_value: bool,
+}
 
-        assert_eq!(runner.num_threads_in_pool(), num_cpus);
+impl EnvOption {
+    fn new(name: &'static str, short: Option<char>, takes_value: bool) -> EnvOption {
+        EnvOption {
+            name: name,
+            short: short,
+            takes_value: takes_value,
+        }
+    }
 
-        runner.shutdown()
+    fn env_var(&self) -> String {
+        env_var_name(self.name)
     }
 }
 
-#[cfg(test)]
-mod runtime_context_tests {
-    use super::*;
-    use std::panic;
-
-    #[test]
-    fn log_location_should_return_the_specified_value() {
-        let arg_vec = vec!["counter", "~/logs"];
+fn env_var_name(arg_name: &str) -> String {
+    format!("{}{}", ENV_PREFIX, arg_name.to_uppercase().replace('-', "_"))
+}
 
-        let runtime_context = RuntimeContext::new_test_runtime_context(arg_vec);
+/// Every option of the app, which must be kept in step with `RuntimeContext::new_app`.
+fn env_options() -> Vec<EnvOption> {
+    let options = vec![EnvOption::new(BENCHMARK_ARG, Some('b'), false),
+                       EnvOption::new(LIMIT_PER_SYSTEM_ARG, None, true),
+                       EnvOption::new(RESULT_HASH_ARG, None, false),
+                       EnvOption::new(DEDUPE_RECORDS_ARG, None, true),
+                       EnvOption::new(SQUASH_WINDOW_ARG, None, true),
+                       EnvOption::new(PROGRESS_ARG, None, false),
+                       EnvOption::new(PATH_DIMENSION_ARG, None, true),
+                       EnvOption::new(PASSTHROUGH_ARG, None, false),
+                       EnvOption::new(DROP_NO_SYSTEM_ARG, None, false),
+                       EnvOption::new(NULL_SYSTEM_LABEL_ARG, None, true),
+                       EnvOption::new(PARSE_ONLY_ARG, None, false),
+                       EnvOption::new(MIN_RECORDS_PER_FILE_ARG, None, true),
+                       EnvOption::new(WITH_FILE_SUBTOTALS_ARG, None, false),
+                       EnvOption::new(WITH_TIMESPAN_ARG, None, false),
+                       EnvOption::new(METRICS_ARG, None, false),
+                       EnvOption::new(WARN_OVERLAPPING_DAYS_ARG, None, false),
+                       EnvOption::new(VERIFY_SORTED_INPUT_ARG, None, false),
+                       EnvOption::new(FROM_ARG, None, true),
+                       EnvOption::new(TO_ARG, None, true),
+                       EnvOption::new(SINCE_ARG, None, true),
+                       EnvOption::new(UNTIL_ARG, None, true),
+                       EnvOption::new(FILTER_ARG, None, true),
+                       EnvOption::new(MAX_DAY_RANGE_ARG, None, true),
+                       EnvOption::new(ALLOW_LARGE_RANGE_ARG, None, false),
+                       EnvOption::new(BUSINESS_HOURS_ARG, None, true),
+                       EnvOption::new(COUNT_FLOOR_ARG, None, true),
+                       EnvOption::new(FLOOR_MODE_ARG, None, true),
+                       EnvOption::new(COUNT_OVERFLOW_POLICY_ARG, None, true),
+                       EnvOption::new(LOG_FORMAT_ARG, None, true),
+                       EnvOption::new(RECORD_SEPARATOR_ARG, None, true),
+                       EnvOption::new(PARSER_COMMAND_ARG, None, true),
+                       EnvOption::new(PROFILE_ARG, None, false),
+                       EnvOption::new(STRICT_CSV_ARG, None, false),
+                       EnvOption::new(THREADS_PER_CORE_ARG, None, true),
+                       EnvOption::new(THREADS_ARG, Some('t'), true),
+                       EnvOption::new(FORMAT_ARG, Some('f'), true),
+                       EnvOption::new(OUTPUT_ARG, None, true),
+                       EnvOption::new(COMPRESS_ARG, None, true),
+                       EnvOption::new(FILE_TIMEOUT_ARG, None, true),
+                       EnvOption::new(TRACE_KEY_ARG, None, true),
+                       EnvOption::new(TRACE_OUTPUT_ARG, None, true),
+                       EnvOption::new(TRACE_LIMIT_ARG, None, true),
+                       EnvOption::new(HASH_KEYS_ARG, None, false),
+                       EnvOption::new(KEY_DICTIONARY_ARG, None, true),
+                       EnvOption::new(REJECTS_ARG, None, true),
+                       EnvOption::new(WEIGHT_FIELD_ARG, None, true),
+                       EnvOption::new(ROUND_TIMESTAMPS_TO_ARG, None, true),
+                       EnvOption::new(MAX_AGGREGATES_ARG, None, true),
+                       EnvOption::new(MERGE_THREADS_ARG, None, true),
+                       EnvOption::new(EXPLAIN_RECORD_ARG, None, true),
+                       EnvOption::new(PRINT_SCHEMA_ARG, None, false),
+                       EnvOption::new(COUNT_STATUS_ARG, None, true),
+                       EnvOption::new(BY_LATENCY_ARG, None, false),
+                       EnvOption::new(LATENCY_BUCKETS_ARG, None, true),
+                       EnvOption::new(GROUP_BY_ARG, None, true),
+                       EnvOption::new(SORT_BY_ARG, None, true),
+                       EnvOption::new(REVERSE_SORT_ARG, None, false),
+                       EnvOption::new(DAY_OF_MONTH_ARG, None, false),
+                       EnvOption::new(SAMPLE_ERRORS_ARG, None, true),
+                       EnvOption::new(SEED_ARG, None, true),
+                       EnvOption::new(LIST_SYSTEMS_ARG, None, false),
+                       EnvOption::new(BASELINE_ARG, None, true),
+                       EnvOption::new(MAX_DROP_ARG, None, true),
+                       EnvOption::new(WATCH_ARG, None, false),
+                       EnvOption::new(FLUSH_INTERVAL_ARG, None, true),
+                       EnvOption::new(MAX_MEMORY_ARG, None, true),
+                       EnvOption::new(CHECKPOINT_ARG, None, true),
+                       EnvOption::new(RESUME_ARG, None, false),
+                       EnvOption::new(SINK_ARG, None, true),
+                       EnvOption::new(METRIC_PREFIX_ARG, None, true),
+                       EnvOption::new(SINK_DRY_RUN_ARG, None, false),
+                       EnvOption::new(TRUNCATE_FIELD_ARG, None, true)];
 
-        assert_eq!(runtime_context.log_location().to_str().unwrap(), "~/logs")
-    }
+    #[cfg(feature = "status-socket")]
+    let options = options.into_iter()
+        .chain(vec![EnvOption::new(STATUS_ADDR_ARG, None, true)])
+        .collect::<Vec<_>>();
 
-    #[test]
-    fn constructing_a_runtime_context_should_not_panic_if_the_log_location_is_specified() {
-        let arg_vec = vec!["counter", "~/logs"];
+    #[cfg(feature = "kafka")]
+    let options = options.into_iter()
+        .chain(vec![EnvOption::new(KAFKA_BROKERS_ARG, None, true),
+                    EnvOption::new(KAFKA_TOPIC_ARG, None, true),
+                    EnvOption::new(KAFKA_REQUIRE_ACKS_ARG, None, false),
+                    EnvOption::new(KAFKA_SUPPRESS_OUTPUT_ARG, None, false)])
+        .collect::<Vec<_>>();
 
-        let result = panic::catch_unwind(|| { RuntimeContext::new_test_runtime_context(arg_vec); });
+    options
+}
 
-        assert!(result.is_ok())
+/// Adds the options and log location missing from the command line `args` that are set in the
+/// environment, as read by `lookup`, so clap validates them like the rest of the command line.
+/// Options given on the command line take precedence over the environment, which takes precedence
+/// over the defaults. A flag is set by a variable of true or 1 and left unset by false, 0 or an
+/// empty variable.
+fn with_env_args<F>(args: Vec<OsString>, lookup: F) -> Vec<OsString>
+    where F: Fn(&str) -> Option<OsString>
+{
+    let options = env_options();
+    let mut given = HashSet::new();
+    let mut has_log_location = false;
+    let mut after_separator = false;
+    let mut args_iter = args.iter().skip(1);
+    while let Some(arg) = args_iter.next() {
+        let arg = arg.to_string_lossy();
+        if after_separator || arg == "-" || !arg.starts_with('-') {
+            has_log_location = true;
+        } else if arg == "--" {
+            after_separator = true;
+        } else if arg.starts_with("--") {
+            let name = arg[2..].split('=').next().unwrap();
+            if let Some(option) = options.iter().find(|option| option.name == name) {
+                given.insert(option.name);
+                if option.takes_value && !arg.contains('=') {
+                    args_iter.next();
+                }
+            }
+        } else {
+            for (i, short) in arg[1..].char_indices() {
+                if let Some(option) = options.iter().find(|option| option.short == Some(short)) {
+                    given.insert(option.name);
+                    if option.takes_value {
+                        if i + short.len_utf8() == arg.len() - 1 {
+                            args_iter.next();
+                        }
+                        break;
+                    }
+                }
+            }
+        }
     }
 
-    #[test]
-    fn constructing_a_runtime_context_should_panic_if_the_log_location_is_not_specified() {
-        let arg_vec = vec!["counter"];
-
-        let result = panic::catch_unwind(|| { RuntimeContext::new_test_runtime_context(arg_vec); });
-
-        assert!(result.is_err())
+    let mut env_args = Vec::new();
+    for option in options.iter().filter(|option| !given.contains(option.name)) {
+        if let Some(value) = lookup(&option.env_var()) {
+            if !option.takes_value {
+                match value.to_str() {
+                    Some("true") | Some("1") => {
+                        env_args.push(OsString::from(format!("--{}", option.name)));
+                        continue;
+                    }
+                    Some("false") | Some("0") | Some("") => continue,
+                    // Passed as a value so clap rejects it like the same flag on the command line.
+                    _ => {}
+                }
+            }
+            let mut env_arg = OsString::from(format!("--{}=", option.name));
+            env_arg.push(&value);
+            env_args.push(env_arg);
+        }
     }
 
-    #[test]
-    fn run_benchmark_should_return_false_when_benchmark_arg_is_not_set() {
+    let mut args = args.into_iter();
+    let mut args_with_env = args.next().into_iter().chain(env_args).chain(args).collect::<Vec<_>>();
+    if !has_log_location {
+        if let Some(log_location) = lookup(&env_var_name(LOG_LOCATION_ARG)) {
+            if !after_separator {
+                args_with_env.push(OsString::from("--"));
+            }
+            args_with_env.push(log_location);
+        }
+    }
+    args_with_env
+}
+
+struct RuntimeContext<'a> {
+    arg_matches: clap::ArgMatches<'a>,
+}
+
+impl<'a> RuntimeContext<'a> {
+    fn new() -> RuntimeContext<'a> {
+        let args = with_env_args(env::args_os().collect(), |name| env::var_os(name));
+        let arg_matches = RuntimeContext::new_app().get_matches_from(args);
+
+        RuntimeContext { arg_matches: arg_matches }
+    }
+
+    #[cfg(test)]
+    fn new_test_runtime_context(args: Vec<&str>) -> RuntimeContext<'a> {
+        let arg_matches = RuntimeContext::new_app()
+            .get_matches_from_safe_borrow(args)
+            .unwrap();
+
+        RuntimeContext { arg_matches: arg_matches }
+    }
+
+    #[cfg(test)]
+    fn new_test_runtime_context_with_env(args: Vec<&str>,
+                                         env_vars: Vec<(&str, &str)>)
+                                         -> RuntimeContext<'a> {
+        let args = with_env_args(args.into_iter().map(OsString::from).collect(), |name| {
+            env_vars.iter()
+                .find(|&&(env_var, _)| env_var == name)
+                .map(|&(_, value)| OsString::from(value))
+        });
+        let arg_matches = RuntimeContext::new_app()
+            .get_matches_from_safe_borrow(args)
+            .unwrap();
+
+        RuntimeContext { arg_matches: arg_matches }
+    }
+
+    fn new_app<'b>() -> clap::App<'a, 'b> {
+        let app = clap::App::new("counter")
+            .version(include_str!("version.txt"))
+            .version_short("v")
+            .after_help("Every option, and the log location, can also be set with an environment \
+                         variable named after it, such as COUNTER_THREADS for --threads or \
+                         COUNTER_LOG_LOCATION. Flags are set by a variable of true or 1. \
+                         Options on the command line take precedence over the environment, \
+                         which takes precedence over the defaults.")
+            .arg(clap::Arg::with_name(LOG_LOCATION_ARG)
+                .required_unless_one(&[EXPLAIN_RECORD_ARG, PRINT_SCHEMA_ARG])
+                .help(LOG_LOCATION_HELP))
+            .arg(clap::Arg::with_name(BENCHMARK_ARG)
+                .required(false)
+                .help("Time the run and provide statistics at the end of the run.")
+                .long("benchmark")
+                .short("b"))
+            .arg(clap::Arg::with_name(LIMIT_PER_SYSTEM_ARG)
+                .required(false)
+                .help("Only output the N aggregates having the highest totals for each system.")
+                .long("limit-per-system")
+                .takes_value(true)
+                .value_name("N")
+                .validator(|value| {
+                    value.parse::<usize>()
+                        .map(|_| ())
+                        .map_err(|_| format!("{} is not a valid number of aggregates.", value))
+                }))
+            .arg(clap::Arg::with_name(RESULT_HASH_ARG)
+                .required(false)
+                .help("Print a SHA-256 of the sorted aggregation results to stderr.")
+                .long("result-hash"))
+            .arg(clap::Arg::with_name(DEDUPE_RECORDS_ARG)
+                .required(false)
+                .help("Skip records whose line is identical to one already seen. per-file only \
+                       compares lines within a file and is cheap. global compares lines across \
+                       every file and keeps two fingerprints of each distinct record in memory \
+                       for the whole run. Records sharing a fingerprint with a different record \
+                       are counted and reported rather than skipped.")
+                .long("dedupe-records")
+                .takes_value(true)
+                .possible_values(&["per-file", "global"]))
+            .arg(clap::Arg::with_name(SQUASH_WINDOW_ARG)
+                .required(false)
+                .help("Count a burst of requests from a client to the same URL, query left out, \
+                       as one, skipping every record within MS milliseconds of the previous one. \
+                       Bursts are only found within a file, which has to be sorted by time, and \
+                       the last request of every client and URL of the file is kept in memory \
+                       while it is read.")
+                .long("squash-window")
+                .takes_value(true)
+                .value_name("MS")
+                .validator(|value| {
+                    value.parse::<u64>()
+                        .ok()
+                        .filter(|&millis| millis > 0)
+                        .map(|_| ())
+                        .ok_or_else(|| format!("{} is not a valid number of milliseconds.", value))
+                }))
+            .arg(clap::Arg::with_name(PROGRESS_ARG)
+                .required(false)
+                .help("Report the percentage of log bytes processed to stderr during the run.")
+                .long("progress"))
+            .arg(clap::Arg::with_name(PATH_DIMENSION_ARG)
+                .required(false)
+                .help("Add the name of the directory DEPTH levels below the log location as an \
+                       aggregation dimension called NAME. Files that are not nested that deeply \
+                       are aggregated under -.")
+                .long("path-dimension")
+                .takes_value(true)
+                .value_name("NAME:DEPTH")
+                .validator(|value| parse_path_dimension(&value).map(|_| ())))
+            .arg(clap::Arg::with_name(PASSTHROUGH_ARG)
+                .required(false)
+                .help("Write the system, timestamp and client of every parsed record to stdout \
+                       instead of aggregating. This produces one line per log record. The lines \
+                       are written by the file handlers as they read the records, so they can't \
+                       go to --output or be compressed.")
+                .long("passthrough")
+                .conflicts_with_all(&[OUTPUT_ARG, COMPRESS_ARG]))
+            .arg(clap::Arg::with_name(DROP_NO_SYSTEM_ARG)
+                .required(false)
+                .help("Skip records that have no system name, or a - system name, instead of \
+                       aggregating them under the null system label.")
+                .long("drop-no-system"))
+            .arg(clap::Arg::with_name(NULL_SYSTEM_LABEL_ARG)
+                .required(false)
+                .help("The system name to aggregate records without a system name under.")
+                .long("null-system-label")
+                .takes_value(true)
+                .value_name("LABEL")
+                .default_value(record_handling::DEFAULT_NULL_SYSTEM_LABEL))
+            .arg(clap::Arg::with_name(PARSE_ONLY_ARG)
+                .required(false)
+                .help("Only parse the records, without aggregating them, and report the parsing \
+                       throughput. Compare with a normal benchmark to separate the cost of \
+                       parsing from the cost of aggregation.")
+                .long("parse-only")
+                .requires(BENCHMARK_ARG))
+            .arg(clap::Arg::with_name(MIN_RECORDS_PER_FILE_ARG)
+                .required(false)
+                .help("Warn about every file yielding fewer than N parseable records, which \
+                       usually means it was truncated. Empty files are reported separately.")
+                .long("min-records-per-file")
+                .takes_value(true)
+                .value_name("N")
+                .validator(|value| {
+                    value.parse::<u64>()
+                        .map(|_| ())
+                        .map_err(|_| format!("{} is not a valid number of records.", value))
+                }))
+            .arg(clap::Arg::with_name(WITH_FILE_SUBTOTALS_ARG)
+                .required(false)
+                .help("After the aggregates, write a blank line and then a file,records CSV \
+                       section holding the number of records aggregated from every file. The \
+                       members of an archive are counted under the archive.")
+                .long("with-file-subtotals"))
+            .arg(clap::Arg::with_name(WITH_TIMESPAN_ARG)
+                .required(false)
+                .help("Write the timestamps of the earliest and latest record counted towards \
+                       every aggregate, as first_seen and last_seen after the total of the CSV, \
+                       TSV and JSON formats. Keeping a copy of the key of every record slows \
+                       aggregation down a little. The other formats and --list-systems leave \
+                       them out.")
+                .long("with-timespan"))
+            .arg(clap::Arg::with_name(METRICS_ARG)
+                .required(false)
+                .help("Write the received and sent bytes, the minimum, maximum and approximate \
+                       50th, 95th and 99th percentile latency in seconds and the number of 2xx, \
+                       3xx, 4xx and 5xx responses of every aggregate after its total in the CSV, \
+                       TSV and JSON formats. The latency is the sum of the processing times. \
+                       Keeping them makes every aggregate take about 1KB more memory and \
+                       aggregation slower. The other formats and --list-systems leave them out.")
+                .long("metrics"))
+            .arg(clap::Arg::with_name(WARN_OVERLAPPING_DAYS_ARG)
+                .required(false)
+                .help("Warn about pairs of files whose records from a day cover overlapping \
+                       times and number about the same, which usually means the same logs were \
+                       delivered twice and the day is counted twice. This is a heuristic, not a \
+                       guarantee: duplicates can be missed and distinct files can be flagged. \
+                       The counts are left unchanged.")
+                .long("warn-overlapping-days"))
+            .arg(clap::Arg::with_name(VERIFY_SORTED_INPUT_ARG)
+                .required(false)
+                .help("Check that the records of every file are sorted by timestamp, as \
+                       --squash-window needs them to be, and report the files holding records \
+                       earlier than a record before them along with the line of the first. The \
+                       members of an archive are checked separately. The counts are left \
+                       unchanged.")
+                .long("verify-sorted-input"))
+            .arg(clap::Arg::with_name(FROM_ARG)
+                .required(false)
+                .help("Only count records from DAY, a YYYY-MM-DD date, onwards. The records \
+                       before it are still read.")
+                .long("from")
+                .takes_value(true)
+                .value_name("DAY")
+                .validator(|value| parse_day(&value).map(|_| ())))
+            .arg(clap::Arg::with_name(TO_ARG)
+                .required(false)
+                .help("Only count records from DAY, a YYYY-MM-DD date, and before. The records \
+                       after it are still read.")
+                .long("to")
+                .takes_value(true)
+                .value_name("DAY")
+                .validator(|value| parse_day(&value).map(|_| ())))
+            .arg(clap::Arg::with_name(SINCE_ARG)
+                .required(false)
+                .help("Only count records from TIMESTAMP, an RFC 3339 timestamp such as \
+                       2015-08-15T09:00:00Z, onwards. The records before it are still read.")
+                .long("since")
+                .takes_value(true)
+                .value_name("TIMESTAMP")
+                .validator(|value| parse_timestamp(&value).map(|_| ())))
+            .arg(clap::Arg::with_name(UNTIL_ARG)
+                .required(false)
+                .help("Only count records from before TIMESTAMP, an RFC 3339 timestamp. The \
+                       records from then on are still read.")
+                .long("until")
+                .takes_value(true)
+                .value_name("TIMESTAMP")
+                .validator(|value| parse_timestamp(&value).map(|_| ())))
+            .arg(clap::Arg::with_name(FILTER_ARG)
+                .required(false)
+                .help("Only count records whose FIELD has VALUE, given as FIELD=VALUE. The \
+                       fields are system, client, backend, port, status, the ELB status code, \
+                       and backend_status. The status codes take a list of codes, ranges and \
+                       classes such as 5xx, while the other fields have to match exactly. \
+                       Repeat --filter to only count records matching every filter.")
+                .long("filter")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .value_name("FIELD=VALUE")
+                .validator(|value| parse_filter(&value).map(|_| ())))
+            .arg(clap::Arg::with_name(MAX_DAY_RANGE_ARG)
+                .required(false)
+                .help("Refuse to count records spanning more than DAYS days, such as a run \
+                       missing its --from and --to scanning years of logs. A --from and --to \
+                       further apart are refused before reading anything. Otherwise every file \
+                       handler stops once its own records span more than DAYS days, and the run \
+                       fails before writing any aggregates. Set it in the environment of shared \
+                       hosts to guard every run.")
+                .long("max-day-range")
+                .takes_value(true)
+                .value_name("DAYS")
+                .validator(|value| parse_max_day_range(&value).map(|_| ())))
+            .arg(clap::Arg::with_name(ALLOW_LARGE_RANGE_ARG)
+                .required(false)
+                .help("Count records spanning any number of days despite --max-day-range.")
+                .long("allow-large-range"))
+            .arg(clap::Arg::with_name(BUSINESS_HOURS_ARG)
+                .required(false)
+                .help("Only count records from the UTC times of day in HOURS, a START-END range \
+                       of HH:MM times such as 09:00-17:00. START is included and END is not, and \
+                       a START after END wraps past midnight. The records outside are still \
+                       read, and counted separately.")
+                .long("business-hours")
+                .takes_value(true)
+                .value_name("HOURS")
+                .validator(|value| parse_business_hours(&value).map(|_| ())))
+            .arg(clap::Arg::with_name(COUNT_FLOOR_ARG)
+                .required(false)
+                .help("Hide aggregates with totals below N, so published results never reveal \
+                       that only a few clients did something. This alters the published counts. \
+                       N can't be negative.")
+                .long("count-floor")
+                .takes_value(true)
+                .value_name("N")
+                .validator(|value| {
+                    value.parse::<i64>()
+                        .ok()
+                        .filter(|&floor| floor >= 0)
+                        .map(|_| ())
+                        .ok_or_else(|| format!("{} is not a valid count floor.", value))
+                }))
+            .arg(clap::Arg::with_name(FLOOR_MODE_ARG)
+                .required(false)
+                .help("suppress drops aggregates below the count floor, round reports the floor \
+                       in place of their totals.")
+                .long("floor-mode")
+                .takes_value(true)
+                .possible_values(&["suppress", "round"])
+                .default_value("suppress"))
+            .arg(clap::Arg::with_name(COUNT_OVERFLOW_POLICY_ARG)
+                .required(false)
+                .help("What happens when counting a record would overflow the total of its \
+                       aggregate, which only totals of --weight-field are likely to. saturate \
+                       stops the total at the largest representable total and warns, wrap \
+                       wraps it around to the most negative total, and error aborts the run.")
+                .long("count-overflow-policy")
+                .takes_value(true)
+                .value_name("POLICY")
+                .possible_values(&["saturate", "wrap", "error"])
+                .default_value("saturate"))
+            .arg(clap::Arg::with_name(LOG_FORMAT_ARG)
+                .required(false)
+                .help("The load balancer that logged the records. alb reads Application Load \
+                       Balancer records by the classic ELB fields they start with, and auto \
+                       reads every record starting with a request type, such as http or h2, as \
+                       an ALB record and every other record as an ELB record, so one run can \
+                       aggregate a mix of both.")
+                .long("log-format")
+                .takes_value(true)
+                .value_name("FORMAT")
+                .possible_values(&["elb", "alb", "auto"])
+                .default_value("auto"))
+            .arg(clap::Arg::with_name(RECORD_SEPARATOR_ARG)
+                .required(false)
+                .help("The byte separating records instead of a newline. Accepts a single \
+                       character or one of the escapes \\0, \\t, \\n, \\r, \\\\ and \\xNN.")
+                .long("record-separator")
+                .takes_value(true)
+                .value_name("BYTE")
+                .validator(|value| parse_record_separator(&value).map(|_| ())))
+            .arg(clap::Arg::with_name(PARSER_COMMAND_ARG)
+                .required(false)
+                .help("Pipe every log file, decompressed when gzipped, through CMD, run by sh, \
+                       and aggregate the lines it writes instead of parsing ELB records. Every \
+                       line must hold the tab separated fields timestamp, system, client, bytes \
+                       and status: an RFC 3339 timestamp, a system name, an IPv4 client address \
+                       with an optional :port, a byte count used as the weight by \
+                       --weight-field and a status code checked by --count-status. A missing \
+                       system, client or byte count is written as -. This makes any log format \
+                       countable at the cost of a process per file and a second parse of every \
+                       record. Archives can't be piped, and aggregates can only be grouped by \
+                       system, day and client.")
+                .long("parser-command")
+                .takes_value(true)
+                .value_name("CMD")
+                .conflicts_with_all(&[PASSTHROUGH_ARG,
+                                      PARSE_ONLY_ARG,
+                                      RECORD_SEPARATOR_ARG,
+                                      SQUASH_WINDOW_ARG,
+                                      TRACE_KEY_ARG,
+                                      BY_LATENCY_ARG,
+                                      LATENCY_BUCKETS_ARG,
+                                      WARN_OVERLAPPING_DAYS_ARG,
+                                      WITH_TIMESPAN_ARG,
+                                      METRICS_ARG]))
+            .arg(clap::Arg::with_name(PROFILE_ARG)
+                .required(false)
+                .help("Report the time spent listing files, reading, parsing, building keys and \
+                       merging aggregates to stderr. Times are summed across threads.")
+                .long("profile"))
+            .arg(clap::Arg::with_name(STRICT_CSV_ARG)
+                .required(false)
+                .help("Fail without writing any output when a system name or path dimension \
+                       contains the CSV or TSV delimiter, instead of writing the field quoted, \
+                       for readers that don't understand quoted fields.")
+                .long("strict-csv"))
+            .arg(clap::Arg::with_name(THREADS_PER_CORE_ARG)
+                .required(false)
+                .help("Run FACTOR file handlers per core instead of one. Oversubscribing helps \
+                       when the handlers mostly wait on I/O, such as uncompressed logs on high \
+                       latency storage, and hurts when they are CPU bound, such as when \
+                       decompressing logs.")
+                .long("threads-per-core")
+                .takes_value(true)
+                .value_name("FACTOR")
+                .validator(|value| {
+                    parse_threads_per_core(&value, num_cpus::get()).map(|_| ())
+                }))
+            .arg(clap::Arg::with_name(THREADS_ARG)
+                .required(false)
+                .help("Run exactly COUNT file handlers instead of one per core, such as to leave \
+                       cores free for other jobs on a shared host.")
+                .long("threads")
+                .short("t")
+                .takes_value(true)
+                .value_name("COUNT")
+                .conflicts_with(THREADS_PER_CORE_ARG)
+                .validator(|value| parse_num_threads(&value).map(|_| ())))
+            .arg(clap::Arg::with_name(FORMAT_ARG)
+                .required(false)
+                .help("The format the aggregates are written in. csv and tsv quote fields \
+                       containing the delimiter or a quote. json writes a single array of objects \
+                       with the dimensions grouped by and count of every aggregate. \
+                       jsonl writes an object per line with the total under total instead. \
+                       influx writes InfluxDB line \
+                       protocol points of the requests measurement, timestamped with the start \
+                       of their day. matrix writes a CSV row of totals for every system with a \
+                       column for every day in the logs, so long date ranges make for wide rows. \
+                       none builds the aggregates without writing them, so --benchmark leaves \
+                       out the cost of output. yaml, when counter is built with the yaml \
+                       feature, writes a sequence of mappings holding the fields of json.")
+                .long("format")
+                .short("f")
+                .takes_value(true)
+                .possible_values(&OUTPUT_FORMATS)
+                .default_value("csv"))
+            .arg(clap::Arg::with_name(OUTPUT_ARG)
+                .required(false)
+                .help("Write the aggregates to the file at PATH instead of stdout, creating or \
+                       truncating it before reading any logs. Summaries still go to stderr.")
+                .long("output")
+                .takes_value(true)
+                .value_name("PATH"))
+            .arg(clap::Arg::with_name(COMPRESS_ARG)
+                .required(false)
+                .help("Compress the aggregates written to stdout or --output with gzip. \
+                       Compressed output isn't written to a terminal.")
+                .long("compress")
+                .takes_value(true)
+                .value_name("METHOD")
+                .possible_values(&["gzip"]))
+            .arg(clap::Arg::with_name(FILE_TIMEOUT_ARG)
+                .required(false)
+                .help("Abandon a file once SECS seconds have been spent on it and move on to the \
+                       next one. The records read before then are still aggregated.")
+                .long("file-timeout")
+                .takes_value(true)
+                .value_name("SECS")
+                .validator(|value| {
+                    value.parse::<u64>()
+                        .map(|_| ())
+                        .map_err(|_| format!("{} is not a valid number of seconds.", value))
+                }))
+            .arg(clap::Arg::with_name(TRACE_KEY_ARG)
+                .required(false)
+                .help("Write raw records aggregated under this key to the trace output, to show \
+                       how its total came about. DAY is formatted as YYYY-MM-DD.")
+                .long("trace-key")
+                .takes_value(true)
+                .value_name("SYSTEM,DAY,CLIENT")
+                .requires(TRACE_OUTPUT_ARG)
+                .validator(|value| parse_trace_key(&value).map(|_| ())))
+            .arg(clap::Arg::with_name(TRACE_OUTPUT_ARG)
+                .required(false)
+                .help("The file the records matching the trace key are written to.")
+                .long("trace-output")
+                .takes_value(true)
+                .value_name("FILE")
+                .requires(TRACE_KEY_ARG))
+            .arg(clap::Arg::with_name(TRACE_LIMIT_ARG)
+                .required(false)
+                .help("The most records written to the trace output.")
+                .long("trace-limit")
+                .takes_value(true)
+                .value_name("N")
+                .default_value("100")
+                .validator(|value| {
+                    value.parse::<usize>()
+                        .map(|_| ())
+                        .map_err(|_| format!("{} is not a valid number of records.", value))
+                }))
+            .arg(clap::Arg::with_name(HASH_KEYS_ARG)
+                .required(false)
+                .help("Key the aggregates by a 64 bit hash of their fields other than the day, \
+                       written in place of the system name, instead of by the fields \
+                       themselves. Saves memory when grouping by several wide dimensions such as \
+                       the URL. Keys sharing a hash are counted as one, which becomes likely \
+                       once there are billions of keys. The hashes can only be decoded with the \
+                       fields written by --key-dictionary.")
+                .long("hash-keys")
+                .conflicts_with(TRACE_KEY_ARG))
+            .arg(clap::Arg::with_name(KEY_DICTIONARY_ARG)
+                .required(false)
+                .help("Write the fields every hashed key was hashed from to FILE, as CSV rows of \
+                       the hash followed by the fields, once for every hash.")
+                .long("key-dictionary")
+                .takes_value(true)
+                .value_name("FILE")
+                .requires(HASH_KEYS_ARG)
+                .conflicts_with(PARSER_COMMAND_ARG))
+            .arg(clap::Arg::with_name(REJECTS_ARG)
+                .required(false)
+                .help("Write every record that couldn't be parsed to FILE, as CSV rows of the \
+                       log file it was read from, its line number, why it couldn't be parsed \
+                       and the record.")
+                .long("rejects")
+                .takes_value(true)
+                .value_name("FILE"))
+            .arg(clap::Arg::with_name(WEIGHT_FIELD_ARG)
+                .required(false)
+                .help("Total this field of the records in each aggregate instead of counting \
+                       the records. A record without a numeric value for the field, which only \
+                       --parser-command can write, is weighted as 0 and reported at the end of \
+                       the run.")
+                .long("weight-field")
+                .takes_value(true)
+                .value_name("NAME")
+                .possible_values(&["received_bytes", "sent_bytes"]))
+            .arg(clap::Arg::with_name(ROUND_TIMESTAMPS_TO_ARG)
+                .required(false)
+                .help("Round the timestamps written by --passthrough and --trace down to a \
+                       multiple of DURATION, given in ms, s, m or h such as 10s. Only passthrough \
+                       and trace output is affected, aggregates are always bucketed by day.")
+                .long("round-timestamps-to")
+                .takes_value(true)
+                .value_name("DURATION")
+                .validator(|value| parse_timestamp_resolution(&value).map(|_| ())))
+            .arg(clap::Arg::with_name(MAX_AGGREGATES_ARG)
+                .required(false)
+                .help("Abort the run once it has accumulated more than N distinct aggregates, as \
+                       a guard against aggregating by too many dimensions. The file handlers \
+                       stop reading as soon as they hold more than N between them, a key held by \
+                       several of them counting once for each, and --watch keeps the aggregation \
+                       of every file read to N too.")
+                .long("max-aggregates")
+                .takes_value(true)
+                .value_name("N")
+                .validator(|value| {
+                    value.parse::<usize>()
+                        .map(|_| ())
+                        .map_err(|_| format!("{} is not a valid number of aggregates.", value))
+                }))
+            .arg(clap::Arg::with_name(MERGE_THREADS_ARG)
+                .required(false)
+                .help("Merge the aggregates of every file handler as it finishes on COUNT \
+                       threads, splitting the keys into a partition per thread, rather than on \
+                       a single thread. Speeds up runs producing huge numbers of aggregates.")
+                .long("merge-threads")
+                .takes_value(true)
+                .value_name("COUNT")
+                .validator(|value| parse_num_threads(&value).map(|_| ())))
+            .arg(clap::Arg::with_name(EXPLAIN_RECORD_ARG)
+                .required(false)
+                .help("Explain how RECORD is parsed and which aggregate it is counted under with \
+                       the other options given, then exit without reading any logs.")
+                .long("explain-record")
+                .takes_value(true)
+                .value_name("RECORD"))
+            .arg(clap::Arg::with_name(PRINT_SCHEMA_ARG)
+                .required(false)
+                .help("Print the names and types of the columns the output would have with the \
+                       other options given, as JSON, then exit without reading any logs.")
+                .long("print-schema"))
+            .arg(clap::Arg::with_name(COUNT_STATUS_ARG)
+                .required(false)
+                .help("Only count records whose backend status is in LIST, a comma separated \
+                       list of codes and ranges such as 200,204,301-304. Records logged with a - \
+                       backend status can't be parsed and are never counted.")
+                .long("count-status")
+                .takes_value(true)
+                .value_name("LIST")
+                .validator(|value| parse_status_codes(&value).map(|_| ())))
+            .arg(clap::Arg::with_name(BY_LATENCY_ARG)
+                .required(false)
+                .help("Also aggregate by latency bucket, the sum of the request, backend and \
+                       response processing times. The buckets are <10ms, <50ms, <100ms, <500ms, \
+                       <1s and >=1s unless --latency-buckets is given. Records missing a \
+                       processing time are put in the unknown bucket.")
+                .long("by-latency"))
+            .arg(clap::Arg::with_name(LATENCY_BUCKETS_ARG)
+                .required(false)
+                .help("Aggregate by latency bucket using BOUNDS, an ascending comma separated \
+                       list of bucket upper bounds in milliseconds such as 10,50,100. Implies \
+                       --by-latency.")
+                .long("latency-buckets")
+                .takes_value(true)
+                .value_name("BOUNDS")
+                .validator(|value| parse_latency_bounds(&value).map(|_| ())))
+            .arg(clap::Arg::with_name(GROUP_BY_ARG)
+                .required(false)
+                .help("Aggregate by DIMENSIONS, a comma separated list of system, day, client, \
+                       backend, url, port, the ELB listener port, hour, the hour of the day, and \
+                       status, the ELB status code, which csv and tsv write in the order given. \
+                       system and day have to be included, though hour alone includes day \
+                       before it, and system_name, client_address, \
+                       backend_address and elb_status_code name the same dimensions as their \
+                       columns. Grouping by url is likely to make an aggregate of almost every \
+                       record, and records without a port in their URL have the port -.")
+                .long("group-by")
+                .takes_value(true)
+                .value_name("DIMENSIONS")
+                .default_value("system,day,client")
+                .validator(|value| parse_group_by(&value).map(|_| ())))
+            .arg(clap::Arg::with_name(SORT_BY_ARG)
+                .required(false)
+                .help("Write the aggregates ordered by ORDER. count puts the largest totals \
+                       first, and key orders by system, day, client and then the other \
+                       dimensions. system, day and client order by that dimension and then by \
+                       key. Ignored by the matrix format and --list-systems.")
+                .long("sort-by")
+                .takes_value(true)
+                .value_name("ORDER")
+                .possible_values(&["count", "key", "system", "day", "client"])
+                .default_value("key"))
+            .arg(clap::Arg::with_name(REVERSE_SORT_ARG)
+                .required(false)
+                .help("Reverse the order of --sort-by.")
+                .long("reverse-sort"))
+            .arg(clap::Arg::with_name(DAY_OF_MONTH_ARG)
+                .required(false)
+                .help("Aggregate by the day of the month, 1 to 31, instead of the day, adding up \
+                       the months. The day of the month is written in place of the day, and the \
+                       days are those of the UTC timestamps.")
+                .long("day-of-month"))
+            .arg(clap::Arg::with_name(SAMPLE_ERRORS_ARG)
+                .required(false)
+                .help("Keep a random sample of up to N distinct unparsable records in each \
+                       aggregating thread, and write them with the files they were read from to \
+                       stderr at the end of the run. The sample is drawn from --seed.")
+                .long("sample-errors")
+                .takes_value(true)
+                .value_name("N")
+                .validator(|value| {
+                    value.parse::<usize>()
+                        .map(|_| ())
+                        .map_err(|_| format!("{} is not a valid number of records.", value))
+                }))
+            .arg(clap::Arg::with_name(SEED_ARG)
+                .required(false)
+                .help("The seed of every random choice of the run, which so far is the sample of \
+                       --sample-errors. A thread reading the same records in the same order \
+                       makes the same choices for the same seed, though the threads of a run may \
+                       not read the same files from one run to the next, which --threads 1 sees \
+                       to.")
+                .long("seed")
+                .takes_value(true)
+                .value_name("SEED")
+                .default_value("0")
+                .validator(|value| {
+                    value.parse::<u64>()
+                        .map(|_| ())
+                        .map_err(|_| format!("{} is not a valid seed.", value))
+                }))
+            .arg(clap::Arg::with_name(LIST_SYSTEMS_ARG)
+                .required(false)
+                .help("Write the total of every system instead of the aggregates, ordered by \
+                       total descending. The records are aggregated by system and day alone, so \
+                       --group-by has no effect. The totals are written as system,total rows, \
+                       or as points when the format is influx.")
+                .long("list-systems"))
+            .arg(clap::Arg::with_name(BASELINE_ARG)
+                .required(false)
+                .help("Compare the total of every system to its total in FILE, the output of a \
+                       prior run with --list-systems or the CSV aggregates of a prior run, \
+                       which are summed by system, and fail the run when any fell by more than \
+                       --max-drop. CSV aggregates without a header row need the system first \
+                       and the total last.")
+                .long("baseline")
+                .takes_value(true)
+                .value_name("FILE")
+                .requires(MAX_DROP_ARG))
+            .arg(clap::Arg::with_name(MAX_DROP_ARG)
+                .required(false)
+                .help("The largest drop of a system's total compared to --baseline, as a \
+                       percentage of its baseline total, that doesn't fail the run.")
+                .long("max-drop")
+                .takes_value(true)
+                .value_name("PCT")
+                .requires(BASELINE_ARG)
+                .validator(|value| parse_percentage(&value).map(|_| ())))
+            .arg(clap::Arg::with_name(WATCH_ARG)
+                .required(false)
+                .help("Keep running and aggregate the log files of the log location as they \
+                       appear, reading every file once, and write the aggregates of every file \
+                       read so far every --flush-interval in which new files were read. The \
+                       output file is replaced every time, and --sink, --count-floor, \
+                       --max-day-range and --profile apply to every flush. Files are expected \
+                       to be complete when they appear, as ELB delivers them.")
+                .long("watch")
+                .conflicts_with_all(&[BASELINE_ARG,
+                                      BENCHMARK_ARG,
+                                      PASSTHROUGH_ARG,
+                                      TRACE_KEY_ARG,
+                                      KEY_DICTIONARY_ARG]))
+            .arg(clap::Arg::with_name(FLUSH_INTERVAL_ARG)
+                .required(false)
+                .help("How often --watch looks for new files, such as 30s or 5m.")
+                .long("flush-interval")
+                .takes_value(true)
+                .value_name("INTERVAL")
+                .default_value("1m")
+                .validator(|value| parse_timestamp_resolution(&value).map(|_| ())))
+            .arg(clap::Arg::with_name(MAX_MEMORY_ARG)
+                .required(false)
+                .help("Keep the aggregations of the file handlers within about SIZE bytes, such \
+                       as 512M or 8G, by spilling them to sorted files in the temporary \
+                       directory whenever a file handler's share is exceeded while reading. Once \
+                       every file is read, the spilled aggregations are merged as the aggregates \
+                       are written, in key order, so the whole aggregation is never held in \
+                       memory. The options needing every aggregate at once, such as --sort-by \
+                       other than key, --limit-per-system, --list-systems, --count-floor, \
+                       --baseline, --result-hash, --sink, --kafka-brokers and the yaml format, \
+                       can't be combined with it.")
+                .long("max-memory")
+                .takes_value(true)
+                .value_name("SIZE")
+                .conflicts_with_all(&[WITH_TIMESPAN_ARG,
+                                      METRICS_ARG,
+                                      REVERSE_SORT_ARG,
+                                      LIMIT_PER_SYSTEM_ARG,
+                                      COUNT_FLOOR_ARG,
+                                      BASELINE_ARG,
+                                      RESULT_HASH_ARG,
+                                      LIST_SYSTEMS_ARG,
+                                      STRICT_CSV_ARG,
+                                      SINK_ARG,
+                                      WATCH_ARG,
+                                      CHECKPOINT_ARG])
+                .validator(|value| parse_memory_size(&value).map(|_| ())))
+            .arg(clap::Arg::with_name(CHECKPOINT_ARG)
+                .required(false)
+                .help("Write the files fully aggregated and the aggregation of them to FILE \
+                       after every batch of files, so that a run that dies can be continued \
+                       with --resume. The file is replaced by every checkpoint and kept once \
+                       the run is done. Error samples and the checks of --verify-sorted-input and \
+                       --warn-overlapping-days aren't kept, nor are --rejects, --trace-key and \
+                       --key-dictionary continued. The timespans of --with-timespan, the \
+                       --metrics and the aggregations --max-memory spills to disk can't be \
+                       checkpointed, so neither can be combined with it.")
+                .long("checkpoint")
+                .takes_value(true)
+                .value_name("FILE")
+                .conflicts_with_all(&[WATCH_ARG,
+                                      PASSTHROUGH_ARG,
+                                      WITH_TIMESPAN_ARG,
+                                      METRICS_ARG,
+                                      MAX_MEMORY_ARG]))
+            .arg(clap::Arg::with_name(RESUME_ARG)
+                .required(false)
+                .help("Continue the run that wrote the --checkpoint file, skipping the files it \
+                       read and starting from its aggregation. The run is refused unless every \
+                       option changing which records are counted or what they're aggregated \
+                       under, such as --group-by, --filter, --from, --weight-field and \
+                       --path-dimension, is that of the run that wrote the file. Without a \
+                       checkpoint file the run starts over.")
+                .long("resume")
+                .requires(CHECKPOINT_ARG))
+            .arg(clap::Arg::with_name(SINK_ARG)
+                .required(false)
+                .help("Also push the aggregates as metrics to a statsd://host:port or \
+                       graphite://host:port URI, each named by the dimensions of its key and \
+                       valued by its total. StatsD gets a gauge per aggregate in batches of UDP \
+                       datagrams and Graphite plaintext lines over TCP, timestamped by the day \
+                       and hour of the aggregate. The run fails when the metrics can't be \
+                       pushed, or when two keys have the same metric name, such as the systems \
+                       sys.1 and sys_1, since dots and other characters in values become \
+                       underscores. Every name is checked before the first metric is pushed.")
+                .long("sink")
+                .takes_value(true)
+                .value_name("URI")
+                .validator(|value| statsd_output::parse_sink_address(&value).map(|_| ())))
+            .arg(clap::Arg::with_name(METRIC_PREFIX_ARG)
+                .required(false)
+                .help("The path the metric names pushed to --sink start with, such as \
+                       elb.requests.")
+                .long("metric-prefix")
+                .takes_value(true)
+                .value_name("PREFIX")
+                .requires(SINK_ARG))
+            .arg(clap::Arg::with_name(SINK_DRY_RUN_ARG)
+                .required(false)
+                .help("Write the metrics that would be pushed to --sink to the output in place \
+                       of the aggregates, without pushing them.")
+                .long("sink-dry-run")
+                .requires(SINK_ARG))
+            .arg(clap::Arg::with_name(TRUNCATE_FIELD_ARG)
+                .required(false)
+                .help("Truncate any output field longer than N characters to its first N \
+                       characters followed by '...'. Truncation is cosmetic and happens after \
+                       aggregating, so two distinct long values may look identical once \
+                       truncated.")
+                .long("truncate-field")
+                .takes_value(true)
+                .value_name("N")
+                .validator(|value| {
+                    value.parse::<usize>()
+                        .map(|_| ())
+                        .map_err(|_| format!("{} is not a valid number of characters.", value))
+                }));
+
+        #[cfg(feature = "status-socket")]
+        let app = app.arg(clap::Arg::with_name(STATUS_ADDR_ARG)
+            .required(false)
+            .help("Serve the progress of the run as JSON over HTTP on HOST:PORT.")
+            .long("status-addr")
+            .takes_value(true)
+            .value_name("HOST:PORT")
+            .validator(|value| {
+                value.parse::<SocketAddr>()
+                    .map(|_| ())
+                    .map_err(|_| format!("{} is not a valid HOST:PORT address.", value))
+            }));
+
+        #[cfg(feature = "kafka")]
+        let app = app.arg(clap::Arg::with_name(KAFKA_BROKERS_ARG)
+                .required(false)
+                .help("A comma separated list of Kafka brokers to publish the aggregates to. \
+                       Not available with --watch, which would publish every aggregate again \
+                       at every flush.")
+                .long("kafka-brokers")
+                .takes_value(true)
+                .value_name("HOST:PORT,...")
+                .requires(KAFKA_TOPIC_ARG)
+                .conflicts_with_all(&[MAX_MEMORY_ARG, WATCH_ARG]))
+            .arg(clap::Arg::with_name(KAFKA_TOPIC_ARG)
+                .required(false)
+                .help("The Kafka topic to publish the aggregates to.")
+                .long("kafka-topic")
+                .takes_value(true)
+                .value_name("TOPIC")
+                .requires(KAFKA_BROKERS_ARG))
+            .arg(clap::Arg::with_name(KAFKA_REQUIRE_ACKS_ARG)
+                .required(false)
+                .help("Require every in-sync replica to acknowledge each aggregate and fail the \
+                       run if any aggregate is not delivered.")
+                .long("kafka-require-acks")
+                .requires(KAFKA_BROKERS_ARG))
+            .arg(clap::Arg::with_name(KAFKA_SUPPRESS_OUTPUT_ARG)
+                .required(false)
+                .help("Do not write the aggregates to stdout when publishing to Kafka.")
+                .long("kafka-suppress-output")
+                .requires(KAFKA_BROKERS_ARG));
+
+        app
+    }
+
+    fn run_benchmark(&self) -> bool {
+        self.arg_matches.is_present(BENCHMARK_ARG)
+    }
+
+    fn file_aggregator_config(&self) -> file_handling::FileAggregatorConfig {
+        let dedupe = self.arg_matches.value_of(DEDUPE_RECORDS_ARG).map(|scope| match scope {
+            "global" => {
+                file_handling::RecordDeduplication::Global(Arc::new(Mutex::new(HashMap::new())))
+            }
+            _ => file_handling::RecordDeduplication::PerFile,
+        });
+
+        let path_dimension = self.arg_matches
+            .value_of(PATH_DIMENSION_ARG)
+            .map(|value| {
+                let (name, depth) = parse_path_dimension(value).unwrap();
+                file_handling::PathDimension {
+                    name: name,
+                    log_root: self.log_location().to_path_buf(),
+                    depth: depth,
+                }
+            });
+
+        file_handling::FileAggregatorConfig {
+            metrics: Arc::new(RunMetrics::new()),
+            dedupe: dedupe,
+            squash_window: self.squash_window(),
+            path_dimension: path_dimension,
+            passthrough: self.arg_matches.is_present(PASSTHROUGH_ARG),
+            parse_only: self.parse_only(),
+            record_separator: self.arg_matches
+                .value_of(RECORD_SEPARATOR_ARG)
+                .map(|separator| parse_record_separator(separator).unwrap())
+                .and_then(|separator| if separator == b'\n' { None } else { Some(separator) }),
+            parser_command: self.parser_command().unwrap(),
+            min_records_per_file: self.arg_matches
+                .value_of(MIN_RECORDS_PER_FILE_ARG)
+                .map(|min| min.parse::<u64>().unwrap()),
+            max_day_range: self.max_day_range(),
+            file_timeout: self.arg_matches
+                .value_of(FILE_TIMEOUT_ARG)
+                .map(|secs| Duration::from_secs(secs.parse::<u64>().unwrap())),
+            record_config: self.record_config(),
+            profile: if self.arg_matches.is_present(PROFILE_ARG) {
+                Some(Arc::new(PhaseTimings::new()))
+            } else {
+                None
+            },
+            // The trace output and key dictionary are created once by main, since this config is
+            // built repeatedly.
+            trace: None,
+            key_dictionary: None,
+            rejects: None,
+            sample_errors: self.arg_matches
+                .value_of(SAMPLE_ERRORS_ARG)
+                .map_or(0, |num| num.parse::<usize>().unwrap()),
+            seed: self.arg_matches.value_of(SEED_ARG).unwrap().parse().unwrap(),
+            file_subtotals: self.arg_matches.is_present(WITH_FILE_SUBTOTALS_ARG),
+            file_days: self.arg_matches.is_present(WARN_OVERLAPPING_DAYS_ARG),
+            timespans: self.arg_matches.is_present(WITH_TIMESPAN_ARG),
+            aggregate_metrics: self.arg_matches.is_present(METRICS_ARG),
+            spill_after: self.arg_matches.value_of(MAX_MEMORY_ARG).map(|size| {
+                let max_bytes = parse_memory_size(size).unwrap();
+                spill::max_aggregates_within(max_bytes / self.num_threads() as u64)
+            }),
+            verify_sorted: self.verify_sorted(),
+        }
+    }
+
+    // The options below are also in the config, which is too costly to build for one of them.
+
+    fn dedupe_enabled(&self) -> bool {
+        self.arg_matches.is_present(DEDUPE_RECORDS_ARG)
+    }
+
+    fn squash_window(&self) -> Option<Duration> {
+        self.arg_matches
+            .value_of(SQUASH_WINDOW_ARG)
+            .map(|millis| Duration::from_millis(millis.parse::<u64>().unwrap()))
+    }
+
+    fn parse_only(&self) -> bool {
+        self.arg_matches.is_present(PARSE_ONLY_ARG)
+    }
+
+    fn verify_sorted(&self) -> bool {
+        self.arg_matches.is_present(VERIFY_SORTED_INPUT_ARG)
+    }
+
+    /// The name of the --path-dimension, which the output names its column or field by.
+    fn path_dimension_name(&self) -> Option<String> {
+        self.arg_matches
+            .value_of(PATH_DIMENSION_ARG)
+            .map(|value| parse_path_dimension(value).unwrap().0)
+    }
+
+    fn drop_no_system(&self) -> bool {
+        self.arg_matches.is_present(DROP_NO_SYSTEM_ARG)
+    }
+
+    fn count_overflow_policy(&self) -> record_handling::CountOverflowPolicy {
+        match self.arg_matches.value_of(COUNT_OVERFLOW_POLICY_ARG) {
+            Some("wrap") => record_handling::CountOverflowPolicy::Wrap,
+            Some("error") => record_handling::CountOverflowPolicy::Error,
+            _ => record_handling::CountOverflowPolicy::Saturate,
+        }
+    }
+
+    fn num_threads(&self) -> usize {
+        let num_cores = num_cpus::get();
+        if let Some(count) = self.arg_matches.value_of(THREADS_ARG) {
+            return parse_num_threads(count).unwrap();
+        }
+        self.arg_matches
+            .value_of(THREADS_PER_CORE_ARG)
+            .map(|factor| parse_threads_per_core(factor, num_cores).unwrap())
+            .unwrap_or(num_cores)
+    }
+
+    fn record_config(&self) -> record_handling::RecordConfig {
+        record_handling::RecordConfig {
+            drop_no_system: self.drop_no_system(),
+            null_system_label: self.arg_matches
+                .value_of(NULL_SYSTEM_LABEL_ARG)
+                .unwrap()
+                .to_owned(),
+            weight_field: match self.arg_matches.value_of(WEIGHT_FIELD_ARG) {
+                Some("received_bytes") => Some(record_handling::WeightField::ReceivedBytes),
+                Some("sent_bytes") => Some(record_handling::WeightField::SentBytes),
+                _ => None,
+            },
+            timestamp_resolution: self.arg_matches
+                .value_of(ROUND_TIMESTAMPS_TO_ARG)
+                .map(|resolution| parse_timestamp_resolution(resolution).unwrap()),
+            count_status: self.arg_matches
+                .value_of(COUNT_STATUS_ARG)
+                .map(|codes| parse_status_codes(codes).unwrap()),
+            latency_buckets: match self.arg_matches.value_of(LATENCY_BUCKETS_ARG) {
+                Some(bounds) => {
+                    Some(record_handling::LatencyBuckets::new(parse_latency_bounds(bounds)
+                        .unwrap()))
+                }
+                None if self.arg_matches.is_present(BY_LATENCY_ARG) => Some(Default::default()),
+                None => None,
+            },
+            date_range: self.date_range().unwrap(),
+            time_window: self.time_window().unwrap(),
+            filters: self.filters(),
+            group_by: self.group_by(),
+            day_of_month: self.arg_matches.is_present(DAY_OF_MONTH_ARG),
+            business_hours: self.arg_matches
+                .value_of(BUSINESS_HOURS_ARG)
+                .map(|hours| parse_business_hours(hours).unwrap()),
+            hash_keys: self.arg_matches.is_present(HASH_KEYS_ARG),
+            count_overflow_policy: self.count_overflow_policy(),
+            log_format: match self.arg_matches.value_of(LOG_FORMAT_ARG) {
+                Some("elb") => record_handling::LogFormat::Elb,
+                Some("alb") => record_handling::LogFormat::Alb,
+                _ => record_handling::LogFormat::Auto,
+            },
+        }
+    }
+
+    fn sort_order(&self) -> record_handling::SortOrder {
+        match self.arg_matches.value_of(SORT_BY_ARG) {
+            Some("count") => record_handling::SortOrder::Count,
+            Some("system") => record_handling::SortOrder::System,
+            Some("day") => record_handling::SortOrder::Day,
+            Some("client") => record_handling::SortOrder::Client,
+            _ => record_handling::SortOrder::Key,
+        }
+    }
+
+    fn reverse_sort(&self) -> bool {
+        self.arg_matches.is_present(REVERSE_SORT_ARG)
+    }
+
+    /// Listing systems only needs the total of each system, so the records are aggregated by
+    /// system and day alone, whatever --group-by is.
+    fn group_by(&self) -> Vec<record_handling::Dimension> {
+        if self.list_systems() {
+            return vec![record_handling::Dimension::System, record_handling::Dimension::Day];
+        }
+        parse_group_by(self.arg_matches.value_of(GROUP_BY_ARG).unwrap()).unwrap()
+    }
+
+    /// Returns an error when --from is after --to, since no record could be counted.
+    fn date_range(&self) -> Result<Option<record_handling::DateRange>, String> {
+        let from = self.arg_matches.value_of(FROM_ARG).map(|day| parse_day(day).unwrap());
+        let to = self.arg_matches.value_of(TO_ARG).map(|day| parse_day(day).unwrap());
+        match (from, to) {
+            (None, None) => Ok(None),
+            (Some(from), Some(to)) if from > to => {
+                Err(format!("--from {} is after --to {}, so no record would be counted.",
+                            from.format("%Y-%m-%d"),
+                            to.format("%Y-%m-%d")))
+            }
+            (from, to) => Ok(Some(record_handling::DateRange { from: from, to: to })),
+        }
+    }
+
+    fn time_window(&self) -> Result<Option<record_handling::TimeWindow>, String> {
+        let since = self.arg_matches.value_of(SINCE_ARG).map(|time| parse_timestamp(time).unwrap());
+        let until = self.arg_matches.value_of(UNTIL_ARG).map(|time| parse_timestamp(time).unwrap());
+        match (since, until) {
+            (None, None) => Ok(None),
+            (Some(since), Some(until)) if since >= until => {
+                Err(format!("--since {} isn't before --until {}, so no record would be counted.",
+                            since.to_rfc3339(),
+                            until.to_rfc3339()))
+            }
+            (since, until) => {
+                Ok(Some(record_handling::TimeWindow {
+                    since: since,
+                    until: until,
+                }))
+            }
+        }
+    }
+
+    fn filters(&self) -> Vec<record_handling::RecordFilter> {
+        self.arg_matches
+            .values_of(FILTER_ARG)
+            .map(|filters| filters.map(|filter| parse_filter(filter).unwrap()).collect())
+            .unwrap_or_default()
+    }
+
+    /// The parser command, or an error when the aggregates are grouped by a dimension its
+    /// normalized records don't have, or filtered by a field they don't have.
+    fn parser_command(&self) -> Result<Option<String>, String> {
+        let command = match self.arg_matches.value_of(PARSER_COMMAND_ARG) {
+            Some(command) => command,
+            None => return Ok(None),
+        };
+        let normalized = [record_handling::Dimension::System,
+                          record_handling::Dimension::Day,
+                          record_handling::Dimension::Client,
+                          record_handling::Dimension::Hour,
+                          record_handling::Dimension::Status];
+        if let Some(dimension) = self.group_by()
+            .into_iter()
+            .find(|dimension| !normalized.contains(dimension)) {
+            return Err(format!("The records written by --parser-command have no {} to group by.",
+                               dimension.name()));
+        }
+        let has_missing_field = |filter: &record_handling::RecordFilter| match *filter {
+            record_handling::RecordFilter::Backend(_) |
+            record_handling::RecordFilter::Port(_) => true,
+            _ => false,
+        };
+        if self.filters().iter().any(has_missing_field) {
+            return Err("The records written by --parser-command have no backend or port to \
+                        filter by."
+                .to_owned());
+        }
+        Ok(Some(command.to_owned()))
+    }
+
+    /// The most days the counted records may span, unless a large range is allowed.
+    fn max_day_range(&self) -> Option<i64> {
+        if self.arg_matches.is_present(ALLOW_LARGE_RANGE_ARG) {
+            return None;
+        }
+        self.arg_matches
+            .value_of(MAX_DAY_RANGE_ARG)
+            .map(|days| parse_max_day_range(days).unwrap())
+    }
+
+    /// An error when --from and --to span more days than the maximum day range, so the run is
+    /// refused before any file is read.
+    fn check_day_range(&self) -> Result<(), String> {
+        let max_days = match self.max_day_range() {
+            Some(max_days) => max_days,
+            None => return Ok(()),
+        };
+        let (from, to) = match self.date_range() {
+            Ok(Some(record_handling::DateRange { from: Some(from), to: Some(to) })) => (from, to),
+            _ => return Ok(()),
+        };
+        let num_days = (to - from).num_days() + 1;
+        if num_days > max_days {
+            Err(format!("--from {} and --to {} span {} days, more than --max-day-range of {}. \
+                         Pass --allow-large-range to count them anyway.",
+                        from.format("%Y-%m-%d"),
+                        to.format("%Y-%m-%d"),
+                        num_days,
+                        max_days))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// An error when --max-memory is set along with an order or format that needs every
+    /// aggregate at once. The aggregates spilled to disk are written as they're merged, in key
+    /// order, so the run is refused before any file is read. The options that read the sorted
+    /// aggregates, see `OutputAggregates::sorted`, conflict with --max-memory instead, so that
+    /// clap refuses them.
+    fn check_max_memory(&self) -> Result<(), String> {
+        if !self.arg_matches.is_present(MAX_MEMORY_ARG) {
+            return Ok(());
+        }
+        if self.sort_order() != record_handling::SortOrder::Key {
+            return Err("--max-memory writes the aggregates in key order as they're merged, so it \
+                        can't be combined with --sort-by other than key."
+                .to_owned());
+        }
+        // The yaml sink holds every aggregate until the sequence is written.
+        if self.arg_matches.value_of(FORMAT_ARG) == Some("yaml") {
+            return Err("--max-memory can't write the yaml format, which holds every aggregate \
+                        until the end of the run."
+                .to_owned());
+        }
+        Ok(())
+    }
+
+    fn explain_record(&self) -> Option<&str> {
+        self.arg_matches.value_of(EXPLAIN_RECORD_ARG)
+    }
+
+    fn print_schema(&self) -> bool {
+        self.arg_matches.is_present(PRINT_SCHEMA_ARG)
+    }
+
+    /// The schema of the output given the other options, see `output::schema_json`.
+    fn output_schema(&self) -> Result<rustc_serialize::json::Json, String> {
+        // Read from the option itself, as the log location the path is relative to isn't needed.
+        let path_dimension_name = self.arg_matches
+            .value_of(PATH_DIMENSION_ARG)
+            .map(|value| parse_path_dimension(value).unwrap().0);
+        output::schema_json(self.output_format(),
+                            &self.group_by(),
+                            path_dimension_name.as_ref().map(|name| name.as_str()),
+                            &self.record_config(),
+                            self.list_systems(),
+                            self.arg_matches.is_present(WITH_TIMESPAN_ARG),
+                            self.arg_matches.is_present(METRICS_ARG))
+    }
+
+    fn max_aggregates(&self) -> Option<usize> {
+        self.arg_matches
+            .value_of(MAX_AGGREGATES_ARG)
+            .map(|max| max.parse::<usize>().unwrap())
+    }
+
+    fn merge_threads(&self) -> Option<usize> {
+        self.arg_matches
+            .value_of(MERGE_THREADS_ARG)
+            .map(|count| parse_num_threads(count).unwrap())
+    }
+
+    fn truncate_field(&self) -> Option<usize> {
+        self.arg_matches
+            .value_of(TRUNCATE_FIELD_ARG)
+            .map(|max_chars| max_chars.parse::<usize>().unwrap())
+    }
+
+    fn count_floor(&self) -> Option<i64> {
+        self.arg_matches
+            .value_of(COUNT_FLOOR_ARG)
+            .map(|floor| floor.parse::<i64>().unwrap())
+    }
+
+    fn floor_mode(&self) -> record_handling::FloorMode {
+        match self.arg_matches.value_of(FLOOR_MODE_ARG) {
+            Some("round") => record_handling::FloorMode::Round,
+            _ => record_handling::FloorMode::Suppress,
+        }
+    }
+
+    /// Creates the trace output when a trace key was given.
+    fn record_trace(&self) -> Result<Option<file_handling::RecordTrace>, String> {
+        let (system_name, day, client_address) = match self.arg_matches.value_of(TRACE_KEY_ARG) {
+            Some(key) => parse_trace_key(key).unwrap(),
+            None => return Ok(None),
+        };
+        let output_path = self.trace_output().unwrap();
+        let limit = self.arg_matches.value_of(TRACE_LIMIT_ARG).unwrap().parse::<usize>().unwrap();
+        File::create(output_path)
+            .map(|output| {
+                Some(file_handling::RecordTrace::new(system_name,
+                                                     day,
+                                                     client_address,
+                                                     limit,
+                                                     output))
+            })
+            .map_err(|e| format!("Unable to create the trace output {}. {}", output_path, e))
+    }
+
+    fn trace_output(&self) -> Option<&str> {
+        self.arg_matches.value_of(TRACE_OUTPUT_ARG)
+    }
+
+    /// Creates the key dictionary when one was asked for.
+    fn key_dictionary(&self) -> Result<Option<file_handling::KeyDictionary>, String> {
+        let output_path = match self.key_dictionary_output() {
+            Some(output_path) => output_path,
+            None => return Ok(None),
+        };
+        File::create(output_path)
+            .map(|output| Some(file_handling::KeyDictionary::new(&self.group_by(), output)))
+            .map_err(|e| format!("Unable to create the key dictionary {}. {}", output_path, e))
+    }
+
+    fn key_dictionary_output(&self) -> Option<&str> {
+        self.arg_matches.value_of(KEY_DICTIONARY_ARG)
+    }
+
+    /// Creates the rejects file when one was asked for.
+    fn rejects(&self) -> Result<Option<file_handling::RejectsFile>, String> {
+        let output_path = match self.rejects_output() {
+            Some(output_path) => output_path,
+            None => return Ok(None),
+        };
+        File::create(output_path)
+            .map(|output| Some(file_handling::RejectsFile::new(output)))
+            .map_err(|e| format!("Unable to create the rejects file {}. {}", output_path, e))
+    }
+
+    fn rejects_output(&self) -> Option<&str> {
+        self.arg_matches.value_of(REJECTS_ARG)
+    }
+
+    /// The checkpoint to continue from when checkpointing, read from the checkpoint file when
+    /// resuming and empty otherwise.
+    fn checkpoint(&self) -> Result<Option<Checkpoint>, String> {
+        let path = match self.checkpoint_path() {
+            Some(path) => path,
+            None => return Ok(None),
+        };
+        let options = self.checkpoint_options();
+        if !self.arg_matches.is_present(RESUME_ARG) || !path.exists() {
+            return Ok(Some(Checkpoint::with_options(options)));
+        }
+        let checkpoint = Checkpoint::read(path).map_err(|e| {
+                format!("Unable to resume from the checkpoint {}. {}", path.display(), e)
+            })?;
+        if checkpoint.options != options {
+            return Err(format!("Unable to resume from the checkpoint {}, as it was written by a \
+                                run with {} rather than {}. Run with the same options or without \
+                                --resume.",
+                               path.display(),
+                               checkpoint.options,
+                               options));
+        }
+        Ok(Some(checkpoint))
+    }
+
+    /// The options a run resuming from a checkpoint has to share with the run that wrote it,
+    /// see `Checkpoint::options` and `CHECKPOINTED_ARGS`, defaults included. The values of an
+    /// option given several times, such as the filters, are sorted, as their order doesn't
+    /// matter.
+    fn checkpoint_options(&self) -> String {
+        let mut options = Vec::new();
+        for &name in CHECKPOINTED_ARGS {
+            match self.arg_matches.values_of(name) {
+                Some(values) => {
+                    let mut values = values.collect::<Vec<_>>();
+                    values.sort();
+                    for value in values {
+                        options.push(format!("--{} {}", name, value));
+                    }
+                }
+                None if self.arg_matches.is_present(name) => options.push(format!("--{}", name)),
+                None => {}
+            }
+        }
+        options.join(" ")
+    }
+
+    fn checkpoint_path(&self) -> Option<&Path> {
+        self.arg_matches.value_of(CHECKPOINT_ARG).map(Path::new)
+    }
+
+    fn output_format(&self) -> record_handling::OutputFormat {
+        match self.arg_matches.value_of(FORMAT_ARG) {
+            Some("tsv") => record_handling::OutputFormat::Tsv,
+            Some("json") => record_handling::OutputFormat::Json,
+            Some("jsonl") => record_handling::OutputFormat::JsonLines,
+            Some("influx") => record_handling::OutputFormat::Influx,
+            Some("matrix") => record_handling::OutputFormat::Matrix,
+            #[cfg(feature = "yaml")]
+            Some("yaml") => record_handling::OutputFormat::Yaml,
+            Some("none") => record_handling::OutputFormat::Discard,
+            _ => record_handling::OutputFormat::Csv,
+        }
+    }
+
+    fn progress(&self) -> bool {
+        self.arg_matches.is_present(PROGRESS_ARG)
+    }
+
+    fn result_hash(&self) -> bool {
+        self.arg_matches.is_present(RESULT_HASH_ARG)
+    }
+
+    fn list_systems(&self) -> bool {
+        self.arg_matches.is_present(LIST_SYSTEMS_ARG)
+    }
+
+    /// Reads the system totals of --baseline, when given.
+    fn baseline(&self) -> Result<Option<BTreeMap<String, i64>>, String> {
+        let path = match self.arg_matches.value_of(BASELINE_ARG) {
+            Some(path) => path,
+            None => return Ok(None),
+        };
+        File::open(path)
+            .map_err(|e| e.to_string())
+            .and_then(|file| output::read_system_totals(io::BufReader::new(file)))
+            .map(Some)
+            .map_err(|e| format!("Unable to read the baseline {}. {}", path, e))
+    }
+
+    fn max_drop(&self) -> Option<f64> {
+        self.arg_matches.value_of(MAX_DROP_ARG).map(|pct| parse_percentage(pct).unwrap())
+    }
+
+    fn strict_csv(&self) -> bool {
+        self.arg_matches.is_present(STRICT_CSV_ARG)
+    }
+
+    #[cfg(feature = "kafka")]
+    fn kafka_brokers(&self) -> Option<Vec<String>> {
+        self.arg_matches
+            .value_of(KAFKA_BROKERS_ARG)
+            .map(|brokers| brokers.split(',').map(|broker| broker.trim().to_owned()).collect())
+    }
+
+    #[cfg(feature = "kafka")]
+    fn kafka_topic(&self) -> Option<&str> {
+        self.arg_matches.value_of(KAFKA_TOPIC_ARG)
+    }
+
+    #[cfg(feature = "kafka")]
+    fn kafka_require_acks(&self) -> bool {
+        self.arg_matches.is_present(KAFKA_REQUIRE_ACKS_ARG)
+    }
+
+    #[cfg(feature = "kafka")]
+    fn suppress_output(&self) -> bool {
+        self.arg_matches.is_present(KAFKA_SUPPRESS_OUTPUT_ARG)
+    }
+
+    #[cfg(not(feature = "kafka"))]
+    fn suppress_output(&self) -> bool {
+        false
+    }
+
+    fn sink_address(&self) -> Option<statsd_output::SinkAddress> {
+        self.arg_matches
+            .value_of(SINK_ARG)
+            .map(|uri| statsd_output::parse_sink_address(uri).unwrap())
+    }
+
+    fn metric_prefix(&self) -> Option<String> {
+        self.arg_matches.value_of(METRIC_PREFIX_ARG).map(|prefix| prefix.to_owned())
+    }
+
+    fn sink_dry_run(&self) -> bool {
+        self.arg_matches.is_present(SINK_DRY_RUN_ARG)
+    }
+
+    fn compress_gzip(&self) -> bool {
+        self.arg_matches.value_of(COMPRESS_ARG) == Some("gzip")
+    }
+
+    fn output_path(&self) -> Option<&Path> {
+        self.arg_matches.value_of(OUTPUT_ARG).map(Path::new)
+    }
+
+    fn limit_per_system(&self) -> Option<usize> {
+        self.arg_matches
+            .value_of(LIMIT_PER_SYSTEM_ARG)
+            .map(|limit| limit.parse::<usize>().unwrap())
+    }
+
+    #[cfg(feature = "status-socket")]
+    fn status_addr(&self) -> Option<SocketAddr> {
+        self.arg_matches
+            .value_of(STATUS_ADDR_ARG)
+            .map(|addr| addr.parse::<SocketAddr>().unwrap())
+    }
+
+    /// How often to look for new files when watching the log location, None when not watching.
+    fn flush_interval(&self) -> Option<Duration> {
+        if !self.arg_matches.is_present(WATCH_ARG) {
+            return None;
+        }
+        self.arg_matches
+            .value_of(FLUSH_INTERVAL_ARG)
+            .map(|interval| parse_timestamp_resolution(interval).unwrap())
+    }
+
+    fn log_location(&self) -> &Path {
+        Path::new(self.arg_matches.value_of(LOG_LOCATION_ARG).unwrap())
+    }
+}
+
+fn parse_path_dimension(value: &str) -> Result<(String, usize), String> {
+    let mut parts = value.rsplitn(2, ':');
+    let depth = parts.next().and_then(|depth| depth.parse::<usize>().ok());
+    let name = parts.next().filter(|name| !name.is_empty());
+    match (name, depth) {
+        (Some(name), Some(depth)) if depth > 0 => Ok((name.to_owned(), depth)),
+        _ => Err(format!("{} is not a valid NAME:DEPTH path dimension.", value)),
+    }
+}
+
+fn parse_status_codes(value: &str) -> Result<record_handling::StatusCodes, String> {
+    value.split(',')
+        .map(|codes| {
+            let mut bounds = codes.splitn(2, '-').map(|code| code.trim().parse::<u16>());
+            match (bounds.next(), bounds.next()) {
+                (Some(Ok(low)), Some(Ok(high))) if low <= high => Ok((low, high)),
+                (Some(Ok(code)), None) => Ok((code, code)),
+                _ => Err(format!("{} is not a valid status code or range of status codes.", codes)),
+            }
+        })
+        .collect::<Result<Vec<_>, _>>()
+        .map(record_handling::StatusCodes)
+}
+
+fn parse_latency_bounds(value: &str) -> Result<Vec<u64>, String> {
+    let bounds = value.split(',')
+        .map(|bound| {
+            bound.trim()
+                .parse::<u64>()
+                .map_err(|_| format!("{} is not a valid number of milliseconds.", bound))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    if bounds.windows(2).any(|pair| pair[0] >= pair[1]) {
+        return Err(format!("The latency bucket bounds {} are not ascending.", value));
+    }
+    Ok(bounds)
+}
+
+fn parse_group_by(value: &str) -> Result<Vec<record_handling::Dimension>, String> {
+    let mut dimensions = value.split(',')
+        .map(|name| match name.trim() {
+            "system" | "system_name" => Ok(record_handling::Dimension::System),
+            "day" => Ok(record_handling::Dimension::Day),
+            "client" | "client_address" => Ok(record_handling::Dimension::Client),
+            "backend" | "backend_address" => Ok(record_handling::Dimension::Backend),
+            "url" => Ok(record_handling::Dimension::Url),
+            "port" => Ok(record_handling::Dimension::ListenerPort),
+            "hour" => Ok(record_handling::Dimension::Hour),
+            "status" | "elb_status_code" => Ok(record_handling::Dimension::Status),
+            name => {
+                Err(format!("{} is not a dimension. The dimensions are system, day, client, \
+                             backend, url, port, hour and status.",
+                            name))
+            }
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    for (i, dimension) in dimensions.iter().enumerate() {
+        if dimensions[..i].contains(dimension) {
+            return Err(format!("{} is given more than once.", dimension.name()));
+        }
+    }
+    // The hour buckets the records of each day, so grouping by it groups by the day too.
+    if !dimensions.contains(&record_handling::Dimension::Day) {
+        if let Some(hour) = dimensions.iter()
+            .position(|&dimension| dimension == record_handling::Dimension::Hour) {
+            dimensions.insert(hour, record_handling::Dimension::Day);
+        }
+    }
+    let required = [record_handling::Dimension::System, record_handling::Dimension::Day];
+    if !required.iter().all(|dimension| dimensions.contains(dimension)) {
+        return Err(format!("{} doesn't include system and either day or hour, which every \
+                            aggregate is keyed by.",
+                           value));
+    }
+    Ok(dimensions)
+}
+
+fn parse_timestamp_resolution(value: &str) -> Result<Duration, String> {
+    let unit_start = value.find(|c: char| !c.is_digit(10)).unwrap_or(value.len());
+    let amount = value[..unit_start].parse::<u64>().ok().filter(|&amount| amount > 0);
+    let resolution = match (amount, &value[unit_start..]) {
+        (Some(amount), "ms") => Some(Duration::from_millis(amount)),
+        (Some(amount), "s") => Some(Duration::from_secs(amount)),
+        (Some(amount), "m") => Some(Duration::from_secs(amount * 60)),
+        (Some(amount), "h") => Some(Duration::from_secs(amount * 60 * 60)),
+        _ => None,
+    };
+
+    resolution.ok_or_else(|| format!("{} is not a valid duration such as 500ms or 10s.", value))
+}
+
+fn parse_memory_size(value: &str) -> Result<u64, String> {
+    let unit_start = value.find(|c: char| !c.is_digit(10)).unwrap_or(value.len());
+    let amount = value[..unit_start].parse::<u64>().ok().filter(|&amount| amount > 0);
+    let multiplier = match &value[unit_start..] {
+        "" => Some(1),
+        "K" => Some(1 << 10),
+        "M" => Some(1 << 20),
+        "G" => Some(1 << 30),
+        "T" => Some(1 << 40),
+        _ => None,
+    };
+    amount.and_then(|amount| multiplier.and_then(|multiplier| amount.checked_mul(multiplier)))
+        .ok_or_else(|| format!("{} is not a valid size such as 512M or 8G.", value))
+}
+
+fn parse_percentage(value: &str) -> Result<f64, String> {
+    value.parse::<f64>()
+        .ok()
+        .filter(|pct| *pct >= 0.0 && *pct <= 100.0)
+        .ok_or_else(|| format!("{} is not a valid percentage from 0 to 100.", value))
+}
+
+fn parse_business_hours(value: &str) -> Result<record_handling::BusinessHours, String> {
+    let parse_minute = |time: &str| {
+        let mut parts = time.trim().splitn(2, ':').map(|part| part.parse::<u32>().ok());
+        match (parts.next(), parts.next()) {
+            (Some(Some(hour)), Some(Some(minute))) if hour < 24 && minute < 60 => {
+                Some(hour * 60 + minute)
+            }
+            (Some(Some(24)), Some(Some(0))) => Some(24 * 60),
+            _ => None,
+        }
+    };
+    let mut times = value.splitn(2, '-').map(parse_minute);
+    match (times.next(), times.next()) {
+        (Some(Some(start_minute)), Some(Some(end_minute))) if start_minute != end_minute => {
+            Ok(record_handling::BusinessHours {
+                start_minute: start_minute,
+                end_minute: end_minute,
+            })
+        }
+        _ => Err(format!("{} is not a valid START-END range of HH:MM times.", value)),
+    }
+}
+
+fn parse_day(value: &str) -> Result<Date<UTC>, String> {
+    format!("{}T00:00:00Z", value)
+        .parse::<DateTime<UTC>>()
+        .map(|day| day.date())
+        .map_err(|_| format!("{} is not a valid YYYY-MM-DD date.", value))
+}
+
+fn parse_timestamp(value: &str) -> Result<DateTime<UTC>, String> {
+    value.parse::<DateTime<UTC>>()
+        .map_err(|_| format!("{} is not a valid RFC 3339 timestamp.", value))
+}
+
+fn parse_filter(value: &str) -> Result<record_handling::RecordFilter, String> {
+    let mut parts = value.splitn(2, '=');
+    let (field, filter_value) = match (parts.next(), parts.next()) {
+        (Some(field), Some(filter_value)) if !filter_value.is_empty() => {
+            (field.trim(), filter_value.trim())
+        }
+        _ => return Err(format!("{} is not a FIELD=VALUE filter.", value)),
+    };
+    // A class such as 5xx stands for the range 500-599.
+    let status_codes = |codes: &str| {
+        match codes.chars().next().and_then(|class| class.to_digit(10)) {
+            Some(class) if 1 <= class && class <= 5 && codes.len() == 3 &&
+                           codes.ends_with("xx") => {
+                let low = class as u16 * 100;
+                Ok(record_handling::StatusCodes(vec![(low, low + 99)]))
+            }
+            _ => parse_status_codes(codes),
+        }
+    };
+    let address = |address: &str| {
+        address.parse::<Ipv4Addr>()
+            .map_err(|_| format!("{} is not a valid IPv4 address.", address))
+    };
+    match field {
+        "system" | "system_name" => {
+            Ok(record_handling::RecordFilter::System(filter_value.to_owned()))
+        }
+        "client" | "client_address" => {
+            address(filter_value).map(record_handling::RecordFilter::Client)
+        }
+        "backend" | "backend_address" => {
+            address(filter_value).map(record_handling::RecordFilter::Backend)
+        }
+        "port" => Ok(record_handling::RecordFilter::Port(filter_value.to_owned())),
+        "status" | "elb_status_code" => {
+            status_codes(filter_value).map(record_handling::RecordFilter::Status)
+        }
+        "backend_status" | "backend_status_code" => {
+            status_codes(filter_value).map(record_handling::RecordFilter::BackendStatus)
+        }
+        field => {
+            Err(format!("{} is not a field to filter by. The fields are system, client, backend, \
+                         port, status and backend_status.",
+                        field))
+        }
+    }
+}
+
+fn parse_trace_key(value: &str) -> Result<(String, Date<UTC>, Ipv4Addr), String> {
+    let invalid_key = || format!("{} is not a valid SYSTEM,DAY,CLIENT key.", value);
+    // System names may contain commas, so the key is split from the right.
+    let mut fields = value.rsplitn(3, ',');
+    let client_address = fields.next()
+        .and_then(|client| client.parse::<Ipv4Addr>().ok())
+        .ok_or_else(&invalid_key)?;
+    let day = fields.next()
+        .and_then(|day| parse_day(day).ok())
+        .ok_or_else(&invalid_key)?;
+    let system_name = fields.next().ok_or_else(&invalid_key)?;
+
+    Ok((system_name.to_owned(), day, client_address))
+}
+
+fn parse_threads_per_core(value: &str, num_cores: usize) -> Result<usize, String> {
+    let factor = value.parse::<f64>()
+        .map_err(|_| format!("{} is not a valid number of threads per core.", value))?;
+    let num_threads = (num_cores as f64 * factor).floor();
+    if num_threads.is_finite() && num_threads >= 1.0 {
+        Ok(num_threads as usize)
+    } else {
+        Err(format!("{} threads per core on {} cores is not at least one thread.",
+                    value,
+                    num_cores))
+    }
+}
+
+fn parse_max_day_range(value: &str) -> Result<i64, String> {
+    match value.parse::<i64>() {
+        Ok(num_days) if num_days > 0 => Ok(num_days),
+        _ => Err(format!("{} is not a positive number of days.", value)),
+    }
+}
+
+fn parse_num_threads(value: &str) -> Result<usize, String> {
+    match value.parse::<usize>() {
+        Ok(num_threads) if num_threads > 0 => Ok(num_threads),
+        _ => Err(format!("{} is not a positive number of threads.", value)),
+    }
+}
+
+fn parse_record_separator(value: &str) -> Result<u8, String> {
+    let separator = match value {
+        "\\0" => Some(b'\0'),
+        "\\t" => Some(b'\t'),
+        "\\n" => Some(b'\n'),
+        "\\r" => Some(b'\r'),
+        "\\\\" => Some(b'\\'),
+        _ if value.starts_with("\\x") && value.len() == 4 => {
+            u8::from_str_radix(&value[2..], 16).ok()
+        }
+        _ if value.len() == 1 => Some(value.as_bytes()[0]),
+        _ => None,
+    };
+
+    separator.ok_or_else(|| format!("{} is not a valid record separator.", value))
+}
+
+#[cfg(test)]
+mod runtime_context_tests {
+    use super::*;
+    use std::panic;
+
+    #[test]
+    fn log_location_should_return_the_specified_value() {
+        let arg_vec = vec!["counter", "~/logs"];
+
+        let runtime_context = RuntimeContext::new_test_runtime_context(arg_vec);
+
+        assert_eq!(runtime_context.log_location().to_str().unwrap(), "~/logs")
+    }
+
+    #[test]
+    fn constructing_a_runtime_context_should_not_panic_if_the_log_location_is_specified() {
+        let arg_vec = vec!["counter", "~/logs"];
+
+        let result = panic::catch_unwind(|| { RuntimeContext::new_test_runtime_context(arg_vec); });
+
+        assert!(result.is_ok())
+    }
+
+    #[test]
+    fn constructing_a_runtime_context_should_panic_if_the_log_location_is_not_specified() {
+        let arg_vec = vec!["counter"];
+
+        let result = panic::catch_unwind(|| { RuntimeContext::new_test_runtime_context(arg_vec); });
+
+        assert!(result.is_err())
+    }
+
+    #[test]
+    fn run_benchmark_should_return_false_when_benchmark_arg_is_not_set() {
+        let arg_vec = vec!["counter", "~/logs"];
+
+        let runtime_context = RuntimeContext::new_test_runtime_context(arg_vec);
+
+        assert_eq!(runtime_context.run_benchmark(), false)
+    }
+
+    #[test]
+    fn run_benchmark_should_return_true_when_long_benchmark_is_set() {
+        let arg_vec = vec!["counter", "--benchmark", "~/logs"];
+
+        let runtime_context = RuntimeContext::new_test_runtime_context(arg_vec);
+
+        assert!(runtime_context.run_benchmark())
+    }
+
+    #[test]
+    fn run_benchmark_should_return_true_when_short_benchmark_is_set() {
+        let arg_vec = vec!["counter", "-b", "~/logs"];
+
+        let runtime_context = RuntimeContext::new_test_runtime_context(arg_vec);
+
+        assert!(runtime_context.run_benchmark())
+    }
+
+    #[test]
+    fn limit_per_system_should_return_none_when_the_arg_is_not_set() {
+        let arg_vec = vec!["counter", "~/logs"];
+
+        let runtime_context = RuntimeContext::new_test_runtime_context(arg_vec);
+
+        assert_eq!(runtime_context.limit_per_system(), None)
+    }
+
+    #[test]
+    fn limit_per_system_should_return_the_specified_value() {
+        let arg_vec = vec!["counter", "--limit-per-system", "5", "~/logs"];
+
+        let runtime_context = RuntimeContext::new_test_runtime_context(arg_vec);
+
+        assert_eq!(runtime_context.limit_per_system(), Some(5))
+    }
+
+    #[test]
+    fn constructing_a_runtime_context_should_panic_if_the_limit_per_system_is_not_a_number() {
+        let arg_vec = vec!["counter", "--limit-per-system", "five", "~/logs"];
+
+        let result = panic::catch_unwind(|| { RuntimeContext::new_test_runtime_context(arg_vec); });
+
+        assert!(result.is_err())
+    }
+
+    #[test]
+    fn result_hash_should_return_false_when_the_arg_is_not_set() {
+        let arg_vec = vec!["counter", "~/logs"];
+
+        let runtime_context = RuntimeContext::new_test_runtime_context(arg_vec);
+
+        assert_eq!(runtime_context.result_hash(), false)
+    }
+
+    #[test]
+    fn result_hash_should_return_true_when_the_arg_is_set() {
+        let arg_vec = vec!["counter", "--result-hash", "~/logs"];
+
+        let runtime_context = RuntimeContext::new_test_runtime_context(arg_vec);
+
+        assert!(runtime_context.result_hash())
+    }
+
+    #[cfg(feature = "kafka")]
+    #[test]
+    fn kafka_brokers_should_split_the_broker_list() {
+        let arg_vec = vec!["counter",
+                           "--kafka-brokers",
+                           "kafka0:9092, kafka1:9092",
+                           "--kafka-topic",
+                           "aggregates",
+                           "~/logs"];
+
+        let runtime_context = RuntimeContext::new_test_runtime_context(arg_vec);
+
+        assert_eq!(runtime_context.kafka_brokers(),
+                   Some(vec!["kafka0:9092".to_owned(), "kafka1:9092".to_owned()]));
+        assert_eq!(runtime_context.kafka_topic(), Some("aggregates"))
+    }
+
+    #[cfg(feature = "kafka")]
+    #[test]
+    fn constructing_a_runtime_context_should_panic_if_kafka_brokers_are_given_without_a_topic() {
+        let arg_vec = vec!["counter", "--kafka-brokers", "kafka0:9092", "~/logs"];
+
+        let result = panic::catch_unwind(|| { RuntimeContext::new_test_runtime_context(arg_vec); });
+
+        assert!(result.is_err())
+    }
+
+    #[test]
+    fn file_aggregator_config_should_include_the_squash_window() {
+        let arg_vec = vec!["counter", "--squash-window", "250", "~/logs"];
+
+        let runtime_context = RuntimeContext::new_test_runtime_context(arg_vec);
+
+        assert_eq!(runtime_context.file_aggregator_config().squash_window,
+                   Some(Duration::from_millis(250)))
+    }
+
+    #[test]
+    fn file_aggregator_config_should_not_dedupe_when_the_arg_is_not_set() {
+        let arg_vec = vec!["counter", "~/logs"];
+
+        let runtime_context = RuntimeContext::new_test_runtime_context(arg_vec);
+
+        assert!(runtime_context.file_aggregator_config().dedupe.is_none())
+    }
+
+    #[test]
+    fn file_aggregator_config_should_dedupe_globally_when_global_is_specified() {
+        let arg_vec = vec!["counter", "--dedupe-records", "global", "~/logs"];
+
+        let runtime_context = RuntimeContext::new_test_runtime_context(arg_vec);
+
+        match runtime_context.file_aggregator_config().dedupe {
+            Some(file_handling::RecordDeduplication::Global(_)) => {}
+            other => panic!("Unexpected deduplication {:?}.", other),
+        }
+    }
+
+    #[test]
+    fn file_aggregator_config_should_include_the_path_dimension_relative_to_the_log_location() {
+        let arg_vec = vec!["counter", "--path-dimension", "region:1", "~/logs"];
+
+        let runtime_context = RuntimeContext::new_test_runtime_context(arg_vec);
+
+        assert_eq!(runtime_context.file_aggregator_config().path_dimension,
+                   Some(file_handling::PathDimension {
+                       name: "region".to_owned(),
+                       log_root: PathBuf::from("~/logs"),
+                       depth: 1,
+                   }))
+    }
+
+    #[test]
+    fn the_path_dimension_name_should_match_the_config() {
+        let arg_vec = vec!["counter", "--path-dimension", "region:1", "~/logs"];
+
+        let runtime_context = RuntimeContext::new_test_runtime_context(arg_vec);
+
+        assert_eq!(runtime_context.path_dimension_name(),
+                   runtime_context.file_aggregator_config().path_dimension.map(|d| d.name))
+    }
+
+    #[test]
+    fn constructing_a_runtime_context_should_panic_if_the_path_dimension_depth_is_zero() {
+        let arg_vec = vec!["counter", "--path-dimension", "region:0", "~/logs"];
+
+        let result = panic::catch_unwind(|| { RuntimeContext::new_test_runtime_context(arg_vec); });
+
+        assert!(result.is_err())
+    }
+
+    #[test]
+    fn constructing_a_runtime_context_should_panic_if_the_path_dimension_has_no_name() {
+        let arg_vec = vec!["counter", "--path-dimension", "1", "~/logs"];
+
+        let result = panic::catch_unwind(|| { RuntimeContext::new_test_runtime_context(arg_vec); });
+
+        assert!(result.is_err())
+    }
+
+    #[test]
+    fn file_aggregator_config_should_default_the_null_system_label() {
+        let arg_vec = vec!["counter", "~/logs"];
+
+        let runtime_context = RuntimeContext::new_test_runtime_context(arg_vec);
+
+        assert_eq!(runtime_context.file_aggregator_config().record_config,
+                   record_handling::RecordConfig::default())
+    }
+
+    #[test]
+    fn file_aggregator_config_should_drop_records_without_a_system_when_drop_no_system_is_set() {
+        let arg_vec = vec!["counter", "--drop-no-system", "~/logs"];
+
+        let runtime_context = RuntimeContext::new_test_runtime_context(arg_vec);
+
+        assert!(runtime_context.file_aggregator_config().record_config.drop_no_system)
+    }
+
+    #[test]
+    fn constructing_a_runtime_context_should_panic_if_parse_only_is_set_without_benchmark() {
+        let arg_vec = vec!["counter", "--parse-only", "~/logs"];
+
+        let result = panic::catch_unwind(|| { RuntimeContext::new_test_runtime_context(arg_vec); });
+
+        assert!(result.is_err())
+    }
+
+    #[test]
+    fn file_aggregator_config_should_include_the_min_records_per_file() {
+        let arg_vec = vec!["counter", "--min-records-per-file", "100", "~/logs"];
+
+        let runtime_context = RuntimeContext::new_test_runtime_context(arg_vec);
+
+        assert_eq!(runtime_context.file_aggregator_config().min_records_per_file, Some(100))
+    }
+
+    #[test]
+    fn floor_mode_should_default_to_suppress() {
+        let arg_vec = vec!["counter", "--count-floor", "5", "~/logs"];
+
+        let runtime_context = RuntimeContext::new_test_runtime_context(arg_vec);
+
+        assert_eq!(runtime_context.count_floor(), Some(5));
+        assert_eq!(runtime_context.floor_mode(), record_handling::FloorMode::Suppress)
+    }
+
+    #[test]
+    fn floor_mode_should_return_round_when_specified() {
+        let arg_vec = vec!["counter", "--count-floor", "5", "--floor-mode", "round", "~/logs"];
+
+        let runtime_context = RuntimeContext::new_test_runtime_context(arg_vec);
+
+        assert_eq!(runtime_context.floor_mode(), record_handling::FloorMode::Round)
+    }
+
+    #[test]
+    fn constructing_a_runtime_context_should_panic_if_the_count_floor_is_negative() {
+        let result = panic::catch_unwind(|| {
+            let arg_vec = vec!["counter", "--count-floor=-5", "~/logs"];
+            RuntimeContext::new_test_runtime_context(arg_vec);
+        });
+
+        assert!(result.is_err())
+    }
+
+    #[test]
+    fn count_overflow_policy_should_default_to_saturate() {
+        let arg_vec = vec!["counter", "~/logs"];
+
+        let runtime_context = RuntimeContext::new_test_runtime_context(arg_vec);
+
+        assert_eq!(runtime_context.record_config().count_overflow_policy,
+                   record_handling::CountOverflowPolicy::Saturate)
+    }
+
+    #[test]
+    fn count_overflow_policy_should_return_the_requested_policy() {
+        let wrap_context = RuntimeContext::new_test_runtime_context(vec!["counter",
+                                                                         "--count-overflow-policy",
+                                                                         "wrap",
+                                                                         "~/logs"]);
+        let error_context = RuntimeContext::new_test_runtime_context(vec!["counter",
+                                                                          "--count-overflow-policy",
+                                                                          "error",
+                                                                          "~/logs"]);
+
+        assert_eq!(wrap_context.record_config().count_overflow_policy,
+                   record_handling::CountOverflowPolicy::Wrap);
+        assert_eq!(error_context.record_config().count_overflow_policy,
+                   record_handling::CountOverflowPolicy::Error)
+    }
+
+    #[test]
+    fn log_format_should_default_to_auto() {
+        let runtime_context = RuntimeContext::new_test_runtime_context(vec!["counter", "~/logs"]);
+
+        assert_eq!(runtime_context.record_config().log_format,
+                   record_handling::LogFormat::Auto)
+    }
+
+    #[test]
+    fn log_format_should_return_the_requested_format() {
+        let arg_vec = vec!["counter", "--log-format", "alb", "~/logs"];
+
+        let runtime_context = RuntimeContext::new_test_runtime_context(arg_vec);
+
+        assert_eq!(runtime_context.record_config().log_format,
+                   record_handling::LogFormat::Alb)
+    }
+
+    #[test]
+    fn file_aggregator_config_should_parse_an_escaped_record_separator() {
+        let arg_vec = vec!["counter", "--record-separator", "\\0", "~/logs"];
+
+        let runtime_context = RuntimeContext::new_test_runtime_context(arg_vec);
+
+        assert_eq!(runtime_context.file_aggregator_config().record_separator, Some(0))
+    }
+
+    #[test]
+    fn file_aggregator_config_should_treat_a_newline_record_separator_as_the_default() {
+        let arg_vec = vec!["counter", "--record-separator", "\\n", "~/logs"];
+
+        let runtime_context = RuntimeContext::new_test_runtime_context(arg_vec);
+
+        assert_eq!(runtime_context.file_aggregator_config().record_separator, None)
+    }
+
+    #[test]
+    fn parse_record_separator_should_accept_hex_escapes_and_single_characters() {
+        assert_eq!(parse_record_separator("\\x1e"), Ok(0x1e));
+        assert_eq!(parse_record_separator("|"), Ok(b'|'));
+        assert!(parse_record_separator("||").is_err())
+    }
+
+    #[test]
+    fn file_aggregator_config_should_not_profile_by_default() {
+        let arg_vec = vec!["counter", "~/logs"];
+
+        let runtime_context = RuntimeContext::new_test_runtime_context(arg_vec);
+
+        assert!(runtime_context.file_aggregator_config().profile.is_none())
+    }
+
+    #[test]
+    fn file_aggregator_config_should_profile_when_the_arg_is_set() {
+        let arg_vec = vec!["counter", "--profile", "~/logs"];
+
+        let runtime_context = RuntimeContext::new_test_runtime_context(arg_vec);
+
+        assert!(runtime_context.file_aggregator_config().profile.is_some())
+    }
+
+    #[test]
+    fn strict_csv_should_return_false_when_the_arg_is_not_set() {
+        let arg_vec = vec!["counter", "~/logs"];
+
+        let runtime_context = RuntimeContext::new_test_runtime_context(arg_vec);
+
+        assert_eq!(runtime_context.strict_csv(), false)
+    }
+
+    #[test]
+    fn strict_csv_should_return_true_when_the_arg_is_set() {
+        let arg_vec = vec!["counter", "--strict-csv", "~/logs"];
+
+        let runtime_context = RuntimeContext::new_test_runtime_context(arg_vec);
+
+        assert_eq!(runtime_context.strict_csv(), true)
+    }
+
+    #[test]
+    fn num_threads_should_default_to_the_number_of_cores() {
+        let arg_vec = vec!["counter", "~/logs"];
+
+        let runtime_context = RuntimeContext::new_test_runtime_context(arg_vec);
+
+        assert_eq!(runtime_context.num_threads(), ::num_cpus::get())
+    }
+
+    #[test]
+    fn num_threads_should_multiply_the_number_of_cores() {
+        let arg_vec = vec!["counter", "--threads-per-core", "2.0", "~/logs"];
+
+        let runtime_context = RuntimeContext::new_test_runtime_context(arg_vec);
+
+        assert_eq!(runtime_context.num_threads(), 2 * ::num_cpus::get())
+    }
+
+    #[test]
+    fn num_threads_should_be_the_requested_count() {
+        let arg_vec = vec!["counter", "-t", "3", "~/logs"];
+
+        let runtime_context = RuntimeContext::new_test_runtime_context(arg_vec);
+
+        assert_eq!(runtime_context.num_threads(), 3)
+    }
+
+    #[test]
+    fn threads_should_not_be_combined_with_threads_per_core() {
+        let result = panic::catch_unwind(|| {
+            let arg_vec = vec!["counter", "--threads", "3", "--threads-per-core", "2.0", "~/logs"];
+            RuntimeContext::new_test_runtime_context(arg_vec);
+        });
+
+        assert!(result.is_err())
+    }
+
+    #[test]
+    fn parse_num_threads_should_reject_zero_and_non_numeric_counts() {
+        assert_eq!(parse_num_threads("8"), Ok(8));
+        assert!(parse_num_threads("0").is_err());
+        assert!(parse_num_threads("-2").is_err());
+        assert!(parse_num_threads("many").is_err())
+    }
+
+    #[test]
+    fn parse_threads_per_core_should_round_down_to_whole_threads() {
+        assert_eq!(parse_threads_per_core("1.5", 3), Ok(4))
+    }
+
+    #[test]
+    fn parse_threads_per_core_should_reject_factors_giving_fewer_than_one_thread() {
+        assert!(parse_threads_per_core("0.2", 4).is_err());
+        assert!(parse_threads_per_core("-1", 4).is_err());
+        assert!(parse_threads_per_core("many", 4).is_err())
+    }
+
+    #[test]
+    fn output_format_should_default_to_csv() {
+        let arg_vec = vec!["counter", "~/logs"];
+
+        let runtime_context = RuntimeContext::new_test_runtime_context(arg_vec);
+
+        assert_eq!(runtime_context.output_format(), record_handling::OutputFormat::Csv)
+    }
+
+    #[test]
+    fn output_format_should_return_influx_when_specified() {
+        let arg_vec = vec!["counter", "--format", "influx", "~/logs"];
+
+        let runtime_context = RuntimeContext::new_test_runtime_context(arg_vec);
+
+        assert_eq!(runtime_context.output_format(), record_handling::OutputFormat::Influx)
+    }
+
+    #[cfg(feature = "yaml")]
+    #[test]
+    fn output_format_should_return_yaml_when_specified() {
+        let arg_vec = vec!["counter", "--format", "yaml", "~/logs"];
+
+        let runtime_context = RuntimeContext::new_test_runtime_context(arg_vec);
+
+        assert_eq!(runtime_context.output_format(), record_handling::OutputFormat::Yaml)
+    }
+
+    #[test]
+    fn file_aggregator_config_should_not_time_out_files_by_default() {
+        let arg_vec = vec!["counter", "~/logs"];
+
+        let runtime_context = RuntimeContext::new_test_runtime_context(arg_vec);
+
+        assert_eq!(runtime_context.file_aggregator_config().file_timeout, None)
+    }
+
+    #[test]
+    fn file_aggregator_config_should_return_the_file_timeout_when_specified() {
+        let arg_vec = vec!["counter", "--file-timeout", "30", "~/logs"];
+
+        let runtime_context = RuntimeContext::new_test_runtime_context(arg_vec);
+
+        assert_eq!(runtime_context.file_aggregator_config().file_timeout,
+                   Some(Duration::from_secs(30)))
+    }
+
+    #[test]
+    fn parse_trace_key_should_split_the_system_from_the_right() {
+        let (system_name, day, client_address) = parse_trace_key("a,b,2016-12-05,10.0.0.1")
+            .unwrap();
+
+        assert_eq!(system_name, "a,b");
+        assert_eq!(day.format("%Y-%m-%d").to_string(), "2016-12-05");
+        assert_eq!(client_address, "10.0.0.1".parse::<Ipv4Addr>().unwrap())
+    }
+
+    #[test]
+    fn parse_trace_key_should_reject_incomplete_keys() {
+        assert!(parse_trace_key("2016-12-05,10.0.0.1").is_err());
+        assert!(parse_trace_key("sys,12/05/2016,10.0.0.1").is_err());
+        assert!(parse_trace_key("sys,2016-12-05,host").is_err())
+    }
+
+    #[test]
+    fn record_trace_should_return_none_without_a_trace_key() {
+        let arg_vec = vec!["counter", "~/logs"];
+
+        let runtime_context = RuntimeContext::new_test_runtime_context(arg_vec);
+
+        assert!(runtime_context.record_trace().unwrap().is_none())
+    }
+
+    #[test]
+    fn record_config_should_hash_keys_when_asked_to() {
+        let arg_vec = vec!["counter", "--hash-keys", "~/logs"];
+
+        let runtime_context = RuntimeContext::new_test_runtime_context(arg_vec);
+
+        assert!(runtime_context.record_config().hash_keys);
+        assert!(runtime_context.key_dictionary().unwrap().is_none())
+    }
+
+    #[test]
+    fn rejects_should_return_none_without_a_rejects_file() {
+        let runtime_context = RuntimeContext::new_test_runtime_context(vec!["counter", "~/logs"]);
+
+        assert!(runtime_context.rejects().unwrap().is_none())
+    }
+
+    #[test]
+    fn rejects_should_fail_when_the_rejects_file_cant_be_created() {
+        let arg_vec = vec!["counter", "--rejects", "/no/such/dir/rejects.csv", "~/logs"];
+
+        let runtime_context = RuntimeContext::new_test_runtime_context(arg_vec);
+
+        assert!(runtime_context.rejects().is_err())
+    }
+
+    #[test]
+    fn checkpoint_should_return_none_without_a_checkpoint_file() {
+        let runtime_context = RuntimeContext::new_test_runtime_context(vec!["counter", "~/logs"]);
+
+        assert_eq!(runtime_context.checkpoint().unwrap(), None)
+    }
+
+    #[test]
+    fn checkpoint_should_start_over_unless_resuming() {
+        let path = env::temp_dir().join("counter_main_checkpoint_start_over");
+        let mut written = Checkpoint::new();
+        written.read_files.insert(PathBuf::from("a.log"));
+        written.write(&path).unwrap();
+        let arg_vec = vec!["counter", "--checkpoint", path.to_str().unwrap(), "~/logs"];
+
+        let runtime_context = RuntimeContext::new_test_runtime_context(arg_vec);
+
+        assert_eq!(runtime_context.checkpoint().unwrap(),
+                   Some(Checkpoint::with_options(runtime_context.checkpoint_options())))
+    }
+
+    #[test]
+    fn checkpoint_should_read_the_checkpoint_file_when_resuming() {
+        let path = env::temp_dir().join("counter_main_checkpoint_resume");
+        let mut written = Checkpoint::with_options("--group-by system,day,client --format csv"
+            .to_owned());
+        written.read_files.insert(PathBuf::from("a.log"));
+        written.write(&path).unwrap();
+        let arg_vec = vec!["counter", "--checkpoint", path.to_str().unwrap(), "--resume", "~/logs"];
+
+        let runtime_context = RuntimeContext::new_test_runtime_context(arg_vec);
+
+        assert_eq!(runtime_context.checkpoint().unwrap(), Some(written))
+    }
+
+    #[test]
+    fn checkpoint_should_refuse_to_resume_a_run_with_other_options() {
+        let path = env::temp_dir().join("counter_main_checkpoint_other_options");
+        Checkpoint::with_options("--group-by system --format csv".to_owned())
+            .write(&path)
+            .unwrap();
+        let arg_vec = vec!["counter", "--checkpoint", path.to_str().unwrap(), "--resume", "~/logs"];
+
+        let runtime_context = RuntimeContext::new_test_runtime_context(arg_vec);
+
+        assert!(runtime_context.checkpoint().is_err())
+    }
+
+    #[test]
+    fn checkpoint_options_should_not_depend_on_the_order_of_the_filters() {
+        let runtime_context = RuntimeContext::new_test_runtime_context(vec!["counter",
+                                                                            "--filter",
+                                                                            "status=5xx",
+                                                                            "--filter",
+                                                                            "backend=10.0.0.5",
+                                                                            "~/logs"]);
+
+        assert_eq!(runtime_context.checkpoint_options(),
+                   "--group-by system,day,client --filter backend=10.0.0.5 --filter status=5xx \
+                    --format csv --null-system-label UNDEFINED_SYSTEM --log-format auto \
+                    --count-overflow-policy saturate")
+    }
+
+    #[test]
+    fn checkpoint_options_should_hold_every_option_changing_the_aggregation() {
+        let options = |args: Vec<&str>| {
+            let mut arg_vec = vec!["counter"];
+            arg_vec.extend(args);
+            arg_vec.push("~/logs");
+            RuntimeContext::new_test_runtime_context(arg_vec).checkpoint_options()
+        };
+        let default_options = options(vec![]);
+
+        for args in vec![vec!["--weight-field", "sent_bytes"],
+                         vec!["--from", "2015-08-15"],
+                         vec!["--until", "2015-08-15T09:00:00Z"],
+                         vec!["--count-status", "200"],
+                         vec!["--path-dimension", "region:1"],
+                         vec!["--drop-no-system"],
+                         vec!["--by-latency"],
+                         vec!["--day-of-month"],
+                         vec!["--list-systems"],
+                         vec!["--log-format", "alb"]] {
+            assert!(options(args.clone()) != default_options,
+                    "{:?} is not in the checkpoint options",
+                    args);
+        }
+    }
+
+    #[test]
+    fn checkpoint_should_start_over_when_resuming_without_a_checkpoint_file() {
+        let path = env::temp_dir().join("counter_main_checkpoint_missing");
+        let _ = fs::remove_file(&path);
+        let arg_vec = vec!["counter", "--checkpoint", path.to_str().unwrap(), "--resume", "~/logs"];
+
+        let runtime_context = RuntimeContext::new_test_runtime_context(arg_vec);
+
+        assert_eq!(runtime_context.checkpoint().unwrap(),
+                   Some(Checkpoint::with_options(runtime_context.checkpoint_options())))
+    }
+
+    #[test]
+    fn checkpointing_should_conflict_with_the_options_it_can_not_checkpoint() {
+        for &option in &["--with-timespan", "--metrics", "--max-memory=1G"] {
+            let arg_vec = vec!["counter", "--checkpoint", "run.checkpoint", option, "~/logs"];
+
+            let result =
+                panic::catch_unwind(|| { RuntimeContext::new_test_runtime_context(arg_vec); });
+
+            assert!(result.is_err(), "{} is not refused with --checkpoint", option)
+        }
+    }
+
+    #[test]
+    fn resume_should_require_a_checkpoint_file() {
+        let arg_vec = vec!["counter", "--resume", "~/logs"];
+
+        let result = panic::catch_unwind(|| { RuntimeContext::new_test_runtime_context(arg_vec); });
+
+        assert!(result.is_err())
+    }
+
+    #[test]
+    fn sink_address_should_return_the_parsed_sink_uri() {
+        let arg_vec = vec!["counter", "--sink", "statsd://metrics.local:8125", "~/logs"];
+
+        let runtime_context = RuntimeContext::new_test_runtime_context(arg_vec);
+
+        assert_eq!(runtime_context.sink_address(),
+                   Some(statsd_output::SinkAddress {
+                       protocol: MetricProtocol::Statsd,
+                       host: "metrics.local".to_owned(),
+                       port: 8125,
+                   }))
+    }
+
+    #[test]
+    fn sink_address_should_return_none_without_a_sink() {
+        let runtime_context = RuntimeContext::new_test_runtime_context(vec!["counter", "~/logs"]);
+
+        assert_eq!(runtime_context.sink_address(), None);
+        assert!(!runtime_context.sink_dry_run())
+    }
+
+    #[test]
+    fn the_sink_should_reject_other_uris() {
+        let arg_vec = vec!["counter", "--sink", "http://metrics.local", "~/logs"];
+
+        let result = panic::catch_unwind(|| { RuntimeContext::new_test_runtime_context(arg_vec); });
+
+        assert!(result.is_err())
+    }
+
+    #[test]
+    fn the_metric_prefix_should_require_a_sink() {
+        let arg_vec = vec!["counter", "--metric-prefix", "elb.requests", "~/logs"];
+
+        let result = panic::catch_unwind(|| { RuntimeContext::new_test_runtime_context(arg_vec); });
+
+        assert!(result.is_err())
+    }
+
+    #[test]
+    fn the_key_dictionary_should_require_hashed_keys() {
+        let arg_vec = vec!["counter", "--key-dictionary", "keys.csv", "~/logs"];
+
+        let result = panic::catch_unwind(|| { RuntimeContext::new_test_runtime_context(arg_vec); });
+
+        assert!(result.is_err())
+    }
+
+    #[test]
+    fn the_trace_key_should_require_a_trace_output() {
+        let arg_vec = vec!["counter", "--trace-key", "sys,2016-12-05,10.0.0.1", "~/logs"];
+
+        let result = panic::catch_unwind(|| { RuntimeContext::new_test_runtime_context(arg_vec); });
+
+        assert!(result.is_err())
+    }
+
+    #[test]
+    fn passthrough_should_conflict_with_an_output_file() {
+        let arg_vec = vec!["counter", "--passthrough", "--output", "records.csv", "~/logs"];
+
+        let result = panic::catch_unwind(|| { RuntimeContext::new_test_runtime_context(arg_vec); });
+
+        assert!(result.is_err())
+    }
+
+    #[test]
+    fn passthrough_should_conflict_with_compression() {
+        let arg_vec = vec!["counter", "--passthrough", "--compress", "gzip", "~/logs"];
+
+        let result = panic::catch_unwind(|| { RuntimeContext::new_test_runtime_context(arg_vec); });
+
+        assert!(result.is_err())
+    }
+
+    #[test]
+    fn output_format_should_return_discard_for_none() {
+        let arg_vec = vec!["counter", "--format", "none", "~/logs"];
+
+        let runtime_context = RuntimeContext::new_test_runtime_context(arg_vec);
+
+        assert_eq!(runtime_context.output_format(), record_handling::OutputFormat::Discard)
+    }
+
+    #[test]
+    fn file_aggregator_config_should_not_weight_totals_by_default() {
+        let arg_vec = vec!["counter", "~/logs"];
+
+        let runtime_context = RuntimeContext::new_test_runtime_context(arg_vec);
+
+        assert_eq!(runtime_context.file_aggregator_config().record_config.weight_field, None)
+    }
+
+    #[test]
+    fn file_aggregator_config_should_return_the_weight_field_when_specified() {
+        let arg_vec = vec!["counter", "--weight-field", "sent_bytes", "~/logs"];
+
+        let runtime_context = RuntimeContext::new_test_runtime_context(arg_vec);
+
+        assert_eq!(runtime_context.file_aggregator_config().record_config.weight_field,
+                   Some(record_handling::WeightField::SentBytes))
+    }
+
+    #[test]
+    fn parse_memory_size_should_accept_bytes_and_each_unit() {
+        assert_eq!(parse_memory_size("4096"), Ok(4096));
+        assert_eq!(parse_memory_size("64K"), Ok(64 * 1024));
+        assert_eq!(parse_memory_size("512M"), Ok(512 * 1024 * 1024));
+        assert_eq!(parse_memory_size("8G"), Ok(8 * 1024 * 1024 * 1024));
+        assert_eq!(parse_memory_size("2T"), Ok(2 * 1024 * 1024 * 1024 * 1024))
+    }
+
+    #[test]
+    fn parse_memory_size_should_reject_other_sizes() {
+        for size in &["", "0", "8GB", "8g", "G", "-1M", "99999999999T"] {
+            assert!(parse_memory_size(size).is_err(), "{} was accepted", size);
+        }
+    }
+
+    #[test]
+    fn file_aggregator_config_should_spill_within_the_share_of_each_file_handler() {
+        let arg_vec = vec!["counter", "--max-memory", "64M", "-t", "4", "~/logs"];
+
+        let runtime_context = RuntimeContext::new_test_runtime_context(arg_vec);
+
+        assert_eq!(runtime_context.file_aggregator_config().spill_after,
+                   Some(spill::max_aggregates_within(16 * 1024 * 1024)))
+    }
+
+    #[test]
+    fn file_aggregator_config_should_not_spill_by_default() {
+        let runtime_context = RuntimeContext::new_test_runtime_context(vec!["counter", "~/logs"]);
+
+        assert_eq!(runtime_context.file_aggregator_config().spill_after, None)
+    }
+
+    #[test]
+    fn max_memory_should_conflict_with_a_limit_per_system() {
+        let arg_vec = vec!["counter", "--max-memory", "1G", "--limit-per-system", "3", "~/logs"];
+
+        let result = panic::catch_unwind(|| { RuntimeContext::new_test_runtime_context(arg_vec); });
+
+        assert!(result.is_err())
+    }
+
+    #[test]
+    fn check_max_memory_should_refuse_an_order_other_than_the_key() {
+        let arg_vec = vec!["counter", "--max-memory", "1G", "--sort-by", "count", "~/logs"];
+
+        let runtime_context = RuntimeContext::new_test_runtime_context(arg_vec);
+
+        assert!(runtime_context.check_max_memory().is_err())
+    }
+
+    /// Each of these reads the aggregates sorted in memory, which a run spilling them has none
+    /// of.
+    fn options_reading_the_sorted_aggregates() -> Vec<Vec<&'static str>> {
+        let mut options = vec![vec!["--baseline", "systems.csv", "--max-drop", "10"],
+                               vec!["--count-floor", "2"],
+                               vec!["--limit-per-system", "2"],
+                               vec!["--list-systems"],
+                               vec!["--result-hash"],
+                               vec!["--sink", "statsd://localhost"],
+                               vec!["--format", "csv", "--strict-csv"]];
+        if cfg!(feature = "kafka") {
+            options.push(vec!["--kafka-brokers", "localhost:9092", "--kafka-topic", "elb"]);
+        }
+        options
+    }
+
+    #[test]
+    fn max_memory_should_conflict_with_every_option_reading_the_sorted_aggregates() {
+        for options in options_reading_the_sorted_aggregates() {
+            let mut args = vec!["counter"];
+            args.extend(options.iter().cloned());
+            args.push("~/logs");
+            assert!(RuntimeContext::new_app().get_matches_from_safe_borrow(args.clone()).is_ok(),
+                    "{:?} is refused without --max-memory",
+                    options);
+
+            args.insert(1, "--max-memory");
+            args.insert(2, "1G");
+            let result = RuntimeContext::new_app().get_matches_from_safe_borrow(args);
+
+            match result {
+                Err(ref e) if e.kind == clap::ErrorKind::ArgumentConflict => {}
+                _ => panic!("{:?} is not refused with --max-memory", options),
+            }
+        }
+    }
+
+    #[test]
+    fn check_max_memory_should_accept_the_key_order() {
+        let arg_vec = vec!["counter", "--max-memory", "1G", "~/logs"];
+
+        let runtime_context = RuntimeContext::new_test_runtime_context(arg_vec);
+
+        assert!(runtime_context.check_max_memory().is_ok())
+    }
+
+    #[test]
+    fn parse_timestamp_resolution_should_accept_each_unit() {
+        assert_eq!(parse_timestamp_resolution("500ms"), Ok(Duration::from_millis(500)));
+        assert_eq!(parse_timestamp_resolution("10s"), Ok(Duration::from_secs(10)));
+        assert_eq!(parse_timestamp_resolution("2m"), Ok(Duration::from_secs(120)));
+        assert_eq!(parse_timestamp_resolution("1h"), Ok(Duration::from_secs(3600)))
+    }
+
+    #[test]
+    fn parse_timestamp_resolution_should_reject_invalid_durations() {
+        assert!(parse_timestamp_resolution("0s").is_err());
+        assert!(parse_timestamp_resolution("10").is_err());
+        assert!(parse_timestamp_resolution("s").is_err());
+        assert!(parse_timestamp_resolution("10d").is_err())
+    }
+
+    #[test]
+    fn file_aggregator_config_should_not_round_timestamps_by_default() {
+        let arg_vec = vec!["counter", "~/logs"];
+
+        let runtime_context = RuntimeContext::new_test_runtime_context(arg_vec);
+
+        assert_eq!(runtime_context.file_aggregator_config().record_config.timestamp_resolution,
+                   None)
+    }
+
+    #[test]
+    fn max_aggregates_should_return_none_when_the_arg_is_not_set() {
+        let arg_vec = vec!["counter", "~/logs"];
+
+        let runtime_context = RuntimeContext::new_test_runtime_context(arg_vec);
+
+        assert_eq!(runtime_context.max_aggregates(), None)
+    }
+
+    #[test]
+    fn max_aggregates_should_return_the_limit_when_specified() {
+        let arg_vec = vec!["counter", "--max-aggregates", "1000", "~/logs"];
+
+        let runtime_context = RuntimeContext::new_test_runtime_context(arg_vec);
+
+        assert_eq!(runtime_context.max_aggregates(), Some(1000))
+    }
+
+    #[test]
+    fn merge_threads_should_return_the_count_when_specified() {
+        let arg_vec = vec!["counter", "--merge-threads", "4", "~/logs"];
+
+        let runtime_context = RuntimeContext::new_test_runtime_context(arg_vec);
+
+        assert_eq!(runtime_context.merge_threads(), Some(4))
+    }
+
+    #[test]
+    fn merge_threads_should_return_none_when_the_arg_is_not_set() {
+        let arg_vec = vec!["counter", "~/logs"];
+
+        let runtime_context = RuntimeContext::new_test_runtime_context(arg_vec);
+
+        assert_eq!(runtime_context.merge_threads(), None)
+    }
+
+    #[test]
+    fn output_format_should_return_matrix_when_specified() {
+        let arg_vec = vec!["counter", "--format", "matrix", "~/logs"];
+
+        let runtime_context = RuntimeContext::new_test_runtime_context(arg_vec);
+
+        assert_eq!(runtime_context.output_format(), record_handling::OutputFormat::Matrix)
+    }
+
+    #[test]
+    fn the_log_location_should_not_be_required_to_explain_a_record() {
+        let arg_vec = vec!["counter", "--explain-record", "a record"];
+
+        let runtime_context = RuntimeContext::new_test_runtime_context(arg_vec);
+
+        assert_eq!(runtime_context.explain_record(), Some("a record"))
+    }
+
+    #[test]
+    fn parse_status_codes_should_accept_codes_and_ranges() {
+        let codes = parse_status_codes("200,204,301-304").unwrap();
+
+        assert_eq!(codes, record_handling::StatusCodes(vec![(200, 200), (204, 204), (301, 304)]));
+        assert!(codes.contains(302));
+        assert!(!codes.contains(404))
+    }
+
+    #[test]
+    fn parse_status_codes_should_reject_invalid_lists() {
+        assert!(parse_status_codes("200,").is_err());
+        assert!(parse_status_codes("304-301").is_err());
+        assert!(parse_status_codes("2xx").is_err());
+        assert!(parse_status_codes("-").is_err())
+    }
+
+    #[test]
+    fn list_systems_should_return_false_when_the_arg_is_not_set() {
+        let arg_vec = vec!["counter", "~/logs"];
+
+        let runtime_context = RuntimeContext::new_test_runtime_context(arg_vec);
+
+        assert_eq!(runtime_context.list_systems(), false)
+    }
+
+    #[test]
+    fn list_systems_should_return_true_when_the_arg_is_set() {
+        let arg_vec = vec!["counter", "--list-systems", "~/logs"];
+
+        let runtime_context = RuntimeContext::new_test_runtime_context(arg_vec);
+
+        assert_eq!(runtime_context.list_systems(), true)
+    }
+
+    #[test]
+    fn listing_systems_should_aggregate_by_system_and_day_alone() {
+        let arg_vec = vec!["counter", "--list-systems", "--group-by", "system,day,url", "~/logs"];
+
+        let runtime_context = RuntimeContext::new_test_runtime_context(arg_vec);
+
+        assert_eq!(runtime_context.record_config().group_by,
+                   vec![record_handling::Dimension::System, record_handling::Dimension::Day])
+    }
+
+    #[test]
+    fn truncate_field_should_return_none_when_the_arg_is_not_set() {
+        let arg_vec = vec!["counter", "~/logs"];
+
+        let runtime_context = RuntimeContext::new_test_runtime_context(arg_vec);
+
+        assert_eq!(runtime_context.truncate_field(), None)
+    }
+
+    #[test]
+    fn truncate_field_should_return_the_number_of_characters_when_set() {
+        let arg_vec = vec!["counter", "--truncate-field", "40", "~/logs"];
+
+        let runtime_context = RuntimeContext::new_test_runtime_context(arg_vec);
+
+        assert_eq!(runtime_context.truncate_field(), Some(40))
+    }
+
+    #[test]
+    fn record_config_should_not_bucket_by_latency_by_default() {
+        let arg_vec = vec!["counter", "~/logs"];
+
+        let runtime_context = RuntimeContext::new_test_runtime_context(arg_vec);
+
+        assert_eq!(runtime_context.record_config().latency_buckets, None)
+    }
+
+    #[test]
+    fn record_config_should_use_the_default_latency_buckets_when_by_latency_is_set() {
+        let arg_vec = vec!["counter", "--by-latency", "~/logs"];
+
+        let runtime_context = RuntimeContext::new_test_runtime_context(arg_vec);
+
+        assert_eq!(runtime_context.record_config().latency_buckets,
+                   Some(record_handling::LatencyBuckets::default()))
+    }
+
+    #[test]
+    fn record_config_should_use_the_given_latency_buckets() {
+        let arg_vec = vec!["counter", "--latency-buckets", "250,2000", "~/logs"];
+
+        let runtime_context = RuntimeContext::new_test_runtime_context(arg_vec);
+
+        assert_eq!(runtime_context.record_config().latency_buckets,
+                   Some(record_handling::LatencyBuckets::new(vec![250, 2000])))
+    }
+
+    #[test]
+    fn parse_latency_bounds_should_reject_invalid_bounds() {
+        assert!(parse_latency_bounds("10,x").is_err());
+        assert!(parse_latency_bounds("50,10").is_err());
+        assert!(parse_latency_bounds("10,10").is_err());
+        assert!(parse_latency_bounds("").is_err())
+    }
+
+    #[test]
+    fn file_aggregator_config_should_sample_no_errors_by_default() {
+        let arg_vec = vec!["counter", "~/logs"];
+
+        let runtime_context = RuntimeContext::new_test_runtime_context(arg_vec);
+
+        assert_eq!(runtime_context.file_aggregator_config().sample_errors, 0)
+    }
+
+    #[test]
+    fn file_aggregator_config_should_include_the_number_of_errors_to_sample() {
+        let arg_vec = vec!["counter", "--sample-errors", "5", "~/logs"];
+
+        let runtime_context = RuntimeContext::new_test_runtime_context(arg_vec);
+
+        assert_eq!(runtime_context.file_aggregator_config().sample_errors, 5)
+    }
+
+    #[test]
+    fn file_aggregator_config_should_include_the_seed() {
+        let arg_vec = vec!["counter", "--seed", "42", "~/logs"];
+
+        let runtime_context = RuntimeContext::new_test_runtime_context(arg_vec);
+
+        assert_eq!(runtime_context.file_aggregator_config().seed, 42)
+    }
+
+    #[test]
+    fn file_aggregator_config_should_not_count_file_subtotals_by_default() {
+        let arg_vec = vec!["counter", "~/logs"];
+
+        let runtime_context = RuntimeContext::new_test_runtime_context(arg_vec);
+
+        assert_eq!(runtime_context.file_aggregator_config().file_subtotals, false)
+    }
+
+    #[test]
+    fn file_aggregator_config_should_count_file_subtotals_when_asked_to() {
+        let arg_vec = vec!["counter", "--with-file-subtotals", "~/logs"];
+
+        let runtime_context = RuntimeContext::new_test_runtime_context(arg_vec);
+
+        assert_eq!(runtime_context.file_aggregator_config().file_subtotals, true)
+    }
+
+    #[test]
+    fn file_aggregator_config_should_verify_sorted_input_when_asked_to() {
+        let arg_vec = vec!["counter", "--verify-sorted-input", "~/logs"];
+
+        let runtime_context = RuntimeContext::new_test_runtime_context(arg_vec);
+
+        assert!(runtime_context.file_aggregator_config().verify_sorted)
+    }
+
+    #[test]
+    fn file_aggregator_config_should_keep_timespans_when_asked_to() {
+        let arg_vec = vec!["counter", "--with-timespan", "~/logs"];
+
+        let runtime_context = RuntimeContext::new_test_runtime_context(arg_vec);
+
+        assert_eq!(runtime_context.file_aggregator_config().timespans, true)
+    }
+
+    #[test]
+    fn file_aggregator_config_should_keep_metrics_when_asked_to() {
+        let arg_vec = vec!["counter", "--metrics", "~/logs"];
+
+        let runtime_context = RuntimeContext::new_test_runtime_context(arg_vec);
+
+        assert!(runtime_context.file_aggregator_config().aggregate_metrics)
+    }
+
+    #[test]
+    fn file_aggregator_config_should_not_keep_metrics_by_default() {
+        let runtime_context = RuntimeContext::new_test_runtime_context(vec!["counter", "~/logs"]);
+
+        assert!(!runtime_context.file_aggregator_config().aggregate_metrics)
+    }
+
+    #[test]
+    fn output_format_should_return_tsv_when_specified() {
+        let arg_vec = vec!["counter", "--format", "tsv", "~/logs"];
+
+        let runtime_context = RuntimeContext::new_test_runtime_context(arg_vec);
+
+        assert_eq!(runtime_context.output_format(), record_handling::OutputFormat::Tsv)
+    }
+
+    #[test]
+    fn output_format_should_return_json_lines_when_specified_with_the_short_flag() {
+        let arg_vec = vec!["counter", "-f", "jsonl", "~/logs"];
+
+        let runtime_context = RuntimeContext::new_test_runtime_context(arg_vec);
+
+        assert_eq!(runtime_context.output_format(), record_handling::OutputFormat::JsonLines)
+    }
+
+    #[test]
+    fn output_format_should_return_csv_when_specified() {
+        let arg_vec = vec!["counter", "--format", "csv", "~/logs"];
+
+        let runtime_context = RuntimeContext::new_test_runtime_context(arg_vec);
+
+        assert_eq!(runtime_context.output_format(), record_handling::OutputFormat::Csv)
+    }
+
+    #[test]
+    fn output_format_should_return_json_when_specified() {
+        let arg_vec = vec!["counter", "--format", "json", "~/logs"];
+
+        let runtime_context = RuntimeContext::new_test_runtime_context(arg_vec);
+
+        assert_eq!(runtime_context.output_format(), record_handling::OutputFormat::Json)
+    }
+
+    #[test]
+    fn constructing_a_runtime_context_should_panic_if_the_format_is_unknown() {
+        let arg_vec = vec!["counter", "--format", "xml", "~/logs"];
+
+        let result = panic::catch_unwind(|| { RuntimeContext::new_test_runtime_context(arg_vec); });
+
+        assert!(result.is_err())
+    }
+
+    #[test]
+    fn date_range_should_return_none_without_from_or_to() {
+        let arg_vec = vec!["counter", "~/logs"];
+
+        let runtime_context = RuntimeContext::new_test_runtime_context(arg_vec);
+
+        assert_eq!(runtime_context.date_range(), Ok(None))
+    }
+
+    #[test]
+    fn date_range_should_leave_the_missing_bound_open() {
+        let arg_vec = vec!["counter", "--from", "2016-12-05", "~/logs"];
+
+        let runtime_context = RuntimeContext::new_test_runtime_context(arg_vec);
+
+        assert_eq!(runtime_context.date_range(),
+                   Ok(Some(record_handling::DateRange {
+                       from: Some(parse_day("2016-12-05").unwrap()),
+                       to: None,
+                   })))
+    }
+
+    #[test]
+    fn date_range_should_accept_a_single_day() {
+        let arg_vec = vec!["counter", "--from", "2016-12-05", "--to", "2016-12-05", "~/logs"];
+
+        let runtime_context = RuntimeContext::new_test_runtime_context(arg_vec);
+
+        assert!(runtime_context.date_range().unwrap().is_some())
+    }
+
+    #[test]
+    fn date_range_should_return_an_error_when_from_is_after_to() {
+        let arg_vec = vec!["counter", "--from", "2016-12-06", "--to", "2016-12-05", "~/logs"];
+
+        let runtime_context = RuntimeContext::new_test_runtime_context(arg_vec);
+
+        assert!(runtime_context.date_range().is_err())
+    }
+
+    #[test]
+    fn time_window_should_return_an_error_unless_since_is_before_until() {
+        let arg_vec = vec!["counter",
+                           "--since",
+                           "2016-12-05T10:00:00Z",
+                           "--until",
+                           "2016-12-05T10:00:00Z",
+                           "~/logs"];
+
+        let runtime_context = RuntimeContext::new_test_runtime_context(arg_vec);
+
+        assert!(runtime_context.time_window().is_err())
+    }
+
+    #[test]
+    fn time_window_should_leave_the_missing_bound_open() {
+        let arg_vec = vec!["counter", "--until", "2016-12-05T10:00:00+01:00", "~/logs"];
+
+        let runtime_context = RuntimeContext::new_test_runtime_context(arg_vec);
+
+        assert_eq!(runtime_context.time_window(),
+                   Ok(Some(record_handling::TimeWindow {
+                       since: None,
+                       until: Some(parse_timestamp("2016-12-05T09:00:00Z").unwrap()),
+                   })))
+    }
+
+    #[test]
+    fn filters_should_return_every_filter_given() {
+        let arg_vec = vec!["counter",
+                           "--filter",
+                           "status=5xx",
+                           "--filter",
+                           "backend=10.0.0.5",
+                           "~/logs"];
+
+        let runtime_context = RuntimeContext::new_test_runtime_context(arg_vec);
+
+        assert_eq!(runtime_context.filters(),
+                   vec![record_handling::RecordFilter::Status(
+                            record_handling::StatusCodes(vec![(500, 599)])),
+                        record_handling::RecordFilter::Backend("10.0.0.5".parse().unwrap())])
+    }
+
+    #[test]
+    fn parse_filter_should_reject_unknown_fields_and_invalid_values() {
+        assert!(parse_filter("referrer=x").is_err());
+        assert!(parse_filter("status").is_err());
+        assert!(parse_filter("status=").is_err());
+        assert!(parse_filter("status=6xx").is_err());
+        assert!(parse_filter("client=host").is_err())
+    }
+
+    #[test]
+    fn parser_command_should_return_an_error_when_filtering_by_a_missing_field() {
+        let arg_vec = vec!["counter",
+                           "--parser-command",
+                           "normalize-logs",
+                           "--filter",
+                           "port=80",
+                           "~/logs"];
+
+        let runtime_context = RuntimeContext::new_test_runtime_context(arg_vec);
+
+        assert!(runtime_context.parser_command().is_err())
+    }
+
+    #[test]
+    fn parser_command_should_return_the_command_given() {
+        let arg_vec = vec!["counter", "--parser-command", "normalize-logs", "~/logs"];
+
+        let runtime_context = RuntimeContext::new_test_runtime_context(arg_vec);
+
+        assert_eq!(runtime_context.parser_command(), Ok(Some("normalize-logs".to_owned())))
+    }
+
+    #[test]
+    fn parser_command_should_return_an_error_when_grouping_by_a_missing_dimension() {
+        let arg_vec = vec!["counter",
+                           "--parser-command",
+                           "normalize-logs",
+                           "--group-by",
+                           "system,url",
+                           "~/logs"];
+
+        let runtime_context = RuntimeContext::new_test_runtime_context(arg_vec);
+
+        assert!(runtime_context.parser_command().is_err())
+    }
+
+    #[test]
+    fn check_day_range_should_refuse_a_from_and_to_spanning_more_days() {
+        let arg_vec = vec!["counter",
+                           "--from",
+                           "2016-12-01",
+                           "--to",
+                           "2016-12-31",
+                           "--max-day-range",
+                           "7",
+                           "~/logs"];
+
+        let runtime_context = RuntimeContext::new_test_runtime_context(arg_vec);
+
+        assert!(runtime_context.check_day_range().is_err())
+    }
+
+    #[test]
+    fn check_day_range_should_accept_a_range_as_long_as_the_maximum() {
+        let arg_vec = vec!["counter",
+                           "--from",
+                           "2016-12-01",
+                           "--to",
+                           "2016-12-07",
+                           "--max-day-range",
+                           "7",
+                           "~/logs"];
+
+        let runtime_context = RuntimeContext::new_test_runtime_context(arg_vec);
+
+        assert_eq!(runtime_context.check_day_range(), Ok(()))
+    }
+
+    #[test]
+    fn max_day_range_should_be_lifted_by_allow_large_range() {
+        let arg_vec = vec!["counter", "--max-day-range", "7", "--allow-large-range", "~/logs"];
+
+        let runtime_context = RuntimeContext::new_test_runtime_context(arg_vec);
+
+        assert_eq!(runtime_context.max_day_range(), None);
+        assert_eq!(runtime_context.file_aggregator_config().max_day_range, None)
+    }
+
+    #[test]
+    fn record_config_should_include_the_business_hours() {
+        let arg_vec = vec!["counter", "--business-hours", "09:00-17:30", "~/logs"];
+
+        let runtime_context = RuntimeContext::new_test_runtime_context(arg_vec);
+
+        assert_eq!(runtime_context.record_config().business_hours,
+                   Some(record_handling::BusinessHours {
+                       start_minute: 9 * 60,
+                       end_minute: 17 * 60 + 30,
+                   }))
+    }
+
+    #[test]
+    fn parse_business_hours_should_reject_invalid_ranges() {
+        assert!(parse_business_hours("22:00-24:00").is_ok());
+        assert!(parse_business_hours("09:00").is_err());
+        assert!(parse_business_hours("9-17").is_err());
+        assert!(parse_business_hours("09:00-25:00").is_err());
+        assert!(parse_business_hours("09:00-09:00").is_err())
+    }
+
+    #[test]
+    fn parse_day_should_reject_other_date_formats() {
+        assert!(parse_day("12/05/2016").is_err());
+        assert!(parse_day("2016-12-05T00:00:00Z").is_err())
+    }
+
+    #[test]
+    fn options_should_fall_back_to_the_environment() {
+        let arg_vec = vec!["counter", "~/logs"];
+        let env_vars = vec![("COUNTER_THREADS", "3"), ("COUNTER_FORMAT", "json")];
+
+        let runtime_context = RuntimeContext::new_test_runtime_context_with_env(arg_vec, env_vars);
+
+        assert_eq!(runtime_context.num_threads(), 3);
+        assert_eq!(runtime_context.output_format(), record_handling::OutputFormat::Json)
+    }
+
+    #[test]
+    fn options_on_the_command_line_should_take_precedence_over_the_environment() {
+        let arg_vec = vec!["counter", "-t", "2", "--format=tsv", "~/logs"];
+        let env_vars = vec![("COUNTER_THREADS", "3"), ("COUNTER_FORMAT", "json")];
+
+        let runtime_context = RuntimeContext::new_test_runtime_context_with_env(arg_vec, env_vars);
+
+        assert_eq!(runtime_context.num_threads(), 2);
+        assert_eq!(runtime_context.output_format(), record_handling::OutputFormat::Tsv)
+    }
+
+    #[test]
+    fn flags_should_only_be_set_by_a_true_environment_variable() {
+        let arg_vec = vec!["counter", "~/logs"];
+
+        let set = RuntimeContext::new_test_runtime_context_with_env(arg_vec.clone(),
+                                                                    vec![("COUNTER_BENCHMARK",
+                                                                          "true")]);
+        let unset = RuntimeContext::new_test_runtime_context_with_env(arg_vec,
+                                                                      vec![("COUNTER_BENCHMARK",
+                                                                            "0")]);
+
+        assert!(set.run_benchmark());
+        assert!(!unset.run_benchmark())
+    }
+
+    #[test]
+    fn log_location_should_fall_back_to_the_environment() {
+        let arg_vec = vec!["counter", "--format", "json"];
+        let env_vars = vec![("COUNTER_LOG_LOCATION", "~/logs")];
+
+        let runtime_context = RuntimeContext::new_test_runtime_context_with_env(arg_vec, env_vars);
+
+        assert_eq!(runtime_context.log_location(), Path::new("~/logs"))
+    }
+
+    #[test]
+    fn log_location_on_the_command_line_should_take_precedence_over_the_environment() {
+        let arg_vec = vec!["counter", "--format", "json", "-t3", "~/logs"];
+        let env_vars = vec![("COUNTER_LOG_LOCATION", "~/other-logs")];
+
+        let runtime_context = RuntimeContext::new_test_runtime_context_with_env(arg_vec, env_vars);
+
+        assert_eq!(runtime_context.log_location(), Path::new("~/logs"))
+    }
+
+    #[test]
+    fn invalid_environment_variables_should_be_rejected_like_the_command_line() {
+        let zero_threads = panic::catch_unwind(|| {
+            RuntimeContext::new_test_runtime_context_with_env(vec!["counter", "~/logs"],
+                                                              vec![("COUNTER_THREADS", "0")]);
+        });
+        let flag_value = panic::catch_unwind(|| {
+            RuntimeContext::new_test_runtime_context_with_env(vec!["counter", "~/logs"],
+                                                              vec![("COUNTER_BENCHMARK", "yes")]);
+        });
+
+        assert!(zero_threads.is_err());
+        assert!(flag_value.is_err())
+    }
+
+    #[test]
+    fn warn_overlapping_days_should_keep_the_records_of_each_file_from_each_day() {
+        let arg_vec = vec!["counter", "--warn-overlapping-days", "~/logs"];
+
+        let runtime_context = RuntimeContext::new_test_runtime_context(arg_vec);
+
+        assert!(runtime_context.file_aggregator_config().file_days)
+    }
+
+    #[test]
+    fn file_aggregator_config_should_not_keep_file_days_by_default() {
         let arg_vec = vec!["counter", "~/logs"];
 
         let runtime_context = RuntimeContext::new_test_runtime_context(arg_vec);
 
-        assert_eq!(runtime_context.run_benchmark(), false)
+        assert!(!runtime_context.file_aggregator_config().file_days)
     }
 
     #[test]
-    fn run_benchmark_should_return_true_when_long_benchmark_is_set() {
-        let arg_vec = vec!["counter", "--benchmark", "~/logs"];
+    fn group_by_should_default_to_system_day_and_client() {
+        let arg_vec = vec!["counter", "~/logs"];
 
         let runtime_context = RuntimeContext::new_test_runtime_context(arg_vec);
 
-        assert!(runtime_context.run_benchmark())
+        assert_eq!(runtime_context.group_by(),
+                   record_handling::DEFAULT_GROUP_BY.to_vec())
     }
 
     #[test]
-    fn run_benchmark_should_return_true_when_short_benchmark_is_set() {
-        let arg_vec = vec!["counter", "-b", "~/logs"];
+    fn group_by_should_keep_the_order_given() {
+        let arg_vec = vec!["counter", "--group-by", "day,backend,system", "~/logs"];
 
         let runtime_context = RuntimeContext::new_test_runtime_context(arg_vec);
 
-        assert!(runtime_context.run_benchmark())
+        assert_eq!(runtime_context.record_config().group_by,
+                   vec![record_handling::Dimension::Day,
+                        record_handling::Dimension::Backend,
+                        record_handling::Dimension::System])
+    }
+
+    #[test]
+    fn parse_group_by_should_reject_unknown_repeated_and_missing_dimensions() {
+        assert!(parse_group_by("system,day,referrer").is_err());
+        assert!(parse_group_by("system,day,day").is_err());
+        assert!(parse_group_by("system,client").is_err());
+        assert!(parse_group_by("system,day,status,elb_status_code").is_err())
+    }
+
+    #[test]
+    fn parse_group_by_should_accept_the_column_names_of_the_dimensions() {
+        assert_eq!(parse_group_by("system_name,day,hour,backend_address,elb_status_code").unwrap(),
+                   vec![record_handling::Dimension::System,
+                        record_handling::Dimension::Day,
+                        record_handling::Dimension::Hour,
+                        record_handling::Dimension::Backend,
+                        record_handling::Dimension::Status])
+    }
+
+    #[test]
+    fn parse_group_by_should_group_by_the_day_before_the_hour_when_day_is_left_out() {
+        assert_eq!(parse_group_by("system_name,hour,backend_address,elb_status_code").unwrap(),
+                   vec![record_handling::Dimension::System,
+                        record_handling::Dimension::Day,
+                        record_handling::Dimension::Hour,
+                        record_handling::Dimension::Backend,
+                        record_handling::Dimension::Status]);
+        assert!(parse_group_by("system,backend").is_err())
+    }
+
+    #[test]
+    fn constructing_a_runtime_context_should_panic_if_a_dimension_is_unknown() {
+        let result = panic::catch_unwind(|| {
+            let arg_vec = vec!["counter", "--group-by", "system,day,referrer", "~/logs"];
+            RuntimeContext::new_test_runtime_context(arg_vec);
+        });
+
+        assert!(result.is_err())
+    }
+
+    #[test]
+    fn output_path_should_be_none_by_default() {
+        let arg_vec = vec!["counter", "~/logs"];
+
+        let runtime_context = RuntimeContext::new_test_runtime_context(arg_vec);
+
+        assert_eq!(runtime_context.output_path(), None)
+    }
+
+    #[test]
+    fn output_path_should_return_the_specified_path() {
+        let arg_vec = vec!["counter", "--output", "/tmp/aggregates.csv", "~/logs"];
+
+        let runtime_context = RuntimeContext::new_test_runtime_context(arg_vec);
+
+        assert_eq!(runtime_context.output_path(), Some(Path::new("/tmp/aggregates.csv")))
+    }
+
+    #[test]
+    fn print_schema_should_not_require_a_log_location() {
+        let arg_vec = vec!["counter", "--print-schema"];
+
+        let runtime_context = RuntimeContext::new_test_runtime_context(arg_vec);
+
+        assert!(runtime_context.print_schema())
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn output_schema_should_describe_the_group_by_and_weight_field() {
+        let arg_vec = vec!["counter",
+                           "--print-schema",
+                           "--group-by",
+                           "system,day",
+                           "--weight-field",
+                           "sent_bytes"];
+
+        let runtime_context = RuntimeContext::new_test_runtime_context(arg_vec);
+
+        assert_eq!(runtime_context.output_schema().unwrap().to_string(),
+                   "{\"columns\":[{\"name\":\"system_name\",\"type\":\"string\"},\
+                    {\"name\":\"day\",\"type\":\"date\"},\
+                    {\"name\":\"total_bytes\",\"type\":\"integer\"}]}")
+    }
+
+    #[test]
+    fn record_config_should_bucket_by_day_of_month_when_the_arg_is_set() {
+        let arg_vec = vec!["counter", "--day-of-month", "~/logs"];
+
+        let runtime_context = RuntimeContext::new_test_runtime_context(arg_vec);
+
+        assert!(runtime_context.record_config().day_of_month)
+    }
+
+    #[test]
+    fn baseline_should_return_none_when_the_arg_is_not_set() {
+        let arg_vec = vec!["counter", "~/logs"];
+
+        let runtime_context = RuntimeContext::new_test_runtime_context(arg_vec);
+
+        assert_eq!(runtime_context.baseline(), Ok(None));
+        assert_eq!(runtime_context.max_drop(), None)
+    }
+
+    #[test]
+    fn baseline_should_read_the_system_totals_of_the_file() {
+        let baseline_path = env::temp_dir().join("counter_baseline_test.csv");
+        File::create(&baseline_path).unwrap().write_all(b"sys1,10\nsys2,4\n").unwrap();
+        let arg_vec = vec!["counter",
+                           "--baseline",
+                           baseline_path.to_str().unwrap(),
+                           "--max-drop",
+                           "12.5",
+                           "~/logs"];
+
+        let runtime_context = RuntimeContext::new_test_runtime_context(arg_vec);
+        let baseline = runtime_context.baseline();
+        let _ = std::fs::remove_file(&baseline_path);
+
+        let mut expected = BTreeMap::new();
+        expected.insert("sys1".to_owned(), 10);
+        expected.insert("sys2".to_owned(), 4);
+        assert_eq!(baseline, Ok(Some(expected)));
+        assert_eq!(runtime_context.max_drop(), Some(12.5))
+    }
+
+    #[test]
+    fn parse_percentage_should_reject_values_outside_of_0_to_100() {
+        assert_eq!(parse_percentage("0"), Ok(0.0));
+        assert_eq!(parse_percentage("100"), Ok(100.0));
+        assert!(parse_percentage("-1").is_err());
+        assert!(parse_percentage("101").is_err());
+        assert!(parse_percentage("x").is_err())
+    }
+
+    #[test]
+    fn sort_order_should_default_to_the_key() {
+        let arg_vec = vec!["counter", "~/logs"];
+
+        let runtime_context = RuntimeContext::new_test_runtime_context(arg_vec);
+
+        assert_eq!(runtime_context.sort_order(), record_handling::SortOrder::Key);
+        assert!(!runtime_context.reverse_sort())
+    }
+
+    #[test]
+    fn sort_order_should_return_the_order_given() {
+        let arg_vec = vec!["counter", "--sort-by", "day", "--reverse-sort", "~/logs"];
+
+        let runtime_context = RuntimeContext::new_test_runtime_context(arg_vec);
+
+        assert_eq!(runtime_context.sort_order(), record_handling::SortOrder::Day);
+        assert!(runtime_context.reverse_sort())
+    }
+
+    #[test]
+    fn flush_interval_should_be_none_without_watching() {
+        let arg_vec = vec!["counter", "--flush-interval", "5m", "~/logs"];
+
+        let runtime_context = RuntimeContext::new_test_runtime_context(arg_vec);
+
+        assert_eq!(runtime_context.flush_interval(), None)
+    }
+
+    #[test]
+    fn flush_interval_should_default_to_a_minute_when_watching() {
+        let arg_vec = vec!["counter", "--watch", "~/logs"];
+
+        let runtime_context = RuntimeContext::new_test_runtime_context(arg_vec);
+
+        assert_eq!(runtime_context.flush_interval(), Some(Duration::from_secs(60)))
+    }
+
+    #[test]
+    fn flush_interval_should_be_the_requested_interval() {
+        let arg_vec = vec!["counter", "--watch", "--flush-interval", "30s", "~/logs"];
+
+        let runtime_context = RuntimeContext::new_test_runtime_context(arg_vec);
+
+        assert_eq!(runtime_context.flush_interval(), Some(Duration::from_secs(30)))
+    }
+
+    #[test]
+    fn watching_should_conflict_with_a_baseline() {
+        let arg_vec = vec!["counter", "--watch", "--baseline", "totals.csv", "--max-drop", "10",
+                           "~/logs"];
+
+        let result = panic::catch_unwind(|| { RuntimeContext::new_test_runtime_context(arg_vec); });
+
+        assert!(result.is_err())
+    }
+
+    #[cfg(feature = "kafka")]
+    #[test]
+    fn watching_should_conflict_with_publishing_to_kafka() {
+        let arg_vec = vec!["counter", "--watch", "--kafka-brokers", "kafka0:9092", "--kafka-topic",
+                           "aggregates", "~/logs"];
+
+        let result = panic::catch_unwind(|| { RuntimeContext::new_test_runtime_context(arg_vec); });
+
+        assert!(result.is_err())
+    }
+}
+
+#[cfg(test)]
+mod write_results_tests {
+    use super::*;
+    use std::io::Read;
+
+    fn run_and_write(num_threads: usize) -> Vec<u8> {
+        let runtime_context = RuntimeContext::new_test_runtime_context(vec!["counter", "~/logs"]);
+        let mut files = vec![PathBuf::from("./test_artifacts/test_elb_log_file.log"); 2];
+        let mut runner = Runner::new();
+        let final_agg = runner.run(num_threads, &mut files).unwrap();
+        runner.shutdown();
+
+        let aggregates = output_aggregates(final_agg.aggregation,
+                                           None,
+                                           record_handling::SortOrder::Key,
+                                           false);
+        let mut output = Vec::new();
+        assert!(write_results(&runtime_context,
+                              &OutputAggregates::Sorted(&aggregates),
+                              &final_agg.timespans,
+                              &final_agg.aggregate_metrics,
+                              &final_agg.file_subtotals,
+                              &mut output));
+        output
+    }
+
+    #[test]
+    fn write_output_should_write_a_complete_gzip_stream_when_compressing() {
+        use std::io::Read;
+
+        let arg_vec = vec!["counter", "--compress", "gzip", "~/logs"];
+        let runtime_context = RuntimeContext::new_test_runtime_context(arg_vec);
+        let mut files = vec![PathBuf::from("./test_artifacts/test_elb_log_file.log")];
+        let mut runner = Runner::new();
+        let final_agg = runner.run(2, &mut files).unwrap();
+        runner.shutdown();
+        let aggregates = output_aggregates(final_agg.aggregation,
+                                           None,
+                                           record_handling::SortOrder::Key,
+                                           false);
+
+        let mut compressed = Vec::new();
+        assert!(write_output(&runtime_context,
+                             &OutputAggregates::Sorted(&aggregates),
+                             &final_agg.timespans,
+                             &final_agg.aggregate_metrics,
+                             &final_agg.file_subtotals,
+                             &mut compressed));
+
+        let mut decompressed = Vec::new();
+        flate2::read::GzDecoder::new(&compressed[..])
+            .unwrap()
+            .read_to_end(&mut decompressed)
+            .unwrap();
+        let mut uncompressed = Vec::new();
+        assert!(write_results(&runtime_context,
+                              &OutputAggregates::Sorted(&aggregates),
+                              &final_agg.timespans,
+                              &final_agg.aggregate_metrics,
+                              &final_agg.file_subtotals,
+                              &mut uncompressed));
+        assert!(!uncompressed.is_empty());
+        assert_eq!(decompressed, uncompressed)
+    }
+
+    #[test]
+    fn write_results_should_start_the_csv_output_with_a_header_row() {
+        let output = String::from_utf8(run_and_write(2)).unwrap();
+
+        assert_eq!(output.lines().next(), Some("system_name,day,client_address,total"))
+    }
+
+    #[test]
+    fn write_results_should_write_the_metrics_of_a_sink_dry_run() {
+        let arg_vec = vec!["counter",
+                           "--sink",
+                           "statsd://localhost",
+                           "--metric-prefix",
+                           "elb.requests",
+                           "--sink-dry-run",
+                           "~/logs"];
+        let runtime_context = RuntimeContext::new_test_runtime_context(arg_vec);
+        let mut files = vec![PathBuf::from("./test_artifacts/test_elb_log_file.log")];
+        let mut runner = Runner::new();
+        let final_agg = runner.run(2, &mut files).unwrap();
+        runner.shutdown();
+        let aggregates = output_aggregates(final_agg.aggregation,
+                                           None,
+                                           record_handling::SortOrder::Key,
+                                           false);
+
+        let mut output = Vec::new();
+        assert!(write_results(&runtime_context,
+                              &OutputAggregates::Sorted(&aggregates),
+                              &final_agg.timespans,
+                              &final_agg.aggregate_metrics,
+                              &final_agg.file_subtotals,
+                              &mut output));
+
+        let output = String::from_utf8(output).unwrap();
+        assert_eq!(output.lines().count(), aggregates.len());
+        assert!(output.lines()
+            .all(|line| line.starts_with("elb.requests.") && line.ends_with("|g")))
+    }
+
+    #[test]
+    fn write_results_should_write_the_spilled_aggregates_as_it_writes_the_sorted_ones() {
+        let runtime_context = RuntimeContext::new_test_runtime_context(vec!["counter", "~/logs"]);
+        let mut files = vec![PathBuf::from("./test_artifacts/test_elb_log_file.log"); 2];
+        let config = file_handling::FileAggregatorConfig {
+            spill_after: Some(10),
+            ..Default::default()
+        };
+        let mut runner = Runner::with_config(config);
+        runner.keep_spill_files();
+        let final_agg = runner.run(2, &mut files).unwrap();
+        runner.shutdown();
+        let spilled = OutputAggregates::Spilled {
+            spill_files: &final_agg.spill_files,
+            policy: record_handling::CountOverflowPolicy::Error,
+            totals: Cell::new(Default::default()),
+        };
+
+        let mut output = Vec::new();
+        assert!(write_results(&runtime_context,
+                              &spilled,
+                              &final_agg.timespans,
+                              &final_agg.aggregate_metrics,
+                              &final_agg.file_subtotals,
+                              &mut output));
+
+        spill::remove_spill_files(&final_agg.spill_files);
+        assert!(final_agg.aggregation.is_empty());
+        assert_eq!(output, run_and_write(2));
+        if let OutputAggregates::Spilled { ref totals, .. } = spilled {
+            assert_eq!(totals.get().num_overflows, 0);
+            let num_lines = output.iter().filter(|&&byte| byte == b'\n').count();
+            assert_eq!(totals.get().num_aggregates, num_lines - 1)
+        }
+    }
+
+    #[test]
+    fn write_results_should_write_identical_output_for_the_same_logs() {
+        let output = run_and_write(1);
+        let other_output = run_and_write(4);
+
+        assert!(!output.is_empty());
+        assert_eq!(output, other_output)
+    }
+
+    #[test]
+    fn output_aggregates_should_order_the_aggregates_by_count_when_asked_to() {
+        let mut files = vec![PathBuf::from("./test_artifacts/test_elb_log_file.log")];
+        let mut runner = Runner::new();
+        let final_agg = runner.run(2, &mut files).unwrap();
+        runner.shutdown();
+
+        let aggregates = output_aggregates(final_agg.aggregation,
+                                           None,
+                                           record_handling::SortOrder::Count,
+                                           true);
+
+        assert!(aggregates.windows(2).all(|pair| pair[0].1 <= pair[1].1))
+    }
+
+    #[test]
+    fn output_aggregates_should_order_the_aggregates_by_key() {
+        let mut files = vec![PathBuf::from("./test_artifacts/test_elb_log_file.log")];
+        let mut runner = Runner::new();
+        let final_agg = runner.run(2, &mut files).unwrap();
+        runner.shutdown();
+
+        let aggregates = output_aggregates(final_agg.aggregation,
+                                           None,
+                                           record_handling::SortOrder::Key,
+                                           false);
+
+        assert!(aggregates.windows(2).all(|pair| {
+            record_handling::compare_by_key(&pair[0].0, &pair[1].0) != ::std::cmp::Ordering::Greater
+        }))
+    }
+
+    #[test]
+    fn flush_watch_should_stop_the_watch_when_the_totals_overflowed() {
+        let runtime_context = RuntimeContext::new_test_runtime_context(vec!["counter", "--watch",
+                                                                            "~/logs"]);
+        let mut file_agg = counter::FileAggregation::default();
+        file_agg.num_count_overflows = 1;
+
+        assert!(!flush_watch(&runtime_context, &file_agg, None))
+    }
+
+    #[test]
+    fn flush_watch_should_write_the_aggregates_at_or_above_the_count_floor() {
+        let path = env::temp_dir().join("counter_flush_watch_count_floor.csv");
+        let runtime_context = RuntimeContext::new_test_runtime_context(vec!["counter",
+                                                                            "--watch",
+                                                                            "--count-floor",
+                                                                            "2",
+                                                                            "--output",
+                                                                            path.to_str()
+                                                                                .unwrap(),
+                                                                            "~/logs"]);
+        let mut files = vec![PathBuf::from("./test_artifacts/test_elb_log_file.log")];
+        let mut runner = Runner::new();
+        let file_agg = runner.run(1, &mut files).unwrap();
+        runner.shutdown();
+        let num_floored = file_agg.aggregation.values().filter(|&&total| total >= 2).count();
+
+        assert!(flush_watch(&runtime_context, &file_agg, None));
+
+        let mut output = String::new();
+        File::open(&path).unwrap().read_to_string(&mut output).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(output.lines().count(), num_floored + 1)
+    }
+}
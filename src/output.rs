@@ -0,0 +1,1527 @@
+use std::borrow::Cow;
+use std::io;
+use std::io::{BufRead, Write};
+use std::collections::{BTreeMap, BTreeSet};
+use std::mem;
+use std::path::PathBuf;
+use chrono::{Date, Datelike, UTC};
+use rustc_serialize::json::{Json, ToJson};
+use record_handling;
+use spill;
+use record_handling::{AggregateELBRecord, AggregateMetrics, Metrics, Timespan, Timespans};
+
+/// A destination for the aggregates of a run. Implement it to write the aggregates to a backend
+/// counter doesn't know about, then hand it to `write_aggregates`.
+pub trait AggregateSink {
+    /// Called once for every aggregate, in the order the aggregates are given.
+    fn write_aggregate(&mut self, key: &AggregateELBRecord, total: i64) -> io::Result<()>;
+
+    /// Called in place of `write_aggregate` by `write_timespan_aggregates`, with the
+    /// timespan of the aggregate when one was kept. Sinks that can't write timespans leave them
+    /// out.
+    fn write_timespan_aggregate(&mut self,
+                                key: &AggregateELBRecord,
+                                total: i64,
+                                _timespan: Option<&Timespan>)
+                                -> io::Result<()> {
+        self.write_aggregate(key, total)
+    }
+
+    /// Called in place of `write_timespan_aggregate` by `write_metrics_aggregates`, with the
+    /// metrics of the aggregate when they were kept. Sinks that can't write metrics leave them
+    /// out.
+    fn write_metrics_aggregate(&mut self,
+                               key: &AggregateELBRecord,
+                               total: i64,
+                               timespan: Option<&Timespan>,
+                               _metrics: Option<&AggregateMetrics>)
+                               -> io::Result<()> {
+        self.write_timespan_aggregate(key, total, timespan)
+    }
+
+    /// Called once after the last aggregate, to flush anything the sink has buffered.
+    fn finish(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Writes every aggregate to the sink and then finishes it, stopping at the first error.
+pub fn write_aggregates<S: AggregateSink + ?Sized>(aggregates: &[(AggregateELBRecord, i64)],
+                                                   sink: &mut S)
+                                                   -> io::Result<()> {
+    for &(ref key, total) in aggregates {
+        sink.write_aggregate(key, total)?;
+    }
+    sink.finish()
+}
+
+/// Writes every aggregate along with its timespan to the sink and then finishes it, stopping at
+/// the first error.
+pub fn write_timespan_aggregates<S>(aggregates: &[(AggregateELBRecord, i64)],
+                                    timespans: &Timespans,
+                                    sink: &mut S)
+                                    -> io::Result<()>
+    where S: AggregateSink + ?Sized
+{
+    for &(ref key, total) in aggregates {
+        sink.write_timespan_aggregate(key, total, timespans.get(key))?;
+    }
+    sink.finish()
+}
+
+/// Writes every aggregate along with its timespan and metrics to the sink and then finishes it,
+/// stopping at the first error.
+pub fn write_metrics_aggregates<S>(aggregates: &[(AggregateELBRecord, i64)],
+                                   timespans: &Timespans,
+                                   metrics: &Metrics,
+                                   sink: &mut S)
+                                   -> io::Result<()>
+    where S: AggregateSink + ?Sized
+{
+    for &(ref key, total) in aggregates {
+        sink.write_metrics_aggregate(key, total, timespans.get(key), metrics.get(key))?;
+    }
+    sink.finish()
+}
+
+/// What `write_spilled_aggregates` counted while merging the aggregates it wrote.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct SpilledTotals {
+    pub num_aggregates: usize,
+    /// The totals that would have overflowed, see `spill::for_each_spilled_aggregate`.
+    pub num_overflows: usize,
+    /// The totals that stopped at `record_handling::SATURATED_TOTAL`.
+    pub num_saturated: usize,
+}
+
+/// Writes every aggregate of the spill files to the sink as they're merged, in key order, and
+/// then finishes it, stopping at the first error. The aggregates are never all in memory.
+pub fn write_spilled_aggregates<S>(spill_files: &[PathBuf],
+                                   policy: record_handling::CountOverflowPolicy,
+                                   sink: &mut S)
+                                   -> io::Result<SpilledTotals>
+    where S: AggregateSink + ?Sized
+{
+    let mut totals = SpilledTotals::default();
+    let num_overflows = spill::for_each_spilled_aggregate(spill_files, policy, |key, total| {
+            totals.num_aggregates += 1;
+            if total == record_handling::SATURATED_TOTAL {
+                totals.num_saturated += 1;
+            }
+            sink.write_aggregate(&key, total)
+        })?;
+    totals.num_overflows = num_overflows;
+    sink.finish()?;
+    Ok(totals)
+}
+
+/// The percentiles of the latency written with the metrics of an aggregate.
+const LATENCY_PERCENTILES: [(&'static str, f64); 3] =
+    [("latency_p50", 50.0), ("latency_p95", 95.0), ("latency_p99", 99.0)];
+
+/// The names of the metrics written after the total and timespan, in the order of the CSV
+/// columns.
+const METRIC_NAMES: [&'static str; 11] = ["received_bytes",
+                                          "sent_bytes",
+                                          "latency_min",
+                                          "latency_max",
+                                          "latency_p50",
+                                          "latency_p95",
+                                          "latency_p99",
+                                          "status_2xx",
+                                          "status_3xx",
+                                          "status_4xx",
+                                          "status_5xx"];
+
+// The ELB logs processing times to the microsecond, so the latencies are rounded to it rather
+// than written with the noise of summing them as floats.
+fn rounded_latency(latency: f64) -> f64 {
+    (latency * 1_000_000.0).round() / 1_000_000.0
+}
+
+/// The metric values in the order of `METRIC_NAMES`, with null latencies when no record of the
+/// aggregate had one.
+fn metric_values(metrics: &AggregateMetrics) -> Vec<Json> {
+    let latency = |latency: Option<f64>| {
+        latency.map_or(Json::Null, |latency| rounded_latency(latency).to_json())
+    };
+    let mut values = vec![metrics.received_bytes.to_json(),
+                          metrics.sent_bytes.to_json(),
+                          latency(metrics.min_latency),
+                          latency(metrics.max_latency)];
+    for &(_, percentile) in &LATENCY_PERCENTILES {
+        values.push(latency(metrics.latency_percentile(percentile)));
+    }
+    values.extend(metrics.status_classes.iter().map(|count| count.to_json()));
+    values
+}
+
+fn insert_metrics(object: &mut BTreeMap<String, Json>, metrics: Option<&AggregateMetrics>) -> () {
+    if let Some(metrics) = metrics {
+        for (name, value) in METRIC_NAMES.iter().zip(metric_values(metrics)) {
+            object.insert((*name).to_owned(), value);
+        }
+    }
+}
+
+fn insert_timespan(object: &mut BTreeMap<String, Json>, timespan: Option<&Timespan>) -> () {
+    if let Some(timespan) = timespan {
+        object.insert("first_seen".to_owned(), timespan.first_seen.to_rfc3339().to_json());
+        object.insert("last_seen".to_owned(), timespan.last_seen.to_rfc3339().to_json());
+    }
+}
+
+/// Renders an aggregate as a JSON object of its key fields and total, with the path dimension
+/// under its name.
+pub fn aggregate_json(agg_key: &AggregateELBRecord,
+                      total: i64,
+                      path_dimension_name: Option<&str>)
+                      -> Json {
+    let mut object = aggregate_object(agg_key, path_dimension_name);
+    object.insert("total".to_owned(), total.to_json());
+    Json::Object(object)
+}
+
+/// Renders an aggregate the way `JsonSink` writes it, as a JSON object of its key fields, its
+/// total under `count` and its timespan and metrics when it has them.
+pub fn counted_aggregate_json(agg_key: &AggregateELBRecord,
+                              total: i64,
+                              timespan: Option<&Timespan>,
+                              metrics: Option<&AggregateMetrics>,
+                              path_dimension_name: Option<&str>)
+                              -> Json {
+    let mut object = aggregate_object(agg_key, path_dimension_name);
+    object.insert("count".to_owned(), total.to_json());
+    insert_timespan(&mut object, timespan);
+    insert_metrics(&mut object, metrics);
+    Json::Object(object)
+}
+
+fn aggregate_object(agg_key: &AggregateELBRecord,
+                    path_dimension_name: Option<&str>)
+                    -> BTreeMap<String, Json> {
+    let mut object = BTreeMap::new();
+    object.insert("system_name".to_owned(), agg_key.system_name.to_json());
+    match agg_key.day_of_month {
+        Some(day_of_month) => object.insert("day_of_month".to_owned(), day_of_month.to_json()),
+        None => {
+            object.insert("day".to_owned(),
+                          agg_key.day.format("%Y-%m-%d").to_string().to_json())
+        }
+    };
+    if let Some(address) = agg_key.client_address {
+        object.insert("client_address".to_owned(), address.to_string().to_json());
+    }
+    if let Some(address) = agg_key.backend_address {
+        object.insert("backend_address".to_owned(), address.to_string().to_json());
+    }
+    if let Some(ref url) = agg_key.url {
+        object.insert("url".to_owned(), url.to_json());
+    }
+    if let Some(ref port) = agg_key.listener_port {
+        object.insert("listener_port".to_owned(), port.to_json());
+    }
+    if let Some(hour) = agg_key.hour {
+        object.insert("hour".to_owned(), hour.to_json());
+    }
+    if let Some(code) = agg_key.elb_status_code {
+        object.insert("elb_status_code".to_owned(), code.to_json());
+    }
+    if let (Some(name), Some(value)) = (path_dimension_name, agg_key.path_dimension.as_ref()) {
+        object.insert(name.to_owned(), value.to_json());
+    }
+    if let Some(ref bucket) = agg_key.latency_bucket {
+        object.insert("latency_bucket".to_owned(), bucket.to_json());
+    }
+    object
+}
+
+/// Renders the number of records aggregated from every file as a JSON array of objects holding
+/// the file and its number of records.
+pub fn file_subtotals_json(file_subtotals: &BTreeMap<PathBuf, u64>) -> Json {
+    Json::Array(file_subtotals.iter()
+        .map(|(path, num_records)| {
+            let mut object = BTreeMap::new();
+            object.insert("file".to_owned(), path.display().to_string().to_json());
+            object.insert("records".to_owned(), num_records.to_json());
+            Json::Object(object)
+        })
+        .collect())
+}
+
+/// Renders a system's total, as listed by `record_handling::totals_by_system`, as a JSON object.
+pub fn system_total_json(system_name: &str, total: i64) -> Json {
+    let mut object = BTreeMap::new();
+    object.insert("system_name".to_owned(), system_name.to_json());
+    object.insert("total".to_owned(), total.to_json());
+    Json::Object(object)
+}
+
+/// Describes the columns written in `format`, in the order they are written, as a JSON object
+/// holding an array of their names and types under `columns`. The types are string, date,
+/// timestamp, integer or number, which the latency metrics are in seconds. The columns of CSV and
+/// TSV are named after the JSON fields, as in their header row, with the total named
+/// `total_bytes` when weighted by a byte field, see `total_column_name`. Hashed keys only have
+/// the system column, which holds the hash, and the day. The matrix format has a column for
+/// every day in the logs, so it can't be described without reading them.
+pub fn schema_json(format: record_handling::OutputFormat,
+                   group_by: &[record_handling::Dimension],
+                   path_dimension_name: Option<&str>,
+                   config: &record_handling::RecordConfig,
+                   list_systems: bool,
+                   with_timespans: bool,
+                   with_metrics: bool)
+                   -> Result<Json, String> {
+    use record_handling::{Dimension, OutputFormat};
+
+    let influx = format == OutputFormat::Influx;
+    let mut columns = Vec::new();
+    match format {
+        OutputFormat::Discard => {}
+        OutputFormat::Matrix => {
+            return Err("The matrix format has a column for every day in the logs, so its schema \
+                        can't be printed without reading them."
+                .to_owned())
+        }
+        _ if list_systems => {
+            columns.push((if influx { "system" } else { "system_name" }, "string"))
+        }
+        _ => {
+            let is_written = |dimension| {
+                !config.hash_keys || dimension == Dimension::System || dimension == Dimension::Day
+            };
+            for dimension in group_by.iter().filter(|&&dimension| is_written(dimension)) {
+                let column = match (*dimension, influx) {
+                    // The day is the timestamp of an Influx point.
+                    (Dimension::Day, _) if config.day_of_month => ("day_of_month", "integer"),
+                    (Dimension::Day, true) => continue,
+                    (Dimension::Day, false) => ("day", "date"),
+                    (dimension, true) => (dimension.name(), "string"),
+                    (dimension @ Dimension::Hour, false) |
+                    (dimension @ Dimension::Status, false) => {
+                        (dimension_column_name(dimension, false), "integer")
+                    }
+                    (dimension, false) => (dimension_column_name(dimension, false), "string"),
+                };
+                columns.push(column);
+            }
+            match path_dimension_name {
+                Some(name) if !config.hash_keys => columns.push((name, "string")),
+                _ => {}
+            }
+            if config.latency_buckets.is_some() && !config.hash_keys {
+                columns.push((if influx { "latency" } else { "latency_bucket" }, "string"));
+            }
+        }
+    }
+    if let Some(name) = total_column_name(format, list_systems, config) {
+        columns.push((name, "integer"));
+    }
+    let writes_timespans = match format {
+        OutputFormat::Csv | OutputFormat::Tsv | OutputFormat::Json | OutputFormat::JsonLines => {
+            with_timespans && !list_systems
+        }
+        #[cfg(feature = "yaml")]
+        OutputFormat::Yaml => with_timespans && !list_systems,
+        _ => false,
+    };
+    if writes_timespans {
+        columns.push(("first_seen", "timestamp"));
+        columns.push(("last_seen", "timestamp"));
+    }
+    let writes_metrics = match format {
+        OutputFormat::Csv | OutputFormat::Tsv | OutputFormat::Json | OutputFormat::JsonLines => {
+            with_metrics && !list_systems
+        }
+        #[cfg(feature = "yaml")]
+        OutputFormat::Yaml => with_metrics && !list_systems,
+        _ => false,
+    };
+    if writes_metrics {
+        for name in &METRIC_NAMES {
+            columns.push((*name, if name.starts_with("latency") { "number" } else { "integer" }));
+        }
+    }
+    if influx && !list_systems && !config.day_of_month {
+        columns.push(("time", "date"));
+    }
+
+    let columns = columns.into_iter()
+        .map(|(name, column_type)| {
+            let mut column = BTreeMap::new();
+            column.insert("name".to_owned(), name.to_json());
+            column.insert("type".to_owned(), column_type.to_json());
+            Json::Object(column)
+        })
+        .collect::<Vec<_>>();
+    let mut schema = BTreeMap::new();
+    schema.insert("columns".to_owned(), Json::Array(columns));
+    Ok(Json::Object(schema))
+}
+
+/// The name of the column or field holding the total of an aggregate in `format`, None when
+/// nothing is written.
+pub fn total_column_name(format: record_handling::OutputFormat,
+                         list_systems: bool,
+                         config: &record_handling::RecordConfig)
+                         -> Option<&'static str> {
+    use record_handling::OutputFormat;
+
+    match format {
+        OutputFormat::Discard => None,
+        OutputFormat::Json if !list_systems => Some("count"),
+        #[cfg(feature = "yaml")]
+        OutputFormat::Yaml if !list_systems => Some("count"),
+        OutputFormat::Influx => Some("count"),
+        OutputFormat::Csv | OutputFormat::Tsv if config.weight_field.is_some() => {
+            Some("total_bytes")
+        }
+        _ => Some("total"),
+    }
+}
+
+/// The name of the column or field holding a dimension, as the JSON and CSV output name it.
+pub fn dimension_column_name(dimension: record_handling::Dimension,
+                             day_of_month: bool)
+                             -> &'static str {
+    use record_handling::Dimension;
+
+    match dimension {
+        Dimension::Day if day_of_month => "day_of_month",
+        Dimension::Day => "day",
+        Dimension::System => "system_name",
+        Dimension::Client => "client_address",
+        Dimension::Backend => "backend_address",
+        Dimension::ListenerPort => "listener_port",
+        Dimension::Hour => "hour",
+        Dimension::Status => "elb_status_code",
+        dimension => dimension.name(),
+    }
+}
+
+/// The names of the column holding the total in CSV output, see `total_column_name`.
+const CSV_TOTAL_COLUMN_NAMES: [&'static str; 2] = ["total", "total_bytes"];
+
+/// Reads the system totals of a prior run, summing the totals of the rows of each system, from
+/// the system,total rows written by --list-systems or the aggregate rows of a CSV run. A header
+/// row names the system_name and total columns, and without one the system is the first column
+/// and the total the last. Quoted fields are read back as `quote_field` wrote them, and blank
+/// lines are skipped.
+pub fn read_system_totals<R: BufRead>(reader: R) -> Result<BTreeMap<String, i64>, String> {
+    let mut totals = BTreeMap::new();
+    let mut is_first_row = true;
+    let mut header_columns = None;
+    for (i, line) in reader.lines().enumerate() {
+        let line = line.map_err(|e| e.to_string())?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let fields = csv_fields(&line, record_handling::CSV_DELIMITER);
+        if mem::replace(&mut is_first_row, false) {
+            let system_column = fields.iter().position(|field| field == "system_name");
+            let total_column = fields.iter()
+                .position(|field| CSV_TOTAL_COLUMN_NAMES.contains(&field.as_str()));
+            if let (Some(system_column), Some(total_column)) = (system_column, total_column) {
+                header_columns = Some((system_column, total_column));
+                continue;
+            }
+        }
+        let (system_column, total_column) = header_columns.unwrap_or((0, fields.len() - 1));
+        let total = fields.get(total_column).and_then(|total| total.trim().parse::<i64>().ok());
+        match (fields.get(system_column), total) {
+            (Some(system_name), Some(total)) if fields.len() > 1 => {
+                let system_total = totals.entry(system_name.clone()).or_insert(0);
+                *system_total = system_total.saturating_add(total);
+            }
+            _ => return Err(format!("Line {} has no system and total.", i + 1)),
+        }
+    }
+    Ok(totals)
+}
+
+/// Splits a CSV row into its fields, unquoting the fields `quote_field` quoted.
+fn csv_fields(row: &str, delimiter: char) -> Vec<String> {
+    let mut fields = Vec::new();
+    let mut field = String::new();
+    let mut is_quoted = false;
+    let mut chars = row.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if is_quoted && chars.peek() == Some(&'"') => {
+                chars.next();
+                field.push('"');
+            }
+            '"' if is_quoted => is_quoted = false,
+            '"' if field.is_empty() => is_quoted = true,
+            c if c == delimiter && !is_quoted => {
+                fields.push(mem::replace(&mut field, String::new()))
+            }
+            c => field.push(c),
+        }
+    }
+    fields.push(field);
+    fields
+}
+
+/// Quotes a field holding the delimiter, a quote or a line break, doubling the quotes inside it,
+/// so that the field reads back as one.
+pub fn quote_field(field: &str, delimiter: char) -> Cow<str> {
+    if field.contains(|c: char| c == delimiter || c == '"' || c == '\n' || c == '\r') {
+        Cow::Owned(format!("\"{}\"", field.replace('"', "\"\"")))
+    } else {
+        Cow::Borrowed(field)
+    }
+}
+
+/// The aggregate as it should be displayed, with its fields truncated when there is a limit.
+pub fn displayed(key: &AggregateELBRecord,
+                 max_field_chars: Option<usize>)
+                 -> Cow<AggregateELBRecord> {
+    match max_field_chars {
+        Some(max_chars) => Cow::Owned(record_handling::truncate_fields(key, max_chars)),
+        None => Cow::Borrowed(key),
+    }
+}
+
+/// Writes aggregates as CSV rows of the dimensions they're grouped by, system, day and client
+/// unless ordered otherwise, the path dimension and latency bucket when the run has them, and
+/// total, followed by the timespan and metrics when written with them. The latencies of metrics
+/// without any are left empty. Fields are quoted when they have to be, see `quote_field`. When
+/// asked to, a header row naming the columns like `schema_json` is written before the first row.
+pub struct CsvSink<W: Write> {
+    writer: W,
+    delimiter: char,
+    columns: Vec<record_handling::Dimension>,
+    max_field_chars: Option<usize>,
+    header: Option<CsvHeader>,
+}
+
+/// The names of the header row of a `CsvSink` that aren't those of the dimensions.
+struct CsvHeader {
+    path_dimension_name: Option<String>,
+    total_name: String,
+    written: bool,
+}
+
+impl<W: Write> CsvSink<W> {
+    pub fn new(writer: W) -> CsvSink<W> {
+        CsvSink::with_delimiter(writer, record_handling::CSV_DELIMITER)
+    }
+
+    /// Separates the fields with `delimiter` instead of a comma, such as a tab for TSV rows.
+    pub fn with_delimiter(writer: W, delimiter: char) -> CsvSink<W> {
+        CsvSink {
+            writer: writer,
+            delimiter: delimiter,
+            columns: record_handling::DEFAULT_GROUP_BY.to_vec(),
+            max_field_chars: None,
+            header: None,
+        }
+    }
+
+    /// Writes a header row before the first row, or on finishing when there are no rows, naming
+    /// the path dimension column `path_dimension_name` and the total `total_name`. The columns
+    /// are those of the first row, so the header of a sink without rows only names the
+    /// dimensions and total.
+    pub fn include_header(&mut self, path_dimension_name: Option<String>, total_name: &str) {
+        self.header = Some(CsvHeader {
+            path_dimension_name: path_dimension_name,
+            total_name: total_name.to_owned(),
+            written: false,
+        });
+    }
+
+    fn write_header(&mut self,
+                    key: Option<&AggregateELBRecord>,
+                    with_timespan: bool,
+                    with_metrics: bool)
+                    -> io::Result<()> {
+        let mut names = Vec::new();
+        {
+            let header = match self.header {
+                Some(ref header) if !header.written => header,
+                _ => return Ok(()),
+            };
+            let day_of_month = key.map_or(false, |key| key.day_of_month.is_some());
+            for &dimension in &self.columns {
+                if key.map_or(true, |key| key.dimension_value(dimension).is_some()) {
+                    names.push(dimension_column_name(dimension, day_of_month).to_owned());
+                }
+            }
+            if key.map_or(false, |key| key.path_dimension.is_some()) {
+                names.push(header.path_dimension_name
+                    .clone()
+                    .unwrap_or_else(|| "path_dimension".to_owned()));
+            }
+            if key.map_or(false, |key| key.latency_bucket.is_some()) {
+                names.push("latency_bucket".to_owned());
+            }
+            names.push(header.total_name.clone());
+            if with_timespan {
+                names.push("first_seen".to_owned());
+                names.push("last_seen".to_owned());
+            }
+            if with_metrics {
+                names.extend(METRIC_NAMES.iter().map(|name| (*name).to_owned()));
+            }
+        }
+        let delimiter = self.delimiter;
+        let names = names.iter().map(|name| quote_field(name, delimiter)).collect::<Vec<_>>();
+        writeln!(self.writer, "{}", names.join(&delimiter.to_string()))?;
+        if let Some(ref mut header) = self.header {
+            header.written = true;
+        }
+        Ok(())
+    }
+
+    /// Writes the dimensions in this order, which should be the ones the aggregates are grouped
+    /// by. Dimensions an aggregate isn't grouped by are left out of its row.
+    pub fn order_columns_by(&mut self, dimensions: &[record_handling::Dimension]) {
+        self.columns = dimensions.to_vec();
+    }
+
+    /// Truncates the text fields written to `max_chars` characters, see
+    /// `record_handling::truncate_field`.
+    pub fn truncate_fields_to(&mut self, max_chars: usize) {
+        self.max_field_chars = Some(max_chars);
+    }
+}
+
+impl<W: Write> AggregateSink for CsvSink<W> {
+    fn write_aggregate(&mut self, key: &AggregateELBRecord, total: i64) -> io::Result<()> {
+        self.write_metrics_aggregate(key, total, None, None)
+    }
+
+    /// Writes the first and last seen timestamps after the total.
+    fn write_timespan_aggregate(&mut self,
+                                key: &AggregateELBRecord,
+                                total: i64,
+                                timespan: Option<&Timespan>)
+                                -> io::Result<()> {
+        self.write_metrics_aggregate(key, total, timespan, None)
+    }
+
+    /// Writes the metrics after the total and timespan.
+    fn write_metrics_aggregate(&mut self,
+                               key: &AggregateELBRecord,
+                               total: i64,
+                               timespan: Option<&Timespan>,
+                               metrics: Option<&AggregateMetrics>)
+                               -> io::Result<()> {
+        let key = displayed(key, self.max_field_chars);
+        self.write_header(Some(&key), timespan.is_some(), metrics.is_some())?;
+        let delimiter = self.delimiter;
+        let fields = self.columns.iter().filter_map(|&dimension| key.dimension_value(dimension));
+        for (i, field) in fields.enumerate() {
+            if i > 0 {
+                write!(self.writer, "{}", delimiter)?;
+            }
+            write!(self.writer, "{}", quote_field(&field, delimiter))?;
+        }
+        if let Some(ref path_dimension) = key.path_dimension {
+            write!(self.writer, "{}{}", delimiter, quote_field(path_dimension, delimiter))?;
+        }
+        if let Some(ref latency_bucket) = key.latency_bucket {
+            write!(self.writer, "{}{}", delimiter, latency_bucket)?;
+        }
+        write!(self.writer, "{}{}", delimiter, total)?;
+        if let Some(timespan) = timespan {
+            write!(self.writer,
+                   "{}{}{}{}",
+                   delimiter,
+                   timespan.first_seen.to_rfc3339(),
+                   delimiter,
+                   timespan.last_seen.to_rfc3339())?;
+        }
+        if let Some(metrics) = metrics {
+            for value in metric_values(metrics) {
+                match value {
+                    Json::Null => write!(self.writer, "{}", delimiter)?,
+                    value => write!(self.writer, "{}{}", delimiter, value)?,
+                }
+            }
+        }
+        writeln!(self.writer)
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        self.write_header(None, false, false)?;
+        self.writer.flush()
+    }
+}
+
+/// Writes aggregates as InfluxDB line protocol points, see `record_handling::influx_line`.
+pub struct InfluxSink<W: Write> {
+    writer: W,
+    path_dimension_name: Option<String>,
+    max_field_chars: Option<usize>,
+}
+
+impl<W: Write> InfluxSink<W> {
+    pub fn new(writer: W, path_dimension_name: Option<String>) -> InfluxSink<W> {
+        InfluxSink {
+            writer: writer,
+            path_dimension_name: path_dimension_name,
+            max_field_chars: None,
+        }
+    }
+
+    /// Truncates the tag values written to `max_chars` characters, see
+    /// `record_handling::truncate_field`.
+    pub fn truncate_fields_to(&mut self, max_chars: usize) {
+        self.max_field_chars = Some(max_chars);
+    }
+}
+
+impl<W: Write> AggregateSink for InfluxSink<W> {
+    fn write_aggregate(&mut self, key: &AggregateELBRecord, total: i64) -> io::Result<()> {
+        let key = displayed(key, self.max_field_chars);
+        let path_dimension_name = self.path_dimension_name.as_ref().map(|name| name.as_str());
+        writeln!(self.writer,
+                 "{}",
+                 record_handling::influx_line(&key, total, path_dimension_name))
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+/// Writes aggregates as newline delimited JSON, one `aggregate_json` object per line.
+pub struct JsonLinesSink<W: Write> {
+    writer: W,
+    path_dimension_name: Option<String>,
+    max_field_chars: Option<usize>,
+}
+
+impl<W: Write> JsonLinesSink<W> {
+    pub fn new(writer: W, path_dimension_name: Option<String>) -> JsonLinesSink<W> {
+        JsonLinesSink {
+            writer: writer,
+            path_dimension_name: path_dimension_name,
+            max_field_chars: None,
+        }
+    }
+
+    /// Truncates the text values written to `max_chars` characters, see
+    /// `record_handling::truncate_field`.
+    pub fn truncate_fields_to(&mut self, max_chars: usize) {
+        self.max_field_chars = Some(max_chars);
+    }
+}
+
+impl<W: Write> AggregateSink for JsonLinesSink<W> {
+    fn write_aggregate(&mut self, key: &AggregateELBRecord, total: i64) -> io::Result<()> {
+        let key = displayed(key, self.max_field_chars);
+        let path_dimension_name = self.path_dimension_name.as_ref().map(|name| name.as_str());
+        writeln!(self.writer, "{}", aggregate_json(&key, total, path_dimension_name))
+    }
+
+    fn write_timespan_aggregate(&mut self,
+                                key: &AggregateELBRecord,
+                                total: i64,
+                                timespan: Option<&Timespan>)
+                                -> io::Result<()> {
+        self.write_metrics_aggregate(key, total, timespan, None)
+    }
+
+    fn write_metrics_aggregate(&mut self,
+                               key: &AggregateELBRecord,
+                               total: i64,
+                               timespan: Option<&Timespan>,
+                               metrics: Option<&AggregateMetrics>)
+                               -> io::Result<()> {
+        let key = displayed(key, self.max_field_chars);
+        let path_dimension_name = self.path_dimension_name.as_ref().map(|name| name.as_str());
+        let mut object = aggregate_object(&key, path_dimension_name);
+        object.insert("total".to_owned(), total.to_json());
+        insert_timespan(&mut object, timespan);
+        insert_metrics(&mut object, metrics);
+        writeln!(self.writer, "{}", Json::Object(object))
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+/// Writes aggregates as a single JSON array of objects holding the key fields of an aggregate and
+/// its total under `count`. The array is only complete once the sink is finished. When given file
+/// subtotals, the array is written under `aggregates` in an object holding the subtotals under
+/// `file_subtotals`.
+pub struct JsonSink<W: Write> {
+    writer: W,
+    path_dimension_name: Option<String>,
+    max_field_chars: Option<usize>,
+    file_subtotals: Option<Json>,
+    num_written: usize,
+}
+
+impl<W: Write> JsonSink<W> {
+    pub fn new(writer: W, path_dimension_name: Option<String>) -> JsonSink<W> {
+        JsonSink {
+            writer: writer,
+            path_dimension_name: path_dimension_name,
+            max_field_chars: None,
+            file_subtotals: None,
+            num_written: 0,
+        }
+    }
+
+    /// Truncates the text values written to `max_chars` characters, see
+    /// `record_handling::truncate_field`.
+    pub fn truncate_fields_to(&mut self, max_chars: usize) {
+        self.max_field_chars = Some(max_chars);
+    }
+
+    /// Writes the subtotals, see `file_subtotals_json`, alongside the aggregates.
+    pub fn include_file_subtotals(&mut self, file_subtotals: Json) {
+        self.file_subtotals = Some(file_subtotals);
+    }
+
+    fn write_opening(&mut self) -> io::Result<()> {
+        if self.file_subtotals.is_some() {
+            write!(self.writer, "{{\"aggregates\":[")
+        } else {
+            write!(self.writer, "[")
+        }
+    }
+}
+
+impl<W: Write> AggregateSink for JsonSink<W> {
+    fn write_aggregate(&mut self, key: &AggregateELBRecord, total: i64) -> io::Result<()> {
+        self.write_metrics_aggregate(key, total, None, None)
+    }
+
+    fn write_timespan_aggregate(&mut self,
+                                key: &AggregateELBRecord,
+                                total: i64,
+                                timespan: Option<&Timespan>)
+                                -> io::Result<()> {
+        self.write_metrics_aggregate(key, total, timespan, None)
+    }
+
+    fn write_metrics_aggregate(&mut self,
+                               key: &AggregateELBRecord,
+                               total: i64,
+                               timespan: Option<&Timespan>,
+                               metrics: Option<&AggregateMetrics>)
+                               -> io::Result<()> {
+        if self.num_written == 0 {
+            self.write_opening()?;
+        } else {
+            write!(self.writer, ",")?;
+        }
+        self.num_written += 1;
+        let key = displayed(key, self.max_field_chars);
+        let path_dimension_name = self.path_dimension_name.as_ref().map(|name| name.as_str());
+        write!(self.writer,
+               "{}",
+               counted_aggregate_json(&key, total, timespan, metrics, path_dimension_name))
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        if self.num_written == 0 {
+            self.write_opening()?;
+        }
+        match self.file_subtotals {
+            Some(ref file_subtotals) => {
+                writeln!(self.writer, "],\"file_subtotals\":{}}}", file_subtotals)?
+            }
+            None => writeln!(self.writer, "]")?,
+        }
+        self.writer.flush()
+    }
+}
+
+/// Pivots the aggregates into a CSV matrix with a row for every system and a column for every day
+/// any system was seen on, summing over the clients, path dimension and latency buckets. Days a
+/// system wasn't seen on are filled with zero. Nothing is written until the sink is finished.
+pub struct MatrixSink<W: Write> {
+    writer: W,
+    days: BTreeSet<Date<UTC>>,
+    /// The aggregates are bucketed by day of month, so the columns are headed by it instead.
+    by_day_of_month: bool,
+    totals: BTreeMap<String, BTreeMap<Date<UTC>, i64>>,
+    max_field_chars: Option<usize>,
+}
+
+impl<W: Write> MatrixSink<W> {
+    pub fn new(writer: W) -> MatrixSink<W> {
+        MatrixSink {
+            writer: writer,
+            days: BTreeSet::new(),
+            by_day_of_month: false,
+            totals: BTreeMap::new(),
+            max_field_chars: None,
+        }
+    }
+
+    /// Truncates the system names written to `max_chars` characters, see
+    /// `record_handling::truncate_field`. The rows are still summed by the full system name.
+    pub fn truncate_fields_to(&mut self, max_chars: usize) {
+        self.max_field_chars = Some(max_chars);
+    }
+}
+
+impl<W: Write> AggregateSink for MatrixSink<W> {
+    fn write_aggregate(&mut self, key: &AggregateELBRecord, total: i64) -> io::Result<()> {
+        self.days.insert(key.day);
+        self.by_day_of_month |= key.day_of_month.is_some();
+        let days = self.totals.entry(key.system_name.clone()).or_insert_with(BTreeMap::new);
+        let day_total = days.entry(key.day).or_insert(0);
+        *day_total = day_total.saturating_add(total);
+        Ok(())
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        let delimiter = record_handling::CSV_DELIMITER;
+        write!(self.writer, "system")?;
+        for day in &self.days {
+            if self.by_day_of_month {
+                write!(self.writer, "{}{}", delimiter, day.day())?;
+            } else {
+                write!(self.writer, "{}{}", delimiter, day.format("%Y-%m-%d"))?;
+            }
+        }
+        writeln!(self.writer)?;
+        for (system_name, days) in &self.totals {
+            let system_name = match self.max_field_chars {
+                Some(max_chars) => record_handling::truncate_field(system_name, max_chars),
+                None => Cow::Borrowed(system_name.as_str()),
+            };
+            write!(self.writer, "{}", quote_field(&system_name, delimiter))?;
+            for day in &self.days {
+                write!(self.writer, "{}{}", delimiter, days.get(day).cloned().unwrap_or(0))?;
+            }
+            writeln!(self.writer)?;
+        }
+        self.writer.flush()
+    }
+}
+
+#[cfg(test)]
+mod write_aggregates_tests {
+
+    use chrono::{DateTime, UTC};
+    use std::collections::{BTreeMap, HashMap};
+    use std::path::PathBuf;
+    use std::net::Ipv4Addr;
+    use record_handling::{AggregateELBRecord, AggregateMetrics, Dimension, Timespan};
+    use test_common::{aggregate, aggregate_on, aggregate_on_path, RecordingSink};
+
+    #[test]
+    fn write_aggregates_should_write_every_aggregate_in_order_and_finish_once() {
+        let aggregates = vec![(aggregate("sys1"), 3), (aggregate("sys2"), 1)];
+        let mut sink = RecordingSink::default();
+
+        let result = super::write_aggregates(&aggregates, &mut sink);
+
+        assert!(result.is_ok());
+        assert_eq!(sink.written, aggregates);
+        assert_eq!(sink.num_finished, 1)
+    }
+
+    #[test]
+    fn write_spilled_aggregates_should_write_the_merged_aggregates_in_key_order() {
+        let max = ::record_handling::SATURATED_TOTAL;
+        let mut spilled = HashMap::new();
+        spilled.insert(aggregate("sys2"), 2);
+        spilled.insert(aggregate("sys1"), max);
+        let spill_files = vec![::spill::spill(&mut spilled).unwrap()];
+        let mut sink = RecordingSink::default();
+
+        let totals = super::write_spilled_aggregates(&spill_files,
+                                                     ::record_handling::CountOverflowPolicy::Error,
+                                                     &mut sink);
+
+        ::spill::remove_spill_files(&spill_files);
+        assert_eq!(totals.unwrap(),
+                   super::SpilledTotals {
+                       num_aggregates: 2,
+                       num_overflows: 0,
+                       num_saturated: 1,
+                   });
+        assert_eq!(sink.written,
+                   vec![(aggregate("sys1"), max), (aggregate("sys2"), 2)]);
+        assert_eq!(sink.num_finished, 1)
+    }
+
+    #[test]
+    fn the_csv_sink_should_write_a_row_for_every_aggregate() {
+        let aggregates = vec![(aggregate("sys1"), 3),
+                              (aggregate_on_path("sys2", Some("us-east-1")), 1)];
+        let mut output = Vec::new();
+
+        let _ = super::write_aggregates(&aggregates, &mut super::CsvSink::new(&mut output));
+
+        assert_eq!(String::from_utf8(output).unwrap(),
+                   "sys1,2015-08-15,172.16.1.6,3\nsys2,2015-08-15,172.16.1.6,us-east-1,1\n")
+    }
+
+    #[test]
+    fn the_csv_sink_should_write_the_latency_bucket_after_the_path_dimension() {
+        let mut key = aggregate_on_path("sys1", Some("us-east-1"));
+        key.latency_bucket = Some(">=1s".to_owned());
+        let mut output = Vec::new();
+
+        let _ = super::write_aggregates(&[(key, 2)], &mut super::CsvSink::new(&mut output));
+
+        assert_eq!(String::from_utf8(output).unwrap(),
+                   "sys1,2015-08-15,172.16.1.6,us-east-1,>=1s,2\n")
+    }
+
+    #[test]
+    fn the_csv_sink_should_write_the_dimensions_in_the_order_given() {
+        let mut key = aggregate("sys1");
+        key.client_address = None;
+        key.backend_address = Some("10.0.0.1".parse::<Ipv4Addr>().unwrap());
+        let mut output = Vec::new();
+        let mut sink = super::CsvSink::new(&mut output);
+        sink.order_columns_by(&[Dimension::Day, Dimension::Backend, Dimension::System]);
+
+        let _ = super::write_aggregates(&[(key, 2)], &mut sink);
+
+        assert_eq!(String::from_utf8(output).unwrap(), "2015-08-15,10.0.0.1,sys1,2\n")
+    }
+
+    #[test]
+    fn the_csv_sink_should_quote_fields_holding_the_delimiter_or_a_quote() {
+        let aggregates = vec![(aggregate_on_path("sys,1", Some("us\"east")), 3)];
+        let mut output = Vec::new();
+
+        let _ = super::write_aggregates(&aggregates, &mut super::CsvSink::new(&mut output));
+
+        assert_eq!(String::from_utf8(output).unwrap(),
+                   "\"sys,1\",2015-08-15,172.16.1.6,\"us\"\"east\",3\n")
+    }
+
+    #[test]
+    fn the_csv_sink_should_write_a_header_row_naming_the_columns_like_the_schema() {
+        let aggregates = vec![(aggregate_on_path("sys1", Some("us-east-1")), 3),
+                              (aggregate_on_path("sys2", Some("us-west-2")), 1)];
+        let mut output = Vec::new();
+        {
+            let mut sink = super::CsvSink::with_delimiter(&mut output, '\t');
+            sink.include_header(Some("region".to_owned()), "total");
+            let _ = super::write_aggregates(&aggregates, &mut sink);
+        }
+
+        assert_eq!(String::from_utf8(output).unwrap(),
+                   "system_name\tday\tclient_address\tregion\ttotal\n\
+                    sys1\t2015-08-15\t172.16.1.6\tus-east-1\t3\n\
+                    sys2\t2015-08-15\t172.16.1.6\tus-west-2\t1\n")
+    }
+
+    #[test]
+    fn the_csv_sink_should_write_the_header_row_without_aggregates() {
+        let mut output = Vec::new();
+        {
+            let mut sink = super::CsvSink::new(&mut output);
+            sink.include_header(None, "total_bytes");
+            let _ = super::write_aggregates(&[], &mut sink);
+        }
+
+        assert_eq!(String::from_utf8(output).unwrap(),
+                   "system_name,day,client_address,total_bytes\n")
+    }
+
+    #[test]
+    fn the_csv_sink_should_separate_fields_with_the_given_delimiter() {
+        let aggregates = vec![(aggregate("sys,1"), 3)];
+        let mut output = Vec::new();
+
+        let _ = super::write_aggregates(&aggregates,
+                                        &mut super::CsvSink::with_delimiter(&mut output, '\t'));
+
+        assert_eq!(String::from_utf8(output).unwrap(),
+                   "sys,1\t2015-08-15\t172.16.1.6\t3\n")
+    }
+
+    #[test]
+    fn the_json_lines_sink_should_write_an_object_per_line() {
+        let aggregates = vec![(aggregate("sys,\"1"), 3), (aggregate("sys2"), 1)];
+        let mut output = Vec::new();
+
+        let _ = super::write_aggregates(&aggregates,
+                                        &mut super::JsonLinesSink::new(&mut output, None));
+
+        assert_eq!(String::from_utf8(output).unwrap(),
+                   "{\"client_address\":\"172.16.1.6\",\"day\":\"2015-08-15\",\
+                    \"system_name\":\"sys,\\\"1\",\"total\":3}\n\
+                    {\"client_address\":\"172.16.1.6\",\"day\":\"2015-08-15\",\
+                    \"system_name\":\"sys2\",\"total\":1}\n")
+    }
+
+    #[test]
+    fn the_json_sink_should_write_a_single_array_of_objects_with_a_count() {
+        let aggregates = vec![(aggregate("sys1"), 3), (aggregate("sys2"), 1)];
+        let mut output = Vec::new();
+
+        let _ = super::write_aggregates(&aggregates,
+                                        &mut super::JsonSink::new(&mut output, None));
+
+        assert_eq!(String::from_utf8(output).unwrap(),
+                   "[{\"client_address\":\"172.16.1.6\",\"count\":3,\"day\":\"2015-08-15\",\
+                    \"system_name\":\"sys1\"},\
+                    {\"client_address\":\"172.16.1.6\",\"count\":1,\"day\":\"2015-08-15\",\
+                    \"system_name\":\"sys2\"}]\n")
+    }
+
+    #[test]
+    fn the_json_sink_should_write_an_empty_array_without_aggregates() {
+        let mut output = Vec::new();
+
+        let _ = super::write_aggregates(&[], &mut super::JsonSink::new(&mut output, None));
+
+        assert_eq!(String::from_utf8(output).unwrap(), "[]\n")
+    }
+
+    #[test]
+    fn the_json_sink_should_write_the_file_subtotals_next_to_the_aggregates() {
+        let mut file_subtotals = BTreeMap::new();
+        file_subtotals.insert(PathBuf::from("a.log"), 3);
+        let mut output = Vec::new();
+        {
+            let mut sink = super::JsonSink::new(&mut output, None);
+            sink.include_file_subtotals(super::file_subtotals_json(&file_subtotals));
+            let _ = super::write_aggregates(&[(aggregate("sys1"), 3)], &mut sink);
+        }
+
+        assert_eq!(String::from_utf8(output).unwrap(),
+                   "{\"aggregates\":[{\"client_address\":\"172.16.1.6\",\"count\":3,\
+                    \"day\":\"2015-08-15\",\"system_name\":\"sys1\"}],\
+                    \"file_subtotals\":[{\"file\":\"a.log\",\"records\":3}]}\n")
+    }
+
+    #[test]
+    fn the_influx_sink_should_write_a_point_for_every_aggregate() {
+        let aggregates = vec![(aggregate("sys1"), 3)];
+        let mut output = Vec::new();
+
+        let _ = super::write_aggregates(&aggregates,
+                                        &mut super::InfluxSink::new(&mut output, None));
+
+        assert_eq!(String::from_utf8(output).unwrap(),
+                   "requests,system=sys1,client=172.16.1.6 count=3i 1439596800000000000\n")
+    }
+
+    #[test]
+    fn the_matrix_sink_should_pivot_systems_by_day_and_fill_missing_days_with_zero() {
+        let aggregates = vec![(aggregate_on("sys2", "2015-08-16", "172.16.1.6"), 4),
+                              (aggregate_on("sys1", "2015-08-15", "172.16.1.6"), 3),
+                              (aggregate_on("sys1", "2015-08-15", "172.16.1.7"), 2),
+                              (aggregate_on("sys1", "2015-08-16", "172.16.1.6"), 1)];
+        let mut output = Vec::new();
+
+        let _ = super::write_aggregates(&aggregates, &mut super::MatrixSink::new(&mut output));
+
+        assert_eq!(String::from_utf8(output).unwrap(),
+                   "system,2015-08-15,2015-08-16\nsys1,5,1\nsys2,0,4\n")
+    }
+
+    #[test]
+    fn the_csv_sink_should_truncate_long_fields_when_asked_to() {
+        let aggregates = vec![(aggregate_on_path("system-1", Some("us-east-1")), 3)];
+        let mut output = Vec::new();
+        {
+            let mut sink = super::CsvSink::new(&mut output);
+            sink.truncate_fields_to(6);
+            let _ = super::write_aggregates(&aggregates, &mut sink);
+        }
+
+        assert_eq!(String::from_utf8(output).unwrap(),
+                   "system...,2015-08-15,172.16.1.6,us-eas...,3\n")
+    }
+
+    #[test]
+    fn the_matrix_sink_should_keep_systems_with_the_same_truncated_name_apart() {
+        let aggregates = vec![(aggregate_on("system-1", "2015-08-15", "172.16.1.6"), 3),
+                              (aggregate_on("system-2", "2015-08-15", "172.16.1.6"), 1)];
+        let mut output = Vec::new();
+        {
+            let mut sink = super::MatrixSink::new(&mut output);
+            sink.truncate_fields_to(6);
+            let _ = super::write_aggregates(&aggregates, &mut sink);
+        }
+
+        assert_eq!(String::from_utf8(output).unwrap(),
+                   "system,2015-08-15\nsystem...,3\nsystem...,1\n")
+    }
+
+    #[test]
+    fn the_matrix_sink_should_saturate_the_sum_of_a_cell() {
+        let max = ::record_handling::SATURATED_TOTAL;
+        let aggregates = vec![(aggregate_on("sys1", "2015-08-15", "172.16.1.6"), max),
+                              (aggregate_on("sys1", "2015-08-15", "172.16.1.7"), 1)];
+        let mut output = Vec::new();
+
+        let _ = super::write_aggregates(&aggregates, &mut super::MatrixSink::new(&mut output));
+
+        assert_eq!(String::from_utf8(output).unwrap(),
+                   format!("system,2015-08-15\nsys1,{}\n", max))
+    }
+
+    #[test]
+    fn the_sinks_should_write_the_day_of_month_in_place_of_the_day() {
+        let mut key = aggregate_on("sys1", "1970-01-15", "172.16.1.6");
+        key.day_of_month = Some(15);
+        let aggregates = vec![(key, 3)];
+        let mut csv_output = Vec::new();
+        let mut matrix_output = Vec::new();
+
+        let _ = super::write_aggregates(&aggregates, &mut super::CsvSink::new(&mut csv_output));
+        let _ = super::write_aggregates(&aggregates,
+                                        &mut super::MatrixSink::new(&mut matrix_output));
+
+        assert_eq!(String::from_utf8(csv_output).unwrap(), "sys1,15,172.16.1.6,3\n");
+        assert_eq!(String::from_utf8(matrix_output).unwrap(), "system,15\nsys1,3\n")
+    }
+
+    fn timespan(first_seen: &str, last_seen: &str) -> Timespan {
+        Timespan {
+            first_seen: first_seen.parse::<DateTime<UTC>>().unwrap(),
+            last_seen: last_seen.parse::<DateTime<UTC>>().unwrap(),
+        }
+    }
+
+    #[test]
+    fn the_csv_sink_should_write_the_timespan_after_the_total_of_aggregates_having_one() {
+        let aggregates = vec![(aggregate("sys1"), 3), (aggregate("sys2"), 1)];
+        let mut timespans = HashMap::new();
+        timespans.insert(aggregate("sys1"),
+                         timespan("2015-08-15T09:00:00Z", "2015-08-15T23:43:05Z"));
+        let mut output = Vec::new();
+
+        let _ = super::write_timespan_aggregates(&aggregates,
+                                                 &timespans,
+                                                 &mut super::CsvSink::new(&mut output));
+
+        assert_eq!(String::from_utf8(output).unwrap(),
+                   "sys1,2015-08-15,172.16.1.6,3,2015-08-15T09:00:00+00:00,\
+                    2015-08-15T23:43:05+00:00\nsys2,2015-08-15,172.16.1.6,1\n")
+    }
+
+    fn metrics(latency: Option<f64>) -> AggregateMetrics {
+        AggregateMetrics {
+            received_bytes: 12,
+            sent_bytes: 7582,
+            min_latency: latency,
+            max_latency: latency,
+            status_classes: [1, 0, 0, 0],
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn the_csv_sink_should_write_the_metrics_after_the_total_leaving_missing_latencies_empty() {
+        let aggregates = vec![(aggregate("sys1"), 1), (aggregate("sys2"), 1)];
+        let mut aggregate_metrics = HashMap::new();
+        aggregate_metrics.insert(aggregate("sys1"), metrics(Some(0.25)));
+        aggregate_metrics.insert(aggregate("sys2"), metrics(None));
+        let mut output = Vec::new();
+
+        let _ = super::write_metrics_aggregates(&aggregates,
+                                                &HashMap::new(),
+                                                &aggregate_metrics,
+                                                &mut super::CsvSink::new(&mut output));
+
+        assert_eq!(String::from_utf8(output).unwrap(),
+                   "sys1,2015-08-15,172.16.1.6,1,12,7582,0.25,0.25,0.25,0.25,0.25,1,0,0,0\n\
+                    sys2,2015-08-15,172.16.1.6,1,12,7582,,,,,,1,0,0,0\n")
+    }
+
+    #[test]
+    fn the_json_lines_sink_should_write_the_metrics_next_to_the_total() {
+        let aggregates = vec![(aggregate("sys1"), 1)];
+        let mut aggregate_metrics = HashMap::new();
+        aggregate_metrics.insert(aggregate("sys1"), metrics(None));
+        let mut output = Vec::new();
+
+        let _ = super::write_metrics_aggregates(&aggregates,
+                                                &HashMap::new(),
+                                                &aggregate_metrics,
+                                                &mut super::JsonLinesSink::new(&mut output, None));
+
+        assert_eq!(String::from_utf8(output).unwrap(),
+                   "{\"client_address\":\"172.16.1.6\",\"day\":\"2015-08-15\",\
+                    \"latency_max\":null,\"latency_min\":null,\"latency_p50\":null,\
+                    \"latency_p95\":null,\"latency_p99\":null,\"received_bytes\":12,\
+                    \"sent_bytes\":7582,\"status_2xx\":1,\"status_3xx\":0,\"status_4xx\":0,\
+                    \"status_5xx\":0,\"system_name\":\"sys1\",\"total\":1}\n")
+    }
+
+    #[test]
+    fn the_json_sink_should_write_the_timespan_next_to_the_count() {
+        let aggregates = vec![(aggregate("sys1"), 3)];
+        let mut timespans = HashMap::new();
+        timespans.insert(aggregate("sys1"),
+                         timespan("2015-08-15T09:00:00Z", "2015-08-15T23:43:05Z"));
+        let mut output = Vec::new();
+
+        let _ = super::write_timespan_aggregates(&aggregates,
+                                                 &timespans,
+                                                 &mut super::JsonSink::new(&mut output, None));
+
+        assert_eq!(String::from_utf8(output).unwrap(),
+                   "[{\"client_address\":\"172.16.1.6\",\"count\":3,\"day\":\"2015-08-15\",\
+                    \"first_seen\":\"2015-08-15T09:00:00+00:00\",\
+                    \"last_seen\":\"2015-08-15T23:43:05+00:00\",\"system_name\":\"sys1\"}]\n")
+    }
+}
+
+#[cfg(test)]
+mod aggregate_json_tests {
+
+    use chrono::{DateTime, UTC};
+    use std::net::Ipv4Addr;
+    use record_handling::AggregateELBRecord;
+
+    #[test]
+    fn aggregate_json_should_include_every_key_field_and_the_total() {
+        let agg_key = AggregateELBRecord {
+            day: "2015-08-15T23:43:05.302180Z"
+                .parse::<DateTime<UTC>>()
+                .unwrap()
+                .date(),
+            client_address: Some("172.16.1.6".parse::<Ipv4Addr>().unwrap()),
+            backend_address: None,
+            url: None,
+            listener_port: None,
+            hour: None,
+            elb_status_code: None,
+            system_name: "sys\"1".to_owned(),
+            path_dimension: None,
+            latency_bucket: None,
+            day_of_month: None,
+        };
+
+        let json = super::aggregate_json(&agg_key, 7, None).to_string();
+
+        assert_eq!(json,
+                   "{\"client_address\":\"172.16.1.6\",\"day\":\"2015-08-15\",\
+                    \"system_name\":\"sys\\\"1\",\"total\":7}")
+    }
+}
+
+#[cfg(test)]
+mod quote_field_tests {
+
+    #[test]
+    fn quote_field_should_leave_plain_fields_alone() {
+        assert_eq!(super::quote_field("sys1", ','), "sys1")
+    }
+
+    #[test]
+    fn quote_field_should_quote_fields_holding_the_delimiter_and_double_their_quotes() {
+        assert_eq!(super::quote_field("sys,1", ','), "\"sys,1\"");
+        assert_eq!(super::quote_field("sys\"1", ','), "\"sys\"\"1\"");
+        assert_eq!(super::quote_field("sys\t1", '\t'), "\"sys\t1\"")
+    }
+}
+
+#[cfg(test)]
+mod read_system_totals_tests {
+
+    use std::collections::BTreeMap;
+
+    #[test]
+    fn read_system_totals_should_read_back_the_rows_of_list_systems() {
+        let rows = "sys1,12\n\"sys,\"\"2\"\",3\n\nsys3,0\n";
+        let mut expected = BTreeMap::new();
+        expected.insert("sys1".to_owned(), 12);
+        expected.insert("sys,\"2\"".to_owned(), 3);
+        expected.insert("sys3".to_owned(), 0);
+
+        assert_eq!(super::read_system_totals(rows.as_bytes()), Ok(expected))
+    }
+
+    #[test]
+    fn read_system_totals_should_reject_rows_without_a_total() {
+        assert_eq!(super::read_system_totals("sys1,12\nsys2\n".as_bytes()),
+                   Err("Line 2 has no system and total.".to_owned()))
+    }
+
+    #[test]
+    fn read_system_totals_should_sum_the_aggregate_rows_of_each_system() {
+        let rows = "day,system_name,client_address,total\n\
+                    2015-08-15,sys1,172.16.1.6,12\n\
+                    2015-08-15,sys2,172.16.1.6,3\n\
+                    2015-08-16,sys1,172.16.1.7,4\n";
+        let mut expected = BTreeMap::new();
+        expected.insert("sys1".to_owned(), 16);
+        expected.insert("sys2".to_owned(), 3);
+
+        assert_eq!(super::read_system_totals(rows.as_bytes()), Ok(expected))
+    }
+
+    #[test]
+    fn read_system_totals_should_take_the_last_column_of_rows_without_a_header_as_the_total() {
+        let rows = "sys1,2015-08-15,172.16.1.6,12\nsys1,2015-08-16,172.16.1.6,4\n";
+        let mut expected = BTreeMap::new();
+        expected.insert("sys1".to_owned(), 16);
+
+        assert_eq!(super::read_system_totals(rows.as_bytes()), Ok(expected))
+    }
+}
+
+#[cfg(test)]
+mod schema_json_tests {
+
+    use record_handling::{Dimension, OutputFormat, RecordConfig, WeightField};
+
+    fn column_list(format: OutputFormat,
+                   group_by: &[Dimension],
+                   config: &RecordConfig,
+                   list_systems: bool)
+                   -> String {
+        let schema = super::schema_json(format, group_by, None, config, list_systems, false, false)
+            .unwrap();
+        schema.find("columns")
+            .unwrap()
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|column| {
+                format!("{}:{}",
+                        column.find("name").unwrap().as_string().unwrap(),
+                        column.find("type").unwrap().as_string().unwrap())
+            })
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+
+    #[test]
+    fn schema_json_should_describe_the_grouped_by_columns_and_the_total() {
+        let config = RecordConfig::default();
+
+        assert_eq!(column_list(OutputFormat::Csv,
+                               &[Dimension::System, Dimension::Day, Dimension::Client],
+                               &config,
+                               false),
+                   "system_name:string, day:date, client_address:string, total:integer")
+    }
+
+    #[test]
+    fn schema_json_should_name_the_total_after_the_bytes_when_weighted() {
+        let config = RecordConfig {
+            weight_field: Some(WeightField::SentBytes),
+            ..Default::default()
+        };
+
+        assert_eq!(column_list(OutputFormat::Csv,
+                               &[Dimension::System, Dimension::Day],
+                               &config,
+                               false),
+                   "system_name:string, day:date, total_bytes:integer")
+    }
+
+    #[test]
+    fn schema_json_should_only_describe_the_hash_and_day_of_hashed_keys() {
+        let config = RecordConfig {
+            hash_keys: true,
+            latency_buckets: Some(Default::default()),
+            ..Default::default()
+        };
+
+        assert_eq!(column_list(OutputFormat::Csv,
+                               &[Dimension::Url, Dimension::System, Dimension::Day],
+                               &config,
+                               false),
+                   "system_name:string, day:date, total:integer")
+    }
+
+    #[test]
+    fn schema_json_should_follow_the_field_names_of_the_format() {
+        let config = RecordConfig::default();
+        let group_by = [Dimension::System, Dimension::Day, Dimension::Url];
+
+        assert_eq!(column_list(OutputFormat::Json, &group_by, &config, false),
+                   "system_name:string, day:date, url:string, count:integer");
+        assert_eq!(column_list(OutputFormat::Influx, &group_by, &config, false),
+                   "system:string, url:string, count:integer, time:date");
+        assert_eq!(column_list(OutputFormat::Tsv, &group_by, &config, true),
+                   "system_name:string, total:integer")
+    }
+
+    #[test]
+    fn schema_json_should_include_the_path_dimension_and_latency_bucket() {
+        let config = RecordConfig {
+            latency_buckets: Some(Default::default()),
+            ..Default::default()
+        };
+        let schema = super::schema_json(OutputFormat::Csv,
+                                        &[Dimension::System, Dimension::Day],
+                                        Some("route"),
+                                        &config,
+                                        false,
+                                        false,
+                                        false)
+            .unwrap();
+
+        assert_eq!(schema.to_string(),
+                   "{\"columns\":[{\"name\":\"system_name\",\"type\":\"string\"},\
+                    {\"name\":\"day\",\"type\":\"date\"},\
+                    {\"name\":\"route\",\"type\":\"string\"},\
+                    {\"name\":\"latency_bucket\",\"type\":\"string\"},\
+                    {\"name\":\"total\",\"type\":\"integer\"}]}")
+    }
+
+    #[test]
+    fn schema_json_should_refuse_the_matrix_format() {
+        let config = RecordConfig::default();
+
+        assert!(super::schema_json(OutputFormat::Matrix, &[], None, &config, false, false, false)
+            .is_err())
+    }
+
+    #[test]
+    fn schema_json_should_describe_the_timespan_after_the_total() {
+        let config = RecordConfig::default();
+        let schema = super::schema_json(OutputFormat::JsonLines,
+                                        &[Dimension::System],
+                                        None,
+                                        &config,
+                                        false,
+                                        true,
+                                        false)
+            .unwrap();
+
+        assert_eq!(schema.to_string(),
+                   "{\"columns\":[{\"name\":\"system_name\",\"type\":\"string\"},\
+                    {\"name\":\"total\",\"type\":\"integer\"},\
+                    {\"name\":\"first_seen\",\"type\":\"timestamp\"},\
+                    {\"name\":\"last_seen\",\"type\":\"timestamp\"}]}")
+    }
+
+    #[test]
+    fn schema_json_should_describe_the_metrics_after_the_total() {
+        let config = RecordConfig::default();
+        let schema = super::schema_json(OutputFormat::Csv,
+                                        &[Dimension::System],
+                                        None,
+                                        &config,
+                                        false,
+                                        false,
+                                        true)
+            .unwrap();
+        let columns = schema.find("columns").unwrap().as_array().unwrap();
+
+        assert_eq!(columns.len(), 13);
+        assert_eq!(columns[2].to_string(),
+                   "{\"name\":\"received_bytes\",\"type\":\"integer\"}");
+        assert_eq!(columns[6].to_string(), "{\"name\":\"latency_p50\",\"type\":\"number\"}");
+        assert_eq!(columns[12].to_string(), "{\"name\":\"status_5xx\",\"type\":\"integer\"}")
+    }
+
+    #[test]
+    fn schema_json_should_describe_the_day_of_month_as_an_integer() {
+        let config = RecordConfig { day_of_month: true, ..Default::default() };
+
+        assert_eq!(column_list(OutputFormat::Csv,
+                               &[Dimension::System, Dimension::Day],
+                               &config,
+                               false),
+                   "system_name:string, day_of_month:integer, total:integer")
+    }
+}
@@ -1,100 +1,2642 @@
 use std::io::Write;
 
-use chrono::{Date, DateTime, UTC};
+use chrono::{Date, Datelike, DateTime, TimeZone, Timelike, UTC};
+use std::borrow::Cow;
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, HashMap};
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::mem;
 use std::net::Ipv4Addr;
+use std::path::Path;
+use std::time::Duration;
 use regex::Regex;
 use ELBRecordAggregation;
 use elp;
+use sha2::{Digest, Sha256};
+use sp;
+use status::{Phase, PhaseTimings};
 
 #[derive(Clone, Debug, Eq, Hash, PartialEq)]
 pub struct AggregateELBRecord {
     pub day: Date<UTC>,
-    pub client_address: Ipv4Addr,
+    /// The dimensions below are only kept when the run groups by them, see `Dimension`.
+    pub client_address: Option<Ipv4Addr>,
+    pub backend_address: Option<Ipv4Addr>,
+    pub url: Option<String>,
+    pub listener_port: Option<String>,
+    /// The hour of the day, which buckets the records of each day by hour.
+    pub hour: Option<u32>,
+    pub elb_status_code: Option<u16>,
     pub system_name: String,
+    /// The directory name selected by --path-dimension, if the run was given one.
+    pub path_dimension: Option<String>,
+    /// The label of the latency bucket the record fell in, if the run groups by latency.
+    pub latency_bucket: Option<String>,
+    /// The day of the month, when the run buckets by it. `day` is then that day of January 1970,
+    /// so that the records of every month on that day of the month are aggregated together.
+    pub day_of_month: Option<u32>,
 }
 
 impl AggregateELBRecord {
-    fn new(day: DateTime<UTC>, client_address: Ipv4Addr, system: String) -> AggregateELBRecord {
+    fn new(elb_record: &elp::ELBRecord,
+           system: String,
+           path_dimension: Option<&str>,
+           config: &RecordConfig)
+           -> AggregateELBRecord {
+        let key = AggregateELBRecord::with_all_fields(elb_record, system, path_dimension, config);
+        if config.hash_keys { key.hashed() } else { key }
+    }
+
+    fn with_all_fields(elb_record: &elp::ELBRecord,
+                       system: String,
+                       path_dimension: Option<&str>,
+                       config: &RecordConfig)
+                       -> AggregateELBRecord {
+        let is_grouped_by = |dimension| config.group_by.contains(&dimension);
+        let day_of_month = if config.day_of_month {
+            Some(elb_record.timestamp.day())
+        } else {
+            None
+        };
         AggregateELBRecord {
-            day: day.date(),
-            client_address: client_address,
+            day: match day_of_month {
+                Some(day_of_month) => UTC.ymd(1970, 1, day_of_month),
+                None => elb_record.timestamp.date(),
+            },
+            client_address: if is_grouped_by(Dimension::Client) {
+                Some(*elb_record.client_address.ip())
+            } else {
+                None
+            },
+            backend_address: if is_grouped_by(Dimension::Backend) {
+                Some(*elb_record.backend_address.ip())
+            } else {
+                None
+            },
+            url: if is_grouped_by(Dimension::Url) {
+                Some(elb_record.request_url.to_owned())
+            } else {
+                None
+            },
+            listener_port: if is_grouped_by(Dimension::ListenerPort) {
+                Some(listener_port(elb_record.request_url).to_owned())
+            } else {
+                None
+            },
+            hour: if is_grouped_by(Dimension::Hour) {
+                Some(elb_record.timestamp.hour())
+            } else {
+                None
+            },
+            elb_status_code: if is_grouped_by(Dimension::Status) {
+                Some(elb_record.elb_status_code)
+            } else {
+                None
+            },
             system_name: system,
+            path_dimension: path_dimension.map(|dimension| dimension.to_owned()),
+            latency_bucket: latency_bucket(elb_record, config),
+            day_of_month: day_of_month,
+        }
+    }
+
+    /// A hash of the fields other than the day, as `KEY_HASH_CHARS` hex digits of its SHA-256.
+    /// Keys that only differ by day share a hash.
+    pub fn key_hash(&self) -> String {
+        fn optional_field<T: ToString>(field: Option<T>) -> String {
+            field.map(|field| format!("{},", field.to_string())).unwrap_or_default()
+        }
+        let mut hasher = Sha256::default();
+        hasher.input(format!("{},{}{}{}{}{}{}{}{}",
+                             self.system_name,
+                             optional_field(self.client_address),
+                             optional_field(self.backend_address),
+                             optional_field(self.url.as_ref()),
+                             optional_field(self.listener_port.as_ref()),
+                             optional_field(self.hour),
+                             optional_field(self.elb_status_code),
+                             optional_field(self.path_dimension.as_ref()),
+                             optional_field(self.latency_bucket.as_ref()))
+            .as_bytes());
+        hasher.result()
+            .iter()
+            .take(KEY_HASH_CHARS / 2)
+            .map(|byte| format!("{:02x}", byte))
+            .collect()
+    }
+
+    /// The key with its hash, see `key_hash`, in place of the system name and without any of the
+    /// other fields but the day, which takes far less memory than a key of several long fields.
+    pub fn hashed(&self) -> AggregateELBRecord {
+        AggregateELBRecord {
+            day: self.day,
+            client_address: None,
+            backend_address: None,
+            url: None,
+            listener_port: None,
+            hour: None,
+            elb_status_code: None,
+            system_name: self.key_hash(),
+            path_dimension: None,
+            latency_bucket: None,
+            day_of_month: self.day_of_month,
+        }
+    }
+
+    /// Renders the value of the dimension, or None when the aggregate isn't grouped by it.
+    pub fn dimension_value(&self, dimension: Dimension) -> Option<Cow<str>> {
+        match dimension {
+            Dimension::System => Some(Cow::Borrowed(self.system_name.as_str())),
+            Dimension::Day => {
+                match self.day_of_month {
+                    Some(day_of_month) => Some(Cow::Owned(day_of_month.to_string())),
+                    None => Some(Cow::Owned(self.day.format("%Y-%m-%d").to_string())),
+                }
+            }
+            Dimension::Client => self.client_address.map(|address| Cow::Owned(address.to_string())),
+            Dimension::Backend => {
+                self.backend_address.map(|address| Cow::Owned(address.to_string()))
+            }
+            Dimension::Url => self.url.as_ref().map(|url| Cow::Borrowed(url.as_str())),
+            Dimension::ListenerPort => {
+                self.listener_port.as_ref().map(|port| Cow::Borrowed(port.as_str()))
+            }
+            Dimension::Hour => self.hour.map(|hour| Cow::Owned(hour.to_string())),
+            Dimension::Status => self.elb_status_code.map(|code| Cow::Owned(code.to_string())),
+        }
+    }
+}
+
+/// A field of the records that aggregates can be grouped by. Every aggregate is keyed by its
+/// system and day, which the output formats rely on, while the other dimensions are optional.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Dimension {
+    System,
+    Day,
+    Client,
+    /// The address of the backend the ELB sent the request to.
+    Backend,
+    /// The whole request URL, query included.
+    Url,
+    /// The port of the ELB listener the request was sent to, see `listener_port`.
+    ListenerPort,
+    /// The hour of the day of the record, from 0 to 23, which with the day buckets by hour.
+    Hour,
+    /// The status code the ELB responded with.
+    Status,
+}
+
+impl Dimension {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Dimension::System => "system",
+            Dimension::Day => "day",
+            Dimension::Client => "client",
+            Dimension::Backend => "backend",
+            Dimension::Url => "url",
+            Dimension::ListenerPort => "port",
+            Dimension::Hour => "hour",
+            Dimension::Status => "status",
+        }
+    }
+}
+
+/// The dimensions aggregates are grouped by unless others are chosen, in the order they are
+/// written.
+pub const DEFAULT_GROUP_BY: [Dimension; 3] = [Dimension::System, Dimension::Day, Dimension::Client];
+
+pub const DEFAULT_NULL_SYSTEM_LABEL: &'static str = "UNDEFINED_SYSTEM";
+
+/// The number of hex digits of a key hash, see `AggregateELBRecord::key_hash`. The 64 bits they
+/// hold make it likely that two of the keys of a run share a hash once there are billions of
+/// them, which would count both as one.
+pub const KEY_HASH_CHARS: usize = 16;
+
+/// Options controlling how individual records become aggregates.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RecordConfig {
+    /// Skip records that have no system name instead of aggregating them.
+    pub drop_no_system: bool,
+    /// The system name used for records that have no system name, or a `-` one.
+    pub null_system_label: String,
+    /// Add this field of every record to its aggregate's total instead of counting the record.
+    pub weight_field: Option<WeightField>,
+    /// Round the timestamps written by passthrough mode down to a multiple of this resolution,
+    /// so they line up with coarser grained systems. Aggregates are always bucketed by day.
+    pub timestamp_resolution: Option<Duration>,
+    /// Only count records whose backend status code is one of these.
+    pub count_status: Option<StatusCodes>,
+    /// Group records by the latency bucket their total processing time falls in.
+    pub latency_buckets: Option<LatencyBuckets>,
+    /// Only count records from the days in this range.
+    pub date_range: Option<DateRange>,
+    /// Only count records from the times in this window, which are skipped like those outside of
+    /// the date range.
+    pub time_window: Option<TimeWindow>,
+    /// Only count records matching every one of these filters.
+    pub filters: Vec<RecordFilter>,
+    /// The dimensions the aggregates are keyed by, in the order they are written.
+    pub group_by: Vec<Dimension>,
+    /// Bucket the records by the day of the month instead of the day, combining the months.
+    pub day_of_month: bool,
+    /// Only count records from these hours of the day.
+    pub business_hours: Option<BusinessHours>,
+    /// What happens when counting a record would overflow the total of its aggregate.
+    pub count_overflow_policy: CountOverflowPolicy,
+    /// Key the aggregates by a hash of their fields, see `AggregateELBRecord::hashed`.
+    pub hash_keys: bool,
+    /// The format the records are logged in, see `as_elb_record`.
+    pub log_format: LogFormat,
+}
+
+/// The load balancer the records were logged by. Application Load Balancer records are read as
+/// the classic ELB record they start with, see `as_elb_record`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LogFormat {
+    Elb,
+    Alb,
+    /// Reads records starting with the request type as ALB records and others as ELB records,
+    /// so a directory can hold both.
+    Auto,
+}
+
+/// What happens when adding to the total of an aggregate would overflow it, which only a weighted
+/// total is likely to.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CountOverflowPolicy {
+    /// Stop the total at `SATURATED_TOTAL`.
+    Saturate,
+    /// Wrap the total around to the most negative total and on up from there.
+    Wrap,
+    /// Leave the total as it was so that the overflow can be reported, see
+    /// `RecordOutcome::CountOverflowed`.
+    Error,
+}
+
+impl CountOverflowPolicy {
+    /// Adds `amount` to `total`, returning None only when it overflows under the error policy.
+    pub fn add(&self, total: i64, amount: i64) -> Option<i64> {
+        match *self {
+            CountOverflowPolicy::Saturate => Some(total.saturating_add(amount)),
+            CountOverflowPolicy::Wrap => Some(total.wrapping_add(amount)),
+            CountOverflowPolicy::Error => total.checked_add(amount),
+        }
+    }
+}
+
+/// An inclusive range of days, open ended on the side without a bound.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct DateRange {
+    pub from: Option<Date<UTC>>,
+    pub to: Option<Date<UTC>>,
+}
+
+impl DateRange {
+    pub fn contains(&self, day: Date<UTC>) -> bool {
+        self.from.map_or(true, |from| from <= day) && self.to.map_or(true, |to| day <= to)
+    }
+}
+
+/// The times from `since` up to but not including `until`, open ended on the side without a
+/// bound.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct TimeWindow {
+    pub since: Option<DateTime<UTC>>,
+    pub until: Option<DateTime<UTC>>,
+}
+
+impl TimeWindow {
+    pub fn contains(&self, timestamp: DateTime<UTC>) -> bool {
+        self.since.map_or(true, |since| since <= timestamp) &&
+        self.until.map_or(true, |until| timestamp < until)
+    }
+}
+
+/// A field of the records and the value it has to have for the record to be counted. The status
+/// codes match any code of their list and the other fields match their value exactly. The
+/// system is the one the record is aggregated under, so the null system label matches records
+/// without one.
+#[derive(Clone, Debug, PartialEq)]
+pub enum RecordFilter {
+    System(String),
+    Client(Ipv4Addr),
+    Backend(Ipv4Addr),
+    /// The status code the ELB responded with.
+    Status(StatusCodes),
+    BackendStatus(StatusCodes),
+    /// The listener port, see `listener_port`.
+    Port(String),
+}
+
+impl RecordFilter {
+    fn matches(&self, elb_record: &elp::ELBRecord, system: &str) -> bool {
+        match *self {
+            RecordFilter::System(ref name) => system == name,
+            RecordFilter::Client(address) => *elb_record.client_address.ip() == address,
+            RecordFilter::Backend(address) => *elb_record.backend_address.ip() == address,
+            RecordFilter::Status(ref codes) => codes.contains(elb_record.elb_status_code),
+            RecordFilter::BackendStatus(ref codes) => {
+                codes.contains(elb_record.backend_status_code)
+            }
+            RecordFilter::Port(ref port) => listener_port(elb_record.request_url) == port,
+        }
+    }
+
+    /// Matches a normalized record, whose single status code stands in for both status codes.
+    /// It has no backend or port to match.
+    fn matches_normalized(&self, record: &NormalizedRecord, system: &str) -> bool {
+        match *self {
+            RecordFilter::System(ref name) => system == name,
+            RecordFilter::Client(address) => record.client_address == Some(address),
+            RecordFilter::Status(ref codes) |
+            RecordFilter::BackendStatus(ref codes) => codes.contains(record.status_code),
+            RecordFilter::Backend(_) |
+            RecordFilter::Port(_) => false,
+        }
+    }
+}
+
+/// The hours of the day, in minutes since midnight UTC, from the start up to but not including
+/// the end, so 09:00-17:00 holds 16:59:59 but not 17:00:00. A start after the end wraps past
+/// midnight.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct BusinessHours {
+    pub start_minute: u32,
+    pub end_minute: u32,
+}
+
+impl BusinessHours {
+    pub fn contains(&self, timestamp: DateTime<UTC>) -> bool {
+        let minute = timestamp.hour() * 60 + timestamp.minute();
+        if self.start_minute <= self.end_minute {
+            self.start_minute <= minute && minute < self.end_minute
+        } else {
+            self.start_minute <= minute || minute < self.end_minute
+        }
+    }
+}
+
+/// The upper bounds, in milliseconds, of the latency buckets used when none are given.
+pub const DEFAULT_LATENCY_BOUNDS_MILLIS: [u64; 5] = [10, 50, 100, 500, 1000];
+
+/// The bucket of records missing one of the processing times, which the ELB logs as -1 when it
+/// couldn't send the request to a backend or the backend didn't answer.
+pub const UNKNOWN_LATENCY_BUCKET: &'static str = "unknown";
+
+/// Buckets records by their total processing time, which is the sum of the request, backend and
+/// response processing times. Each bound is the exclusive upper end of a bucket labelled like
+/// `<50ms` or `<1s`, and a last bucket labelled like `>=1s` holds the records at or above the
+/// largest bound.
+#[derive(Clone, Debug, PartialEq)]
+pub struct LatencyBuckets {
+    bounds_millis: Vec<u64>,
+}
+
+impl LatencyBuckets {
+    /// The bounds must be ascending.
+    pub fn new(bounds_millis: Vec<u64>) -> LatencyBuckets {
+        LatencyBuckets { bounds_millis: bounds_millis }
+    }
+
+    /// Returns the label of the bucket a total processing time in seconds falls in.
+    pub fn label(&self, total_seconds: f32) -> String {
+        if total_seconds < 0.0 || !total_seconds.is_finite() {
+            return UNKNOWN_LATENCY_BUCKET.to_owned();
+        }
+        let total_millis = total_seconds * 1000.0;
+        match self.bounds_millis.iter().find(|&&bound| total_millis < bound as f32) {
+            Some(&bound) => format!("<{}", latency_bound_name(bound)),
+            None => {
+                match self.bounds_millis.last() {
+                    Some(&bound) => format!(">={}", latency_bound_name(bound)),
+                    None => ">=0ms".to_owned(),
+                }
+            }
+        }
+    }
+
+    fn bucket(&self, elb_record: &elp::ELBRecord) -> String {
+        let times = [elb_record.request_processing_time,
+                     elb_record.backend_processing_time,
+                     elb_record.response_processing_time];
+        if times.iter().any(|&time| time < 0.0) {
+            UNKNOWN_LATENCY_BUCKET.to_owned()
+        } else {
+            self.label(times.iter().sum())
+        }
+    }
+}
+
+impl Default for LatencyBuckets {
+    fn default() -> LatencyBuckets {
+        LatencyBuckets::new(DEFAULT_LATENCY_BOUNDS_MILLIS.to_vec())
+    }
+}
+
+fn latency_bound_name(bound_millis: u64) -> String {
+    if bound_millis > 0 && bound_millis % 1000 == 0 {
+        format!("{}s", bound_millis / 1000)
+    } else {
+        format!("{}ms", bound_millis)
+    }
+}
+
+/// A set of HTTP status codes, held as inclusive ranges.
+#[derive(Clone, Debug, PartialEq)]
+pub struct StatusCodes(pub Vec<(u16, u16)>);
+
+impl StatusCodes {
+    pub fn contains(&self, status_code: u16) -> bool {
+        self.0.iter().any(|&(low, high)| low <= status_code && status_code <= high)
+    }
+}
+
+/// A numeric record field that aggregate totals can be weighted by. Every ELB record carries both
+/// byte counts, but a record written by a parser command may lack its byte count, and is then
+/// weighted as zero, see `CountedRecord`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum WeightField {
+    ReceivedBytes,
+    SentBytes,
+}
+
+impl WeightField {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            WeightField::ReceivedBytes => "received_bytes",
+            WeightField::SentBytes => "sent_bytes",
+        }
+    }
+
+    fn weight(&self, elb_record: &elp::ELBRecord) -> i64 {
+        match *self {
+            WeightField::ReceivedBytes => elb_record.received_bytes as i64,
+            WeightField::SentBytes => elb_record.sent_bytes as i64,
+        }
+    }
+}
+
+impl Default for RecordConfig {
+    fn default() -> RecordConfig {
+        RecordConfig {
+            drop_no_system: false,
+            null_system_label: DEFAULT_NULL_SYSTEM_LABEL.to_owned(),
+            weight_field: None,
+            timestamp_resolution: None,
+            count_status: None,
+            latency_buckets: None,
+            date_range: None,
+            time_window: None,
+            filters: Vec::new(),
+            group_by: DEFAULT_GROUP_BY.to_vec(),
+            day_of_month: false,
+            business_hours: None,
+            count_overflow_policy: CountOverflowPolicy::Saturate,
+            hash_keys: false,
+            log_format: LogFormat::Auto,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum RecordOutcome {
+    Aggregated,
+    /// The record had no system name and was aggregated under the null system label.
+    AggregatedWithoutSystem,
+    /// The record had no system name and was skipped.
+    DroppedWithoutSystem,
+    /// The record's backend status isn't one of the status codes to count.
+    ExcludedByStatus,
+    /// The record is from a day outside of the date range to count.
+    OutsideDateRange,
+    /// The record is from a time of day outside of the business hours to count.
+    OutsideBusinessHours,
+    /// The record doesn't match one of the filters, see `RecordFilter`.
+    Filtered,
+    /// elp couldn't parse the record, for the described errors.
+    Unparsable(String),
+    /// Counting the record would have overflowed the total of its aggregate, which was left as it
+    /// was under the error overflow policy.
+    CountOverflowed,
+}
+
+pub fn try_parse_record(possible_record: &str,
+                        path_dimension: Option<&str>,
+                        config: &RecordConfig,
+                        dst_agg: &mut ELBRecordAggregation)
+                        -> RecordOutcome {
+    try_parse_record_with_metrics(possible_record, path_dimension, config, dst_agg, None, None)
+}
+
+/// Behaves like `try_parse_record` while including the record in the metrics of the aggregate
+/// it's counted towards, when there are metrics to keep, and filling in what is known of the
+/// record once it's aggregated, when given a `CountedRecord`.
+pub fn try_parse_record_with_metrics(possible_record: &str,
+                                     path_dimension: Option<&str>,
+                                     config: &RecordConfig,
+                                     dst_agg: &mut ELBRecordAggregation,
+                                     metrics: Option<&mut Metrics>,
+                                     counted: Option<&mut CountedRecord>)
+                                     -> RecordOutcome {
+    match elp::parse_record(possible_record) {
+        Ok(elb_record) => {
+            aggregate_parsed_record(&elb_record, path_dimension, config, dst_agg, metrics, counted)
+        }
+        Err(ref errs) => {
+            println_stderr!("{:?}", errs.record);
+            RecordOutcome::Unparsable(format!("{:?}", errs.errors))
+        }
+    }
+}
+
+/// Behaves like `try_parse_record_with_metrics` while adding the time spent parsing the record
+/// and building and counting its key to the timings.
+pub fn try_parse_record_profiled(possible_record: &str,
+                                 path_dimension: Option<&str>,
+                                 config: &RecordConfig,
+                                 dst_agg: &mut ELBRecordAggregation,
+                                 metrics: Option<&mut Metrics>,
+                                 counted: Option<&mut CountedRecord>,
+                                 timings: &PhaseTimings)
+                                 -> RecordOutcome {
+    let possible_elb_record = timings.time(Phase::Parsing, || elp::parse_record(possible_record));
+    match possible_elb_record {
+        Ok(elb_record) => {
+            timings.time(Phase::KeyBuilding, || {
+                aggregate_parsed_record(&elb_record,
+                                        path_dimension,
+                                        config,
+                                        dst_agg,
+                                        metrics,
+                                        counted)
+            })
+        }
+        Err(ref errs) => {
+            println_stderr!("{:?}", errs.record);
+            RecordOutcome::Unparsable(format!("{:?}", errs.errors))
+        }
+    }
+}
+
+/// A record written by a parser command in counter's normalized format, a tab separated line of
+/// its RFC 3339 timestamp, system name, client IPv4 address, byte count and status code. A
+/// missing system or client is written as `-`, and the client may carry a `:port`.
+#[derive(Debug, PartialEq)]
+pub struct NormalizedRecord {
+    pub timestamp: DateTime<UTC>,
+    pub system_name: Option<String>,
+    pub client_address: Option<Ipv4Addr>,
+    /// None when the byte count is missing or not a number, which only matters to weighted runs.
+    pub bytes: Option<u64>,
+    pub status_code: u16,
+}
+
+pub const NORMALIZED_FIELD_NAMES: [&'static str; 5] =
+    ["timestamp", "system", "client", "bytes", "status"];
+
+pub fn parse_normalized_record(line: &str) -> Result<NormalizedRecord, String> {
+    let fields: Vec<&str> = line.split('\t').collect();
+    if fields.len() != NORMALIZED_FIELD_NAMES.len() {
+        return Err(format!("expected the {} tab separated fields {}, found {}",
+                           NORMALIZED_FIELD_NAMES.len(),
+                           NORMALIZED_FIELD_NAMES.join(", "),
+                           fields.len()));
+    }
+    let timestamp = fields[0].parse::<DateTime<UTC>>()
+        .map_err(|_| format!("{} is not an RFC 3339 timestamp", fields[0]))?;
+    let system_name = match fields[1] {
+        "" | "-" => None,
+        system => Some(system.to_owned()),
+    };
+    let client_address = match fields[2] {
+        "" | "-" => None,
+        client => {
+            let address = client.splitn(2, ':').next().unwrap_or(client);
+            Some(address.parse::<Ipv4Addr>()
+                .map_err(|_| format!("{} is not an IPv4 client address", client))?)
+        }
+    };
+    let bytes = fields[3].parse::<u64>().ok();
+    let status_code = fields[4].parse::<u16>()
+        .map_err(|_| format!("{} is not a status code", fields[4]))?;
+    Ok(NormalizedRecord {
+        timestamp: timestamp,
+        system_name: system_name,
+        client_address: client_address,
+        bytes: bytes,
+        status_code: status_code,
+    })
+}
+
+/// What is known of a record once it has been aggregated, filled in by the functions that
+/// aggregate records when they are given one, so that the bookkeeping done after aggregating a
+/// record, such as its timespan and the key dictionary, doesn't parse it a second time.
+#[derive(Debug, Default, PartialEq)]
+pub struct CountedRecord {
+    pub timestamp: Option<DateTime<UTC>>,
+    /// The key the record was aggregated under with all of its fields, before it's hashed when
+    /// the keys are, see `full_record_key`.
+    pub full_key: Option<AggregateELBRecord>,
+    /// Whether the totals are weighted and the record had no numeric weight, so that it was
+    /// weighted as zero.
+    pub is_unweighted: bool,
+}
+
+/// Behaves like `try_parse_record` for a record in the normalized format, see
+/// `NormalizedRecord`. Its byte count is the weight whichever byte field the totals are weighted
+/// by. It has no backend, URL, listener port or processing times, so it is never grouped by them,
+/// and its status code stands in for the ELB's when grouped by status.
+pub fn try_parse_normalized_record(line: &str,
+                                   path_dimension: Option<&str>,
+                                   config: &RecordConfig,
+                                   dst_agg: &mut ELBRecordAggregation,
+                                   counted: Option<&mut CountedRecord>)
+                                   -> RecordOutcome {
+    let record = match parse_normalized_record(line) {
+        Ok(record) => record,
+        Err(error) => {
+            println_stderr!("{:?}", line);
+            return RecordOutcome::Unparsable(error);
+        }
+    };
+    if !config.date_range.as_ref().map_or(true, |range| range.contains(record.timestamp.date())) ||
+       !config.time_window.map_or(true, |window| window.contains(record.timestamp)) {
+        return RecordOutcome::OutsideDateRange;
+    }
+    if !config.business_hours.map_or(true, |hours| hours.contains(record.timestamp)) {
+        return RecordOutcome::OutsideBusinessHours;
+    }
+    if !config.count_status.as_ref().map_or(true, |codes| codes.contains(record.status_code)) {
+        return RecordOutcome::ExcludedByStatus;
+    }
+    let (system, outcome) = match record.system_name {
+        Some(system) => (system, RecordOutcome::Aggregated),
+        None if config.drop_no_system => return RecordOutcome::DroppedWithoutSystem,
+        None => (config.null_system_label.clone(), RecordOutcome::AggregatedWithoutSystem),
+    };
+    if !config.filters.iter().all(|filter| filter.matches_normalized(&record, &system)) {
+        return RecordOutcome::Filtered;
+    }
+    let day_of_month = if config.day_of_month {
+        Some(record.timestamp.day())
+    } else {
+        None
+    };
+    let aer = AggregateELBRecord {
+        day: match day_of_month {
+            Some(day_of_month) => UTC.ymd(1970, 1, day_of_month),
+            None => record.timestamp.date(),
+        },
+        client_address: if config.group_by.contains(&Dimension::Client) {
+            record.client_address
+        } else {
+            None
+        },
+        backend_address: None,
+        url: None,
+        listener_port: None,
+        hour: if config.group_by.contains(&Dimension::Hour) {
+            Some(record.timestamp.hour())
+        } else {
+            None
+        },
+        elb_status_code: if config.group_by.contains(&Dimension::Status) {
+            Some(record.status_code)
+        } else {
+            None
+        },
+        system_name: system,
+        path_dimension: path_dimension.map(|dimension| dimension.to_owned()),
+        latency_bucket: None,
+        day_of_month: day_of_month,
+    };
+    let weight = config.weight_field.map_or(1, |_| record.bytes.unwrap_or(0) as i64);
+    if let Some(counted) = counted {
+        counted.timestamp = Some(record.timestamp);
+        counted.full_key = Some(aer.clone());
+        counted.is_unweighted = config.weight_field.is_some() && record.bytes.is_none();
+    }
+    let aer = if config.hash_keys { aer.hashed() } else { aer };
+    if aggregate_weighted_record(aer, weight, config.count_overflow_policy, dst_agg) {
+        outcome
+    } else {
+        RecordOutcome::CountOverflowed
+    }
+}
+
+fn aggregate_parsed_record(elb_record: &elp::ELBRecord,
+                           path_dimension: Option<&str>,
+                           config: &RecordConfig,
+                           dst_agg: &mut ELBRecordAggregation,
+                           metrics: Option<&mut Metrics>,
+                           counted: Option<&mut CountedRecord>)
+                           -> RecordOutcome {
+    if !is_in_date_range(elb_record, config) {
+        return RecordOutcome::OutsideDateRange;
+    }
+    if !is_in_business_hours(elb_record, config) {
+        return RecordOutcome::OutsideBusinessHours;
+    }
+    if !is_counted_status(elb_record, config) {
+        return RecordOutcome::ExcludedByStatus;
+    }
+    let (system, outcome) = match parse_system_name(elb_record.request_url) {
+        Some(system) => (system, RecordOutcome::Aggregated),
+        None if config.drop_no_system => return RecordOutcome::DroppedWithoutSystem,
+        None => (config.null_system_label.clone(), RecordOutcome::AggregatedWithoutSystem),
+    };
+    if !matches_filters(elb_record, &system, config) {
+        return RecordOutcome::Filtered;
+    }
+    let aer = match counted {
+        Some(counted) => {
+            let full_key =
+                AggregateELBRecord::with_all_fields(elb_record, system, path_dimension, config);
+            let aer = if config.hash_keys { full_key.hashed() } else { full_key.clone() };
+            counted.timestamp = Some(elb_record.timestamp);
+            counted.full_key = Some(full_key);
+            aer
+        }
+        None => AggregateELBRecord::new(elb_record, system, path_dimension, config),
+    };
+    // Included before counting, which takes the key. A record whose count overflowed is still
+    // included, which only matters to runs that are aborted for the overflow.
+    if let Some(metrics) = metrics {
+        include_record_metrics(&aer, elb_record, metrics);
+    }
+    let weight = config.weight_field.map_or(1, |field| field.weight(elb_record));
+    if aggregate_weighted_record(aer, weight, config.count_overflow_policy, dst_agg) {
+        outcome
+    } else {
+        RecordOutcome::CountOverflowed
+    }
+}
+
+/// Returns the key the record would be aggregated under, or None when the record can't be parsed
+/// or is dropped. Unlike `try_parse_record` nothing is reported for records that can't be parsed.
+pub fn record_key(possible_record: &str,
+                  path_dimension: Option<&str>,
+                  config: &RecordConfig)
+                  -> Option<AggregateELBRecord> {
+    full_record_key(possible_record, path_dimension, config)
+        .map(|key| if config.hash_keys { key.hashed() } else { key })
+}
+
+/// Returns the key like `record_key`, but with all of its fields even when the keys are hashed.
+pub fn full_record_key(possible_record: &str,
+                       path_dimension: Option<&str>,
+                       config: &RecordConfig)
+                       -> Option<AggregateELBRecord> {
+    let elb_record = match elp::parse_record(possible_record) {
+        Ok(elb_record) => elb_record,
+        Err(_) => return None,
+    };
+    if !is_in_date_range(&elb_record, config) || !is_in_business_hours(&elb_record, config) ||
+       !is_counted_status(&elb_record, config) {
+        return None;
+    }
+    let system = match parse_system_name(elb_record.request_url) {
+        Some(system) => system,
+        None if config.drop_no_system => return None,
+        None => config.null_system_label.clone(),
+    };
+    if !matches_filters(&elb_record, &system, config) {
+        return None;
+    }
+    Some(AggregateELBRecord::with_all_fields(&elb_record, system, path_dimension, config))
+}
+
+fn latency_bucket(elb_record: &elp::ELBRecord, config: &RecordConfig) -> Option<String> {
+    config.latency_buckets.as_ref().map(|buckets| buckets.bucket(elb_record))
+}
+
+// Records the ELB answered without a backend log a - for the backend status, which elp rejects,
+// so every parsed record has a backend status to check.
+fn is_in_date_range(elb_record: &elp::ELBRecord, config: &RecordConfig) -> bool {
+    config.date_range
+        .as_ref()
+        .map_or(true, |range| range.contains(elb_record.timestamp.date())) &&
+    config.time_window.map_or(true, |window| window.contains(elb_record.timestamp))
+}
+
+fn is_in_business_hours(elb_record: &elp::ELBRecord, config: &RecordConfig) -> bool {
+    config.business_hours
+        .map_or(true, |hours| hours.contains(elb_record.timestamp))
+}
+
+fn is_counted_status(elb_record: &elp::ELBRecord, config: &RecordConfig) -> bool {
+    config.count_status
+        .as_ref()
+        .map_or(true, |codes| codes.contains(elb_record.backend_status_code))
+}
+
+fn matches_filters(elb_record: &elp::ELBRecord, system: &str, config: &RecordConfig) -> bool {
+    config.filters.iter().all(|filter| filter.matches(elb_record, system))
+}
+
+/// Describes, one step per line, how the record is parsed and which aggregate it would be counted
+/// under with the given options. Path dimensions come from the file a record was read from, so
+/// they aren't applied.
+pub fn explain_record(possible_record: &str, config: &RecordConfig) -> Vec<String> {
+    let mut steps = Vec::new();
+    let elb_part = as_elb_record(possible_record, config.log_format);
+    if elb_part.len() != possible_record.len() {
+        steps.push("Read the ALB record as the ELB record it starts with.".to_owned());
+    }
+    let elb_record = match elp::parse_record(elb_part) {
+        Ok(elb_record) => elb_record,
+        Err(ref errs) => {
+            steps.push(format!("The record could not be parsed and is skipped. {:?}",
+                               errs.errors));
+            return steps;
+        }
+    };
+    steps.push(format!("Parsed the record with timestamp {}, client address {} and request URL \
+                        {}.",
+                       elb_record.timestamp.to_rfc3339(),
+                       elb_record.client_address,
+                       elb_record.request_url));
+
+    if !is_in_date_range(&elb_record, config) {
+        steps.push(format!("The time {} is outside of --from, --to, --since and --until, so the \
+                            record is skipped.",
+                           elb_record.timestamp.to_rfc3339()));
+        return steps;
+    }
+
+    if !is_in_business_hours(&elb_record, config) {
+        steps.push(format!("The time {} is outside of --business-hours, so the record is skipped.",
+                           elb_record.timestamp.format("%H:%M:%S")));
+        return steps;
+    }
+
+    if !is_counted_status(&elb_record, config) {
+        steps.push(format!("The backend status {} isn't one of the --count-status codes, so the \
+                            record is excluded.",
+                           elb_record.backend_status_code));
+        return steps;
+    }
+
+    let system = match parse_system_name(elb_record.request_url) {
+        Some(system) => {
+            steps.push(format!("Found the system name {} in the request URL.", system));
+            system
+        }
+        None if config.drop_no_system => {
+            steps.push("Found no system name in the request URL, so the record is dropped \
+                        because --drop-no-system is set."
+                .to_owned());
+            return steps;
+        }
+        None => {
+            steps.push(format!("Found no system name in the request URL, so the record is \
+                                aggregated under the null system label {}.",
+                               config.null_system_label));
+            config.null_system_label.clone()
+        }
+    };
+
+    if !matches_filters(&elb_record, &system, config) {
+        steps.push("The record doesn't match every --filter, so it is skipped.".to_owned());
+        return steps;
+    }
+
+    let weight = match config.weight_field {
+        Some(field) => {
+            let weight = field.weight(&elb_record);
+            steps.push(format!("The record adds its {} of {} to the total.",
+                               field.name(),
+                               weight));
+            weight
+        }
+        None => 1,
+    };
+
+    let latency_bucket = match latency_bucket(&elb_record, config) {
+        Some(bucket) => {
+            steps.push(format!("The request, backend and response processing times of {}s, {}s \
+                                and {}s put the record in the latency bucket {}.",
+                               elb_record.request_processing_time,
+                               elb_record.backend_processing_time,
+                               elb_record.response_processing_time,
+                               bucket));
+            format!(",{}", bucket)
+        }
+        None => String::new(),
+    };
+
+    if config.day_of_month {
+        steps.push(format!("The record is counted under day {} of the month, together with the \
+                            same day of every other month.",
+                           elb_record.timestamp.day()));
+    }
+
+    let key = AggregateELBRecord::new(&elb_record, system, None, config);
+    let fields = config.group_by
+        .iter()
+        .filter_map(|&dimension| key.dimension_value(dimension))
+        .collect::<Vec<_>>();
+    steps.push(format!("Aggregated under the key {}{} with a weight of {}.",
+                       fields.join(","),
+                       latency_bucket,
+                       weight));
+    steps
+}
+
+/// Parses the record and discards the result, used to measure the cost of parsing on its own.
+pub fn parse_only(possible_record: &str) -> bool {
+    elp::parse_record(possible_record).is_ok()
+}
+
+/// Renders the fields a record would be aggregated by, using the full timestamp in place of the
+/// day, instead of aggregating it. Returns None when the record can't be parsed or is dropped.
+pub fn passthrough_record(possible_record: &str,
+                          path_dimension: Option<&str>,
+                          config: &RecordConfig)
+                          -> Option<String> {
+    match elp::parse_record(possible_record) {
+        Ok(ref elb_record) if !is_in_date_range(elb_record, config) ||
+                              !is_in_business_hours(elb_record, config) ||
+                              !is_counted_status(elb_record, config) => None,
+        Ok(elb_record) => {
+            let system = match parse_system_name(elb_record.request_url) {
+                Some(system) => system,
+                None if config.drop_no_system => return None,
+                None => config.null_system_label.clone(),
+            };
+            if !matches_filters(&elb_record, &system, config) {
+                return None;
+            }
+            let timestamp = match config.timestamp_resolution {
+                Some(resolution) => round_timestamp(elb_record.timestamp, resolution),
+                None => elb_record.timestamp,
+            };
+            let mut fields = vec![system,
+                                  timestamp.to_rfc3339(),
+                                  elb_record.client_address.ip().to_string()];
+            if let Some(dimension) = path_dimension {
+                fields.push(dimension.to_owned());
+            }
+            if let Some(bucket) = latency_bucket(&elb_record, config) {
+                fields.push(bucket);
+            }
+            Some(fields.join(","))
+        }
+        Err(ref errs) => {
+            println_stderr!("{:?}", errs.record);
+            None
+        }
+    }
+}
+
+/// Rounds the timestamp down to a multiple of the resolution since the epoch. The resolution is
+/// only honoured to the millisecond.
+pub fn round_timestamp(timestamp: DateTime<UTC>, resolution: Duration) -> DateTime<UTC> {
+    let resolution_millis = (resolution.as_secs() * 1000 +
+                             resolution.subsec_nanos() as u64 / 1_000_000)
+        .max(1) as i64;
+    let millis = timestamp.timestamp() * 1000 + timestamp.nanosecond() as i64 / 1_000_000;
+    let rounded_millis = millis - millis.rem_euclid(resolution_millis);
+    UTC.timestamp(rounded_millis.div_euclid(1000),
+                  (rounded_millis.rem_euclid(1000) * 1_000_000) as u32)
+}
+
+/// Returns the record with its timestamp rounded down to the resolution of the config, see
+/// `round_timestamp`, written back in the form ELB writes it. The record is returned as it is
+/// when the config has no resolution or the record has no timestamp.
+pub fn round_record_timestamp<'a>(record: &'a str, config: &RecordConfig) -> Cow<'a, str> {
+    let resolution = match config.timestamp_resolution {
+        Some(resolution) => resolution,
+        None => return Cow::Borrowed(record),
+    };
+    // ELB records start with the timestamp and application load balancer records with the type.
+    let timestamp_field = record.split(' ')
+        .take(2)
+        .find(|field| field.parse::<DateTime<UTC>>().is_ok());
+    match timestamp_field {
+        Some(field) => {
+            let rounded = round_timestamp(field.parse::<DateTime<UTC>>().unwrap(), resolution);
+            let start = record.find(field).unwrap();
+            Cow::Owned(format!("{}{}.{:06}Z{}",
+                               &record[..start],
+                               rounded.format("%Y-%m-%dT%H:%M:%S"),
+                               rounded.nanosecond() / 1000,
+                               &record[start + field.len()..]))
+        }
+        None => Cow::Borrowed(record),
+    }
+}
+
+lazy_static! {
+    static ref SYSTEM_REGEX: Regex = Regex::new(r"(?i)system=([^&]*)").unwrap();
+}
+
+/// Returns the name of the directory `depth` levels below `log_root` on the way to `file_path`,
+/// or `-` when the file is not nested that deeply.
+pub fn path_dimension_value(log_root: &Path, file_path: &Path, depth: usize) -> String {
+    file_path.strip_prefix(log_root)
+        .ok()
+        .and_then(|relative_path| relative_path.parent())
+        .and_then(|relative_dir| relative_dir.components().nth(depth - 1))
+        .and_then(|component| component.as_os_str().to_str())
+        .map(|dir_name| dir_name.to_owned())
+        .unwrap_or_else(|| "-".to_owned())
+}
+
+/// The classic ELB record an ALB record starts with once its leading request type is left out.
+/// The fields an ALB logs after the request, such as the user agent and the target group, aren't
+/// aggregated by, so they are left out too. ELB records are returned as they are. Auto detects ALB
+/// records by their request type, such as `http` or `h2`, as ELB records start with a timestamp.
+pub fn as_elb_record(record: &str, format: LogFormat) -> &str {
+    let is_alb = match format {
+        LogFormat::Elb => false,
+        LogFormat::Alb => true,
+        LogFormat::Auto => !record.starts_with(|c: char| c.is_digit(10)),
+    };
+    if !is_alb {
+        return record;
+    }
+    let record = match record.find(' ') {
+        Some(type_end) => &record[type_end + 1..],
+        None => return record,
+    };
+    // No field before the request is quoted.
+    let request_start = match record.find('"') {
+        Some(request_start) => request_start,
+        None => return record,
+    };
+    match record[request_start + 1..].find('"') {
+        Some(request_len) => &record[..request_start + request_len + 2],
+        None => record,
+    }
+}
+
+/// Finds the listener port in the request URL, which the ELB logs with the port the request was
+/// sent to, such as http://some.domain.com:80/path. Requests to TCP listeners are logged without
+/// a URL, so they and URLs without a port have the port `-`.
+fn listener_port(request_url: &str) -> &str {
+    let authority = match request_url.find("://") {
+        Some(scheme_end) => {
+            let rest = &request_url[scheme_end + 3..];
+            &rest[..rest.find('/').unwrap_or(rest.len())]
+        }
+        None => return "-",
+    };
+    match authority.rfind(':') {
+        Some(port_start) => {
+            let port = &authority[port_start + 1..];
+            if !port.is_empty() && port.chars().all(|c| c.is_digit(10)) {
+                port
+            } else {
+                "-"
+            }
+        }
+        None => "-",
+    }
+}
+
+/// Returns None when the URL has no system parameter or the system is empty or `-`.
+fn parse_system_name(src_str: &str) -> Option<String> {
+    SYSTEM_REGEX
+        .captures(src_str)
+        .and_then(|cap| cap.get(1).map(|sys| sys.as_str().to_string()))
+        .and_then(|sys| if sys.is_empty() || sys == "-" { None } else { Some(sys) })
+}
+
+pub fn merge_aggregates(src_aggs: &ELBRecordAggregation,
+                        dst_aggs: &mut ELBRecordAggregation)
+                        -> () {
+    merge_aggregates_with_policy(src_aggs, dst_aggs, CountOverflowPolicy::Saturate);
+}
+
+/// Merges like `merge_aggregates` while adding the totals by the overflow policy. Returns the
+/// number of totals that would have overflowed under the error policy, which are left as they
+/// were in `dst_aggs`.
+pub fn merge_aggregates_with_policy(src_aggs: &ELBRecordAggregation,
+                                    dst_aggs: &mut ELBRecordAggregation,
+                                    policy: CountOverflowPolicy)
+                                    -> usize {
+    let mut num_overflows = 0;
+    for (agg_key, agg_val) in src_aggs {
+        let total = dst_aggs.entry(agg_key.clone()).or_insert(0);
+        match policy.add(*total, *agg_val) {
+            Some(sum) => *total = sum,
+            None => num_overflows += 1,
+        }
+    }
+    num_overflows
+}
+
+/// Merges the aggregations into partitions holding the same totals as merging them one by one
+/// with `merge_aggregates_with_policy`, see `PartitionedAggregation`, and returns them along with
+/// the number of totals that would have overflowed.
+pub fn merge_partitioned(aggregations: Vec<ELBRecordAggregation>,
+                         num_partitions: usize,
+                         policy: CountOverflowPolicy)
+                         -> (Vec<ELBRecordAggregation>, usize) {
+    let mut partitioned = PartitionedAggregation::new(num_partitions, policy);
+    for aggregation in aggregations {
+        partitioned.merge(aggregation);
+    }
+    let num_overflows = partitioned.num_overflows();
+    (partitioned.into_partitions(), num_overflows)
+}
+
+/// An aggregation whose keys are split into partitions by their hash, so that an aggregation is
+/// merged into it on a thread per partition without sharing a map with the others. No key is in
+/// two partitions, so they're read one after the other as the merged aggregation rather than
+/// being merged into one map, which would hash every key again on a single thread.
+pub struct PartitionedAggregation {
+    pool: sp::Pool,
+    policy: CountOverflowPolicy,
+    partitions: Vec<ELBRecordAggregation>,
+    num_overflows: usize,
+}
+
+impl PartitionedAggregation {
+    /// Merges into `num_partitions` partitions, at least one, adding the totals by the policy.
+    pub fn new(num_partitions: usize, policy: CountOverflowPolicy) -> PartitionedAggregation {
+        let num_partitions = num_partitions.max(1);
+        PartitionedAggregation {
+            pool: sp::Pool::new(num_partitions),
+            policy: policy,
+            partitions: (0..num_partitions).map(|_| HashMap::new()).collect(),
+            num_overflows: 0,
+        }
+    }
+
+    /// Splits the aggregation by key and merges every piece into its partition, on a thread of
+    /// its own. The aggregation is dropped once it's merged, so that only the partitions are
+    /// held between merges.
+    pub fn merge(&mut self, aggregation: ELBRecordAggregation) -> () {
+        let pieces = partition_aggregation(aggregation, self.partitions.len());
+        let mut num_overflows = vec![0; pieces.len()];
+        let policy = self.policy;
+        let partitions = &mut self.partitions;
+        self.pool.scoped(|scope| {
+            for ((piece, partition), num_piece_overflows) in pieces.into_iter()
+                .zip(partitions.iter_mut())
+                .zip(num_overflows.iter_mut()) {
+                scope.execute(move || {
+                    *num_piece_overflows = merge_into_partition(piece, partition, policy)
+                });
+            }
+        });
+        self.num_overflows += num_overflows.iter().sum::<usize>();
+    }
+
+    /// The number of aggregates across the partitions.
+    pub fn len(&self) -> usize {
+        self.partitions.iter().map(|partition| partition.len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The number of totals that would have overflowed as the aggregations were merged.
+    pub fn num_overflows(&self) -> usize {
+        self.num_overflows
+    }
+
+    /// Stops the threads merging the partitions and returns the partitions.
+    pub fn into_partitions(mut self) -> Vec<ELBRecordAggregation> {
+        mem::replace(&mut self.partitions, Vec::new())
+    }
+}
+
+impl Drop for PartitionedAggregation {
+    fn drop(&mut self) {
+        self.pool.shutdown();
+    }
+}
+
+/// Splits the aggregation into `num_partitions` aggregations, placing every key by its hash.
+fn partition_aggregation(aggregation: ELBRecordAggregation,
+                         num_partitions: usize)
+                         -> Vec<ELBRecordAggregation> {
+    let mut partitions: Vec<ELBRecordAggregation> =
+        (0..num_partitions).map(|_| HashMap::new()).collect();
+    for (agg_key, agg_val) in aggregation {
+        let mut hasher = DefaultHasher::new();
+        agg_key.hash(&mut hasher);
+        let partition_idx = (hasher.finish() % num_partitions as u64) as usize;
+        partitions[partition_idx].insert(agg_key, agg_val);
+    }
+    partitions
+}
+
+/// Merges a piece of an aggregation into its partition, returning the number of totals that
+/// would have overflowed. The smaller of the two is merged into the larger, which saves
+/// rehashing the keys of the larger one.
+fn merge_into_partition(mut piece: ELBRecordAggregation,
+                        partition: &mut ELBRecordAggregation,
+                        policy: CountOverflowPolicy)
+                        -> usize {
+    if piece.len() > partition.len() {
+        mem::swap(&mut piece, partition);
+    }
+    let mut num_overflows = 0;
+    for (agg_key, agg_val) in piece {
+        let total = partition.entry(agg_key).or_insert(0);
+        match policy.add(*total, agg_val) {
+            Some(sum) => *total = sum,
+            None => num_overflows += 1,
         }
     }
+    num_overflows
+}
+
+/// The earliest and latest timestamps of the records counted towards an aggregate.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Timespan {
+    pub first_seen: DateTime<UTC>,
+    pub last_seen: DateTime<UTC>,
+}
+
+impl Timespan {
+    pub fn new(timestamp: DateTime<UTC>) -> Timespan {
+        Timespan {
+            first_seen: timestamp,
+            last_seen: timestamp,
+        }
+    }
+
+    pub fn include(&mut self, timestamp: DateTime<UTC>) -> () {
+        self.first_seen = self.first_seen.min(timestamp);
+        self.last_seen = self.last_seen.max(timestamp);
+    }
+
+    pub fn merge(&mut self, src: &Timespan) -> () {
+        self.first_seen = self.first_seen.min(src.first_seen);
+        self.last_seen = self.last_seen.max(src.last_seen);
+    }
+}
+
+/// The timespan of every aggregate, by the same keys as the aggregation.
+pub type Timespans = HashMap<AggregateELBRecord, Timespan>;
+
+pub fn merge_timespans(src: &Timespans, dst: &mut Timespans) -> () {
+    for (agg_key, timespan) in src {
+        match dst.get_mut(agg_key) {
+            Some(dst_timespan) => dst_timespan.merge(timespan),
+            None => {
+                dst.insert(agg_key.clone(), *timespan);
+            }
+        }
+    }
+}
+
+/// The latency histogram of `AggregateMetrics` has this many buckets, each this much wider than
+/// the one below it, starting from the smallest latency below. The percentiles it gives are at
+/// most a fifth above the actual latency, up to about 15 minutes.
+const LATENCY_HISTOGRAM_BUCKETS: usize = 96;
+const LATENCY_HISTOGRAM_GROWTH: f64 = 1.2;
+const MIN_HISTOGRAM_LATENCY_SECONDS: f64 = 0.0001;
+
+/// The bytes, latencies and ELB status codes of the records counted towards an aggregate. The
+/// latency of a record is the sum of its request, backend and response processing times, in
+/// seconds. Records the ELB logged without a processing time, as -1, have no latency.
+#[derive(Clone, Debug, PartialEq)]
+pub struct AggregateMetrics {
+    pub received_bytes: u64,
+    pub sent_bytes: u64,
+    pub min_latency: Option<f64>,
+    pub max_latency: Option<f64>,
+    /// The number of records answered with a 2xx, 3xx, 4xx and 5xx ELB status code, in that
+    /// order.
+    pub status_classes: [u64; 4],
+    latency_histogram: Vec<u64>,
+}
+
+impl AggregateMetrics {
+    pub fn new() -> AggregateMetrics {
+        AggregateMetrics {
+            received_bytes: 0,
+            sent_bytes: 0,
+            min_latency: None,
+            max_latency: None,
+            status_classes: [0; 4],
+            latency_histogram: vec![0; LATENCY_HISTOGRAM_BUCKETS],
+        }
+    }
+
+    /// Includes the record in the metrics. Like the totals under the saturate overflow policy,
+    /// the sums and counts stop at the largest value they can hold rather than overflowing.
+    pub fn include(&mut self, elb_record: &elp::ELBRecord) -> () {
+        self.received_bytes = self.received_bytes.saturating_add(elb_record.received_bytes as u64);
+        self.sent_bytes = self.sent_bytes.saturating_add(elb_record.sent_bytes as u64);
+        let class = (elb_record.elb_status_code / 100) as usize;
+        if (2..6).contains(&class) {
+            self.status_classes[class - 2] = self.status_classes[class - 2].saturating_add(1);
+        }
+        let times = [elb_record.request_processing_time,
+                     elb_record.backend_processing_time,
+                     elb_record.response_processing_time];
+        if times.iter().any(|&time| time < 0.0) {
+            return;
+        }
+        let latency = times.iter().map(|&time| time as f64).sum::<f64>();
+        self.min_latency = Some(self.min_latency.map_or(latency, |min| min.min(latency)));
+        self.max_latency = Some(self.max_latency.map_or(latency, |max| max.max(latency)));
+        let bucket = latency_histogram_bucket(latency);
+        self.latency_histogram[bucket] = self.latency_histogram[bucket].saturating_add(1);
+    }
+
+    /// Adds the metrics of `src`, saturating like `include`.
+    pub fn merge(&mut self, src: &AggregateMetrics) -> () {
+        self.received_bytes = self.received_bytes.saturating_add(src.received_bytes);
+        self.sent_bytes = self.sent_bytes.saturating_add(src.sent_bytes);
+        self.min_latency = match (self.min_latency, src.min_latency) {
+            (Some(min), Some(src_min)) => Some(min.min(src_min)),
+            (min, src_min) => min.or(src_min),
+        };
+        self.max_latency = match (self.max_latency, src.max_latency) {
+            (Some(max), Some(src_max)) => Some(max.max(src_max)),
+            (max, src_max) => max.or(src_max),
+        };
+        for (class, src_class) in self.status_classes.iter_mut().zip(&src.status_classes) {
+            *class = class.saturating_add(*src_class);
+        }
+        for (count, src_count) in self.latency_histogram.iter_mut().zip(&src.latency_histogram) {
+            *count = count.saturating_add(*src_count);
+        }
+    }
+
+    /// The approximate latency that `percentile` percent of the records with a latency are at or
+    /// under, the upper bound of the histogram bucket it falls in kept within the minimum and
+    /// maximum latency. None when no record had a latency.
+    pub fn latency_percentile(&self, percentile: f64) -> Option<f64> {
+        let (min, max) = match (self.min_latency, self.max_latency) {
+            (Some(min), Some(max)) => (min, max),
+            _ => return None,
+        };
+        let num_records = self.latency_histogram
+            .iter()
+            .fold(0u64, |num_records, count| num_records.saturating_add(*count));
+        let rank = ((percentile / 100.0 * num_records as f64).ceil() as u64).max(1);
+        let mut num_seen = 0;
+        for (bucket, count) in self.latency_histogram.iter().enumerate() {
+            num_seen += *count;
+            if num_seen >= rank {
+                let upper_bound = MIN_HISTOGRAM_LATENCY_SECONDS *
+                                  LATENCY_HISTOGRAM_GROWTH.powi(bucket as i32);
+                return Some(upper_bound.max(min).min(max));
+            }
+        }
+        Some(max)
+    }
+}
+
+impl Default for AggregateMetrics {
+    fn default() -> AggregateMetrics {
+        AggregateMetrics::new()
+    }
+}
+
+fn latency_histogram_bucket(latency: f64) -> usize {
+    if latency <= MIN_HISTOGRAM_LATENCY_SECONDS {
+        return 0;
+    }
+    let bucket = ((latency / MIN_HISTOGRAM_LATENCY_SECONDS).ln() / LATENCY_HISTOGRAM_GROWTH.ln())
+        .ceil() as usize;
+    bucket.min(LATENCY_HISTOGRAM_BUCKETS - 1)
+}
+
+/// The metrics of every aggregate, by the same keys as the aggregation.
+pub type Metrics = HashMap<AggregateELBRecord, AggregateMetrics>;
+
+pub fn merge_metrics(src: &Metrics, dst: &mut Metrics) -> () {
+    for (agg_key, metrics) in src {
+        dst.entry(agg_key.clone()).or_insert_with(AggregateMetrics::new).merge(metrics);
+    }
+}
+
+/// Includes the record in the metrics of the aggregate keyed by `key`, which is only cloned for
+/// the first record of the aggregate.
+fn include_record_metrics(key: &AggregateELBRecord,
+                          elb_record: &elp::ELBRecord,
+                          metrics: &mut Metrics)
+                          -> () {
+    if let Some(aggregate_metrics) = metrics.get_mut(key) {
+        aggregate_metrics.include(elb_record);
+        return;
+    }
+    let mut aggregate_metrics = AggregateMetrics::new();
+    aggregate_metrics.include(elb_record);
+    metrics.insert(key.clone(), aggregate_metrics);
+}
+
+/// How aggregates with totals below a count floor are published.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FloorMode {
+    /// Drop the aggregate entirely.
+    Suppress,
+    /// Report the floor in place of the real total.
+    Round,
+}
+
+/// Hides small totals so published aggregates don't reveal that only a handful of clients, or a
+/// single one, did something. Either mode alters the published counts, with `Round` inflating
+/// the grand total.
+pub fn apply_count_floor(aggs: &mut ELBRecordAggregation, floor: i64, mode: FloorMode) -> () {
+    match mode {
+        FloorMode::Suppress => aggs.retain(|_, total| *total >= floor),
+        FloorMode::Round => {
+            for total in aggs.values_mut() {
+                if *total < floor {
+                    *total = floor;
+                }
+            }
+        }
+    }
+}
+
+/// Keeps at most `limit` aggregates for each system, choosing the aggregates with the highest
+/// totals. Systems are returned in name order and the aggregates within a system are ordered by
+/// total descending, with ties broken by day and then client address so the result is stable.
+pub fn limit_per_system<I>(aggs: I, limit: usize) -> Vec<(AggregateELBRecord, i64)>
+    where I: IntoIterator<Item = (AggregateELBRecord, i64)>
+{
+    let mut systems: HashMap<String, Vec<(AggregateELBRecord, i64)>> = HashMap::new();
+    for (agg_key, agg_val) in aggs {
+        systems.entry(agg_key.system_name.clone())
+            .or_insert_with(Vec::new)
+            .push((agg_key, agg_val));
+    }
+
+    let mut system_names = systems.keys().cloned().collect::<Vec<_>>();
+    system_names.sort();
+
+    let mut limited_aggs = Vec::new();
+    for system_name in system_names {
+        let mut system_aggs = systems.remove(&system_name).unwrap();
+        system_aggs.sort_by(compare_by_total_desc);
+        system_aggs.truncate(limit);
+        limited_aggs.extend(system_aggs);
+    }
+
+    limited_aggs
+}
+
+/// Sums the totals of each system, ordering the systems by total descending and then by name.
+pub fn totals_by_system(aggs: &[(AggregateELBRecord, i64)]) -> Vec<(String, i64)> {
+    let mut systems: HashMap<&str, i64> = HashMap::new();
+    for &(ref agg_key, agg_val) in aggs {
+        let total = systems.entry(agg_key.system_name.as_str()).or_insert(0);
+        *total = total.saturating_add(agg_val);
+    }
+
+    let mut totals = systems.into_iter()
+        .map(|(system_name, total)| (system_name.to_owned(), total))
+        .collect::<Vec<_>>();
+    totals.sort_by(|lhs, rhs| rhs.1.cmp(&lhs.1).then_with(|| lhs.0.cmp(&rhs.0)));
+    totals
+}
+
+/// A system whose total fell by more than allowed compared to a baseline, see `regressions`.
+#[derive(Debug, PartialEq)]
+pub struct Regression {
+    pub system_name: String,
+    pub baseline_total: i64,
+    pub total: i64,
+    /// The change of the total relative to the baseline total, negative for a drop.
+    pub percent_change: f64,
+}
+
+/// Finds the systems of the baseline whose total in the aggregation fell by more than
+/// `max_drop_percent` percent, ordered by system name. Systems missing from the aggregation have
+/// a total of zero, while those with no total in the baseline can't have dropped.
+pub fn regressions<'a, I>(aggregates: I,
+                          baseline: &BTreeMap<String, i64>,
+                          max_drop_percent: f64)
+                          -> Vec<Regression>
+    where I: IntoIterator<Item = (&'a AggregateELBRecord, &'a i64)>
+{
+    let mut totals: HashMap<&str, i64> = HashMap::new();
+    for (agg_key, &agg_val) in aggregates {
+        let total = totals.entry(agg_key.system_name.as_str()).or_insert(0);
+        *total = total.saturating_add(agg_val);
+    }
+
+    baseline.iter()
+        .filter(|&(_, &baseline_total)| baseline_total > 0)
+        .filter_map(|(system_name, &baseline_total)| {
+            let total = totals.get(system_name.as_str()).cloned().unwrap_or(0);
+            let percent_change = (total - baseline_total) as f64 * 100.0 / baseline_total as f64;
+            if -percent_change > max_drop_percent {
+                Some(Regression {
+                    system_name: system_name.clone(),
+                    baseline_total: baseline_total,
+                    total: total,
+                    percent_change: percent_change,
+                })
+            } else {
+                None
+            }
+        })
+        .collect()
+}
+
+fn compare_by_total_desc(lhs: &(AggregateELBRecord, i64),
+                         rhs: &(AggregateELBRecord, i64))
+                         -> Ordering {
+    rhs.1
+        .cmp(&lhs.1)
+        .then_with(|| lhs.0.day.cmp(&rhs.0.day))
+        .then_with(|| lhs.0.client_address.cmp(&rhs.0.client_address))
+        .then_with(|| lhs.0.backend_address.cmp(&rhs.0.backend_address))
+        .then_with(|| lhs.0.url.cmp(&rhs.0.url))
+        .then_with(|| lhs.0.listener_port.cmp(&rhs.0.listener_port))
+        .then_with(|| lhs.0.hour.cmp(&rhs.0.hour))
+        .then_with(|| lhs.0.elb_status_code.cmp(&rhs.0.elb_status_code))
+        .then_with(|| lhs.0.path_dimension.cmp(&rhs.0.path_dimension))
+        .then_with(|| lhs.0.latency_bucket.cmp(&rhs.0.latency_bucket))
+}
+
+/// How the aggregates are ordered in the output. Every order falls back on `compare_by_key`, so
+/// aggregates are never left in an arbitrary order.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SortOrder {
+    /// By total, largest first.
+    Count,
+    /// By the whole key, see `compare_by_key`.
+    Key,
+    System,
+    Day,
+    /// By client address, with the aggregates not grouped by client first.
+    Client,
+}
+
+/// Orders aggregates the way described by `order`, reversed when `reverse` is set.
+pub fn compare_by(order: SortOrder,
+                  reverse: bool,
+                  lhs: &(AggregateELBRecord, i64),
+                  rhs: &(AggregateELBRecord, i64))
+                  -> Ordering {
+    let ordering = match order {
+        SortOrder::Count => rhs.1.cmp(&lhs.1),
+        SortOrder::Key | SortOrder::System => Ordering::Equal,
+        SortOrder::Day => lhs.0.day.cmp(&rhs.0.day),
+        SortOrder::Client => lhs.0.client_address.cmp(&rhs.0.client_address),
+    };
+    let ordering = ordering.then_with(|| compare_by_key(&lhs.0, &rhs.0));
+    if reverse {
+        ordering.reverse()
+    } else {
+        ordering
+    }
+}
+
+/// Orders aggregates by system name, then day, then client address, then the other dimensions.
+pub fn compare_by_key(lhs: &AggregateELBRecord, rhs: &AggregateELBRecord) -> Ordering {
+    lhs.system_name
+        .cmp(&rhs.system_name)
+        .then_with(|| lhs.day.cmp(&rhs.day))
+        .then_with(|| lhs.client_address.cmp(&rhs.client_address))
+        .then_with(|| lhs.backend_address.cmp(&rhs.backend_address))
+        .then_with(|| lhs.url.cmp(&rhs.url))
+        .then_with(|| lhs.listener_port.cmp(&rhs.listener_port))
+        .then_with(|| lhs.hour.cmp(&rhs.hour))
+        .then_with(|| lhs.elb_status_code.cmp(&rhs.elb_status_code))
+        .then_with(|| lhs.path_dimension.cmp(&rhs.path_dimension))
+        .then_with(|| lhs.latency_bucket.cmp(&rhs.latency_bucket))
+}
+
+/// Computes a SHA-256 over the aggregates in key order so that two runs over the same data
+/// produce the same hash regardless of the order the aggregates were produced in.
+pub fn result_hash(aggs: &[(AggregateELBRecord, i64)]) -> String {
+    let mut sorted_aggs = aggs.iter().collect::<Vec<_>>();
+    sorted_aggs.sort_by(|lhs, rhs| compare_by_key(&lhs.0, &rhs.0));
+
+    let mut hasher = Sha256::default();
+    // Fields the aggregates aren't keyed by are left out, so the hash of the default dimensions
+    // stays the same.
+    fn optional_field<T: ToString>(field: Option<T>) -> String {
+        field.map(|field| format!("{},", field.to_string())).unwrap_or_default()
+    }
+    for &&(ref agg_key, agg_val) in &sorted_aggs {
+        hasher.input(format!("{},{},{}{}{}{}{}{}{}{}{}\n",
+                             agg_key.system_name,
+                             agg_key.day.format("%Y-%m-%d"),
+                             optional_field(agg_key.client_address),
+                             optional_field(agg_key.backend_address),
+                             optional_field(agg_key.url.as_ref()),
+                             optional_field(agg_key.listener_port.as_ref()),
+                             optional_field(agg_key.hour),
+                             optional_field(agg_key.elb_status_code),
+                             optional_field(agg_key.path_dimension.as_ref()),
+                             optional_field(agg_key.latency_bucket.as_ref()),
+                             agg_val)
+            .as_bytes());
+    }
+
+    hasher.result()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+/// How the aggregates are written to stdout.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum OutputFormat {
+    Csv,
+    /// Like Csv, with the fields separated by tabs.
+    Tsv,
+    /// A single JSON array of the aggregates, see `output::JsonSink`.
+    Json,
+    /// Newline delimited JSON, see `output::JsonLinesSink`.
+    JsonLines,
+    /// InfluxDB line protocol, with the start of the aggregate's day as the timestamp.
+    Influx,
+    /// A CSV matrix of the totals of every system on every day, see `output::MatrixSink`.
+    Matrix,
+    /// A YAML sequence of the aggregates, see `yaml_output::YamlSink`.
+    #[cfg(feature = "yaml")]
+    Yaml,
+    /// Build the aggregates without writing them, so benchmarks leave out the cost of output.
+    Discard,
+}
+
+/// Renders an aggregate as an InfluxDB line protocol point of the requests measurement, tagged
+/// with its system, the client, backend and URL it's grouped by, path dimension and latency
+/// bucket. Tags with empty values can't be written, so they are left out.
+pub fn influx_line(aggregate: &AggregateELBRecord,
+                   total: i64,
+                   path_dimension_name: Option<&str>)
+                   -> String {
+    let client_address = aggregate.client_address.map(|address| address.to_string());
+    let backend_address = aggregate.backend_address.map(|address| address.to_string());
+    let hour = aggregate.hour.map(|hour| hour.to_string());
+    let elb_status_code = aggregate.elb_status_code.map(|code| code.to_string());
+    let mut tags = vec![("system", aggregate.system_name.as_str())];
+    if let Some(ref address) = client_address {
+        tags.push(("client", address.as_str()));
+    }
+    if let Some(ref address) = backend_address {
+        tags.push(("backend", address.as_str()));
+    }
+    if let Some(ref url) = aggregate.url {
+        tags.push(("url", url.as_str()));
+    }
+    if let Some(ref port) = aggregate.listener_port {
+        tags.push(("port", port.as_str()));
+    }
+    if let Some(ref hour) = hour {
+        tags.push(("hour", hour.as_str()));
+    }
+    if let Some(ref code) = elb_status_code {
+        tags.push(("status", code.as_str()));
+    }
+    if let (Some(name), Some(value)) = (path_dimension_name, aggregate.path_dimension.as_ref()) {
+        tags.push((name, value.as_str()));
+    }
+    if let Some(ref bucket) = aggregate.latency_bucket {
+        tags.push(("latency", bucket.as_str()));
+    }
+    let day_of_month = aggregate.day_of_month.map(|day_of_month| day_of_month.to_string());
+    if let Some(ref day_of_month) = day_of_month {
+        tags.push(("day_of_month", day_of_month.as_str()));
+    }
+
+    let mut line = String::from("requests");
+    for (key, value) in tags.into_iter().filter(|&(_, value)| !value.is_empty()) {
+        line.push_str(&format!(",{}={}", escape_influx_tag(key), escape_influx_tag(value)));
+    }
+    line.push_str(&format!(" count={}i", total));
+    // A day of the month is no point in time, so those points are left for Influx to timestamp.
+    if aggregate.day_of_month.is_none() {
+        line.push_str(&format!(" {}000000000", aggregate.day.and_hms(0, 0, 0).timestamp()));
+    }
+    line
+}
+
+/// Builds the InfluxDB line protocol point of a system's total, as listed by `totals_by_system`.
+/// The point has no timestamp, so the database stamps it with the time it is written.
+pub fn influx_system_line(system_name: &str, total: i64) -> String {
+    if system_name.is_empty() {
+        format!("requests count={}i", total)
+    } else {
+        format!("requests,system={} count={}i", escape_influx_tag(system_name), total)
+    }
+}
+
+fn escape_influx_tag(tag: &str) -> String {
+    tag.replace('\\', "\\\\")
+        .replace(',', "\\,")
+        .replace('=', "\\=")
+        .replace(' ', "\\ ")
+}
+
+/// The delimiter separating the fields of the CSV output.
+pub const CSV_DELIMITER: char = ',';
+
+/// The delimiter separating the fields of the TSV output.
+pub const TSV_DELIMITER: char = '\t';
+
+/// Returns the first of the aggregate's text fields containing the delimiter. Such a field is
+/// written quoted, which some readers don't understand.
+pub fn field_containing(aggregate: &AggregateELBRecord, delimiter: char) -> Option<&str> {
+    Some(aggregate.system_name.as_str())
+        .into_iter()
+        .chain(aggregate.url.as_ref().map(|url| url.as_str()))
+        .chain(aggregate.path_dimension.as_ref().map(|dimension| dimension.as_str()))
+        .find(|field| field.contains(delimiter))
+}
+
+/// Marks the end of a field shortened by `truncate_field`.
+pub const TRUNCATION_MARKER: &'static str = "...";
+
+/// Shortens a field longer than `max_chars` characters to its first `max_chars` characters
+/// followed by `TRUNCATION_MARKER`. Truncation is cosmetic: two distinct values sharing their
+/// first `max_chars` characters look identical once truncated.
+pub fn truncate_field(field: &str, max_chars: usize) -> Cow<str> {
+    match field.char_indices().nth(max_chars) {
+        Some((end, _)) => Cow::Owned(format!("{}{}", &field[..end], TRUNCATION_MARKER)),
+        None => Cow::Borrowed(field),
+    }
+}
+
+/// Copies the aggregate with each of its text fields shortened by `truncate_field`.
+pub fn truncate_fields(aggregate: &AggregateELBRecord, max_chars: usize) -> AggregateELBRecord {
+    AggregateELBRecord {
+        system_name: truncate_field(&aggregate.system_name, max_chars).into_owned(),
+        url: aggregate.url.as_ref().map(|url| truncate_field(url, max_chars).into_owned()),
+        path_dimension: aggregate.path_dimension
+            .as_ref()
+            .map(|dimension| truncate_field(dimension, max_chars).into_owned()),
+        ..aggregate.clone()
+    }
+}
+
+fn aggregate_record(aggregate_record: AggregateELBRecord,
+                    dst_aggs: &mut ELBRecordAggregation)
+                    -> () {
+    aggregate_weighted_record(aggregate_record, 1, CountOverflowPolicy::Saturate, dst_aggs);
+}
+
+/// Returns false when the weight would overflow the total under the error policy, leaving the
+/// total as it was.
+fn aggregate_weighted_record(aggregate_record: AggregateELBRecord,
+                             weight: i64,
+                             policy: CountOverflowPolicy,
+                             dst_aggs: &mut ELBRecordAggregation)
+                             -> bool {
+    let total = dst_aggs.entry(aggregate_record).or_insert(0);
+    match policy.add(*total, weight) {
+        Some(sum) => {
+            *total = sum;
+            true
+        }
+        None => false,
+    }
+}
+
+/// Totals saturate at `SATURATED_TOTAL` rather than wrapping around, which byte weighted totals of
+/// the busiest aggregates could otherwise do.
+pub const SATURATED_TOTAL: i64 = ::std::i64::MAX;
+
+/// Counts the aggregates whose totals saturated, and so are less than the true total.
+pub fn num_saturated(aggs: &ELBRecordAggregation) -> usize {
+    aggs.values().filter(|&&total| total == SATURATED_TOTAL).count()
+}
+
+#[cfg(test)]
+mod handle_parsing_result_tests {
+
+    extern crate elp;
+
+    use chrono::{DateTime, UTC};
+    use std::collections::HashMap;
+
+    const GOOD_RECORD0: &'static str = "2015-08-15T23:43:05.302180Z elb-name 172.16.1.6:54814 \
+                    172.16.1.5:9000 0.000039 0.145507 0.00003 200 200 0 7582 \
+                    \"GET http://some.domain.com:80/path0/path1?param0=p0&param1=p1 HTTP/1.1\"";
+    const GOOD_RECORD1: &'static str = "2016-08-15T23:43:05.302180Z elb-name 172.16.1.6:54814 \
+                    172.16.1.5:9000 0.000039 0.145507 0.00003 200 200 0 7582 \
+                    \"GET http://some.domain.com:80/path0/path1?param0=p0&param1=p1 HTTP/1.1\"";
+    #[test]
+    fn handle_parsing_result_should_not_alter_the_dst_agg_when_passed_bad_records() {
+        let mut dst_agg: super::ELBRecordAggregation = HashMap::new();
+        let bad_record = "";
+
+        super::try_parse_record(GOOD_RECORD0, None, &Default::default(), &mut dst_agg);
+        super::try_parse_record(bad_record, None, &Default::default(), &mut dst_agg);
+
+        assert_eq!(dst_agg.len(), 1)
+    }
+
+    #[test]
+    fn handle_parsing_result_should_update_the_dst_agg_when_passed_good_records() {
+        let mut dst_agg: super::ELBRecordAggregation = HashMap::new();
+
+        super::try_parse_record(GOOD_RECORD0, None, &Default::default(), &mut dst_agg);
+        super::try_parse_record(GOOD_RECORD1, None, &Default::default(), &mut dst_agg);
+
+        assert_eq!(dst_agg.len(), 2)
+    }
+
+    #[test]
+    fn handle_parsing_result_should_use_the_null_system_label_for_records_without_a_system() {
+        let mut dst_agg: super::ELBRecordAggregation = HashMap::new();
+        let config = super::RecordConfig {
+            null_system_label: "-".to_owned(),
+            ..Default::default()
+        };
+
+        let outcome = super::try_parse_record(GOOD_RECORD0, None, &config, &mut dst_agg);
+
+        assert_eq!(outcome, super::RecordOutcome::AggregatedWithoutSystem);
+        assert!(dst_agg.keys().all(|key| key.system_name == "-"))
+    }
+
+    #[test]
+    fn handle_parsing_result_should_drop_records_without_a_system_when_configured_to() {
+        let mut dst_agg: super::ELBRecordAggregation = HashMap::new();
+        let config = super::RecordConfig {
+            drop_no_system: true,
+            ..Default::default()
+        };
+
+        let outcome = super::try_parse_record(GOOD_RECORD0, None, &config, &mut dst_agg);
+
+        assert_eq!(outcome, super::RecordOutcome::DroppedWithoutSystem);
+        assert_eq!(dst_agg.len(), 0)
+    }
+
+    #[test]
+    fn handle_parsing_result_should_add_the_weight_field_to_the_total_when_configured_to() {
+        let mut dst_agg: super::ELBRecordAggregation = HashMap::new();
+        let config = super::RecordConfig {
+            weight_field: Some(super::WeightField::SentBytes),
+            ..Default::default()
+        };
+
+        super::try_parse_record(GOOD_RECORD0, None, &config, &mut dst_agg);
+        super::try_parse_record(GOOD_RECORD0, None, &config, &mut dst_agg);
+
+        assert_eq!(dst_agg.values().collect::<Vec<_>>(), vec![&(2 * 7582)])
+    }
+
+    #[test]
+    fn handle_parsing_result_should_exclude_records_outside_the_status_codes_to_count() {
+        let mut dst_agg: super::ELBRecordAggregation = HashMap::new();
+        let config = super::RecordConfig {
+            count_status: Some(super::StatusCodes(vec![(201, 204), (300, 399)])),
+            ..Default::default()
+        };
+
+        let outcome = super::try_parse_record(GOOD_RECORD0, None, &config, &mut dst_agg);
+
+        assert_eq!(outcome, super::RecordOutcome::ExcludedByStatus);
+        assert_eq!(dst_agg.len(), 0)
+    }
+
+    #[test]
+    fn handle_parsing_result_should_count_records_inside_the_status_codes_to_count() {
+        let mut dst_agg: super::ELBRecordAggregation = HashMap::new();
+        let config = super::RecordConfig {
+            count_status: Some(super::StatusCodes(vec![(200, 200)])),
+            ..Default::default()
+        };
+
+        super::try_parse_record(GOOD_RECORD0, None, &config, &mut dst_agg);
+
+        assert_eq!(dst_agg.len(), 1)
+    }
+
+    fn date_range_config(from: Option<&str>, to: Option<&str>) -> super::RecordConfig {
+        let day = |day: &str| format!("{}T00:00:00Z", day).parse::<DateTime<UTC>>().unwrap().date();
+        super::RecordConfig {
+            date_range: Some(super::DateRange {
+                from: from.map(&day),
+                to: to.map(&day),
+            }),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn handle_parsing_result_should_count_records_on_either_bound_of_the_date_range() {
+        let mut dst_agg: super::ELBRecordAggregation = HashMap::new();
+
+        let on_from = super::try_parse_record(GOOD_RECORD0,
+                                              None,
+                                              &date_range_config(Some("2015-08-15"), None),
+                                              &mut dst_agg);
+        let on_to = super::try_parse_record(GOOD_RECORD0,
+                                            None,
+                                            &date_range_config(None, Some("2015-08-15")),
+                                            &mut dst_agg);
+
+        assert_eq!(on_from, super::RecordOutcome::AggregatedWithoutSystem);
+        assert_eq!(on_to, super::RecordOutcome::AggregatedWithoutSystem);
+        assert_eq!(dst_agg.values().collect::<Vec<_>>(), vec![&2])
+    }
+
+    #[test]
+    fn handle_parsing_result_should_skip_records_outside_of_the_date_range() {
+        let mut dst_agg: super::ELBRecordAggregation = HashMap::new();
+
+        let before_from = super::try_parse_record(GOOD_RECORD0,
+                                                  None,
+                                                  &date_range_config(Some("2015-08-16"), None),
+                                                  &mut dst_agg);
+        let after_to = super::try_parse_record(GOOD_RECORD0,
+                                               None,
+                                               &date_range_config(Some("2015-08-01"),
+                                                                  Some("2015-08-14")),
+                                               &mut dst_agg);
+
+        assert_eq!(before_from, super::RecordOutcome::OutsideDateRange);
+        assert_eq!(after_to, super::RecordOutcome::OutsideDateRange);
+        assert_eq!(dst_agg.len(), 0)
+    }
+
+    #[test]
+    fn handle_parsing_result_should_skip_records_outside_of_the_time_window() {
+        let mut dst_agg: super::ELBRecordAggregation = HashMap::new();
+        let window_config = |since: Option<&str>, until: Option<&str>| {
+            super::RecordConfig {
+                time_window: Some(super::TimeWindow {
+                    since: since.map(|since| since.parse::<DateTime<UTC>>().unwrap()),
+                    until: until.map(|until| until.parse::<DateTime<UTC>>().unwrap()),
+                }),
+                ..Default::default()
+            }
+        };
+
+        let on_since = super::try_parse_record(GOOD_RECORD0,
+                                               None,
+                                               &window_config(Some("2015-08-15T23:43:05.302180Z"),
+                                                              None),
+                                               &mut dst_agg);
+        let on_until = super::try_parse_record(GOOD_RECORD0,
+                                               None,
+                                               &window_config(None,
+                                                              Some("2015-08-15T23:43:05.302180Z")),
+                                               &mut dst_agg);
+        let before_since = super::try_parse_record(GOOD_RECORD0,
+                                                   None,
+                                                   &window_config(Some("2015-08-15T23:44:00Z"),
+                                                                  None),
+                                                   &mut dst_agg);
+
+        assert_eq!(on_since, super::RecordOutcome::AggregatedWithoutSystem);
+        assert_eq!(on_until, super::RecordOutcome::OutsideDateRange);
+        assert_eq!(before_since, super::RecordOutcome::OutsideDateRange);
+        assert_eq!(dst_agg.values().collect::<Vec<_>>(), vec![&1])
+    }
+
+    #[test]
+    fn handle_parsing_result_should_only_count_records_matching_every_filter() {
+        let mut dst_agg: super::ELBRecordAggregation = HashMap::new();
+        let filter_config = |filters| {
+            super::RecordConfig {
+                filters: filters,
+                ..Default::default()
+            }
+        };
+        let backend = super::RecordFilter::Backend("172.16.1.5".parse().unwrap());
+        let server_errors = super::RecordFilter::Status(super::StatusCodes(vec![(500, 599)]));
+        let system = super::RecordFilter::System(super::DEFAULT_NULL_SYSTEM_LABEL.to_owned());
+
+        let from_backend = super::try_parse_record(GOOD_RECORD0,
+                                                   None,
+                                                   &filter_config(vec![backend.clone()]),
+                                                   &mut dst_agg);
+        let from_backend_with_error =
+            super::try_parse_record(GOOD_RECORD0,
+                                    None,
+                                    &filter_config(vec![backend, server_errors]),
+                                    &mut dst_agg);
+        let from_system = super::try_parse_record(GOOD_RECORD0,
+                                                  None,
+                                                  &filter_config(vec![system]),
+                                                  &mut dst_agg);
+
+        assert_eq!(from_backend, super::RecordOutcome::AggregatedWithoutSystem);
+        assert_eq!(from_backend_with_error, super::RecordOutcome::Filtered);
+        assert_eq!(from_system, super::RecordOutcome::AggregatedWithoutSystem);
+        assert_eq!(dst_agg.values().collect::<Vec<_>>(), vec![&2])
+    }
+
+    #[test]
+    fn handle_parsing_result_should_skip_records_outside_of_the_business_hours() {
+        let mut dst_agg: super::ELBRecordAggregation = HashMap::new();
+        let hours_config = |start_minute, end_minute| {
+            super::RecordConfig {
+                business_hours: Some(super::BusinessHours {
+                    start_minute: start_minute,
+                    end_minute: end_minute,
+                }),
+                ..Default::default()
+            }
+        };
+
+        // The record is from 23:43.
+        let before_end = super::try_parse_record(GOOD_RECORD0,
+                                                 None,
+                                                 &hours_config(9 * 60, 24 * 60),
+                                                 &mut dst_agg);
+        let on_end = super::try_parse_record(GOOD_RECORD0,
+                                             None,
+                                             &hours_config(9 * 60, 23 * 60 + 43),
+                                             &mut dst_agg);
+        let past_midnight = super::try_parse_record(GOOD_RECORD0,
+                                                    None,
+                                                    &hours_config(22 * 60, 6 * 60),
+                                                    &mut dst_agg);
+
+        assert_eq!(before_end, super::RecordOutcome::AggregatedWithoutSystem);
+        assert_eq!(on_end, super::RecordOutcome::OutsideBusinessHours);
+        assert_eq!(past_midnight, super::RecordOutcome::AggregatedWithoutSystem);
+        assert_eq!(dst_agg.values().collect::<Vec<_>>(), vec![&2])
+    }
+
+    #[test]
+    fn try_parse_record_profiled_should_aggregate_like_try_parse_record() {
+        let mut dst_agg: super::ELBRecordAggregation = HashMap::new();
+        let mut expected_agg: super::ELBRecordAggregation = HashMap::new();
+        let timings = ::status::PhaseTimings::new();
+
+        super::try_parse_record_profiled(GOOD_RECORD0,
+                                         None,
+                                         &Default::default(),
+                                         &mut dst_agg,
+                                         None,
+                                         None,
+                                         &timings);
+        super::try_parse_record(GOOD_RECORD0, None, &Default::default(), &mut expected_agg);
+
+        assert_eq!(dst_agg, expected_agg);
+        assert!(timings.nanos(::status::Phase::Parsing) > 0)
+    }
+}
+
+#[cfg(test)]
+mod parse_only_tests {
+
+    const GOOD_RECORD: &'static str = "2015-08-15T23:43:05.302180Z elb-name 172.16.1.6:54814 \
+                    172.16.1.5:9000 0.000039 0.145507 0.00003 200 200 0 7582 \
+                    \"GET http://some.domain.com:80/path0/path1?system=sys1&param1=p1 HTTP/1.1\"";
+
+    #[test]
+    fn parse_only_returns_true_for_a_good_record() {
+        assert!(super::parse_only(GOOD_RECORD))
+    }
+
+    #[test]
+    fn parse_only_returns_false_for_a_bad_record() {
+        assert!(!super::parse_only(""))
+    }
+}
+
+#[cfg(test)]
+mod normalized_record_tests {
+
+    use std::collections::HashMap;
+    use std::net::Ipv4Addr;
+    use super::{CountedRecord, RecordConfig, RecordOutcome, StatusCodes, WeightField};
+
+    const GOOD_RECORD: &'static str = "2015-08-15T23:43:05.302180Z\tsys1\t172.16.1.6:54814\t\
+                                       7582\t200";
+
+    #[test]
+    fn parse_normalized_record_should_read_every_field() {
+        let record = super::parse_normalized_record(GOOD_RECORD).unwrap();
+
+        assert_eq!(record.timestamp.to_rfc3339(), "2015-08-15T23:43:05.302180+00:00");
+        assert_eq!(record.system_name, Some("sys1".to_owned()));
+        assert_eq!(record.client_address, Some("172.16.1.6".parse::<Ipv4Addr>().unwrap()));
+        assert_eq!(record.bytes, Some(7582));
+        assert_eq!(record.status_code, 200)
+    }
+
+    #[test]
+    fn parse_normalized_record_should_reject_a_record_missing_a_field() {
+        let error = super::parse_normalized_record("2015-08-15T23:43:05Z\tsys1\t-\t7582")
+            .unwrap_err();
+
+        assert!(error.contains("found 4"))
+    }
+
+    #[test]
+    fn try_parse_normalized_record_should_weight_the_total_by_the_bytes_when_asked_to() {
+        let config = RecordConfig {
+            weight_field: Some(WeightField::SentBytes),
+            ..Default::default()
+        };
+        let mut agg = HashMap::new();
+
+        let outcome =
+            super::try_parse_normalized_record(GOOD_RECORD, None, &config, &mut agg, None);
+
+        assert_eq!(outcome, RecordOutcome::Aggregated);
+        assert_eq!(agg.values().collect::<Vec<_>>(), vec![&7582])
+    }
+
+    #[test]
+    fn try_parse_normalized_record_should_weight_a_record_without_bytes_as_zero() {
+        let config = RecordConfig {
+            weight_field: Some(WeightField::SentBytes),
+            ..Default::default()
+        };
+        let mut agg = HashMap::new();
+        let mut counted = CountedRecord::default();
+
+        let outcome = super::try_parse_normalized_record("2015-08-15T23:43:05Z\tsys1\t-\t-\t200",
+                                                         None,
+                                                         &config,
+                                                         &mut agg,
+                                                         Some(&mut counted));
+
+        assert_eq!(outcome, RecordOutcome::Aggregated);
+        assert_eq!(agg.values().collect::<Vec<_>>(), vec![&0]);
+        assert!(counted.is_unweighted)
+    }
+
+    #[test]
+    fn try_parse_normalized_record_should_skip_records_of_other_statuses_or_no_system() {
+        let config = RecordConfig {
+            count_status: Some(StatusCodes(vec![(500, 599)])),
+            ..Default::default()
+        };
+        let dropping_config = RecordConfig { drop_no_system: true, ..Default::default() };
+        let no_system_record = "2015-08-15T23:43:05Z\t-\t172.16.1.6\t0\t500";
+        let mut agg = HashMap::new();
+
+        assert_eq!(super::try_parse_normalized_record(GOOD_RECORD, None, &config, &mut agg, None),
+                   RecordOutcome::ExcludedByStatus);
+        assert_eq!(super::try_parse_normalized_record(no_system_record,
+                                                      None,
+                                                      &dropping_config,
+                                                      &mut agg,
+                                                      None),
+                   RecordOutcome::DroppedWithoutSystem);
+        assert!(agg.is_empty())
+    }
+}
+
+#[cfg(test)]
+mod explain_record_tests {
+
+    const GOOD_RECORD: &'static str = "2015-08-15T23:43:05.302180Z elb-name 172.16.1.6:54814 \
+                    172.16.1.5:9000 0.000039 0.145507 0.00003 200 200 0 7582 \
+                    \"GET http://some.domain.com:80/path0/path1?system=sys1&param1=p1 HTTP/1.1\"";
+    const NO_SYSTEM_RECORD: &'static str = "2015-08-15T23:43:05.302180Z elb-name \
+                    172.16.1.6:54814 172.16.1.5:9000 0.000039 0.145507 0.00003 200 200 0 7582 \
+                    \"GET http://some.domain.com:80/path0/path1?param1=p1 HTTP/1.1\"";
+
+    #[test]
+    fn explain_record_ends_with_the_aggregate_key() {
+        let steps = super::explain_record(GOOD_RECORD, &Default::default());
+
+        assert_eq!(steps.last().unwrap(),
+                   "Aggregated under the key sys1,2015-08-15,172.16.1.6 with a weight of 1.")
+    }
+
+    #[test]
+    fn explain_record_explains_why_a_record_without_a_system_is_dropped() {
+        let config = super::RecordConfig {
+            drop_no_system: true,
+            ..Default::default()
+        };
+
+        let steps = super::explain_record(NO_SYSTEM_RECORD, &config);
+
+        assert!(steps.last().unwrap().contains("dropped because --drop-no-system is set"))
+    }
+
+    #[test]
+    fn explain_record_explains_that_a_bad_record_is_skipped() {
+        let steps = super::explain_record("", &Default::default());
+
+        assert_eq!(steps.len(), 1);
+        assert!(steps[0].starts_with("The record could not be parsed"))
+    }
+}
+
+#[cfg(test)]
+mod record_key_tests {
+
+    use chrono::{DateTime, UTC};
+    use std::collections::HashMap;
+    use std::net::Ipv4Addr;
+
+    const GOOD_RECORD: &'static str = "2015-08-15T23:43:05.302180Z elb-name 172.16.1.6:54814 \
+                    172.16.1.5:9000 0.000039 0.145507 0.00003 200 200 0 7582 \
+                    \"GET http://some.domain.com:80/path0/path1?system=sys1&param1=p1 HTTP/1.1\"";
+
+    #[test]
+    fn record_key_returns_the_key_the_record_is_aggregated_under() {
+        let key = super::record_key(GOOD_RECORD, None, &Default::default());
+
+        assert_eq!(key,
+                   Some(super::AggregateELBRecord {
+                       day: "2015-08-15T23:43:05.302180Z".parse::<DateTime<UTC>>().unwrap().date(),
+                       client_address: Some("172.16.1.6".parse::<Ipv4Addr>().unwrap()),
+                       backend_address: None,
+                       url: None,
+                       listener_port: None,
+                       hour: None,
+                       elb_status_code: None,
+                       system_name: "sys1".to_owned(),
+                       path_dimension: None,
+                       latency_bucket: None,
+                       day_of_month: None,
+                   }))
+    }
+
+    #[test]
+    fn record_key_keeps_only_the_dimensions_grouped_by() {
+        let config = super::RecordConfig {
+            group_by: vec![super::Dimension::System,
+                           super::Dimension::Day,
+                           super::Dimension::Backend],
+            ..Default::default()
+        };
+
+        let key = super::record_key(GOOD_RECORD, None, &config).unwrap();
+
+        assert_eq!(key.client_address, None);
+        assert_eq!(key.backend_address, Some("172.16.1.5".parse::<Ipv4Addr>().unwrap()));
+        assert_eq!(key.url, None)
+    }
+
+    #[test]
+    fn record_key_keeps_the_listener_port_when_grouped_by_it() {
+        let config = super::RecordConfig {
+            group_by: vec![super::Dimension::System,
+                           super::Dimension::Day,
+                           super::Dimension::ListenerPort],
+            ..Default::default()
+        };
+
+        let key = super::record_key(GOOD_RECORD, None, &config).unwrap();
+
+        assert_eq!(key.listener_port, Some("80".to_owned()));
+        assert_eq!(key.dimension_value(super::Dimension::ListenerPort).unwrap(), "80")
+    }
+
+    #[test]
+    fn record_key_keeps_the_hour_and_status_when_grouped_by_them() {
+        let config = super::RecordConfig {
+            group_by: vec![super::Dimension::System,
+                           super::Dimension::Day,
+                           super::Dimension::Hour,
+                           super::Dimension::Status],
+            ..Default::default()
+        };
+
+        let key = super::record_key(GOOD_RECORD, None, &config).unwrap();
+
+        assert_eq!(key.hour, Some(23));
+        assert_eq!(key.elb_status_code, Some(200));
+        assert_eq!(key.client_address, None);
+        assert_eq!(key.dimension_value(super::Dimension::Hour).unwrap(), "23")
+    }
+
+    #[test]
+    fn record_key_buckets_the_record_by_latency_when_configured_to() {
+        let config = super::RecordConfig {
+            latency_buckets: Some(Default::default()),
+            ..Default::default()
+        };
+
+        let key = super::record_key(GOOD_RECORD, None, &config);
+
+        assert_eq!(key.and_then(|key| key.latency_bucket), Some("<500ms".to_owned()))
+    }
+
+    #[test]
+    fn record_key_buckets_records_missing_a_processing_time_as_unknown() {
+        let record = "2015-08-15T23:43:05.302180Z elb-name 172.16.1.6:54814 \
+                    172.16.1.5:9000 0.000039 -1 -1 504 0 0 0 \
+                    \"GET http://some.domain.com:80/path0/path1?system=sys1&param1=p1 HTTP/1.1\"";
+        let config = super::RecordConfig {
+            latency_buckets: Some(Default::default()),
+            ..Default::default()
+        };
+
+        let key = super::record_key(record, None, &config);
+
+        assert_eq!(key.and_then(|key| key.latency_bucket), Some("unknown".to_owned()))
+    }
+
+    #[test]
+    fn record_key_buckets_the_record_by_day_of_month_when_configured_to() {
+        let config = super::RecordConfig { day_of_month: true, ..Default::default() };
+        let later_month = GOOD_RECORD.replace("2015-08-15", "2016-02-15");
+
+        let key = super::record_key(GOOD_RECORD, None, &config).unwrap();
+
+        assert_eq!(key.day_of_month, Some(15));
+        assert_eq!(key.dimension_value(super::Dimension::Day).unwrap(), "15");
+        assert_eq!(super::record_key(&later_month, None, &config), Some(key))
+    }
+
+    #[test]
+    fn record_key_returns_none_for_a_bad_record() {
+        assert_eq!(super::record_key("", None, &Default::default()), None)
+    }
+
+    #[test]
+    fn record_key_hashes_the_fields_other_than_the_day_when_configured_to() {
+        let config = super::RecordConfig { hash_keys: true, ..Default::default() };
+        let full_key = super::record_key(GOOD_RECORD, None, &Default::default()).unwrap();
+
+        let key = super::record_key(GOOD_RECORD, None, &config).unwrap();
+
+        assert_eq!(key.system_name.len(), super::KEY_HASH_CHARS);
+        assert_eq!(key.system_name, full_key.key_hash());
+        assert_eq!(key.day, full_key.day);
+        assert_eq!(key.client_address, None);
+        assert_eq!(super::full_record_key(GOOD_RECORD, None, &config), Some(full_key))
+    }
+
+    #[test]
+    fn the_counted_record_should_hold_the_full_key_and_timestamp_of_the_record() {
+        let config = super::RecordConfig { hash_keys: true, ..Default::default() };
+        let mut agg = HashMap::new();
+        let mut counted = super::CountedRecord::default();
+
+        super::try_parse_record_with_metrics(GOOD_RECORD,
+                                             None,
+                                             &config,
+                                             &mut agg,
+                                             None,
+                                             Some(&mut counted));
+
+        assert_eq!(counted.timestamp,
+                   Some("2015-08-15T23:43:05.302180Z".parse::<DateTime<UTC>>().unwrap()));
+        assert_eq!(counted.full_key, super::full_record_key(GOOD_RECORD, None, &config));
+        assert_eq!(agg.keys().collect::<Vec<_>>(),
+                   vec![&counted.full_key.unwrap().hashed()])
+    }
+
+    #[test]
+    fn key_hash_differs_between_keys_that_differ_by_more_than_the_day() {
+        let key = super::record_key(GOOD_RECORD, None, &Default::default()).unwrap();
+        let other_client = GOOD_RECORD.replace("172.16.1.6", "172.16.1.7");
+        let other_day = GOOD_RECORD.replace("2015-08-15", "2015-08-16");
+
+        let other_client_key = super::record_key(&other_client, None, &Default::default()).unwrap();
+        let other_day_key = super::record_key(&other_day, None, &Default::default()).unwrap();
+
+        assert!(key.key_hash().chars().all(|c| c.is_digit(16)));
+        assert!(key.key_hash() != other_client_key.key_hash());
+        assert_eq!(key.key_hash(), other_day_key.key_hash());
+        assert!(key.hashed() != other_day_key.hashed())
+    }
 }
 
-pub fn try_parse_record(possible_record: &str, dst_agg: &mut ELBRecordAggregation) -> () {
-    match elp::parse_record(possible_record) {
-        Ok(elb_record) => {
-            let aer =
-                AggregateELBRecord::new(elb_record.timestamp,
-                                        *elb_record.client_address.ip(),
-                                        parse_system_name(elb_record.request_url)
-                                            .unwrap_or_else(|| "UNDEFINED_SYSTEM".to_owned()));
-            aggregate_record(aer, dst_agg);
+#[cfg(test)]
+mod aggregate_metrics_tests {
+
+    use std::collections::HashMap;
+    use elp;
+
+    fn record(processing_times: &str, elb_status_code: u16) -> String {
+        format!("2015-08-15T23:43:05.302180Z elb-name 172.16.1.6:54814 172.16.1.5:9000 {} {} 200 \
+                 12 7582 \"GET http://some.domain.com:80/path0/path1?system=sys1 HTTP/1.1\"",
+                processing_times,
+                elb_status_code)
+    }
+
+    fn metrics_of(records: &[String]) -> super::AggregateMetrics {
+        let mut metrics = super::AggregateMetrics::new();
+        for record in records {
+            metrics.include(&elp::parse_record(record).unwrap());
         }
-        Err(ref errs) => println_stderr!("{:?}", errs.record),
+        metrics
     }
-}
 
-lazy_static! {
-    static ref SYSTEM_REGEX: Regex = Regex::new(r"(?i)system=([^&]*)").unwrap();
-}
+    #[test]
+    fn include_should_sum_the_bytes_and_count_the_status_classes() {
+        let metrics = metrics_of(&[record("0 0.1 0", 200),
+                                   record("0 0.1 0", 304),
+                                   record("0 0.1 0", 503),
+                                   record("0 0.1 0", 502)]);
 
-fn parse_system_name(src_str: &str) -> Option<String> {
-    SYSTEM_REGEX
-        .captures(src_str)
-        .and_then(|cap| cap.get(1).map(|sys| sys.as_str().to_string()))
+        assert_eq!(metrics.received_bytes, 48);
+        assert_eq!(metrics.sent_bytes, 4 * 7582);
+        assert_eq!(metrics.status_classes, [1, 1, 0, 2])
+    }
+
+    #[test]
+    fn include_should_keep_the_min_and_max_of_the_summed_processing_times() {
+        let metrics = metrics_of(&[record("0.25 0.5 0.25", 200), record("0 0.25 0", 200)]);
+
+        assert_eq!(metrics.min_latency, Some(0.25));
+        assert_eq!(metrics.max_latency, Some(1.0))
+    }
+
+    #[test]
+    fn include_should_leave_out_the_latency_of_records_missing_a_processing_time() {
+        let metrics = metrics_of(&[record("0.000039 -1 -1", 504)]);
+
+        assert_eq!(metrics.status_classes, [0, 0, 0, 1]);
+        assert_eq!(metrics.min_latency, None);
+        assert_eq!(metrics.latency_percentile(50.0), None)
+    }
+
+    #[test]
+    fn latency_percentile_should_be_within_a_fifth_of_the_actual_latency() {
+        let records = (1..101)
+            .map(|millis| record(&format!("0 {} 0", millis as f64 / 1000.0), 200))
+            .collect::<Vec<_>>();
+        let metrics = metrics_of(&records);
+
+        for &(percentile, actual) in &[(50.0, 0.05), (95.0, 0.095), (99.0, 0.099)] {
+            let latency = metrics.latency_percentile(percentile).unwrap();
+            assert!(latency >= actual * 0.999 && latency <= actual * 1.2,
+                    "p{} was {}",
+                    percentile,
+                    latency);
+        }
+        assert_eq!(metrics.latency_percentile(100.0), metrics.max_latency)
+    }
+
+    #[test]
+    fn try_parse_record_with_metrics_should_add_to_the_metrics_of_the_aggregate_of_the_record() {
+        let mut metrics = HashMap::new();
+        let mut agg = HashMap::new();
+        let config = Default::default();
+
+        for possible_record in &[record("0 0.1 0", 200), record("0 0.1 0", 404)] {
+            super::try_parse_record_with_metrics(possible_record,
+                                                 None,
+                                                 &config,
+                                                 &mut agg,
+                                                 Some(&mut metrics),
+                                                 None);
+        }
+        super::try_parse_record_with_metrics("not a record",
+                                             None,
+                                             &config,
+                                             &mut agg,
+                                             Some(&mut metrics),
+                                             None);
+
+        assert_eq!(metrics.len(), 1);
+        assert_eq!(metrics.keys().collect::<Vec<_>>(), agg.keys().collect::<Vec<_>>());
+        assert_eq!(metrics.values().next().unwrap().status_classes, [1, 0, 1, 0])
+    }
+
+    #[test]
+    fn the_metrics_should_saturate_rather_than_overflow() {
+        let mut metrics = metrics_of(&[record("0 0.1 0", 200)]);
+        metrics.received_bytes = u64::max_value();
+        metrics.status_classes[0] = u64::max_value();
+        let other = metrics.clone();
+
+        metrics.merge(&other);
+
+        assert_eq!(metrics.received_bytes, u64::max_value());
+        assert_eq!(metrics.status_classes[0], u64::max_value())
+    }
 }
 
-pub fn merge_aggregates(src_aggs: &ELBRecordAggregation,
-                        dst_aggs: &mut ELBRecordAggregation)
-                        -> () {
-    for (agg_key, agg_val) in src_aggs {
-        let total = dst_aggs.entry(agg_key.clone()).or_insert(0);
-        *total += *agg_val;
+#[cfg(test)]
+mod latency_buckets_tests {
+
+    use super::LatencyBuckets;
+
+    #[test]
+    fn the_default_buckets_should_label_times_below_each_bound() {
+        let buckets = LatencyBuckets::default();
+
+        assert_eq!(buckets.label(0.005), "<10ms");
+        assert_eq!(buckets.label(0.01), "<50ms");
+        assert_eq!(buckets.label(0.25), "<500ms");
+        assert_eq!(buckets.label(0.5), "<1s")
+    }
+
+    #[test]
+    fn the_default_buckets_should_label_times_at_or_above_the_largest_bound() {
+        assert_eq!(LatencyBuckets::default().label(1.0), ">=1s");
+        assert_eq!(LatencyBuckets::default().label(30.0), ">=1s")
+    }
+
+    #[test]
+    fn negative_times_should_be_labelled_unknown() {
+        assert_eq!(LatencyBuckets::default().label(-1.0), "unknown")
+    }
+
+    #[test]
+    fn given_bounds_should_replace_the_default_ones() {
+        let buckets = LatencyBuckets::new(vec![250, 2000]);
+
+        assert_eq!(buckets.label(0.1), "<250ms");
+        assert_eq!(buckets.label(1.5), "<2s");
+        assert_eq!(buckets.label(2.5), ">=2s")
     }
 }
 
-fn aggregate_record(aggregate_record: AggregateELBRecord,
-                    dst_aggs: &mut ELBRecordAggregation)
-                    -> () {
-    let total = dst_aggs.entry(aggregate_record).or_insert(0);
-    *total += 1;
+#[cfg(test)]
+mod passthrough_record_tests {
+
+    use std::time::Duration;
+
+    const GOOD_RECORD: &'static str = "2015-08-15T23:43:05.302180Z elb-name 172.16.1.6:54814 \
+                    172.16.1.5:9000 0.000039 0.145507 0.00003 200 200 0 7582 \
+                    \"GET http://some.domain.com:80/path0/path1?system=sys1&param1=p1 HTTP/1.1\"";
+
+    #[test]
+    fn passthrough_record_renders_the_key_fields_of_the_record() {
+        let config = Default::default();
+
+        let rendered = super::passthrough_record(GOOD_RECORD, Some("us-east-1"), &config);
+
+        assert_eq!(rendered,
+                   Some("sys1,2015-08-15T23:43:05.302180+00:00,172.16.1.6,us-east-1".to_owned()))
+    }
+
+    #[test]
+    fn passthrough_record_rounds_the_timestamp_to_the_resolution() {
+        let config = super::RecordConfig {
+            timestamp_resolution: Some(Duration::from_secs(10)),
+            ..Default::default()
+        };
+
+        let rendered = super::passthrough_record(GOOD_RECORD, None, &config);
+
+        assert_eq!(rendered, Some("sys1,2015-08-15T23:43:00+00:00,172.16.1.6".to_owned()))
+    }
+
+    #[test]
+    fn passthrough_record_returns_none_for_a_bad_record() {
+        assert_eq!(super::passthrough_record("", None, &Default::default()), None)
+    }
 }
 
 #[cfg(test)]
-mod handle_parsing_result_tests {
+mod round_timestamp_tests {
 
-    extern crate elp;
+    use chrono::{DateTime, UTC};
+    use std::time::Duration;
 
-    use std::collections::HashMap;
+    fn timestamp(value: &str) -> DateTime<UTC> {
+        value.parse::<DateTime<UTC>>().unwrap()
+    }
 
-    const GOOD_RECORD0: &'static str = "2015-08-15T23:43:05.302180Z elb-name 172.16.1.6:54814 \
-                    172.16.1.5:9000 0.000039 0.145507 0.00003 200 200 0 7582 \
-                    \"GET http://some.domain.com:80/path0/path1?param0=p0&param1=p1 HTTP/1.1\"";
-    const GOOD_RECORD1: &'static str = "2016-08-15T23:43:05.302180Z elb-name 172.16.1.6:54814 \
-                    172.16.1.5:9000 0.000039 0.145507 0.00003 200 200 0 7582 \
-                    \"GET http://some.domain.com:80/path0/path1?param0=p0&param1=p1 HTTP/1.1\"";
     #[test]
-    fn handle_parsing_result_should_not_alter_the_dst_agg_when_passed_bad_records() {
-        let mut dst_agg: super::ELBRecordAggregation = HashMap::new();
-        let bad_record = "";
+    fn round_timestamp_rounds_down_to_a_multiple_of_the_resolution() {
+        let rounded = super::round_timestamp(timestamp("2015-08-15T23:43:05.302180Z"),
+                                             Duration::from_secs(10));
+
+        assert_eq!(rounded, timestamp("2015-08-15T23:43:00Z"))
+    }
 
-        super::try_parse_record(GOOD_RECORD0, &mut dst_agg);
-        super::try_parse_record(bad_record, &mut dst_agg);
+    #[test]
+    fn round_timestamp_keeps_milliseconds_finer_than_a_second() {
+        let rounded = super::round_timestamp(timestamp("2015-08-15T23:43:05.302180Z"),
+                                             Duration::from_millis(250));
 
-        assert_eq!(dst_agg.len(), 1)
+        assert_eq!(rounded, timestamp("2015-08-15T23:43:05.250Z"))
     }
 
     #[test]
-    fn handle_parsing_result_should_update_the_dst_agg_when_passed_good_records() {
-        let mut dst_agg: super::ELBRecordAggregation = HashMap::new();
+    fn round_timestamp_rounds_timestamps_before_the_epoch_down() {
+        let rounded = super::round_timestamp(timestamp("1969-12-31T23:59:55Z"),
+                                             Duration::from_secs(60));
 
-        super::try_parse_record(GOOD_RECORD0, &mut dst_agg);
-        super::try_parse_record(GOOD_RECORD1, &mut dst_agg);
+        assert_eq!(rounded, timestamp("1969-12-31T23:59:00Z"))
+    }
 
-        assert_eq!(dst_agg.len(), 2)
+    #[test]
+    fn round_record_timestamp_rounds_the_timestamp_of_the_record() {
+        let config = ::record_handling::RecordConfig {
+            timestamp_resolution: Some(Duration::from_secs(10)),
+            ..Default::default()
+        };
+
+        let rounded = super::round_record_timestamp("2015-08-15T23:43:05.302180Z elb-name 1.2.3.4",
+                                                    &config);
+
+        assert_eq!(rounded, "2015-08-15T23:43:00.000000Z elb-name 1.2.3.4")
+    }
+
+    #[test]
+    fn round_record_timestamp_rounds_the_timestamp_after_the_type_of_the_record() {
+        let config = ::record_handling::RecordConfig {
+            timestamp_resolution: Some(Duration::from_secs(60)),
+            ..Default::default()
+        };
+
+        let rounded = super::round_record_timestamp("http 2015-08-15T23:43:05.302180Z app/name",
+                                                    &config);
+
+        assert_eq!(rounded, "http 2015-08-15T23:43:00.000000Z app/name")
+    }
+
+    #[test]
+    fn round_record_timestamp_keeps_the_record_without_a_resolution() {
+        let record = "2015-08-15T23:43:05.302180Z elb-name 1.2.3.4";
+
+        assert_eq!(super::round_record_timestamp(record, &Default::default()), record)
     }
 }
 
@@ -110,6 +2652,15 @@ mod parse_system_name_tests {
         assert!(maybe_system_name.is_none())
     }
 
+    #[test]
+    fn parse_system_name_regex_returns_a_none_when_the_system_name_is_a_dash() {
+        let test_uri = "http://ie.trafficland.com:80/5435/full?system=-&pubtoken=alkdjf";
+
+        let maybe_system_name = super::parse_system_name(&test_uri);
+
+        assert!(maybe_system_name.is_none())
+    }
+
     #[test]
     fn parse_system_name_regex_returns_the_system_name_when_it_exists() {
         let system_name = "intravenus_de_milo".to_string();
@@ -123,54 +2674,370 @@ mod parse_system_name_tests {
     }
 }
 
+#[cfg(test)]
+mod as_elb_record_tests {
+
+    use super::LogFormat;
+
+    const ALB_RECORD: &'static str = "https 2015-08-15T23:43:05.302180Z \
+                    app/alb-name/50dc6c495c0c9188 172.16.1.6:54814 172.16.1.5:9000 0.000039 \
+                    0.145507 0.00003 200 200 0 7582 \
+                    \"GET https://some.domain.com:443/path0?system=sys1 HTTP/1.1\" \
+                    \"curl/7.46.0\" ECDHE-RSA-AES128-GCM-SHA256 TLSv1.2 \
+                    arn:aws:elasticloadbalancing:us-east-2:123456789012:targetgroup/tg/1 \
+                    \"Root=1-58337262-36d228ad5d99923122bbe354\"";
+    const ELB_RECORD: &'static str = "2015-08-15T23:43:05.302180Z elb-name 172.16.1.6:54814 \
+                    172.16.1.5:9000 0.000039 0.145507 0.00003 200 200 0 7582 \
+                    \"GET http://some.domain.com:80/path0/path1?system=sys1&param1=p1 HTTP/1.1\"";
+
+    #[test]
+    fn as_elb_record_should_leave_out_the_fields_an_elb_record_does_not_have() {
+        assert_eq!(super::as_elb_record(ALB_RECORD, LogFormat::Alb),
+                   "2015-08-15T23:43:05.302180Z app/alb-name/50dc6c495c0c9188 172.16.1.6:54814 \
+                    172.16.1.5:9000 0.000039 0.145507 0.00003 200 200 0 7582 \
+                    \"GET https://some.domain.com:443/path0?system=sys1 HTTP/1.1\"")
+    }
+
+    #[test]
+    fn as_elb_record_should_detect_the_format_of_every_record_when_auto() {
+        assert_eq!(super::as_elb_record(ALB_RECORD, LogFormat::Auto),
+                   super::as_elb_record(ALB_RECORD, LogFormat::Alb));
+        assert_eq!(super::as_elb_record(ELB_RECORD, LogFormat::Auto), ELB_RECORD);
+        assert_eq!(super::as_elb_record(ALB_RECORD, LogFormat::Elb), ALB_RECORD)
+    }
+
+    #[test]
+    fn an_alb_record_should_be_aggregated_like_the_elb_record_it_starts_with() {
+        let key = super::record_key(super::as_elb_record(ALB_RECORD, LogFormat::Auto),
+                                    None,
+                                    &Default::default())
+            .unwrap();
+
+        assert_eq!(key.system_name, "sys1");
+        assert_eq!(key.dimension_value(super::Dimension::Client).unwrap(), "172.16.1.6")
+    }
+}
+
+#[cfg(test)]
+mod listener_port_tests {
+
+    #[test]
+    fn listener_port_should_return_the_port_of_the_request_url() {
+        assert_eq!(super::listener_port("http://some.domain.com:80/path0?system=sys1"), "80");
+        assert_eq!(super::listener_port("https://some.domain.com:8443"), "8443")
+    }
+
+    #[test]
+    fn listener_port_should_return_a_dash_when_the_url_has_no_port() {
+        assert_eq!(super::listener_port("http://some.domain.com/path0"), "-");
+        assert_eq!(super::listener_port("http://[::1]/path0:80"), "-");
+        assert_eq!(super::listener_port("-"), "-")
+    }
+}
+
+#[cfg(test)]
+mod path_dimension_value_tests {
+
+    use std::path::Path;
+
+    #[test]
+    fn path_dimension_value_returns_the_directory_at_the_requested_depth() {
+        let log_root = Path::new("/logs");
+        let file_path = Path::new("/logs/us-east-1/2016-12-05/elb.log");
+
+        assert_eq!(super::path_dimension_value(log_root, file_path, 1), "us-east-1");
+        assert_eq!(super::path_dimension_value(log_root, file_path, 2), "2016-12-05")
+    }
+
+    #[test]
+    fn path_dimension_value_returns_a_dash_when_the_file_is_not_nested_deeply_enough() {
+        let log_root = Path::new("/logs");
+        let file_path = Path::new("/logs/us-east-1/elb.log");
+
+        assert_eq!(super::path_dimension_value(log_root, file_path, 2), "-")
+    }
+}
+
 #[cfg(test)]
 mod merge_aggregates_tests {
     extern crate rand;
 
-    use chrono::{DateTime, UTC};
-    use std::net::SocketAddrV4;
-    use self::rand::distributions::{IndependentSample, Range};
-    use std::collections::HashMap;
+    use chrono::{DateTime, UTC};
+    use std::net::{Ipv4Addr, SocketAddrV4};
+    use self::rand::distributions::{IndependentSample, Range};
+    use std::collections::HashMap;
+
+    #[test]
+    fn merge_aggregates_updates_the_dst_aggs_according_to_the_src_aggs() {
+        let num_new_records = 100;
+        let src_agg = generate_test_agg(num_new_records);
+        let mut thread_range = rand::thread_rng();
+        let rec_range = Range::new(0, src_agg.len());
+        let mut dst_agg = HashMap::new();
+        let mut test_keys = Vec::new();
+        let num_test_records = 10;
+        for _ in 0..num_test_records {
+            let key_idx = rec_range.ind_sample(&mut thread_range);
+            let mut keys = src_agg.keys();
+            let key_of_interest = keys.nth(key_idx).unwrap();
+            let value_of_interest = src_agg.get(key_of_interest).unwrap();
+            dst_agg.insert(key_of_interest.clone(), value_of_interest.clone());
+            test_keys.push(key_of_interest);
+        }
+
+        super::merge_aggregates(&src_agg, &mut dst_agg);
+
+        assert_eq!(dst_agg.len(), src_agg.len());
+        for idx in 0..num_test_records {
+            let key = test_keys.get(idx).unwrap();
+            let dst_rec_val = *dst_agg.get(key).unwrap();
+            let src_rec_val = *src_agg.get(key).unwrap();
+            assert_eq!(dst_rec_val, src_rec_val * 2);
+        }
+    }
+
+    #[test]
+    fn merge_aggregates_insert_the_src_aggs_into_dst_aggs_when_dst_aggs_is_empty() {
+        let num_records = 50;
+        let src_agg = generate_test_agg(num_records);
+
+        let mut dst_agg = HashMap::new();
+
+        super::merge_aggregates(&src_agg, &mut dst_agg);
+
+        assert_eq!(src_agg.len(), dst_agg.len())
+    }
+
+    #[test]
+    fn merge_aggregates_saturates_totals_that_would_overflow() {
+        let src_agg = generate_test_agg(1);
+        let mut dst_agg = src_agg.clone();
+        for total in dst_agg.values_mut() {
+            *total = super::SATURATED_TOTAL;
+        }
+
+        super::merge_aggregates(&src_agg, &mut dst_agg);
+
+        assert!(dst_agg.values().all(|&total| total == super::SATURATED_TOTAL))
+    }
+
+    #[test]
+    fn merge_aggregates_with_policy_wraps_totals_that_would_overflow_when_wrapping() {
+        let src_agg = generate_test_agg(1);
+        let mut dst_agg = src_agg.clone();
+        for total in dst_agg.values_mut() {
+            *total = super::SATURATED_TOTAL;
+        }
+
+        let num_overflows = super::merge_aggregates_with_policy(&src_agg,
+                                                                &mut dst_agg,
+                                                                super::CountOverflowPolicy::Wrap);
+
+        assert_eq!(num_overflows, 0);
+        assert!(dst_agg.values().all(|&total| total == ::std::i64::MIN))
+    }
+
+    #[test]
+    fn merge_aggregates_with_policy_counts_and_keeps_totals_that_would_overflow_on_error() {
+        let src_agg = generate_test_agg(1);
+        let mut dst_agg = src_agg.clone();
+        for total in dst_agg.values_mut() {
+            *total = super::SATURATED_TOTAL - 1;
+        }
+
+        let num_overflows_to_max =
+            super::merge_aggregates_with_policy(&src_agg,
+                                                &mut dst_agg,
+                                                super::CountOverflowPolicy::Error);
+        let num_overflows_past_max =
+            super::merge_aggregates_with_policy(&src_agg,
+                                                &mut dst_agg,
+                                                super::CountOverflowPolicy::Error);
+
+        assert_eq!(num_overflows_to_max, 0);
+        assert_eq!(num_overflows_past_max, src_agg.len());
+        assert!(dst_agg.values().all(|&total| total == super::SATURATED_TOTAL))
+    }
+
+    #[test]
+    fn merge_partitioned_counts_the_totals_that_would_overflow_on_error() {
+        let src_agg = generate_test_agg(1);
+        let mut dst_agg = src_agg.clone();
+        for total in dst_agg.values_mut() {
+            *total = super::SATURATED_TOTAL;
+        }
+
+        let (partitions, num_overflows) =
+            super::merge_partitioned(vec![dst_agg.clone(), src_agg.clone()],
+                                     2,
+                                     super::CountOverflowPolicy::Error);
+
+        assert_eq!(num_overflows, src_agg.len());
+        assert_eq!(combined(partitions), dst_agg)
+    }
+
+    /// The partitions of a partitioned merge in one aggregation, checking that no key is in two.
+    fn combined(partitions: Vec<super::ELBRecordAggregation>) -> super::ELBRecordAggregation {
+        let num_aggregates = partitions.iter().map(|partition| partition.len()).sum::<usize>();
+        let merged = partitions.into_iter()
+            .flat_map(|partition| partition)
+            .collect::<super::ELBRecordAggregation>();
+        assert_eq!(merged.len(), num_aggregates);
+        merged
+    }
+
+    #[test]
+    fn merge_timespans_should_keep_the_earliest_first_seen_and_latest_last_seen() {
+        let at = |time: &str| format!("2015-08-15T{}Z", time).parse::<DateTime<UTC>>().unwrap();
+        let src_agg = generate_test_agg(1);
+        let key = src_agg.keys().next().unwrap();
+        let mut src = HashMap::new();
+        src.insert(key.clone(),
+                   super::Timespan {
+                       first_seen: at("09:00:00"),
+                       last_seen: at("10:30:00"),
+                   });
+        let mut dst = HashMap::new();
+        dst.insert(key.clone(),
+                   super::Timespan {
+                       first_seen: at("10:00:00"),
+                       last_seen: at("11:00:00"),
+                   });
+
+        super::merge_timespans(&src, &mut dst);
+
+        assert_eq!(dst[key],
+                   super::Timespan {
+                       first_seen: at("09:00:00"),
+                       last_seen: at("11:00:00"),
+                   })
+    }
+
+    #[test]
+    fn merge_metrics_should_sum_the_bytes_and_statuses_and_widen_the_latencies() {
+        let src_agg = generate_test_agg(1);
+        let key = src_agg.keys().next().unwrap();
+        let mut src = HashMap::new();
+        src.insert(key.clone(),
+                   super::AggregateMetrics {
+                       received_bytes: 10,
+                       sent_bytes: 100,
+                       min_latency: Some(0.5),
+                       max_latency: Some(0.5),
+                       status_classes: [1, 0, 0, 0],
+                       ..Default::default()
+                   });
+        let mut dst = HashMap::new();
+        dst.insert(key.clone(),
+                   super::AggregateMetrics {
+                       received_bytes: 1,
+                       sent_bytes: 2,
+                       min_latency: Some(0.1),
+                       max_latency: Some(0.2),
+                       status_classes: [1, 0, 0, 1],
+                       ..Default::default()
+                   });
+
+        super::merge_metrics(&src, &mut dst);
+
+        assert_eq!(dst[key],
+                   super::AggregateMetrics {
+                       received_bytes: 11,
+                       sent_bytes: 102,
+                       min_latency: Some(0.1),
+                       max_latency: Some(0.5),
+                       status_classes: [2, 0, 0, 1],
+                       ..Default::default()
+                   })
+    }
+
+    #[test]
+    fn merge_partitioned_gives_the_same_totals_as_merging_serially() {
+        let aggregations = (0..6).map(|_| generate_varied_test_agg(20_000)).collect::<Vec<_>>();
+        let mut serially_merged = HashMap::new();
+        for aggregation in &aggregations {
+            super::merge_aggregates(aggregation, &mut serially_merged);
+        }
+
+        let (partitions, num_overflows) =
+            super::merge_partitioned(aggregations, 4, super::CountOverflowPolicy::Saturate);
+
+        assert!(serially_merged.len() > 1_000);
+        assert_eq!(partitions.len(), 4);
+        assert_eq!(num_overflows, 0);
+        assert_eq!(combined(partitions), serially_merged)
+    }
+
+    #[test]
+    fn a_partitioned_aggregation_should_count_the_aggregates_merged_so_far() {
+        let aggregation = generate_varied_test_agg(1_000);
+        let mut partitioned =
+            super::PartitionedAggregation::new(3, super::CountOverflowPolicy::Saturate);
+
+        partitioned.merge(aggregation.clone());
+        partitioned.merge(aggregation.clone());
+
+        assert_eq!(partitioned.len(), aggregation.len());
+        let doubled: super::ELBRecordAggregation =
+            aggregation.into_iter().map(|(key, total)| (key, 2 * total)).collect();
+        assert_eq!(combined(partitioned.into_partitions()), doubled)
+    }
 
     #[test]
-    fn merge_aggregates_updates_the_dst_aggs_according_to_the_src_aggs() {
-        let num_new_records = 100;
-        let src_agg = generate_test_agg(num_new_records);
-        let mut thread_range = rand::thread_rng();
-        let rec_range = Range::new(0, src_agg.len());
-        let mut dst_agg = HashMap::new();
-        let mut test_keys = Vec::new();
-        let num_test_records = 10;
-        for _ in 0..num_test_records {
-            let key_idx = rec_range.ind_sample(&mut thread_range);
-            let mut keys = src_agg.keys();
-            let key_of_interest = keys.nth(key_idx).unwrap();
-            let value_of_interest = src_agg.get(key_of_interest).unwrap();
-            dst_agg.insert(key_of_interest.clone(), value_of_interest.clone());
-            test_keys.push(key_of_interest);
+    fn merge_partitioned_merges_into_a_single_partition_when_given_none() {
+        let aggregations = vec![generate_test_agg(50), generate_test_agg(50)];
+        let mut serially_merged = HashMap::new();
+        for aggregation in &aggregations {
+            super::merge_aggregates(aggregation, &mut serially_merged);
         }
 
-        super::merge_aggregates(&src_agg, &mut dst_agg);
+        let (partitions, num_overflows) =
+            super::merge_partitioned(aggregations, 0, super::CountOverflowPolicy::Saturate);
 
-        assert_eq!(dst_agg.len(), src_agg.len());
-        for idx in 0..num_test_records {
-            let key = test_keys.get(idx).unwrap();
-            let dst_rec_val = *dst_agg.get(key).unwrap();
-            let src_rec_val = *src_agg.get(key).unwrap();
-            assert_eq!(dst_rec_val, src_rec_val * 2);
-        }
+        assert_eq!(partitions.len(), 1);
+        assert_eq!(num_overflows, 0);
+        assert_eq!(combined(partitions), serially_merged)
     }
 
     #[test]
-    fn merge_aggregates_insert_the_src_aggs_into_dst_aggs_when_dst_aggs_is_empty() {
-        let num_records = 50;
-        let src_agg = generate_test_agg(num_records);
+    fn merge_partitioned_returns_an_empty_aggregation_without_aggregations() {
+        let (partitions, num_overflows) =
+            super::merge_partitioned(Vec::new(), 4, super::CountOverflowPolicy::Saturate);
 
-        let mut dst_agg = HashMap::new();
-
-        super::merge_aggregates(&src_agg, &mut dst_agg);
+        assert!(partitions.iter().all(|partition| partition.is_empty()));
+        assert_eq!(num_overflows, 0)
+    }
 
-        assert_eq!(src_agg.len(), dst_agg.len())
+    /// Generates records of many clients, so that the keys of the aggregations only partly overlap.
+    fn generate_varied_test_agg(num_records: usize) -> super::ELBRecordAggregation {
+        let mut agg = HashMap::new();
+        let mut thread_range = rand::thread_rng();
+        let sys_id_range = Range::new(0, 7);
+        let client_id_range = Range::new(0, 2_000);
+        for _ in 0..num_records {
+            let client_id: u16 = client_id_range.ind_sample(&mut thread_range);
+            let record = super::AggregateELBRecord {
+                day: "2015-08-15T23:43:05.302180Z"
+                    .parse::<DateTime<UTC>>()
+                    .unwrap()
+                    .date(),
+                client_address: Some(Ipv4Addr::new(10,
+                                                   0,
+                                                   (client_id / 256) as u8,
+                                                   client_id as u8)),
+                backend_address: None,
+                url: None,
+                listener_port: None,
+                hour: None,
+                elb_status_code: None,
+                system_name: format!("sys{}", sys_id_range.ind_sample(&mut thread_range)),
+                path_dimension: None,
+                latency_bucket: None,
+                day_of_month: None,
+            };
+            super::aggregate_record(record, &mut agg);
+        }
+        agg
     }
 
     fn generate_test_agg(num_records: usize) -> super::ELBRecordAggregation {
@@ -184,8 +3051,16 @@ mod merge_aggregates_tests {
                     .parse::<DateTime<UTC>>()
                     .unwrap()
                     .date(),
-                client_address: *"172.16.1.6:54814".parse::<SocketAddrV4>().unwrap().ip(),
+                client_address: Some(*"172.16.1.6:54814".parse::<SocketAddrV4>().unwrap().ip()),
+                backend_address: None,
+                url: None,
+                listener_port: None,
+                hour: None,
+                elb_status_code: None,
                 system_name: format!("sys{}", sys_id),
+                path_dimension: None,
+                latency_bucket: None,
+                day_of_month: None,
             };
             super::aggregate_record(record, &mut agg);
         }
@@ -209,8 +3084,16 @@ mod aggregate_record_tests {
                 .parse::<DateTime<UTC>>()
                 .unwrap()
                 .date(),
-            client_address: *"172.16.1.6:54814".parse::<SocketAddrV4>().unwrap().ip(),
+            client_address: Some(*"172.16.1.6:54814".parse::<SocketAddrV4>().unwrap().ip()),
+            backend_address: None,
+            url: None,
+            listener_port: None,
+            hour: None,
+            elb_status_code: None,
             system_name: "sys1".to_owned(),
+            path_dimension: None,
+            latency_bucket: None,
+            day_of_month: None,
         };
 
         let ar1 = super::AggregateELBRecord {
@@ -218,8 +3101,16 @@ mod aggregate_record_tests {
                 .parse::<DateTime<UTC>>()
                 .unwrap()
                 .date(),
-            client_address: *"172.16.1.6:54814".parse::<SocketAddrV4>().unwrap().ip(),
+            client_address: Some(*"172.16.1.6:54814".parse::<SocketAddrV4>().unwrap().ip()),
+            backend_address: None,
+            url: None,
+            listener_port: None,
+            hour: None,
+            elb_status_code: None,
             system_name: "sys2".to_owned(),
+            path_dimension: None,
+            latency_bucket: None,
+            day_of_month: None,
         };
 
         super::aggregate_record(ar0, &mut agg);
@@ -240,8 +3131,16 @@ mod aggregate_record_tests {
                 .parse::<DateTime<UTC>>()
                 .unwrap()
                 .date(),
-            client_address: *"172.16.1.6:54814".parse::<SocketAddrV4>().unwrap().ip(),
+            client_address: Some(*"172.16.1.6:54814".parse::<SocketAddrV4>().unwrap().ip()),
+            backend_address: None,
+            url: None,
+            listener_port: None,
+            hour: None,
+            elb_status_code: None,
             system_name: "sys1".to_owned(),
+            path_dimension: None,
+            latency_bucket: None,
+            day_of_month: None,
         };
 
         let ar1 = ar0.clone();
@@ -252,4 +3151,472 @@ mod aggregate_record_tests {
 
         assert_eq!(agg[&ar3], 2);
     }
+
+    #[test]
+    fn weighted_totals_saturate_instead_of_wrapping() {
+        let mut agg: super::ELBRecordAggregation = HashMap::new();
+
+        let ar0 = super::AggregateELBRecord {
+            day: "2015-08-15T23:43:05.302180Z"
+                .parse::<DateTime<UTC>>()
+                .unwrap()
+                .date(),
+            client_address: Some(*"172.16.1.6:54814".parse::<SocketAddrV4>().unwrap().ip()),
+            backend_address: None,
+            url: None,
+            listener_port: None,
+            hour: None,
+            elb_status_code: None,
+            system_name: "sys1".to_owned(),
+            path_dimension: None,
+            latency_bucket: None,
+            day_of_month: None,
+        };
+        let ar1 = ar0.clone();
+
+        super::aggregate_weighted_record(ar0,
+                                         super::SATURATED_TOTAL - 1,
+                                         super::CountOverflowPolicy::Saturate,
+                                         &mut agg);
+        super::aggregate_weighted_record(ar1.clone(),
+                                         10,
+                                         super::CountOverflowPolicy::Saturate,
+                                         &mut agg);
+
+        assert_eq!(agg[&ar1], super::SATURATED_TOTAL);
+        assert_eq!(super::num_saturated(&agg), 1)
+    }
+
+    #[test]
+    fn weighted_totals_wrap_around_under_the_wrap_policy() {
+        let mut agg: super::ELBRecordAggregation = HashMap::new();
+        let ar0 = overflow_test_record();
+
+        super::aggregate_weighted_record(ar0.clone(),
+                                         super::SATURATED_TOTAL - 1,
+                                         super::CountOverflowPolicy::Wrap,
+                                         &mut agg);
+        let counted = super::aggregate_weighted_record(ar0.clone(),
+                                                       2,
+                                                       super::CountOverflowPolicy::Wrap,
+                                                       &mut agg);
+
+        assert!(counted);
+        assert_eq!(agg[&ar0], ::std::i64::MIN)
+    }
+
+    #[test]
+    fn weighted_totals_are_left_as_they_were_when_overflowing_under_the_error_policy() {
+        let mut agg: super::ELBRecordAggregation = HashMap::new();
+        let ar0 = overflow_test_record();
+
+        let counted_to_max = super::aggregate_weighted_record(ar0.clone(),
+                                                              super::SATURATED_TOTAL - 1,
+                                                              super::CountOverflowPolicy::Error,
+                                                              &mut agg) &&
+                             super::aggregate_weighted_record(ar0.clone(),
+                                                              1,
+                                                              super::CountOverflowPolicy::Error,
+                                                              &mut agg);
+        let counted_past_max = super::aggregate_weighted_record(ar0.clone(),
+                                                                1,
+                                                                super::CountOverflowPolicy::Error,
+                                                                &mut agg);
+
+        assert!(counted_to_max);
+        assert!(!counted_past_max);
+        assert_eq!(agg[&ar0], super::SATURATED_TOTAL)
+    }
+
+    fn overflow_test_record() -> super::AggregateELBRecord {
+        super::AggregateELBRecord {
+            day: "2015-08-15T23:43:05.302180Z"
+                .parse::<DateTime<UTC>>()
+                .unwrap()
+                .date(),
+            client_address: Some(*"172.16.1.6:54814".parse::<SocketAddrV4>().unwrap().ip()),
+            backend_address: None,
+            url: None,
+            listener_port: None,
+            hour: None,
+            elb_status_code: None,
+            system_name: "sys1".to_owned(),
+            path_dimension: None,
+            latency_bucket: None,
+            day_of_month: None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod limit_per_system_tests {
+
+    use std::collections::HashMap;
+    use test_common::test_record;
+
+    #[test]
+    fn limit_per_system_keeps_the_top_aggregates_of_each_system() {
+        let mut agg: super::ELBRecordAggregation = HashMap::new();
+        agg.insert(test_record("sys1", "172.16.1.1"), 10);
+        agg.insert(test_record("sys1", "172.16.1.2"), 30);
+        agg.insert(test_record("sys1", "172.16.1.3"), 20);
+        agg.insert(test_record("sys2", "172.16.1.1"), 5);
+
+        let limited = super::limit_per_system(agg, 2);
+
+        assert_eq!(limited,
+                   vec![(test_record("sys1", "172.16.1.2"), 30),
+                        (test_record("sys1", "172.16.1.3"), 20),
+                        (test_record("sys2", "172.16.1.1"), 5)]);
+    }
+
+    #[test]
+    fn limit_per_system_breaks_ties_by_client_address() {
+        let mut agg: super::ELBRecordAggregation = HashMap::new();
+        agg.insert(test_record("sys1", "172.16.1.3"), 10);
+        agg.insert(test_record("sys1", "172.16.1.1"), 10);
+        agg.insert(test_record("sys1", "172.16.1.2"), 10);
+
+        let limited = super::limit_per_system(agg, 2);
+
+        assert_eq!(limited,
+                   vec![(test_record("sys1", "172.16.1.1"), 10),
+                        (test_record("sys1", "172.16.1.2"), 10)]);
+    }
+}
+
+#[cfg(test)]
+mod totals_by_system_tests {
+
+    use test_common::test_record;
+
+    #[test]
+    fn totals_by_system_sums_each_system_and_orders_by_total_descending() {
+        let aggs = vec![(test_record("sys1", "172.16.1.1"), 2),
+                        (test_record("sys2", "172.16.1.1"), 5),
+                        (test_record("sys1", "172.16.1.2"), 4),
+                        (test_record("sys3", "172.16.1.1"), 6)];
+
+        let totals = super::totals_by_system(&aggs);
+
+        assert_eq!(totals,
+                   vec![("sys1".to_owned(), 6), ("sys3".to_owned(), 6), ("sys2".to_owned(), 5)])
+    }
+}
+
+#[cfg(test)]
+mod compare_by_tests {
+
+    use super::SortOrder;
+    use test_common::aggregate_on;
+
+    fn sorted_totals(order: SortOrder, reverse: bool) -> Vec<i64> {
+        let mut aggs = vec![(aggregate_on("sys2", "2015-08-14", "172.16.1.1"), 5),
+                            (aggregate_on("sys1", "2015-08-15", "172.16.1.3"), 1),
+                            (aggregate_on("sys1", "2015-08-14", "172.16.1.2"), 9),
+                            (aggregate_on("sys3", "2015-08-16", "172.16.1.1"), 5)];
+        aggs.sort_by(|lhs, rhs| super::compare_by(order, reverse, lhs, rhs));
+        aggs.into_iter().map(|(_, total)| total).collect()
+    }
+
+    #[test]
+    fn compare_by_should_order_by_the_chosen_dimension_and_then_by_key() {
+        assert_eq!(sorted_totals(SortOrder::Count, false), vec![9, 5, 5, 1]);
+        assert_eq!(sorted_totals(SortOrder::Key, false), vec![9, 1, 5, 5]);
+        assert_eq!(sorted_totals(SortOrder::System, false), vec![9, 1, 5, 5]);
+        assert_eq!(sorted_totals(SortOrder::Day, false), vec![9, 5, 1, 5]);
+        assert_eq!(sorted_totals(SortOrder::Client, false), vec![5, 5, 9, 1])
+    }
+
+    #[test]
+    fn compare_by_should_reverse_the_order_when_asked_to() {
+        assert_eq!(sorted_totals(SortOrder::Count, true), vec![1, 5, 5, 9]);
+        assert_eq!(sorted_totals(SortOrder::Key, true), vec![5, 5, 1, 9])
+    }
+}
+
+#[cfg(test)]
+mod regressions_tests {
+
+    use std::collections::{BTreeMap, HashMap};
+    use test_common::test_record;
+
+    fn baseline(totals: &[(&str, i64)]) -> BTreeMap<String, i64> {
+        totals.iter().map(|&(system_name, total)| (system_name.to_owned(), total)).collect()
+    }
+
+    #[test]
+    fn regressions_should_list_the_systems_that_dropped_by_more_than_allowed() {
+        let mut aggregation = HashMap::new();
+        aggregation.insert(test_record("sys1", "172.16.1.1"), 40);
+        aggregation.insert(test_record("sys1", "172.16.1.2"), 5);
+        aggregation.insert(test_record("sys2", "172.16.1.1"), 95);
+
+        let regressions = super::regressions(&aggregation,
+                                             &baseline(&[("sys1", 100), ("sys2", 100)]),
+                                             10.0);
+
+        assert_eq!(regressions,
+                   vec![super::Regression {
+                            system_name: "sys1".to_owned(),
+                            baseline_total: 100,
+                            total: 45,
+                            percent_change: -55.0,
+                        }])
+    }
+
+    #[test]
+    fn regressions_should_count_missing_systems_as_a_full_drop() {
+        let aggregation = HashMap::new();
+
+        let regressions = super::regressions(&aggregation,
+                                             &baseline(&[("sys1", 10), ("sys2", 0)]),
+                                             50.0);
+
+        assert_eq!(regressions.len(), 1);
+        assert_eq!(regressions[0].system_name, "sys1");
+        assert_eq!(regressions[0].percent_change, -100.0)
+    }
+
+    #[test]
+    fn regressions_should_ignore_growth_and_new_systems() {
+        let mut aggregation = HashMap::new();
+        aggregation.insert(test_record("sys1", "172.16.1.1"), 200);
+        aggregation.insert(test_record("sys3", "172.16.1.1"), 1);
+
+        assert!(super::regressions(&aggregation, &baseline(&[("sys1", 100)]), 0.0).is_empty())
+    }
+}
+
+#[cfg(test)]
+mod result_hash_tests {
+
+    use test_common::test_record;
+
+    #[test]
+    fn result_hash_does_not_depend_on_the_order_of_the_aggregates() {
+        let aggs = vec![(test_record("sys1", "172.16.1.1"), 10),
+                        (test_record("sys2", "172.16.1.2"), 20)];
+        let reversed_aggs = vec![(test_record("sys2", "172.16.1.2"), 20),
+                                 (test_record("sys1", "172.16.1.1"), 10)];
+
+        assert_eq!(super::result_hash(&aggs), super::result_hash(&reversed_aggs))
+    }
+
+    #[test]
+    fn result_hash_changes_when_a_total_changes() {
+        let aggs = vec![(test_record("sys1", "172.16.1.1"), 10)];
+        let changed_aggs = vec![(test_record("sys1", "172.16.1.1"), 11)];
+
+        assert_ne!(super::result_hash(&aggs), super::result_hash(&changed_aggs))
+    }
+
+    #[test]
+    fn result_hash_is_a_hex_encoded_sha256() {
+        let hash = super::result_hash(&Vec::new());
+
+        assert_eq!(hash, "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855")
+    }
+}
+
+#[cfg(test)]
+mod field_containing_tests {
+
+    use test_common::{aggregate, aggregate_on_path};
+
+    #[test]
+    fn field_containing_should_return_none_when_no_field_contains_the_delimiter() {
+        let agg = aggregate_on_path("sys1", Some("us-east-1"));
+
+        assert_eq!(super::field_containing(&agg, ','), None)
+    }
+
+    #[test]
+    fn field_containing_should_return_the_system_name_containing_the_delimiter() {
+        let agg = aggregate("sys1,sys2");
+
+        assert_eq!(super::field_containing(&agg, ','), Some("sys1,sys2"))
+    }
+
+    #[test]
+    fn field_containing_should_return_the_path_dimension_containing_the_delimiter() {
+        let agg = aggregate_on_path("sys1", Some("us,east"));
+
+        assert_eq!(super::field_containing(&agg, ','), Some("us,east"))
+    }
+}
+
+#[cfg(test)]
+mod truncate_field_tests {
+
+    use chrono::{DateTime, UTC};
+    use std::net::Ipv4Addr;
+
+    #[test]
+    fn truncate_field_should_leave_fields_within_the_limit_alone() {
+        assert_eq!(super::truncate_field("/cameras", 8), "/cameras")
+    }
+
+    #[test]
+    fn truncate_field_should_shorten_longer_fields_and_mark_them() {
+        assert_eq!(super::truncate_field("/cameras/1234", 8), "/cameras...")
+    }
+
+    #[test]
+    fn truncate_field_should_count_characters_rather_than_bytes() {
+        assert_eq!(super::truncate_field("caméras", 4), "camé...")
+    }
+
+    #[test]
+    fn truncate_fields_should_shorten_the_system_name_and_path_dimension() {
+        let aggregate = super::AggregateELBRecord {
+            day: "2015-08-15T23:43:05.302180Z".parse::<DateTime<UTC>>().unwrap().date(),
+            client_address: Some("172.16.1.6".parse::<Ipv4Addr>().unwrap()),
+            backend_address: None,
+            url: None,
+            listener_port: None,
+            hour: None,
+            elb_status_code: None,
+            system_name: "system-1".to_owned(),
+            path_dimension: Some("us-east-1".to_owned()),
+            latency_bucket: None,
+            day_of_month: None,
+        };
+
+        let truncated = super::truncate_fields(&aggregate, 6);
+
+        assert_eq!(truncated.system_name, "system...");
+        assert_eq!(truncated.path_dimension, Some("us-eas...".to_owned()));
+        assert_eq!(truncated.day, aggregate.day);
+        assert_eq!(truncated.client_address, aggregate.client_address)
+    }
+}
+
+#[cfg(test)]
+mod influx_line_tests {
+
+    use test_common::{aggregate, aggregate_on_path};
+
+    #[test]
+    fn influx_line_should_use_the_start_of_the_day_as_the_timestamp() {
+        let line = super::influx_line(&aggregate("sys1"), 42, None);
+
+        assert_eq!(line, "requests,system=sys1,client=172.16.1.6 count=42i 1439596800000000000")
+    }
+
+    #[test]
+    fn influx_line_should_tag_the_path_dimension() {
+        let key = aggregate_on_path("sys1", Some("us-east-1"));
+
+        let line = super::influx_line(&key, 1, Some("region"));
+
+        assert!(line.starts_with("requests,system=sys1,client=172.16.1.6,region=us-east-1 "))
+    }
+
+    #[test]
+    fn influx_line_should_tag_the_latency_bucket() {
+        let mut agg = aggregate("sys1");
+        agg.latency_bucket = Some("<50ms".to_owned());
+
+        let line = super::influx_line(&agg, 1, None);
+
+        assert!(line.starts_with("requests,system=sys1,client=172.16.1.6,latency=<50ms "))
+    }
+
+    #[test]
+    fn influx_line_should_escape_tag_values() {
+        let line = super::influx_line(&aggregate("a b,c=d"), 1, None);
+
+        assert!(line.starts_with("requests,system=a\\ b\\,c\\=d,client="))
+    }
+
+    #[test]
+    fn influx_line_should_escape_backslashes_before_the_other_characters() {
+        let line = super::influx_line(&aggregate("a\\,b\\"), 1, None);
+
+        assert!(line.starts_with("requests,system=a\\\\\\,b\\\\,client="))
+    }
+
+    #[test]
+    fn influx_line_should_leave_out_empty_tags() {
+        let line = super::influx_line(&aggregate(""), 1, None);
+
+        assert!(line.starts_with("requests,client=172.16.1.6 "))
+    }
+
+    #[test]
+    fn influx_line_should_tag_the_day_of_month_and_leave_out_the_timestamp() {
+        let mut agg = aggregate("sys1");
+        agg.day_of_month = Some(15);
+
+        let line = super::influx_line(&agg, 42, None);
+
+        assert_eq!(line, "requests,system=sys1,client=172.16.1.6,day_of_month=15 count=42i")
+    }
+
+    #[test]
+    fn influx_system_line_should_tag_the_total_with_the_system() {
+        assert_eq!(super::influx_system_line("sys 1", 4),
+                   "requests,system=sys\\ 1 count=4i")
+    }
+
+    #[test]
+    fn influx_system_line_should_leave_out_an_empty_system() {
+        assert_eq!(super::influx_system_line("", 4), "requests count=4i")
+    }
+}
+
+#[cfg(test)]
+mod apply_count_floor_tests {
+
+    use std::collections::HashMap;
+    use chrono::{DateTime, UTC};
+    use std::net::Ipv4Addr;
+
+    fn test_agg() -> super::ELBRecordAggregation {
+        let mut agg = HashMap::new();
+        let totals = vec![("172.16.1.1", 1), ("172.16.1.2", 5), ("172.16.1.3", 9)];
+        for (client_address, total) in totals {
+            agg.insert(super::AggregateELBRecord {
+                           day: "2015-08-15T23:43:05.302180Z"
+                               .parse::<DateTime<UTC>>()
+                               .unwrap()
+                               .date(),
+                           client_address: Some(client_address.parse::<Ipv4Addr>().unwrap()),
+                           backend_address: None,
+                           url: None,
+                           listener_port: None,
+                           hour: None,
+                           elb_status_code: None,
+                           system_name: "sys1".to_owned(),
+                           path_dimension: None,
+                           latency_bucket: None,
+                           day_of_month: None,
+                       },
+                       total);
+        }
+        agg
+    }
+
+    #[test]
+    fn apply_count_floor_suppresses_aggregates_below_the_floor() {
+        let mut agg = test_agg();
+
+        super::apply_count_floor(&mut agg, 5, super::FloorMode::Suppress);
+
+        let mut totals = agg.values().cloned().collect::<Vec<_>>();
+        totals.sort();
+        assert_eq!(totals, vec![5, 9])
+    }
+
+    #[test]
+    fn apply_count_floor_rounds_aggregates_below_the_floor_up_to_the_floor() {
+        let mut agg = test_agg();
+
+        super::apply_count_floor(&mut agg, 5, super::FloorMode::Round);
+
+        let mut totals = agg.values().cloned().collect::<Vec<_>>();
+        totals.sort();
+        assert_eq!(totals, vec![5, 5, 9])
+    }
 }
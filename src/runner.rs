@@ -0,0 +1,884 @@
+use std::collections::HashSet;
+use std::fmt;
+use std::fmt::{Display, Formatter};
+use std::error::Error;
+use std::io;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{mpsc, Arc};
+use aggregation_control::{AggregationController, AggregationError, TooManyAggregates};
+use checkpoint::Checkpoint;
+use file_handling;
+use num_cpus;
+use output::AggregateSink;
+use record_handling;
+use sp;
+use spill;
+use status::RunMetrics;
+use FileAggregation;
+
+/// The number of files each FileAggregator reads between the checkpoints of a checkpointed run.
+/// Every checkpoint writes the whole aggregation, so checkpointing after every file would spend
+/// the run writing them.
+const FILES_PER_CHECKPOINT_PER_WORKER: usize = 64;
+
+/// Runs FileAggregators over the files on a thread pool and merges their aggregations with an
+/// AggregationController. The pool is kept until the runner is shut down, so that a runner can
+/// run again on more files without starting new threads.
+pub struct Runner {
+    thread_pool: sp::Pool,
+    file_handling_msg_senders: Vec<mpsc::Sender<file_handling::FileHandlingMessages>>,
+    file_aggregator_config: file_handling::FileAggregatorConfig,
+    metrics: Arc<RunMetrics>,
+    report_progress: bool,
+    max_aggregates: Option<usize>,
+    merge_threads: Option<usize>,
+    keep_spill_files: bool,
+}
+
+impl Runner {
+    pub fn new() -> Runner {
+        Runner::with_config(file_handling::FileAggregatorConfig::default())
+    }
+
+    pub fn with_config(file_aggregator_config: file_handling::FileAggregatorConfig) -> Runner {
+        // The file aggregators count records into the metrics shared through their config.
+        let metrics = file_aggregator_config.metrics.clone();
+        Runner {
+            thread_pool: sp::Pool::empty(),
+            file_handling_msg_senders: Vec::new(),
+            file_aggregator_config: file_aggregator_config,
+            metrics: metrics,
+            report_progress: false,
+            max_aggregates: None,
+            merge_threads: None,
+            keep_spill_files: false,
+        }
+    }
+
+    pub fn enable_progress(&mut self) -> () {
+        self.report_progress = true;
+    }
+
+    /// Fails the run once there are more than `max_aggregates` aggregates, see
+    /// `AggregationController::limit_aggregates`. The file handlers stop once they hold more
+    /// than that between them, and the aggregation a watch or a checkpointed run adds every run
+    /// to is held to the limit too.
+    pub fn limit_aggregates(&mut self, max_aggregates: usize) -> () {
+        self.max_aggregates = Some(max_aggregates);
+        self.file_aggregator_config.max_aggregates = Some(max_aggregates);
+    }
+
+    pub fn merge_in_parallel(&mut self, num_threads: usize) -> () {
+        self.merge_threads = Some(num_threads);
+    }
+
+    /// See `AggregationController::keep_spill_files`.
+    pub fn keep_spill_files(&mut self) -> () {
+        self.keep_spill_files = true;
+    }
+
+    pub fn metrics(&self) -> Arc<RunMetrics> {
+        self.metrics.clone()
+    }
+
+    /// Counts the files read with the sizes they were listed with, see
+    /// `file_handling::FileAggregatorConfig::listed_sizes`.
+    pub fn set_listed_sizes(&mut self, listed_sizes: file_handling::FileSizes) -> () {
+        self.file_aggregator_config.listed_sizes = Arc::new(listed_sizes);
+    }
+
+    pub fn run(&mut self,
+               num_file_aggregators: usize,
+               mut filenames: &mut Vec<PathBuf>)
+               -> Result<FileAggregation, RunError> {
+        let (agg_msg_sender, agg_msg_receiver) = mpsc::channel::<_>();
+        let num_records_before = self.metrics.num_raw_records();
+        self.metrics.reset_held_aggregates();
+        // The FileAggregators of an earlier run stopped once it was done, leaving their threads
+        // in the pool for the ones of this run.
+        self.file_handling_msg_senders.clear();
+        for sender_id in 0..num_file_aggregators {
+            let (file_handling_msg_sender, file_handling_msg_receiver) = mpsc::channel::<_>();
+            self.file_handling_msg_senders.push(file_handling_msg_sender);
+            let cloned_agg_msg_sender = agg_msg_sender.clone();
+            let file_aggregator_config = self.file_aggregator_config.clone();
+            if self.thread_pool.workers() < num_file_aggregators {
+                self.thread_pool.expand();
+            }
+            self.thread_pool.spawn(move || {
+                file_handling::FileAggregator::with_config(sender_id, file_aggregator_config)
+                    .run(&file_handling_msg_receiver, &cloned_agg_msg_sender);
+            });
+        }
+        let mut agg_control =
+            AggregationController::with_metrics(agg_msg_receiver,
+                                                self.file_handling_msg_senders.clone(),
+                                                self.metrics.clone());
+        if self.report_progress {
+            agg_control.enable_progress();
+        }
+        agg_control.set_listed_sizes(self.file_aggregator_config.listed_sizes.clone());
+        if let Some(ref timings) = self.file_aggregator_config.profile {
+            agg_control.enable_profiling(timings.clone());
+        }
+        if let Some(max_aggregates) = self.max_aggregates {
+            agg_control.limit_aggregates(max_aggregates);
+        }
+        if let Some(merge_threads) = self.merge_threads {
+            agg_control.merge_in_parallel(merge_threads);
+        }
+        if self.keep_spill_files {
+            agg_control.keep_spill_files();
+        }
+        agg_control.set_count_overflow_policy(self.file_aggregator_config
+            .record_config
+            .count_overflow_policy);
+        let final_agg = agg_control.run_aggregation(filenames).map_err(|err| {
+                match err {
+                    AggregationError::TooManyAggregates(err) => RunError::TooManyAggregates(err),
+                    AggregationError::Spill(err) => RunError::Spill(err),
+                }
+            })?;
+
+        // The shared counter counts records as they are read, before any are found to be outside
+        // of the date range, and keeps counting over every run.
+        let num_records_read = final_agg.num_raw_records + final_agg.num_outside_date_range_records;
+        let num_records_counted = self.metrics.num_raw_records() - num_records_before;
+        if num_records_counted != num_records_read as u64 {
+            println_stderr!("The shared record counter saw {} records but the file aggregators \
+                             reported {}. This should be reported to the developers.",
+                            num_records_counted,
+                            num_records_read);
+        }
+        Ok(final_agg)
+    }
+
+    /// Runs over the files not already read by the checkpoint in batches, adding each batch to
+    /// the aggregation of the checkpoint and writing it to `checkpoint_path` after every batch,
+    /// so that a run that dies can be resumed from the last batch, see `Checkpoint`. Returns the
+    /// aggregation of every file of the checkpoint. A checkpoint only holds the aggregates in
+    /// memory, so the aggregations a batch spilled to disk are merged back into it, even when the
+    /// spill files are kept, see `keep_spill_files`.
+    pub fn run_checkpointed(&mut self,
+                            num_file_aggregators: usize,
+                            filenames: &mut Vec<PathBuf>,
+                            mut checkpoint: Checkpoint,
+                            checkpoint_path: &Path)
+                            -> Result<FileAggregation, RunError> {
+        filenames.retain(|filename| !checkpoint.read_files.contains(filename));
+        let batch_size = num_file_aggregators.max(1) * FILES_PER_CHECKPOINT_PER_WORKER;
+        let policy = self.file_aggregator_config.record_config.count_overflow_policy;
+        while !filenames.is_empty() {
+            // The files are read from the back of the list, so the batches are taken from it too.
+            let split = filenames.len().saturating_sub(batch_size);
+            let mut batch = filenames.split_off(split);
+            let batch_files = batch.clone();
+            let mut file_agg = self.run(num_file_aggregators, &mut batch)?;
+            if !file_agg.spill_files.is_empty() {
+                let merged = spill::merge_spill_files(&file_agg.spill_files,
+                                                      &mut file_agg.aggregation,
+                                                      policy);
+                spill::remove_spill_files(&file_agg.spill_files);
+                file_agg.spill_files.clear();
+                file_agg.num_count_overflows += merged.map_err(RunError::Spill)?;
+            }
+            checkpoint.file_agg.merge_with_policy(&file_agg, policy);
+            self.check_num_aggregates(&checkpoint.file_agg)?;
+            checkpoint.read_files.extend(batch_files);
+            checkpoint.write(checkpoint_path).map_err(|err| {
+                    RunError::Checkpoint {
+                        path: checkpoint_path.to_path_buf(),
+                        err: err,
+                    }
+                })?;
+        }
+        Ok(checkpoint.file_agg)
+    }
+
+    /// Fails once an aggregation added to over several runs holds more aggregates than the
+    /// limit, which the runs only check of their own aggregations.
+    fn check_num_aggregates(&self, file_agg: &FileAggregation) -> Result<(), RunError> {
+        match self.max_aggregates {
+            Some(max_aggregates) if file_agg.num_aggregates() > max_aggregates => {
+                Err(RunError::TooManyAggregates(TooManyAggregates {
+                    num_aggregates: file_agg.num_aggregates(),
+                    max_aggregates: max_aggregates,
+                }))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    #[cfg(test)]
+    fn num_threads_in_pool(&self) -> usize {
+        self.thread_pool.workers()
+    }
+
+    #[cfg(test)]
+    fn num_file_handling_msg_senders(&self) -> usize {
+        self.file_handling_msg_senders.len()
+    }
+
+    pub fn shutdown(&mut self) -> () {
+        // Shutting down the file handlers is a precaution to prevent hangs and aids in testing.
+        for msg_sender in self.file_handling_msg_senders.iter() {
+            let _ = msg_sender.send(file_handling::FileHandlingMessages::Done);
+        }
+        self.thread_pool.shutdown()
+    }
+}
+
+impl Default for Runner {
+    fn default() -> Runner {
+        Runner::new()
+    }
+}
+
+/// Aggregates the log files of a location as they appear, for running counter continuously
+/// against a location that logs are delivered to. Every poll reads the files that weren't listed
+/// before on the workers of the runner and adds them to the aggregation of the files read
+/// earlier. Files are expected to be complete when they appear, as ELB delivers them.
+pub struct Watch {
+    runner: Runner,
+    num_file_aggregators: usize,
+    location: PathBuf,
+    read_files: HashSet<PathBuf>,
+    aggregation: FileAggregation,
+}
+
+impl Watch {
+    pub fn new<P: AsRef<Path>>(runner: Runner, num_file_aggregators: usize, location: P) -> Watch {
+        Watch {
+            runner: runner,
+            num_file_aggregators: num_file_aggregators,
+            location: location.as_ref().to_path_buf(),
+            read_files: HashSet::new(),
+            aggregation: FileAggregation::new(),
+        }
+    }
+
+    /// Reads the files that appeared since the last poll, returning how many there were. A file
+    /// is only read once, even when the poll fails.
+    pub fn poll(&mut self) -> Result<usize, RunError> {
+        let (listed, listed_sizes) = file_handling::log_file_list(&self.location).map_err(|err| {
+                RunError::FileListing {
+                    source: self.location.clone(),
+                    err: err,
+                }
+            })?;
+        self.runner.set_listed_sizes(listed_sizes);
+        let mut filenames = listed.into_iter()
+            .filter(|filename| !self.read_files.contains(filename))
+            .collect::<Vec<_>>();
+        let num_files = filenames.len();
+        if num_files == 0 {
+            return Ok(0);
+        }
+        self.read_files.extend(filenames.iter().cloned());
+        let file_agg = self.runner.run(self.num_file_aggregators, &mut filenames)?;
+        let policy = self.runner.file_aggregator_config.record_config.count_overflow_policy;
+        self.aggregation.merge_with_policy(&file_agg, policy);
+        // The controller of the poll only counted the aggregates of the files of the poll.
+        self.runner.metrics.set_num_aggregates(self.aggregation.num_aggregates());
+        self.runner.check_num_aggregates(&self.aggregation)?;
+        Ok(num_files)
+    }
+
+    /// The aggregation of every file read so far.
+    pub fn aggregation(&self) -> &FileAggregation {
+        &self.aggregation
+    }
+
+    pub fn num_files_read(&self) -> usize {
+        self.read_files.len()
+    }
+
+    pub fn metrics(&self) -> Arc<RunMetrics> {
+        self.runner.metrics()
+    }
+
+    pub fn shutdown(mut self) -> () {
+        self.runner.shutdown()
+    }
+}
+
+/// Aggregates the log files of its sources on a pool of workers and returns the aggregation,
+/// without writing anything, so that counter can be embedded in another program, as in
+/// `Counter::new().workers(4).sources(vec!["/var/log/elb"]).run()`, or writes the aggregates to
+/// a sink of the program's own with `run_into`. The defaults are those of the counter command
+/// without options.
+pub struct Counter {
+    num_workers: usize,
+    sources: Vec<PathBuf>,
+    file_aggregator_config: file_handling::FileAggregatorConfig,
+    max_aggregates: Option<usize>,
+    merge_threads: Option<usize>,
+}
+
+/// Returned when a `Counter` couldn't list the files of a source, made too many aggregates,
+/// couldn't read back the aggregations it spilled to disk or couldn't write the aggregates to its
+/// sink, or when a checkpointed run couldn't write its checkpoint.
+#[derive(Debug)]
+pub enum RunError {
+    FileListing { source: PathBuf, err: String },
+    TooManyAggregates(TooManyAggregates),
+    Spill(io::Error),
+    Checkpoint { path: PathBuf, err: io::Error },
+    Sink(io::Error),
+}
+
+impl Display for RunError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match *self {
+            RunError::FileListing { ref source, ref err } => {
+                write!(f, "Couldn't list the files of {}: {}", source.display(), err)
+            }
+            RunError::TooManyAggregates(ref err) => {
+                write!(f,
+                       "Accumulated {} aggregates, more than the limit of {}.",
+                       err.num_aggregates,
+                       err.max_aggregates)
+            }
+            RunError::Spill(ref err) => {
+                write!(f, "Couldn't merge the aggregations spilled to disk: {}", err)
+            }
+            RunError::Checkpoint { ref path, ref err } => {
+                write!(f, "Couldn't write the checkpoint {}: {}", path.display(), err)
+            }
+            RunError::Sink(ref err) => write!(f, "Couldn't write the aggregates: {}", err),
+        }
+    }
+}
+
+impl Error for RunError {
+    fn description(&self) -> &str {
+        match *self {
+            RunError::FileListing { .. } => "failed to list the log files",
+            RunError::TooManyAggregates(_) => "made too many aggregates",
+            RunError::Spill(_) => "failed to merge the spilled aggregations",
+            RunError::Checkpoint { .. } => "failed to write the checkpoint",
+            RunError::Sink(_) => "failed to write the aggregates",
+        }
+    }
+}
+
+impl Counter {
+    pub fn new() -> Counter {
+        Counter {
+            num_workers: num_cpus::get(),
+            sources: Vec::new(),
+            file_aggregator_config: file_handling::FileAggregatorConfig::default(),
+            max_aggregates: None,
+            merge_threads: None,
+        }
+    }
+
+    /// The number of files read at once, one per CPU unless set.
+    pub fn workers(mut self, num_workers: usize) -> Counter {
+        self.num_workers = num_workers.max(1);
+        self
+    }
+
+    /// Adds log locations to aggregate the log files of. Each is a directory, a log file or an
+    /// `s3://bucket/prefix` URI, see `file_handling::log_file_list`.
+    pub fn sources<I, P>(mut self, sources: I) -> Counter
+        where I: IntoIterator<Item = P>,
+              P: AsRef<Path>
+    {
+        self.sources.extend(sources.into_iter().map(|source| source.as_ref().to_path_buf()));
+        self
+    }
+
+    /// Reads the files as configured, replacing the record options set by `record_config`.
+    pub fn file_aggregator_config(mut self,
+                                  config: file_handling::FileAggregatorConfig)
+                                  -> Counter {
+        self.file_aggregator_config = config;
+        self
+    }
+
+    /// Turns the records into aggregates as configured.
+    pub fn record_config(mut self, config: record_handling::RecordConfig) -> Counter {
+        self.file_aggregator_config.record_config = config;
+        self
+    }
+
+    /// Fails the run once there are more than `max_aggregates` aggregates, see
+    /// `AggregationController::limit_aggregates`.
+    pub fn limit_aggregates(mut self, max_aggregates: usize) -> Counter {
+        self.max_aggregates = Some(max_aggregates);
+        self
+    }
+
+    /// Merges the aggregations of the workers on this many threads, see
+    /// `AggregationController::merge_in_parallel`. The aggregates are then returned in
+    /// `partitions` of the aggregation rather than in `aggregation`.
+    pub fn merge_threads(mut self, num_threads: usize) -> Counter {
+        self.merge_threads = Some(num_threads);
+        self
+    }
+
+    /// Lists the files of every source and aggregates them. The worker threads are shut down
+    /// before returning.
+    pub fn run(self) -> Result<FileAggregation, RunError> {
+        let mut filenames = Vec::new();
+        let mut listed_sizes = file_handling::FileSizes::new();
+        for source in &self.sources {
+            let (files, sizes) = file_handling::log_file_list(source).map_err(|err| {
+                    RunError::FileListing {
+                        source: source.clone(),
+                        err: err,
+                    }
+                })?;
+            filenames.extend(files);
+            listed_sizes.extend(sizes);
+        }
+        let mut runner = Runner::with_config(self.file_aggregator_config);
+        runner.set_listed_sizes(listed_sizes);
+        if let Some(max_aggregates) = self.max_aggregates {
+            runner.limit_aggregates(max_aggregates);
+        }
+        if let Some(merge_threads) = self.merge_threads {
+            runner.merge_in_parallel(merge_threads);
+        }
+        let result = runner.run(self.num_workers, &mut filenames);
+        runner.shutdown();
+        result
+    }
+
+    /// Runs like `run` and then writes every aggregate to the sink and finishes it, so that the
+    /// aggregates can be streamed to any backend implementing `AggregateSink`. The aggregates are
+    /// written in no particular order, and the aggregation is returned without them.
+    pub fn run_into<S>(self, sink: &mut S) -> Result<FileAggregation, RunError>
+        where S: AggregateSink + ?Sized
+    {
+        let mut file_agg = self.run()?;
+        for aggregation in file_agg.take_aggregations() {
+            for (key, total) in aggregation {
+                sink.write_aggregate(&key, total).map_err(RunError::Sink)?;
+            }
+        }
+        sink.finish().map_err(RunError::Sink)?;
+        Ok(file_agg)
+    }
+}
+
+impl Default for Counter {
+    fn default() -> Counter {
+        Counter::new()
+    }
+}
+
+#[cfg(test)]
+mod full_run_tests {
+
+    use std::path::PathBuf;
+    use std::sync::Arc;
+    use file_handling;
+    use status::{Phase, PhaseTimings};
+
+    const LOG_FILES: &'static str = "./test_artifacts/log_files";
+
+    #[test]
+    #[ignore]
+    fn a_full_run_should_return_the_correct_aggregation_results() {
+        let num_cpus = ::num_cpus::get();
+        let mut files = file_handling::file_list(&PathBuf::from(LOG_FILES)).unwrap();
+        let mut runner = super::Runner::new();
+
+        let file_agg = runner.run(num_cpus, &mut files).unwrap();
+
+        assert_eq!(file_agg.num_raw_records, 838140);
+        assert_eq!(file_agg.num_aggregates(), 95479);
+    }
+
+    /// The aggregation of a full run and the time spent merging it, see `Phase::Merging`.
+    fn timed_full_run(merge_threads: Option<usize>) -> (::FileAggregation, f64) {
+        let timings = Arc::new(PhaseTimings::new());
+        let config = file_handling::FileAggregatorConfig {
+            profile: Some(timings.clone()),
+            ..Default::default()
+        };
+        let mut runner = super::Runner::with_config(config);
+        if let Some(merge_threads) = merge_threads {
+            runner.merge_in_parallel(merge_threads);
+        }
+        let mut files = file_handling::file_list(&PathBuf::from(LOG_FILES)).unwrap();
+
+        let file_agg = runner.run(::num_cpus::get(), &mut files).unwrap();
+
+        runner.shutdown();
+        (file_agg, timings.nanos(Phase::Merging) as f64 / 1_000_000.0)
+    }
+
+    /// Compares the merge of the file handlers' aggregations on a thread per core with the merge
+    /// on the aggregation controller's thread, run with `cargo test -- --ignored --nocapture`.
+    #[test]
+    #[ignore]
+    fn merging_in_parallel_should_aggregate_the_full_run_like_merging_serially() {
+        let (serial_agg, serial_millis) = timed_full_run(None);
+        let (mut parallel_agg, parallel_millis) = timed_full_run(Some(::num_cpus::get()));
+
+        println!("Merged {} aggregates in {:.1} ms serially and {:.1} ms in parallel.",
+                 serial_agg.num_aggregates(),
+                 serial_millis,
+                 parallel_millis);
+        assert_eq!(parallel_agg.num_aggregates(), 95479);
+        parallel_agg.combine_partitions();
+        assert_eq!(parallel_agg.aggregation, serial_agg.aggregation)
+    }
+}
+
+#[cfg(test)]
+mod runner_tests {
+
+    use std::path::PathBuf;
+
+    #[test]
+    fn runner_should_create_the_same_number_of_file_handling_message_senders_as_host_cpus() {
+        let num_cpus = ::num_cpus::get();
+        let mut files = Vec::new();
+        files.push(PathBuf::from("./test_artifacts/test_elb_log_file.log"));
+        let mut runner = super::Runner::new();
+
+        let _ = runner.run(num_cpus, &mut files);
+
+        assert_eq!(runner.num_file_handling_msg_senders(), num_cpus);
+
+        runner.shutdown()
+    }
+
+    #[test]
+    fn runner_should_create_a_thread_pool_having_the_same_number_of_cpus_as_the_host() {
+        let num_cpus = ::num_cpus::get();
+        let mut files = Vec::new();
+        files.push(PathBuf::from("./test_artifacts/test_elb_log_file.log"));
+        let mut runner = super::Runner::new();
+
+        let _ = runner.run(num_cpus, &mut files);
+
+        assert_eq!(runner.num_threads_in_pool(), num_cpus);
+
+        runner.shutdown()
+    }
+
+    #[test]
+    fn runner_should_create_as_many_senders_and_threads_as_requested() {
+        let num_threads = 2;
+        let mut files = vec![PathBuf::from("./test_artifacts/test_elb_log_file.log"); 3];
+        let mut runner = super::Runner::new();
+
+        let _ = runner.run(num_threads, &mut files);
+
+        assert_eq!(runner.num_file_handling_msg_senders(), num_threads);
+        assert_eq!(runner.num_threads_in_pool(), num_threads);
+
+        runner.shutdown()
+    }
+
+    #[test]
+    fn runner_should_aggregate_the_same_regardless_of_the_number_of_threads() {
+        let files = vec![PathBuf::from("./test_artifacts/test_elb_log_file.log"); 3];
+        let mut single_threaded_runner = super::Runner::new();
+        let mut multi_threaded_runner = super::Runner::new();
+
+        let single_threaded_agg = single_threaded_runner.run(1, &mut files.clone()).unwrap();
+        let multi_threaded_agg = multi_threaded_runner.run(4, &mut files.clone()).unwrap();
+
+        assert_eq!(multi_threaded_agg, single_threaded_agg);
+
+        single_threaded_runner.shutdown();
+        multi_threaded_runner.shutdown()
+    }
+
+    #[test]
+    fn runner_should_count_every_record_in_the_shared_record_counter() {
+        let num_cpus = ::num_cpus::get();
+        let mut files = vec![PathBuf::from("./test_artifacts/test_elb_log_file.log"); 3];
+        let mut runner = super::Runner::new();
+
+        let file_agg = runner.run(num_cpus, &mut files).unwrap();
+
+        assert_eq!(runner.metrics.num_raw_records(),
+                   (file_agg.num_raw_records + file_agg.num_outside_date_range_records) as u64);
+
+        runner.shutdown()
+    }
+
+    #[test]
+    fn runner_should_aggregate_the_same_when_spilling_aggregations_to_disk() {
+        let files = vec![PathBuf::from("./test_artifacts/test_elb_log_file.log"); 3];
+        let mut runner = super::Runner::new();
+        let expected_agg = runner.run(2, &mut files.clone()).unwrap();
+        runner.shutdown();
+        let config = ::file_handling::FileAggregatorConfig {
+            spill_after: Some(1),
+            ..Default::default()
+        };
+        let mut spilling_runner = super::Runner::with_config(config);
+
+        let file_agg = spilling_runner.run(2, &mut files.clone()).unwrap();
+
+        assert!(file_agg.spill_files.is_empty());
+        assert_eq!(file_agg.aggregation, expected_agg.aggregation);
+        spilling_runner.shutdown()
+    }
+
+    #[test]
+    fn runner_should_fail_once_there_are_more_aggregates_than_the_limit() {
+        let num_cpus = ::num_cpus::get();
+        let mut files = vec![PathBuf::from("./test_artifacts/test_elb_log_file.log")];
+        let mut runner = super::Runner::new();
+        runner.limit_aggregates(1);
+
+        let result = runner.run(num_cpus, &mut files);
+
+        match result {
+            Err(super::RunError::TooManyAggregates(e)) => {
+                assert!(e.max_aggregates == 1 && e.num_aggregates > 1)
+            }
+            _ => panic!("The run was not stopped at the limit."),
+        }
+
+        runner.shutdown()
+    }
+}
+
+#[cfg(test)]
+mod watch_tests {
+
+    use std::env;
+    use std::fs;
+    use std::path::PathBuf;
+    use test_common;
+
+    fn watched_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(name);
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn poll_should_only_read_the_files_that_appeared_since_the_last_poll() {
+        let dir = watched_dir("counter_watch_new_files");
+        fs::copy(test_common::TEST_LOG_FILE, dir.join("a.log")).unwrap();
+        let mut watch = super::Watch::new(super::Runner::new(), 2, &dir);
+
+        assert_eq!(watch.poll().unwrap(), 1);
+        let num_records = watch.aggregation().num_raw_records;
+        assert_eq!(watch.poll().unwrap(), 0);
+        assert_eq!(watch.aggregation().num_raw_records, num_records);
+
+        fs::copy(test_common::TEST_LOG_FILE, dir.join("b.log")).unwrap();
+        assert_eq!(watch.poll().unwrap(), 1);
+        assert_eq!(watch.aggregation().num_raw_records, 2 * num_records);
+        assert_eq!(watch.num_files_read(), 2);
+        assert_eq!(watch.metrics().num_aggregates(), watch.aggregation().num_aggregates());
+
+        watch.shutdown()
+    }
+
+    #[test]
+    fn the_aggregation_of_every_poll_should_match_a_single_run_over_the_same_files() {
+        let dir = watched_dir("counter_watch_same_aggregation");
+        let mut watch = super::Watch::new(super::Runner::new(), 2, &dir);
+        fs::copy(test_common::TEST_LOG_FILE, dir.join("a.log")).unwrap();
+        let _ = watch.poll().unwrap();
+        fs::copy(test_common::TEST_LOG_FILE, dir.join("b.log")).unwrap();
+        let _ = watch.poll().unwrap();
+
+        let mut runner = super::Runner::new();
+        let mut files = vec![dir.join("a.log"), dir.join("b.log")];
+        let expected_agg = runner.run(2, &mut files).unwrap();
+        runner.shutdown();
+
+        assert_eq!(watch.aggregation().aggregation, expected_agg.aggregation);
+        watch.shutdown()
+    }
+
+    #[test]
+    fn poll_should_fail_once_the_aggregation_of_every_poll_holds_more_aggregates_than_the_limit() {
+        let dir = watched_dir("counter_watch_limit");
+        let mut runner = super::Runner::new();
+        runner.limit_aggregates(test_common::TEST_LOG_FILE_AGGS);
+        let mut watch = super::Watch::new(runner, 2, &dir);
+        fs::copy(test_common::TEST_LOG_FILE, dir.join("a.log")).unwrap();
+        assert_eq!(watch.poll().unwrap(), 1);
+        watch.aggregation.aggregation.insert(test_common::aggregate("other-system"), 1);
+
+        fs::copy(test_common::TEST_LOG_FILE, dir.join("b.log")).unwrap();
+        let result = watch.poll();
+
+        match result {
+            Err(super::RunError::TooManyAggregates(e)) => {
+                assert_eq!(e.num_aggregates, test_common::TEST_LOG_FILE_AGGS + 1)
+            }
+            _ => panic!("The watch was not stopped at the limit."),
+        }
+        watch.shutdown()
+    }
+
+    #[test]
+    fn a_runner_should_run_again_on_the_threads_of_its_pool() {
+        let num_threads = 2;
+        let mut runner = super::Runner::new();
+        let mut files = vec![PathBuf::from(test_common::TEST_LOG_FILE)];
+        let first_agg = runner.run(num_threads, &mut files.clone()).unwrap();
+
+        let second_agg = runner.run(num_threads, &mut files).unwrap();
+
+        assert_eq!(second_agg.aggregation, first_agg.aggregation);
+        assert_eq!(runner.num_threads_in_pool(), num_threads);
+        runner.shutdown()
+    }
+}
+
+#[cfg(test)]
+mod checkpointed_run_tests {
+
+    use std::env;
+    use std::fs;
+    use std::path::PathBuf;
+    use checkpoint::Checkpoint;
+    use test_common;
+
+    fn log_files(name: &str) -> Vec<PathBuf> {
+        let dir = env::temp_dir().join(name);
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        ["a.log", "b.log", "c.log"]
+            .iter()
+            .map(|name| {
+                fs::copy(test_common::TEST_LOG_FILE, dir.join(name)).unwrap();
+                dir.join(name)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn a_checkpointed_run_should_write_the_aggregation_of_every_file_to_the_checkpoint() {
+        let files = log_files("counter_checkpointed_run");
+        let checkpoint_path = env::temp_dir().join("counter_checkpointed_run.checkpoint");
+        let mut runner = super::Runner::new();
+
+        let file_agg =
+            runner.run_checkpointed(2, &mut files.clone(), Checkpoint::new(), &checkpoint_path)
+                .unwrap();
+
+        let checkpoint = Checkpoint::read(&checkpoint_path).unwrap();
+        assert_eq!(checkpoint.read_files, files.into_iter().collect());
+        assert_eq!(checkpoint.file_agg.aggregation, file_agg.aggregation);
+        assert_eq!(checkpoint.file_agg.num_raw_records, file_agg.num_raw_records);
+        runner.shutdown()
+    }
+
+    #[test]
+    fn a_resumed_run_should_match_a_single_run_over_the_same_files() {
+        let files = log_files("counter_resumed_run");
+        let checkpoint_path = env::temp_dir().join("counter_resumed_run.checkpoint");
+        let mut runner = super::Runner::new();
+        let expected_agg = runner.run(2, &mut files.clone()).unwrap();
+        let mut first_file = files[..1].to_vec();
+        let _ = runner.run_checkpointed(2, &mut first_file, Checkpoint::new(), &checkpoint_path)
+            .unwrap();
+
+        let checkpoint = Checkpoint::read(&checkpoint_path).unwrap();
+        let file_agg = runner.run_checkpointed(2, &mut files.clone(), checkpoint, &checkpoint_path)
+            .unwrap();
+
+        assert_eq!(file_agg.aggregation, expected_agg.aggregation);
+        assert_eq!(file_agg.num_raw_records, expected_agg.num_raw_records);
+        runner.shutdown()
+    }
+
+    #[test]
+    fn a_checkpointed_run_should_keep_the_aggregations_spilled_to_disk() {
+        let files = log_files("counter_checkpointed_spilling_run");
+        let checkpoint_path = env::temp_dir().join("counter_checkpointed_spilling_run.checkpoint");
+        let mut runner = super::Runner::new();
+        let expected_agg = runner.run(2, &mut files.clone()).unwrap();
+        runner.shutdown();
+        let config = ::file_handling::FileAggregatorConfig {
+            spill_after: Some(1),
+            ..Default::default()
+        };
+        let mut spilling_runner = super::Runner::with_config(config);
+        spilling_runner.keep_spill_files();
+
+        let file_agg = spilling_runner.run_checkpointed(2,
+                                                        &mut files.clone(),
+                                                        Checkpoint::new(),
+                                                        &checkpoint_path)
+            .unwrap();
+
+        let checkpoint = Checkpoint::read(&checkpoint_path).unwrap();
+        assert!(file_agg.spill_files.is_empty());
+        assert_eq!(file_agg.aggregation, expected_agg.aggregation);
+        assert_eq!(checkpoint.file_agg.aggregation, expected_agg.aggregation);
+        spilling_runner.shutdown()
+    }
+}
+
+#[cfg(test)]
+mod counter_tests {
+
+    use std::path::PathBuf;
+    use test_common;
+    use test_common::RecordingSink;
+
+    #[test]
+    fn counter_should_aggregate_like_a_runner() {
+        let file = PathBuf::from(test_common::TEST_LOG_FILE);
+        let mut runner = super::Runner::new();
+        let expected_agg = runner.run(2, &mut vec![file.clone(), file.clone()]).unwrap();
+        runner.shutdown();
+
+        let file_agg = super::Counter::new().workers(2).sources(vec![&file, &file]).run().unwrap();
+
+        assert_eq!(file_agg, expected_agg)
+    }
+
+    #[test]
+    fn counter_should_write_every_aggregate_to_the_sink_and_finish_it() {
+        let file = PathBuf::from(test_common::TEST_LOG_FILE);
+        let mut sink = RecordingSink::default();
+
+        let file_agg = super::Counter::new()
+            .workers(2)
+            .sources(vec![&file])
+            .merge_threads(2)
+            .run_into(&mut sink)
+            .unwrap();
+
+        assert_eq!(sink.written.len(), test_common::TEST_LOG_FILE_AGGS);
+        assert_eq!(sink.num_finished, 1);
+        assert_eq!(file_agg.num_aggregates(), 0)
+    }
+
+    #[test]
+    fn counter_should_fail_once_there_are_more_aggregates_than_the_limit() {
+        let result = super::Counter::new()
+            .sources(vec![test_common::TEST_LOG_FILE])
+            .limit_aggregates(1)
+            .run();
+
+        match result {
+            Err(super::RunError::TooManyAggregates(e)) => assert_eq!(e.max_aggregates, 1),
+            _ => panic!("The run was not stopped at the limit."),
+        }
+    }
+
+    #[test]
+    fn counter_should_fail_when_a_source_cannot_be_listed() {
+        let result = super::Counter::new().sources(vec!["./test_artifacts/missing"]).run();
+
+        match result {
+            Err(super::RunError::FileListing { source, .. }) => {
+                assert_eq!(source, PathBuf::from("./test_artifacts/missing"))
+            }
+            _ => panic!("The missing source was not reported."),
+        }
+    }
+}
@@ -0,0 +1,404 @@
+use std::cmp::Ordering as KeyOrdering;
+use std::collections::BinaryHeap;
+use std::env;
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::{BufRead, BufReader, BufWriter, Lines, Write};
+use std::mem;
+use std::net::Ipv4Addr;
+use std::path::PathBuf;
+use std::process;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use chrono::{DateTime, UTC};
+use record_handling;
+use record_handling::AggregateELBRecord;
+use ELBRecordAggregation;
+
+/// Numbers the spill files of the process so that every FileAggregator, of every run, writes its
+/// own.
+static NUM_SPILL_FILES: AtomicUsize = AtomicUsize::new(0);
+
+/// Written for the fields of a key that are None. A field holding `\N` is written escaped, so
+/// the two can't be confused.
+const NONE_FIELD: &'static str = "\\N";
+
+/// The memory an aggregate takes in an aggregation, roughly. The key and total are counted along
+/// with the hash table overhead and the text of a typical system name.
+pub fn estimated_aggregate_bytes() -> usize {
+    mem::size_of::<(AggregateELBRecord, i64)>() + 64
+}
+
+/// The number of aggregates an aggregation can hold within `max_bytes`, at least one, see
+/// `estimated_aggregate_bytes`.
+pub fn max_aggregates_within(max_bytes: u64) -> usize {
+    (max_bytes / estimated_aggregate_bytes() as u64).max(1) as usize
+}
+
+/// Moves every aggregate of the aggregation to a new file in the temporary directory, as lines
+/// of the encoded key, see `encode_key`, and total sorted by key, see `compare_spilled`, leaving
+/// the aggregation empty. Returns the path of the file, which `for_each_spilled_aggregate` reads
+/// back.
+pub fn spill(aggregation: &mut ELBRecordAggregation) -> io::Result<PathBuf> {
+    let path = env::temp_dir().join(format!("counter-spill-{}-{}.tsv",
+                                            process::id(),
+                                            NUM_SPILL_FILES.fetch_add(1, Ordering::SeqCst)));
+    let mut aggregates = aggregation.iter().collect::<Vec<_>>();
+    aggregates.sort_unstable_by(|lhs, rhs| compare_spilled(lhs.0, rhs.0));
+    let mut writer = BufWriter::new(File::create(&path)?);
+    for (key, total) in aggregates {
+        writeln!(writer, "{}\t{}", encode_key(key), total)?;
+    }
+    writer.flush()?;
+    aggregation.clear();
+    Ok(path)
+}
+
+/// The order aggregates are spilled and merged back in, that of `record_handling::compare_by_key`
+/// with the day of the month breaking its ties, so that merged aggregates come out in the order
+/// of `--sort-by key` and only equal keys compare equal.
+pub fn compare_spilled(lhs: &AggregateELBRecord, rhs: &AggregateELBRecord) -> KeyOrdering {
+    record_handling::compare_by_key(lhs, rhs).then_with(|| lhs.day_of_month.cmp(&rhs.day_of_month))
+}
+
+/// The next aggregate of a spill file, ordered for the heap of `for_each_spilled_aggregate` so
+/// that the smallest key is popped first.
+struct RunHead {
+    key: AggregateELBRecord,
+    total: i64,
+    run_idx: usize,
+}
+
+impl PartialEq for RunHead {
+    fn eq(&self, other: &RunHead) -> bool {
+        self.cmp(other) == KeyOrdering::Equal
+    }
+}
+
+impl Eq for RunHead {}
+
+impl PartialOrd for RunHead {
+    fn partial_cmp(&self, other: &RunHead) -> Option<KeyOrdering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for RunHead {
+    fn cmp(&self, other: &RunHead) -> KeyOrdering {
+        compare_spilled(&other.key, &self.key).then_with(|| other.run_idx.cmp(&self.run_idx))
+    }
+}
+
+/// Merges the aggregates of the spill files like sorted runs and calls `merged` with every key
+/// and its total summed across the files by the overflow policy, in key order, see
+/// `compare_spilled`, stopping at the first error. Only the next aggregate of every file is held
+/// in memory. Returns the number of totals that would have overflowed.
+pub fn for_each_spilled_aggregate<F>(paths: &[PathBuf],
+                                     policy: record_handling::CountOverflowPolicy,
+                                     mut merged: F)
+                                     -> io::Result<usize>
+    where F: FnMut(AggregateELBRecord, i64) -> io::Result<()>
+{
+    let mut runs = Vec::new();
+    for path in paths {
+        runs.push(BufReader::new(File::open(path)?).lines());
+    }
+    let mut heap = BinaryHeap::new();
+    for (run_idx, run) in runs.iter_mut().enumerate() {
+        if let Some((key, total)) = next_aggregate(run)? {
+            heap.push(RunHead {
+                key: key,
+                total: total,
+                run_idx: run_idx,
+            });
+        }
+    }
+    let mut num_overflows = 0;
+    let mut current: Option<(AggregateELBRecord, i64)> = None;
+    while let Some(RunHead { key, total, run_idx }) = heap.pop() {
+        if let Some((next_key, next_total)) = next_aggregate(&mut runs[run_idx])? {
+            heap.push(RunHead {
+                key: next_key,
+                total: next_total,
+                run_idx: run_idx,
+            });
+        }
+        current = match current.take() {
+            Some((current_key, current_total)) => {
+                if current_key != key {
+                    merged(current_key, current_total)?;
+                    Some((key, total))
+                } else {
+                    match policy.add(current_total, total) {
+                        Some(sum) => Some((current_key, sum)),
+                        None => {
+                            num_overflows += 1;
+                            Some((current_key, current_total))
+                        }
+                    }
+                }
+            }
+            None => Some((key, total)),
+        };
+    }
+    if let Some((current_key, current_total)) = current {
+        merged(current_key, current_total)?;
+    }
+    Ok(num_overflows)
+}
+
+/// Adds the aggregates of the spill files to the aggregation by the overflow policy, returning
+/// the number of totals that would have overflowed. The totals of a key are summed across every
+/// file before it's added, see `for_each_spilled_aggregate`, so each key is only added once
+/// however many files hold it.
+pub fn merge_spill_files(paths: &[PathBuf],
+                         dst: &mut ELBRecordAggregation,
+                         policy: record_handling::CountOverflowPolicy)
+                         -> io::Result<usize> {
+    let mut num_dst_overflows = 0;
+    let num_overflows = for_each_spilled_aggregate(paths, policy, |key, total| {
+            let dst_total = dst.entry(key).or_insert(0);
+            match policy.add(*dst_total, total) {
+                Some(sum) => *dst_total = sum,
+                None => num_dst_overflows += 1,
+            }
+            Ok(())
+        })?;
+    Ok(num_overflows + num_dst_overflows)
+}
+
+/// Deletes the spill files, ignoring the ones already gone.
+pub fn remove_spill_files(paths: &[PathBuf]) -> () {
+    for path in paths {
+        if let Err(err) = fs::remove_file(path) {
+            debug!("Failed to remove the spill file {}. {}", path.display(), err);
+        }
+    }
+}
+
+fn next_aggregate(run: &mut Lines<BufReader<File>>)
+                  -> io::Result<Option<(AggregateELBRecord, i64)>> {
+    let line = match run.next() {
+        Some(line) => line?,
+        None => return Ok(None),
+    };
+    let aggregate = line.rfind('\t').and_then(|tab| {
+        let total = line[tab + 1..].parse::<i64>().ok()?;
+        decode_key(&line[..tab]).map(|key| (key, total))
+    });
+    match aggregate {
+        Some(aggregate) => Ok(Some(aggregate)),
+        None => Err(invalid_data(&line)),
+    }
+}
+
+fn invalid_data(line: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData,
+                   format!("The spill file line {} is not an aggregate.", line))
+}
+
+/// Encodes every field of the key as tab separated text that `decode_key` reads back. Tabs,
+/// newlines and backslashes in the fields are escaped.
+pub fn encode_key(key: &AggregateELBRecord) -> String {
+    let fields = [Some(key.day.format("%Y-%m-%d").to_string()),
+                  key.client_address.map(|address| address.to_string()),
+                  key.backend_address.map(|address| address.to_string()),
+                  key.url.clone(),
+                  key.listener_port.clone(),
+                  key.hour.map(|hour| hour.to_string()),
+                  key.elb_status_code.map(|code| code.to_string()),
+                  Some(key.system_name.clone()),
+                  key.path_dimension.clone(),
+                  key.latency_bucket.clone(),
+                  key.day_of_month.map(|day| day.to_string())];
+    fields.iter()
+        .map(|field| field.as_ref().map_or_else(|| NONE_FIELD.to_owned(), |field| escape(field)))
+        .collect::<Vec<_>>()
+        .join("\t")
+}
+
+/// Decodes a key encoded by `encode_key`, or returns None when it isn't one.
+pub fn decode_key(encoded: &str) -> Option<AggregateELBRecord> {
+    let fields = encoded.split('\t')
+        .map(|field| if field == NONE_FIELD { None } else { Some(unescape(field)) })
+        .collect::<Vec<_>>();
+    if fields.len() != 11 {
+        return None;
+    }
+    let day = fields[0]
+        .as_ref()
+        .and_then(|day| format!("{}T00:00:00Z", day).parse::<DateTime<UTC>>().ok())?
+        .date();
+    Some(AggregateELBRecord {
+        day: day,
+        client_address: parse_field::<Ipv4Addr>(&fields[1])?,
+        backend_address: parse_field::<Ipv4Addr>(&fields[2])?,
+        url: fields[3].clone(),
+        listener_port: fields[4].clone(),
+        hour: parse_field::<u32>(&fields[5])?,
+        elb_status_code: parse_field::<u16>(&fields[6])?,
+        system_name: fields[7].clone()?,
+        path_dimension: fields[8].clone(),
+        latency_bucket: fields[9].clone(),
+        day_of_month: parse_field::<u32>(&fields[10])?,
+    })
+}
+
+/// Parses an optional field, returning None when it's present but can't be parsed.
+fn parse_field<T: FromStr>(field: &Option<String>) -> Option<Option<T>> {
+    match *field {
+        Some(ref field) => field.parse::<T>().ok().map(Some),
+        None => Some(None),
+    }
+}
+
+/// Escapes tabs, newlines and backslashes so that the field can be written in a tab separated
+/// line, see `unescape`.
+pub fn escape(field: &str) -> String {
+    field.replace('\\', "\\\\").replace('\t', "\\t").replace('\n', "\\n")
+}
+
+pub fn unescape(field: &str) -> String {
+    let mut unescaped = String::with_capacity(field.len());
+    let mut chars = field.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            unescaped.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('t') => unescaped.push('\t'),
+            Some('n') => unescaped.push('\n'),
+            Some(c) => unescaped.push(c),
+            None => unescaped.push('\\'),
+        }
+    }
+    unescaped
+}
+
+#[cfg(test)]
+mod encode_key_tests {
+
+    use record_handling::AggregateELBRecord;
+    use test_common;
+
+    fn aggregate(system_name: &str) -> AggregateELBRecord {
+        AggregateELBRecord {
+            listener_port: Some("80".to_owned()),
+            hour: Some(23),
+            ..test_common::aggregate(system_name)
+        }
+    }
+
+    #[test]
+    fn decode_key_should_read_back_every_field_of_an_encoded_key() {
+        let key = aggregate("sys1");
+
+        assert_eq!(super::decode_key(&super::encode_key(&key)), Some(key))
+    }
+
+    #[test]
+    fn encode_key_should_escape_fields_that_could_be_mistaken_for_the_layout() {
+        for system_name in &["sys\t1", "sys\n1", "sys\\1", "\\N", "sys\\"] {
+            let key = aggregate(system_name);
+            let encoded = super::encode_key(&key);
+
+            assert!(!encoded.contains('\n'));
+            assert_eq!(encoded.split('\t').count(), 11);
+            assert_eq!(super::decode_key(&encoded), Some(key));
+        }
+    }
+
+    #[test]
+    fn decode_key_should_reject_text_that_is_not_a_key() {
+        assert_eq!(super::decode_key("sys1\t2015-08-15"), None);
+        assert_eq!(super::decode_key(&super::encode_key(&aggregate("sys1")).replace("23", "x")),
+                   None)
+    }
+}
+
+#[cfg(test)]
+mod merge_spill_files_tests {
+
+    use std::collections::HashMap;
+    use record_handling::{AggregateELBRecord, CountOverflowPolicy};
+    use test_common::aggregate;
+
+    fn aggregation(totals: &[(&str, i64)]) -> HashMap<AggregateELBRecord, i64> {
+        totals.iter().map(|&(system_name, total)| (aggregate(system_name), total)).collect()
+    }
+
+    #[test]
+    fn spill_should_empty_the_aggregation_into_a_file_sorted_by_key() {
+        let mut spilled = aggregation(&[("sys2", 2), ("sys1", 1), ("sys3", 3)]);
+
+        let path = super::spill(&mut spilled).unwrap();
+
+        let contents = ::std::fs::read_to_string(&path).unwrap();
+        let mut lines = contents.lines().collect::<Vec<_>>();
+        super::remove_spill_files(&[path]);
+        assert!(spilled.is_empty());
+        assert_eq!(lines.len(), 3);
+        assert!(lines[0].ends_with("\t1") && lines[2].ends_with("\t3"));
+        let unsorted = lines.clone();
+        lines.sort();
+        assert_eq!(lines, unsorted)
+    }
+
+    #[test]
+    fn merge_spill_files_should_sum_the_totals_of_every_file_and_the_aggregation() {
+        let paths = vec![super::spill(&mut aggregation(&[("sys1", 1), ("sys2", 2)])).unwrap(),
+                         super::spill(&mut aggregation(&[("sys2", 20), ("sys3", 30)])).unwrap(),
+                         super::spill(&mut aggregation(&[("sys1", 100)])).unwrap()];
+        let mut dst = aggregation(&[("sys3", 300), ("sys4", 400)]);
+
+        let num_overflows = super::merge_spill_files(&paths, &mut dst, CountOverflowPolicy::Error);
+
+        super::remove_spill_files(&paths);
+        assert_eq!(num_overflows.unwrap(), 0);
+        assert_eq!(dst,
+                   aggregation(&[("sys1", 101), ("sys2", 22), ("sys3", 330), ("sys4", 400)]))
+    }
+
+    #[test]
+    fn merge_spill_files_should_count_the_totals_that_would_overflow() {
+        let max = ::std::i64::MAX;
+        let paths = vec![super::spill(&mut aggregation(&[("sys1", max)])).unwrap(),
+                         super::spill(&mut aggregation(&[("sys1", 1)])).unwrap()];
+        let mut dst = aggregation(&[]);
+
+        let num_overflows = super::merge_spill_files(&paths, &mut dst, CountOverflowPolicy::Error);
+
+        super::remove_spill_files(&paths);
+        assert_eq!(num_overflows.unwrap(), 1);
+        assert_eq!(dst, aggregation(&[("sys1", max)]))
+    }
+
+    #[test]
+    fn for_each_spilled_aggregate_should_merge_the_aggregates_in_key_order() {
+        let paths = vec![super::spill(&mut aggregation(&[("sys3", 3), ("sys1", 1)])).unwrap(),
+                         super::spill(&mut aggregation(&[("sys2", 2), ("sys1", 10)])).unwrap()];
+        let mut merged = Vec::new();
+
+        let num_overflows = super::for_each_spilled_aggregate(&paths,
+                                                              CountOverflowPolicy::Error,
+                                                              |key, total| {
+                                                                  merged.push((key, total));
+                                                                  Ok(())
+                                                              });
+
+        super::remove_spill_files(&paths);
+        assert_eq!(num_overflows.unwrap(), 0);
+        assert_eq!(merged,
+                   vec![(aggregate("sys1"), 11), (aggregate("sys2"), 2), (aggregate("sys3"), 3)])
+    }
+
+    #[test]
+    fn merge_spill_files_should_fail_on_a_missing_file() {
+        let paths = vec![::std::env::temp_dir().join("counter-spill-missing.tsv")];
+
+        assert!(super::merge_spill_files(&paths, &mut HashMap::new(), CountOverflowPolicy::Error)
+            .is_err())
+    }
+}
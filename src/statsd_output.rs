@@ -0,0 +1,438 @@
+use std::collections::HashMap;
+use std::io;
+use std::io::Write;
+use std::net::{TcpStream, UdpSocket};
+use chrono::{Date, UTC};
+use record_handling::AggregateELBRecord;
+
+/// The largest StatsD datagram sent, which fits in the MTU of an Internet path with room for the
+/// headers, as the StatsD documentation recommends.
+const MAX_STATSD_DATAGRAM_BYTES: usize = 512;
+
+/// The lines written to Graphite at a time.
+const MAX_GRAPHITE_BATCH_BYTES: usize = 64 * 1024;
+
+/// How a `MetricSink` writes the metrics of the aggregates.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MetricProtocol {
+    /// StatsD gauges, `path:total|g`. StatsD has no timestamps, so the day and hour of an
+    /// aggregate end its path.
+    Statsd,
+    /// Graphite plaintext lines, `path total timestamp`, timestamped by the day and hour of the
+    /// aggregate.
+    Graphite,
+}
+
+impl MetricProtocol {
+    fn default_port(&self) -> u16 {
+        match *self {
+            MetricProtocol::Statsd => 8125,
+            MetricProtocol::Graphite => 2003,
+        }
+    }
+
+    fn max_batch_bytes(&self) -> usize {
+        match *self {
+            MetricProtocol::Statsd => MAX_STATSD_DATAGRAM_BYTES,
+            MetricProtocol::Graphite => MAX_GRAPHITE_BATCH_BYTES,
+        }
+    }
+}
+
+/// Where to push metrics, as given by a `statsd://host:port` or `graphite://host:port` URI.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SinkAddress {
+    pub protocol: MetricProtocol,
+    pub host: String,
+    pub port: u16,
+}
+
+/// Parses a sink URI, the port defaulting to 8125 for StatsD and 2003 for Graphite.
+pub fn parse_sink_address(uri: &str) -> Result<SinkAddress, String> {
+    let (protocol, address) = if uri.starts_with("statsd://") {
+        (MetricProtocol::Statsd, &uri["statsd://".len()..])
+    } else if uri.starts_with("graphite://") {
+        (MetricProtocol::Graphite, &uri["graphite://".len()..])
+    } else {
+        return Err(format!("{} is not a statsd://host:port or graphite://host:port URI.", uri));
+    };
+    let address = address.trim_right_matches('/');
+    let (host, port) = match address.rfind(':') {
+        Some(split) => {
+            let port = address[split + 1..]
+                .parse::<u16>()
+                .map_err(|_| format!("{} does not name a valid port.", uri))?;
+            (&address[..split], port)
+        }
+        None => (address, protocol.default_port()),
+    };
+    if host.is_empty() {
+        return Err(format!("{} does not name a host.", uri));
+    }
+    Ok(SinkAddress {
+        protocol: protocol,
+        host: host.to_owned(),
+        port: port,
+    })
+}
+
+/// Sends every write as one UDP datagram, for the batches of a StatsD `MetricSink`.
+pub struct Datagrams {
+    socket: UdpSocket,
+}
+
+impl Datagrams {
+    pub fn connect(host: &str, port: u16) -> io::Result<Datagrams> {
+        let socket = UdpSocket::bind("0.0.0.0:0")?;
+        socket.connect((host, port))?;
+        Ok(Datagrams { socket: socket })
+    }
+}
+
+impl Write for Datagrams {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        // The newline ending the last metric of a batch is left out of the datagram.
+        let datagram = if buf.ends_with(b"\n") {
+            &buf[..buf.len() - 1]
+        } else {
+            buf
+        };
+        self.socket.send(datagram)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Connects to a Graphite server's plaintext listener.
+pub fn connect_graphite(host: &str, port: u16) -> io::Result<TcpStream> {
+    TcpStream::connect((host, port))
+}
+
+/// Writes every aggregate as a metric named by its key, see `metric_path`, with its total as
+/// the value. The metrics are written in batches of whole lines, each batch in a single write, so
+/// that a StatsD batch is sent as one datagram, see `Datagrams`. Writing to any other writer,
+/// such as stdout, shows the metrics that would be pushed.
+///
+/// Two keys can have the same metric path, such as the systems sys.1 and sys_1, which would
+/// push one metric over the other. Writing the second of them fails with both keys named, and
+/// `check_metric_paths` finds them before any metric is written.
+pub struct MetricSink<W: Write> {
+    writer: W,
+    protocol: MetricProtocol,
+    prefix: Option<String>,
+    written_keys: MetricKeys,
+    batch: Vec<u8>,
+    max_batch_bytes: usize,
+    num_metrics: usize,
+    num_batches: usize,
+}
+
+impl<W: Write> MetricSink<W> {
+    pub fn new(writer: W, protocol: MetricProtocol, prefix: Option<String>) -> MetricSink<W> {
+        MetricSink {
+            writer: writer,
+            protocol: protocol,
+            prefix: prefix,
+            written_keys: HashMap::new(),
+            batch: Vec::new(),
+            max_batch_bytes: protocol.max_batch_bytes(),
+            num_metrics: 0,
+            num_batches: 0,
+        }
+    }
+
+    /// Writes batches of up to `max_bytes` bytes, in place of the default of the protocol. A
+    /// metric longer than that is written in a batch of its own.
+    pub fn batch_bytes(&mut self, max_bytes: usize) {
+        self.max_batch_bytes = max_bytes.max(1);
+    }
+
+    /// Fails like writing them would when two of the keys have the same metric path, so that a
+    /// collision is found before the first batch is sent.
+    pub fn check_metric_paths<'a, I>(&self, keys: I) -> io::Result<()>
+        where I: IntoIterator<Item = &'a AggregateELBRecord>
+    {
+        let prefix = self.prefix.as_ref().map(|prefix| prefix.as_str());
+        let mut metric_keys = HashMap::new();
+        for key in keys {
+            add_metric_key(&mut metric_keys, self.protocol, prefix, key)?;
+        }
+        Ok(())
+    }
+
+    pub fn num_metrics(&self) -> usize {
+        self.num_metrics
+    }
+
+    pub fn num_batches(&self) -> usize {
+        self.num_batches
+    }
+
+    fn write_batch(&mut self) -> io::Result<()> {
+        if self.batch.is_empty() {
+            return Ok(());
+        }
+        self.writer.write_all(&self.batch)?;
+        self.batch.clear();
+        self.num_batches += 1;
+        Ok(())
+    }
+}
+
+impl<W: Write> ::output::AggregateSink for MetricSink<W> {
+    fn write_aggregate(&mut self, key: &AggregateELBRecord, total: i64) -> io::Result<()> {
+        let path = {
+            let prefix = self.prefix.as_ref().map(|prefix| prefix.as_str());
+            add_metric_key(&mut self.written_keys, self.protocol, prefix, key)?
+        };
+        let line = path_line(self.protocol, path, key, total);
+        if !self.batch.is_empty() && self.batch.len() + line.len() + 1 > self.max_batch_bytes {
+            self.write_batch()?;
+        }
+        self.batch.extend_from_slice(line.as_bytes());
+        self.batch.push(b'\n');
+        self.num_metrics += 1;
+        Ok(())
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        self.write_batch()?;
+        self.writer.flush()
+    }
+}
+
+/// The key of every metric, by its path, day and hour.
+type MetricKeys = HashMap<(String, Date<UTC>, Option<u32>), AggregateELBRecord>;
+
+/// Adds the key to `metric_keys` and returns its metric path, or fails naming both keys when
+/// another key already has the path.
+fn add_metric_key(metric_keys: &mut MetricKeys,
+                  protocol: MetricProtocol,
+                  prefix: Option<&str>,
+                  key: &AggregateELBRecord)
+                  -> io::Result<String> {
+    let path = metric_path(protocol, prefix, key);
+    // Graphite paths leave out the day and hour, which the timestamp holds instead.
+    let metric_key = (path.clone(), key.day, key.hour);
+    if let Some(other_key) = metric_keys.get(&metric_key) {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                  format!("The aggregates keyed {} and {} both have the metric \
+                                           path {}, as every character but letters, digits and \
+                                           hyphens is replaced with an underscore.",
+                                          describe_key(other_key),
+                                          describe_key(key),
+                                          path)));
+    }
+    metric_keys.insert(metric_key, key.clone());
+    Ok(path)
+}
+
+/// The line of the metric of an aggregate, without the newline ending it.
+pub fn metric_line(protocol: MetricProtocol,
+                   prefix: Option<&str>,
+                   key: &AggregateELBRecord,
+                   total: i64)
+                   -> String {
+    path_line(protocol, metric_path(protocol, prefix, key), key, total)
+}
+
+fn path_line(protocol: MetricProtocol,
+             path: String,
+             key: &AggregateELBRecord,
+             total: i64)
+             -> String {
+    match protocol {
+        MetricProtocol::Statsd => format!("{}:{}|g", path, total),
+        MetricProtocol::Graphite => {
+            // A day of the month is no point in time, so those metrics are stamped with now.
+            let timestamp = match key.day_of_month {
+                Some(_) => UTC::now().timestamp(),
+                None => key.day.and_hms(key.hour.unwrap_or(0), 0, 0).timestamp(),
+            };
+            format!("{} {} {}", path, total, timestamp)
+        }
+    }
+}
+
+/// The dotted metric path of an aggregate: the prefix followed by the system name and the values
+/// of the other dimensions the aggregate is grouped by, in the order of the tags of
+/// `record_handling::influx_line`. StatsD paths end with the day and hour of the aggregate,
+/// which Graphite takes from the timestamp instead. Empty values are left out and every character
+/// but letters, digits and hyphens is replaced with an underscore, so that an address such as
+/// 10.0.0.1 becomes the single component 10_0_0_1.
+pub fn metric_path(protocol: MetricProtocol,
+                   prefix: Option<&str>,
+                   key: &AggregateELBRecord)
+                   -> String {
+    let mut path = prefix.map(|prefix| prefix.trim_matches('.').to_owned()).unwrap_or_default();
+    for component in metric_components(protocol, key) {
+        if !path.is_empty() {
+            path.push('.');
+        }
+        path.push_str(&sanitize_component(&component));
+    }
+    path
+}
+
+/// The values of the key making up its metric path, as they are before being sanitized.
+fn metric_components(protocol: MetricProtocol, key: &AggregateELBRecord) -> Vec<String> {
+    let mut components = vec![key.system_name.clone()];
+    components.extend(key.client_address.map(|address| address.to_string()));
+    components.extend(key.backend_address.map(|address| address.to_string()));
+    components.extend(key.url.clone());
+    components.extend(key.listener_port.clone());
+    components.extend(key.elb_status_code.map(|code| code.to_string()));
+    components.extend(key.path_dimension.clone());
+    components.extend(key.latency_bucket.clone());
+    if let Some(day_of_month) = key.day_of_month {
+        components.push(format!("day{}", day_of_month));
+    }
+    if protocol == MetricProtocol::Statsd {
+        if key.day_of_month.is_none() {
+            components.push(key.day.format("%Y-%m-%d").to_string());
+        }
+        components.extend(key.hour.map(|hour| format!("{:02}h", hour)));
+    }
+    components.retain(|component| !component.is_empty());
+    components
+}
+
+/// The values of the key making up its metric path, comma separated, to name it in errors.
+fn describe_key(key: &AggregateELBRecord) -> String {
+    metric_components(MetricProtocol::Statsd, key).join(",")
+}
+
+fn sanitize_component(component: &str) -> String {
+    component.chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '-' { c } else { '_' })
+        .collect()
+}
+
+#[cfg(test)]
+mod metric_sink_tests {
+
+    use output::{write_aggregates, AggregateSink};
+    use super::{MetricProtocol, MetricSink, SinkAddress};
+    use test_common::aggregate;
+
+    #[test]
+    fn parse_sink_address_should_read_the_protocol_host_and_port() {
+        assert_eq!(super::parse_sink_address("statsd://metrics.local:9125"),
+                   Ok(SinkAddress {
+                       protocol: MetricProtocol::Statsd,
+                       host: "metrics.local".to_owned(),
+                       port: 9125,
+                   }));
+        assert_eq!(super::parse_sink_address("graphite://10.0.0.1").map(|address| address.port),
+                   Ok(2003));
+    }
+
+    #[test]
+    fn parse_sink_address_should_reject_other_uris() {
+        assert!(super::parse_sink_address("http://metrics.local:8125").is_err());
+        assert!(super::parse_sink_address("statsd://:8125").is_err());
+        assert!(super::parse_sink_address("statsd://metrics.local:port").is_err());
+    }
+
+    #[test]
+    fn statsd_metrics_should_be_gauges_named_by_the_key_and_day() {
+        let mut key = aggregate("sys.1");
+        key.hour = Some(7);
+
+        let line = super::metric_line(MetricProtocol::Statsd, Some("elb.requests"), &key, 42);
+
+        assert_eq!(line, "elb.requests.sys_1.172_16_1_6.2015-08-15.07h:42|g")
+    }
+
+    #[test]
+    fn graphite_metrics_should_be_timestamped_by_the_day_and_hour() {
+        let mut key = aggregate("sys1");
+        key.hour = Some(1);
+
+        let line = super::metric_line(MetricProtocol::Graphite, None, &key, 42);
+
+        assert_eq!(line, "sys1.172_16_1_6 42 1439600400")
+    }
+
+    #[test]
+    fn metric_paths_should_leave_out_empty_values() {
+        let path = super::metric_path(MetricProtocol::Graphite, Some("elb."), &aggregate(""));
+
+        assert_eq!(path, "elb.172_16_1_6")
+    }
+
+    #[test]
+    fn a_metric_sink_should_write_the_metrics_in_batches_of_whole_lines() {
+        let aggregates = vec![(aggregate("sys1"), 1),
+                              (aggregate("sys2"), 2),
+                              (aggregate("sys3"), 3)];
+        let mut output = Vec::new();
+        let (num_metrics, num_batches) = {
+            let mut sink = MetricSink::new(&mut output, MetricProtocol::Statsd, None);
+            sink.batch_bytes(70);
+            write_aggregates(&aggregates, &mut sink).unwrap();
+            (sink.num_metrics(), sink.num_batches())
+        };
+
+        assert_eq!(num_metrics, 3);
+        assert_eq!(num_batches, 2);
+        assert_eq!(String::from_utf8(output).unwrap(),
+                   "sys1.172_16_1_6.2015-08-15:1|g\nsys2.172_16_1_6.2015-08-15:2|g\n\
+                    sys3.172_16_1_6.2015-08-15:3|g\n")
+    }
+
+    #[test]
+    fn a_metric_sink_should_write_nothing_without_aggregates() {
+        let mut output = Vec::new();
+        {
+            let mut sink = MetricSink::new(&mut output, MetricProtocol::Graphite, None);
+            sink.finish().unwrap();
+            assert_eq!(sink.num_batches(), 0);
+        }
+
+        assert!(output.is_empty())
+    }
+
+    #[test]
+    fn a_metric_sink_should_fail_on_keys_with_the_same_metric_path() {
+        let aggregates = vec![(aggregate("sys.1"), 1), (aggregate("sys_1"), 2)];
+        let mut output = Vec::new();
+        let mut sink = MetricSink::new(&mut output, MetricProtocol::Statsd, None);
+
+        let err = write_aggregates(&aggregates, &mut sink).unwrap_err().to_string();
+
+        assert!(err.contains("sys.1,172.16.1.6,2015-08-15 and sys_1,172.16.1.6,2015-08-15"));
+        assert!(err.contains("sys_1.172_16_1_6.2015-08-15"))
+    }
+
+    #[test]
+    fn checking_the_metric_paths_should_fail_on_keys_with_the_same_metric_path() {
+        let keys = vec![aggregate("sys1"), aggregate("sys.1"), aggregate("sys_1")];
+        let mut output = Vec::new();
+        {
+            let sink = MetricSink::new(&mut output, MetricProtocol::Statsd, None);
+
+            let err = sink.check_metric_paths(&keys).unwrap_err().to_string();
+
+            assert!(err.contains("sys.1,172.16.1.6,2015-08-15 and sys_1,172.16.1.6,2015-08-15"));
+            assert!(sink.check_metric_paths(&keys[..2]).is_ok());
+        }
+        assert!(output.is_empty())
+    }
+
+    #[test]
+    fn graphite_metrics_of_other_hours_should_share_a_metric_path() {
+        let mut first_hour = aggregate("sys1");
+        first_hour.hour = Some(1);
+        let mut second_hour = aggregate("sys1");
+        second_hour.hour = Some(2);
+        let aggregates = vec![(first_hour, 1), (second_hour, 2)];
+        let mut output = Vec::new();
+        let mut sink = MetricSink::new(&mut output, MetricProtocol::Graphite, None);
+
+        assert!(write_aggregates(&aggregates, &mut sink).is_ok())
+    }
+}
@@ -0,0 +1,367 @@
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+#[cfg(feature = "status-socket")]
+use std::io;
+#[cfg(feature = "status-socket")]
+use std::io::{Read, Write};
+#[cfg(feature = "status-socket")]
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+#[cfg(feature = "status-socket")]
+use std::sync::Arc;
+#[cfg(feature = "status-socket")]
+use std::sync::atomic::AtomicBool;
+#[cfg(feature = "status-socket")]
+use std::thread;
+
+/// Counters describing the progress of a run, readable from other threads while the run is in
+/// flight. Every `FileAggregator` of a run shares the raw record counter and the count of the
+/// aggregates they hold, while the `AggregationController` keeps the file and aggregate counts as
+/// messages arrive, and uncounts the aggregates a file handler held once it has merged them. The
+/// counters are only used for reporting and for stopping at a limit, so relaxed ordering is
+/// sufficient.
+#[derive(Debug)]
+pub struct RunMetrics {
+    start: Instant,
+    files_processed: AtomicUsize,
+    num_raw_records: AtomicU64,
+    num_aggregates: AtomicUsize,
+    num_held_aggregates: AtomicUsize,
+}
+
+impl RunMetrics {
+    pub fn new() -> RunMetrics {
+        RunMetrics {
+            start: Instant::now(),
+            files_processed: AtomicUsize::new(0),
+            num_raw_records: AtomicU64::new(0),
+            num_aggregates: AtomicUsize::new(0),
+            num_held_aggregates: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn file_processed(&self) -> () {
+        self.files_processed.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn add_raw_records(&self, num_raw_records: u64) -> () {
+        self.num_raw_records.fetch_add(num_raw_records, Ordering::Relaxed);
+    }
+
+    pub fn set_num_aggregates(&self, num_aggregates: usize) -> () {
+        self.num_aggregates.store(num_aggregates, Ordering::Relaxed);
+    }
+
+    /// Counts an aggregate added to the aggregation of a file handler, returning how many the
+    /// file handlers hold between them. A key held by several of them counts once for each, as
+    /// each of them keeps it in memory.
+    pub fn add_held_aggregate(&self) -> usize {
+        self.num_held_aggregates.fetch_add(1, Ordering::Relaxed) + 1
+    }
+
+    /// Uncounts the aggregates a file handler no longer holds in memory, such as once it spilled
+    /// them to disk or they were merged. The count stops at zero.
+    pub fn remove_held_aggregates(&self, num_aggregates: usize) -> () {
+        let _ = self.num_held_aggregates.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |held| {
+            Some(held.saturating_sub(num_aggregates))
+        });
+    }
+
+    /// Starts counting the aggregates held again, for the file handlers of a new run.
+    pub fn reset_held_aggregates(&self) -> () {
+        self.num_held_aggregates.store(0, Ordering::Relaxed);
+    }
+
+    pub fn files_processed(&self) -> usize {
+        self.files_processed.load(Ordering::Relaxed)
+    }
+
+    pub fn num_raw_records(&self) -> u64 {
+        self.num_raw_records.load(Ordering::Relaxed)
+    }
+
+    pub fn num_aggregates(&self) -> usize {
+        self.num_aggregates.load(Ordering::Relaxed)
+    }
+
+    pub fn num_held_aggregates(&self) -> usize {
+        self.num_held_aggregates.load(Ordering::Relaxed)
+    }
+
+    /// The aggregates are those merged so far plus those the file handlers still hold, so that
+    /// the count grows as the files are read rather than once the file handlers are done. A key
+    /// held by several file handlers counts once for each of them until they are merged.
+    pub fn to_json(&self) -> String {
+        format!("{{\"files_processed\":{},\"num_raw_records\":{},\"num_aggregates\":{},\
+                 \"uptime_secs\":{}}}",
+                self.files_processed(),
+                self.num_raw_records(),
+                self.num_aggregates() + self.num_held_aggregates(),
+                self.start.elapsed().as_secs())
+    }
+}
+
+impl Default for RunMetrics {
+    fn default() -> RunMetrics {
+        RunMetrics::new()
+    }
+}
+
+/// The phases of a run timed by `--profile`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Phase {
+    FileListing,
+    /// Reading and splitting records out of the files, including decoding them as UTF-8.
+    ReadIo,
+    Parsing,
+    /// Extracting the aggregation key from a parsed record and counting it.
+    KeyBuilding,
+    /// Merging the aggregation of every file handler into the final aggregation.
+    Merging,
+}
+
+const PHASES: [Phase; 5] = [Phase::FileListing,
+                            Phase::ReadIo,
+                            Phase::Parsing,
+                            Phase::KeyBuilding,
+                            Phase::Merging];
+
+impl Phase {
+    fn label(&self) -> &'static str {
+        match *self {
+            Phase::FileListing => "file listing",
+            Phase::ReadIo => "read I/O",
+            Phase::Parsing => "parsing",
+            Phase::KeyBuilding => "key building",
+            Phase::Merging => "merging",
+        }
+    }
+
+    fn index(&self) -> usize {
+        PHASES.iter().position(|phase| phase == self).unwrap()
+    }
+}
+
+/// The wall clock time spent in each `Phase`, summed across every thread of the run. The phases
+/// running on the file handlers overlap in time, so their sum can exceed the run's duration.
+#[derive(Debug, Default)]
+pub struct PhaseTimings {
+    nanos: [AtomicU64; 5],
+}
+
+impl PhaseTimings {
+    pub fn new() -> PhaseTimings {
+        Default::default()
+    }
+
+    pub fn add(&self, phase: Phase, elapsed: Duration) -> () {
+        let nanos = elapsed.as_secs() * 1_000_000_000 + elapsed.subsec_nanos() as u64;
+        self.nanos[phase.index()].fetch_add(nanos, Ordering::Relaxed);
+    }
+
+    pub fn time<T, F: FnOnce() -> T>(&self, phase: Phase, f: F) -> T {
+        let start = Instant::now();
+        let result = f();
+        self.add(phase, start.elapsed());
+        result
+    }
+
+    pub fn nanos(&self, phase: Phase) -> u64 {
+        self.nanos[phase.index()].load(Ordering::Relaxed)
+    }
+
+    pub fn report(&self) -> String {
+        let total_nanos: u64 = PHASES.iter().map(|phase| self.nanos(*phase)).sum();
+        let mut report = String::from("Time spent per phase, summed across threads:");
+        for phase in PHASES.iter() {
+            let nanos = self.nanos(*phase);
+            let percent = if total_nanos == 0 {
+                0.0
+            } else {
+                nanos as f64 * 100.0 / total_nanos as f64
+            };
+            report.push_str(&format!("\n  {:<13}{:>12.1} ms {:>6.1}%",
+                                     phase.label(),
+                                     nanos as f64 / 1_000_000.0,
+                                     percent));
+        }
+        report
+    }
+}
+
+/// A minimal HTTP listener answering every request with the current `RunMetrics` as JSON. The
+/// listener is shut down when the server is dropped.
+#[cfg(feature = "status-socket")]
+pub struct StatusServer {
+    local_addr: SocketAddr,
+    shutdown: Arc<AtomicBool>,
+    handle: Option<thread::JoinHandle<()>>,
+}
+
+#[cfg(feature = "status-socket")]
+impl StatusServer {
+    pub fn start<A: ToSocketAddrs>(addr: A, metrics: Arc<RunMetrics>) -> io::Result<StatusServer> {
+        let listener = TcpListener::bind(addr)?;
+        // The listener is polled so the serving thread can notice a shutdown request.
+        listener.set_nonblocking(true)?;
+        let local_addr = listener.local_addr()?;
+        let shutdown = Arc::new(AtomicBool::new(false));
+        let thread_shutdown = shutdown.clone();
+        let handle = thread::spawn(move || {
+            while !thread_shutdown.load(Ordering::Relaxed) {
+                match listener.accept() {
+                    Ok((stream, _)) => respond(stream, &metrics),
+                    Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => {
+                        thread::sleep(Duration::from_millis(100))
+                    }
+                    Err(err) => debug!("The status socket failed to accept a connection. {}", err),
+                }
+            }
+        });
+
+        Ok(StatusServer {
+            local_addr: local_addr,
+            shutdown: shutdown,
+            handle: Some(handle),
+        })
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+}
+
+#[cfg(feature = "status-socket")]
+impl Drop for StatusServer {
+    fn drop(&mut self) {
+        self.shutdown.store(true, Ordering::Relaxed);
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+#[cfg(feature = "status-socket")]
+fn respond(mut stream: TcpStream, metrics: &RunMetrics) -> () {
+    let _ = stream.set_nonblocking(false);
+    let _ = stream.set_read_timeout(Some(Duration::from_millis(1000)));
+    // Every path returns the metrics so the request itself only needs to be drained.
+    let mut request = [0; 1024];
+    let _ = stream.read(&mut request);
+    let body = metrics.to_json();
+    let _ = write!(stream,
+                   "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: \
+                    {}\r\nConnection: close\r\n\r\n{}",
+                   body.len(),
+                   body);
+}
+
+#[cfg(test)]
+mod run_metrics_tests {
+
+    #[test]
+    fn to_json_should_include_the_current_counts() {
+        let metrics = super::RunMetrics::new();
+        metrics.file_processed();
+        metrics.file_processed();
+        metrics.add_raw_records(10);
+        metrics.set_num_aggregates(3);
+
+        let json = metrics.to_json();
+
+        assert!(json.starts_with("{\"files_processed\":2,\"num_raw_records\":10,\
+                                  \"num_aggregates\":3,\"uptime_secs\":"))
+    }
+
+    #[test]
+    fn to_json_should_include_the_aggregates_still_held_by_the_file_handlers() {
+        let metrics = super::RunMetrics::new();
+        metrics.set_num_aggregates(3);
+        metrics.add_held_aggregate();
+        metrics.add_held_aggregate();
+
+        assert!(metrics.to_json().contains("\"num_aggregates\":5,"))
+    }
+
+    #[test]
+    fn the_held_aggregates_should_be_counted_until_reset() {
+        let metrics = super::RunMetrics::new();
+        metrics.add_held_aggregate();
+        metrics.add_held_aggregate();
+
+        assert_eq!(metrics.add_held_aggregate(), 3);
+        metrics.remove_held_aggregates(2);
+        assert_eq!(metrics.num_held_aggregates(), 1);
+        metrics.reset_held_aggregates();
+        assert_eq!(metrics.num_held_aggregates(), 0);
+        metrics.remove_held_aggregates(1);
+        assert_eq!(metrics.num_held_aggregates(), 0)
+    }
+}
+
+#[cfg(test)]
+mod phase_timings_tests {
+
+    use std::time::Duration;
+    use super::Phase;
+
+    #[test]
+    fn add_should_sum_the_time_spent_in_a_phase() {
+        let timings = super::PhaseTimings::new();
+
+        timings.add(Phase::Parsing, Duration::from_millis(2));
+        timings.add(Phase::Parsing, Duration::new(1, 500));
+
+        assert_eq!(timings.nanos(Phase::Parsing), 1_002_000_500);
+        assert_eq!(timings.nanos(Phase::Merging), 0)
+    }
+
+    #[test]
+    fn time_should_return_the_result_of_the_timed_function() {
+        let timings = super::PhaseTimings::new();
+
+        let result = timings.time(Phase::KeyBuilding, || {
+            ::std::thread::sleep(Duration::from_millis(1));
+            42
+        });
+
+        assert_eq!(result, 42);
+        assert!(timings.nanos(Phase::KeyBuilding) >= 1_000_000)
+    }
+
+    #[test]
+    fn report_should_include_every_phase_with_its_share_of_the_total() {
+        let timings = super::PhaseTimings::new();
+        timings.add(Phase::ReadIo, Duration::from_millis(30));
+        timings.add(Phase::Parsing, Duration::from_millis(10));
+
+        let report = timings.report();
+
+        assert_eq!(report.lines().count(), 6);
+        assert!(report.contains("read I/O             30.0 ms   75.0%"));
+        assert!(report.contains("parsing              10.0 ms   25.0%"));
+        assert!(report.contains("merging               0.0 ms    0.0%"))
+    }
+}
+
+#[cfg(all(test, feature = "status-socket"))]
+mod status_server_tests {
+
+    use std::io::{Read, Write};
+    use std::net::TcpStream;
+    use std::sync::Arc;
+
+    #[test]
+    fn the_status_server_should_respond_with_the_current_metrics() {
+        let metrics = Arc::new(super::RunMetrics::new());
+        metrics.add_raw_records(42);
+        let server = super::StatusServer::start("127.0.0.1:0", metrics.clone()).unwrap();
+        let mut stream = TcpStream::connect(server.local_addr()).unwrap();
+        let mut response = String::new();
+
+        let _ = stream.write(b"GET / HTTP/1.1\r\n\r\n");
+        let _ = stream.read_to_string(&mut response);
+
+        assert!(response.starts_with("HTTP/1.1 200 OK"));
+        assert!(response.contains("\"num_raw_records\":42"))
+    }
+}
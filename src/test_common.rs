@@ -1,4 +1,83 @@
+use std::io;
+use std::net::Ipv4Addr;
+use chrono::{DateTime, UTC};
+use output::AggregateSink;
+use record_handling::AggregateELBRecord;
+
 #[cfg(test)]
 // DO NOT MODIFY THESE PATHS. USE SYMLINKS TO REDIRECT TO SOMETHING ELSE.
 pub const TEST_LOG_FILE: &'static str = "./test_artifacts/test_elb_log_file.log";
 pub const TEST_LOG_FILE_AGGS: usize = 88;
+pub const EMPTY_LOG_FILE: &'static str = "./test_artifacts/empty_elb_log_file.log";
+pub const TEST_LOG_ARCHIVE: &'static str = "./test_artifacts/test_elb_log_archive.tar";
+/// TEST_LOG_ARCHIVE gzipped.
+pub const GZIPPED_LOG_ARCHIVE: &'static str = "./test_artifacts/test_elb_log_archive.tar.gz";
+pub const GZIPPED_LOG_FILE: &'static str = "./test_artifacts/test_elb_log_file.log.gz";
+/// The records of TEST_LOG_FILE in two concatenated gzip members, the first holding 100 of them.
+pub const MULTI_MEMBER_GZIPPED_LOG_FILE: &'static str =
+    "./test_artifacts/test_elb_log_file_multi_member.log.gz";
+pub const MALFORMED_LOG_FILE: &'static str = "./test_artifacts/malformed_elb_log_file.log";
+pub const NUL_SEPARATED_LOG_FILE: &'static str =
+    "./test_artifacts/test_elb_log_file_nul_separated.log";
+
+/// The aggregate of the system from 172.16.1.6 on 2015-08-15, with every other field of the key
+/// None. Tests needing other fields set them with the struct update syntax.
+pub fn aggregate(system_name: &str) -> AggregateELBRecord {
+    AggregateELBRecord {
+        day: "2015-08-15T23:43:05.302180Z".parse::<DateTime<UTC>>().unwrap().date(),
+        client_address: Some("172.16.1.6".parse::<Ipv4Addr>().unwrap()),
+        backend_address: None,
+        url: None,
+        listener_port: None,
+        hour: None,
+        elb_status_code: None,
+        system_name: system_name.to_owned(),
+        path_dimension: None,
+        latency_bucket: None,
+        day_of_month: None,
+    }
+}
+
+/// The aggregate of the system with the value of the path dimension, see `aggregate`.
+pub fn aggregate_on_path(system_name: &str, path_dimension: Option<&str>) -> AggregateELBRecord {
+    AggregateELBRecord {
+        path_dimension: path_dimension.map(|dimension| dimension.to_owned()),
+        ..aggregate(system_name)
+    }
+}
+
+/// The aggregate of the system from `client_address` on `day`, given as 2015-08-15.
+pub fn aggregate_on(system_name: &str, day: &str, client_address: &str) -> AggregateELBRecord {
+    AggregateELBRecord {
+        day: format!("{}T00:00:00Z", day).parse::<DateTime<UTC>>().unwrap().date(),
+        client_address: Some(client_address.parse::<Ipv4Addr>().unwrap()),
+        ..aggregate(system_name)
+    }
+}
+
+/// The aggregate of the system from `client_address` on 2015-08-15, see `aggregate`.
+pub fn test_record(system_name: &str, client_address: &str) -> AggregateELBRecord {
+    AggregateELBRecord {
+        client_address: Some(client_address.parse::<Ipv4Addr>().unwrap()),
+        ..aggregate(system_name)
+    }
+}
+
+/// Keeps every aggregate written to it and counts how often it was finished.
+#[derive(Default)]
+pub struct RecordingSink {
+    pub written: Vec<(AggregateELBRecord, i64)>,
+    pub num_finished: usize,
+}
+
+impl AggregateSink for RecordingSink {
+    fn write_aggregate(&mut self, key: &AggregateELBRecord, total: i64) -> io::Result<()> {
+        self.written.push((key.clone(), total));
+        Ok(())
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        self.num_finished += 1;
+        Ok(())
+    }
+}
@@ -0,0 +1,147 @@
+use std::io;
+use std::io::Write;
+use rustc_serialize::json::Json;
+use yaml_rust::{Yaml, YamlEmitter};
+use output;
+use output::AggregateSink;
+use record_handling::{AggregateELBRecord, AggregateMetrics, Timespan};
+
+/// Converts JSON, as rendered by the `output` functions, to YAML, so that both formats name and
+/// order the fields alike. Objects keep the sorted order of their keys.
+pub fn json_to_yaml(json: &Json) -> Yaml {
+    match *json {
+        Json::I64(number) => Yaml::Integer(number),
+        Json::U64(number) => Yaml::Integer(number.min(i64::max_value() as u64) as i64),
+        Json::F64(number) => Yaml::Real(number.to_string()),
+        Json::String(ref string) => Yaml::String(string.clone()),
+        Json::Boolean(boolean) => Yaml::Boolean(boolean),
+        Json::Array(ref items) => Yaml::Array(items.iter().map(json_to_yaml).collect()),
+        Json::Object(ref object) => {
+            Yaml::Hash(object.iter()
+                .map(|(key, value)| (Yaml::String(key.clone()), json_to_yaml(value)))
+                .collect())
+        }
+        Json::Null => Yaml::Null,
+    }
+}
+
+/// Writes YAML as a single document.
+pub fn write_yaml<W: Write>(writer: &mut W, yaml: &Yaml) -> io::Result<()> {
+    let mut document = String::new();
+    YamlEmitter::new(&mut document)
+        .dump(yaml)
+        .map_err(|err| io::Error::new(io::ErrorKind::Other, format!("{:?}", err)))?;
+    writeln!(writer, "{}", document)
+}
+
+/// Writes aggregates as a YAML sequence of mappings holding the same fields as the objects of
+/// `output::JsonSink`, an empty one without aggregates. The sequence is only written once the
+/// sink is finished. When given file subtotals, the sequence is written under `aggregates` in a
+/// mapping holding the subtotals under `file_subtotals`.
+pub struct YamlSink<W: Write> {
+    writer: W,
+    path_dimension_name: Option<String>,
+    max_field_chars: Option<usize>,
+    aggregates: Vec<Yaml>,
+    file_subtotals: Option<Yaml>,
+}
+
+impl<W: Write> YamlSink<W> {
+    pub fn new(writer: W, path_dimension_name: Option<String>) -> YamlSink<W> {
+        YamlSink {
+            writer: writer,
+            path_dimension_name: path_dimension_name,
+            max_field_chars: None,
+            aggregates: Vec::new(),
+            file_subtotals: None,
+        }
+    }
+
+    /// Truncates the text values written to `max_chars` characters, see
+    /// `record_handling::truncate_field`.
+    pub fn truncate_fields_to(&mut self, max_chars: usize) {
+        self.max_field_chars = Some(max_chars);
+    }
+
+    /// Writes the subtotals, see `output::file_subtotals_json`, alongside the aggregates.
+    pub fn include_file_subtotals(&mut self, file_subtotals: Json) {
+        self.file_subtotals = Some(json_to_yaml(&file_subtotals));
+    }
+}
+
+impl<W: Write> AggregateSink for YamlSink<W> {
+    fn write_aggregate(&mut self, key: &AggregateELBRecord, total: i64) -> io::Result<()> {
+        self.write_metrics_aggregate(key, total, None, None)
+    }
+
+    fn write_timespan_aggregate(&mut self,
+                                key: &AggregateELBRecord,
+                                total: i64,
+                                timespan: Option<&Timespan>)
+                                -> io::Result<()> {
+        self.write_metrics_aggregate(key, total, timespan, None)
+    }
+
+    fn write_metrics_aggregate(&mut self,
+                               key: &AggregateELBRecord,
+                               total: i64,
+                               timespan: Option<&Timespan>,
+                               metrics: Option<&AggregateMetrics>)
+                               -> io::Result<()> {
+        let key = output::displayed(key, self.max_field_chars);
+        let path_dimension_name = self.path_dimension_name.as_ref().map(|name| name.as_str());
+        let json =
+            output::counted_aggregate_json(&key, total, timespan, metrics, path_dimension_name);
+        self.aggregates.push(json_to_yaml(&json));
+        Ok(())
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        let aggregates = Yaml::Array(self.aggregates.drain(..).collect());
+        let document = match self.file_subtotals.take() {
+            Some(file_subtotals) => {
+                Yaml::Hash(vec![(Yaml::String("aggregates".to_owned()), aggregates),
+                                (Yaml::String("file_subtotals".to_owned()), file_subtotals)]
+                    .into_iter()
+                    .collect())
+            }
+            None => aggregates,
+        };
+        write_yaml(&mut self.writer, &document)?;
+        self.writer.flush()
+    }
+}
+
+#[cfg(test)]
+mod yaml_sink_tests {
+
+    use yaml_rust::{Yaml, YamlLoader};
+    use output;
+    use record_handling::AggregateELBRecord;
+    use test_common::aggregate;
+
+    fn written_yaml(aggregates: &[(AggregateELBRecord, i64)]) -> Yaml {
+        let mut output = Vec::new();
+        let _ = output::write_aggregates(aggregates, &mut super::YamlSink::new(&mut output, None));
+        let documents = YamlLoader::load_from_str(&String::from_utf8(output).unwrap()).unwrap();
+        assert_eq!(documents.len(), 1);
+        documents[0].clone()
+    }
+
+    #[test]
+    fn the_yaml_sink_should_write_a_sequence_of_mappings_with_a_count() {
+        let yaml = written_yaml(&[(aggregate("sys1"), 3), (aggregate("sys2"), 1)]);
+
+        assert_eq!(yaml[0]["system_name"].as_str(), Some("sys1"));
+        assert_eq!(yaml[0]["day"].as_str(), Some("2015-08-15"));
+        assert_eq!(yaml[0]["client_address"].as_str(), Some("172.16.1.6"));
+        assert_eq!(yaml[0]["count"].as_i64(), Some(3));
+        assert_eq!(yaml[1]["system_name"].as_str(), Some("sys2"));
+        assert_eq!(yaml[1]["count"].as_i64(), Some(1))
+    }
+
+    #[test]
+    fn the_yaml_sink_should_write_an_empty_sequence_without_aggregates() {
+        assert_eq!(written_yaml(&[]), Yaml::Array(Vec::new()))
+    }
+}